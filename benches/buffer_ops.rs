@@ -0,0 +1,109 @@
+//! Benchmarks of the buffer operations on the audio path: interleaving, mixing, RMS and sample
+//! format conversion, across buffer sizes and channel counts.
+//!
+//! Run with `cargo bench --bench buffer_ops`. Criterion compares each run with the previous one,
+//! reporting regressions of these primitives.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use interflow::audio_buffer::{AudioBuffer, AudioRef, SampleFormat};
+
+const BUFFER_SIZES: [usize; 3] = [64, 512, 4096];
+const CHANNEL_COUNTS: [usize; 3] = [1, 2, 8];
+
+/// Buffer filled with a deterministic signal, so that operations do not run on denormals or
+/// zeroes only.
+fn signal(channels: usize, frames: usize) -> AudioBuffer<f32> {
+    AudioBuffer::fill_with(channels, frames, |channel, frame| {
+        ((frame * (channel + 1)) as f32 * 0.01).sin() * 0.5
+    })
+}
+
+/// Run a benchmark for every combination of buffer size and channel count, with a throughput
+/// counted in samples.
+fn bench_sizes(
+    c: &mut Criterion,
+    name: &str,
+    mut bench: impl FnMut(&mut criterion::Bencher, usize, usize),
+) {
+    let mut group = c.benchmark_group(name);
+    for channels in CHANNEL_COUNTS {
+        for frames in BUFFER_SIZES {
+            group.throughput(Throughput::Elements((channels * frames) as u64));
+            let id = BenchmarkId::new(format!("{channels}ch"), frames);
+            group.bench_with_input(id, &(channels, frames), |b, &(channels, frames)| {
+                bench(b, channels, frames)
+            });
+        }
+    }
+    group.finish();
+}
+
+fn interleave(c: &mut Criterion) {
+    bench_sizes(c, "interleave", |b, channels, frames| {
+        let buffer = signal(channels, frames);
+        let mut output = vec![0f32; channels * frames];
+        b.iter(|| buffer.copy_into_interleaved(black_box(&mut output)));
+    });
+}
+
+fn deinterleave(c: &mut Criterion) {
+    bench_sizes(c, "deinterleave", |b, channels, frames| {
+        let mut input = vec![0f32; channels * frames];
+        assert!(signal(channels, frames).copy_into_interleaved(&mut input));
+        let mut buffer = AudioBuffer::<f32>::zeroed(channels, frames);
+        b.iter(|| {
+            let input = AudioRef::from_interleaved(black_box(&input), channels).unwrap();
+            buffer.as_interleaved_mut().assign(&input.as_interleaved());
+        });
+    });
+}
+
+fn mix(c: &mut Criterion) {
+    bench_sizes(c, "mix", |b, channels, frames| {
+        let other = signal(channels, frames);
+        let mut buffer = signal(channels, frames);
+        b.iter(|| buffer.mix(black_box(other.as_ref()), 0.5));
+    });
+}
+
+fn rms(c: &mut Criterion) {
+    bench_sizes(c, "rms", |b, channels, frames| {
+        let buffer = signal(channels, frames);
+        b.iter(|| black_box(&buffer).rms());
+    });
+}
+
+fn format_conversion(c: &mut Criterion) {
+    let formats = [
+        SampleFormat::I16,
+        SampleFormat::I24,
+        SampleFormat::I24Packed,
+        SampleFormat::I32,
+        SampleFormat::F32,
+    ];
+    for format in formats {
+        bench_sizes(c, &format!("encode/{format:?}"), |b, channels, frames| {
+            let mut input = vec![0f32; channels * frames];
+            assert!(signal(channels, frames).copy_into_interleaved(&mut input));
+            let mut output = vec![0u8; input.len() * format.sample_size()];
+            b.iter(|| format.encode(black_box(&input), &mut output));
+        });
+        bench_sizes(c, &format!("decode/{format:?}"), |b, channels, frames| {
+            let mut samples = vec![0f32; channels * frames];
+            assert!(signal(channels, frames).copy_into_interleaved(&mut samples));
+            let mut input = vec![0u8; samples.len() * format.sample_size()];
+            format.encode(&samples, &mut input);
+            b.iter(|| format.decode(black_box(&input), &mut samples));
+        });
+    }
+}
+
+criterion_group!(
+    benches,
+    interleave,
+    deinterleave,
+    mix,
+    rms,
+    format_conversion
+);
+criterion_main!(benches);
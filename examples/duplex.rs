@@ -1,17 +1,15 @@
-use crate::util::sine::SineWave;
 use anyhow::Result;
 use interflow::duplex::AudioDuplexCallback;
 use interflow::prelude::*;
-
-mod util;
+use interflow::signals::Sine;
 
 fn main() -> Result<()> {
     let input = default_input_device();
     let output = default_output_device();
     let mut input_config = input.default_input_config().unwrap();
-    input_config.buffer_size_range = (Some(128), Some(512));
+    input_config.buffer_size_range = (Some(128.into()), Some(512.into()));
     let mut output_config = output.default_output_config().unwrap();
-    output_config.buffer_size_range = (Some(128), Some(512));
+    output_config.buffer_size_range = (Some(128.into()), Some(512.into()));
     let stream =
         duplex::create_duplex_stream(input, input_config, output, output_config, RingMod::new())
             .unwrap();
@@ -22,13 +20,16 @@ fn main() -> Result<()> {
 }
 
 struct RingMod {
-    carrier: SineWave,
+    carrier: Sine,
 }
 
 impl RingMod {
     fn new() -> Self {
         Self {
-            carrier: SineWave::new(440.0),
+            carrier: Sine {
+                amplitude: 1.0,
+                ..Sine::new(440.0)
+            },
         }
     }
 }
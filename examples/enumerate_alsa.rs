@@ -3,11 +3,32 @@ mod util;
 #[cfg(os_alsa)]
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     use crate::util::enumerate::enumerate_devices;
-    use interflow::backends::alsa::AlsaDriver;
+    use interflow::backends::alsa::{AlsaDriver, AlsaPcmKind};
+    use interflow::AudioDevice;
 
     env_logger::init();
 
-    enumerate_devices(AlsaDriver::default())
+    enumerate_devices(AlsaDriver::default())?;
+
+    eprintln!("Cards");
+    for card in AlsaDriver.list_cards()? {
+        eprintln!("\t{}: {} ({})", card.index, card.name, card.description);
+        for card_device in card.devices {
+            let device = &card_device.device;
+            eprintln!(
+                "\t\t{} ({:?}, {:?})",
+                device.name(),
+                device.device_type(),
+                card_device.kind
+            );
+            if let AlsaPcmKind::Hardware = card_device.kind {
+                let formats = device.native_formats()?;
+                let formats = formats.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+                eprintln!("\t\t\tNative formats: {}", formats.join(", "));
+            }
+        }
+    }
+    Ok(())
 }
 
 #[cfg(not(os_alsa))]
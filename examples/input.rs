@@ -63,8 +63,10 @@ impl AudioInputCallback for RmsMeter {
                 100. * pc
             };
             self.progress.set_position(pos as _);
-            self.progress
-                .set_message(format!("Peak: {peak_db:2.1} dB | Runtime: {time:2.3} s"));
+            self.progress.set_message(format!(
+                "Peak: {peak_db:2.1} dB | Runtime: {}",
+                context.timestamp
+            ));
             self.last_show = time;
         }
     }
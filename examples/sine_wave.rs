@@ -1,8 +1,6 @@
 use anyhow::Result;
-use util::sine::SineWave;
 use interflow::prelude::*;
-
-mod util;
+use interflow::signals::Sine;
 
 fn main() -> Result<()> {
     env_logger::init();
@@ -10,7 +8,7 @@ fn main() -> Result<()> {
     let device = default_output_device();
     println!("Using device {}", device.name());
     let stream = device
-        .default_output_stream(SineWave::new(440.0))
+        .default_output_stream(Sine::new(440.0))
         .unwrap();
     println!("Press Enter to stop");
     std::io::stdin().read_line(&mut String::new())?;
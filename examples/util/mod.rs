@@ -1,2 +1 @@
 pub mod enumerate;
-pub mod sine;
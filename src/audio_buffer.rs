@@ -4,7 +4,7 @@ use std::fmt::Formatter;
 use std::ops::{AddAssign, RangeBounds};
 
 use ndarray::{
-    s, Array0, ArrayBase, ArrayView1, ArrayView2, ArrayViewMut1, ArrayViewMut2, AsArray, CowRepr,
+    s, ArrayBase, ArrayView1, ArrayView2, ArrayViewMut1, ArrayViewMut2, AsArray, CowRepr,
     Data, DataMut, DataOwned, Ix1, Ix2, OwnedArcRepr, OwnedRepr, RawData, RawDataClone, ViewRepr,
 };
 
@@ -314,10 +314,7 @@ where
     /// Sets audio data of a single sample, copying the provided value to each channel at that
     /// sample index. Panics when the sample index is out of range.
     pub fn set_mono(&mut self, i: usize, value: S::Elem) {
-        Array0::from_elem([], value)
-            .broadcast((self.num_channels(),))
-            .unwrap()
-            .assign_to(self.storage.column_mut(i))
+        self.storage.column_mut(i).fill(value)
     }
 }
 
@@ -433,6 +430,87 @@ impl Sample for ty {
     }
 }
 
+/// Formats of the samples exchanged with audio drivers. Streams convert between these and the
+/// floating-point samples given to callbacks when a device does not support `f32` natively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SampleFormat {
+    /// Signed 16-bit integer samples.
+    I16,
+    /// Signed 24-bit integer samples, stored in the lower bits of 32-bit words.
+    I24,
+    /// Signed 24-bit integer samples, packed in 3 bytes.
+    I24Packed,
+    /// Signed 32-bit integer samples.
+    I32,
+    /// 32-bit floating-point samples.
+    F32,
+}
+
+impl SampleFormat {
+    /// Size in bytes of a single sample in this format.
+    pub const fn sample_size(&self) -> usize {
+        match self {
+            Self::I16 => 2,
+            Self::I24Packed => 3,
+            Self::I24 | Self::I32 | Self::F32 => 4,
+        }
+    }
+
+    /// Decode native-endian samples of this format into floating-point samples.
+    ///
+    /// Returns the number of samples decoded, which is the minimum between the number of samples
+    /// in `input` and the length of `output`.
+    pub fn decode(&self, input: &[u8], output: &mut [f32]) -> usize {
+        let samples = input.chunks_exact(self.sample_size());
+        let len = samples.len().min(output.len());
+        for (out, bytes) in output.iter_mut().zip(samples) {
+            *out = match self {
+                Self::I16 => i16::from_ne_bytes([bytes[0], bytes[1]]).into_float(),
+                // 24-bit samples are shifted into the upper bits to be read as 32-bit samples
+                Self::I24 => {
+                    (i32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) << 8).into_float()
+                }
+                Self::I24Packed if cfg!(target_endian = "little") => {
+                    i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]).into_float()
+                }
+                Self::I24Packed => {
+                    i32::from_be_bytes([bytes[0], bytes[1], bytes[2], 0]).into_float()
+                }
+                Self::I32 => {
+                    i32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).into_float()
+                }
+                Self::F32 => f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            };
+        }
+        len
+    }
+
+    /// Encode floating-point samples into native-endian samples of this format. Values outside
+    /// of the `-1..=1` range are clipped for integer formats.
+    ///
+    /// Returns the number of samples encoded, which is the minimum between the length of `input`
+    /// and the number of samples that fit in `output`.
+    pub fn encode(&self, input: &[f32], output: &mut [u8]) -> usize {
+        let samples = output.chunks_exact_mut(self.sample_size());
+        let len = samples.len().min(input.len());
+        for (bytes, &sample) in samples.zip(input) {
+            match self {
+                Self::I16 => bytes.copy_from_slice(&i16::from_float(sample).to_ne_bytes()),
+                Self::I24 => bytes.copy_from_slice(&(i32::from_float(sample) >> 8).to_ne_bytes()),
+                Self::I24Packed if cfg!(target_endian = "little") => {
+                    bytes.copy_from_slice(&i32::from_float(sample).to_le_bytes()[1..])
+                }
+                Self::I24Packed => {
+                    bytes.copy_from_slice(&i32::from_float(sample).to_be_bytes()[..3])
+                }
+                Self::I32 => bytes.copy_from_slice(&i32::from_float(sample).to_ne_bytes()),
+                Self::F32 => bytes.copy_from_slice(&sample.to_ne_bytes()),
+            }
+        }
+        len
+    }
+}
+
 impl<T: Sample> AudioBuffer<T> {
     /// Construct a zeroed buffer with the provided channels and sample size.
     ///
@@ -474,6 +552,16 @@ impl<'a, S: DataMut<Elem: Sample>> AudioBufferBase<S> {
         }
     }
 
+    /// Change the amplitude of each channel of this buffer by the amplitude at the same index.
+    /// Channels without a corresponding amplitude are left untouched.
+    pub fn change_channel_amplitudes(&mut self, amplitudes: &[<S::Elem as Sample>::Float]) {
+        for (mut channel, &amplitude) in self.channels_mut().zip(amplitudes) {
+            for s in channel.iter_mut() {
+                s.change_amplitude(amplitude);
+            }
+        }
+    }
+
     /// Mix a buffer into this buffer at the specified amplitude. The audio will be mixed into
     /// this buffer as a result, and the other buffer's amplitude will be changed similarly to
     /// applying [`Self::change_amplitude`] first.
@@ -490,3 +578,88 @@ impl<'a, S: DataMut<Elem: Sample>> AudioBufferBase<S> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::SampleFormat;
+
+    const FORMATS: [SampleFormat; 5] = [
+        SampleFormat::I16,
+        SampleFormat::I24,
+        SampleFormat::I24Packed,
+        SampleFormat::I32,
+        SampleFormat::F32,
+    ];
+
+    fn encode(format: SampleFormat, input: &[f32]) -> Vec<u8> {
+        let mut output = vec![0; input.len() * format.sample_size()];
+        assert_eq!(input.len(), format.encode(input, &mut output));
+        output
+    }
+
+    fn decode(format: SampleFormat, input: &[u8]) -> Vec<f32> {
+        let mut output = vec![0.; input.len() / format.sample_size()];
+        assert_eq!(output.len(), format.decode(input, &mut output));
+        output
+    }
+
+    #[test]
+    fn test_sample_format_roundtrip() {
+        let samples = [-1., -0.5, -0.125, 0., 0.125, 0.5, 0.99];
+        for format in FORMATS {
+            let tolerance = match format {
+                SampleFormat::I16 => 1. / 32767.,
+                SampleFormat::I24 | SampleFormat::I24Packed => 1. / 8388607.,
+                SampleFormat::I32 | SampleFormat::F32 => 1e-6,
+            };
+            let decoded = decode(format, &encode(format, &samples));
+            for (expected, actual) in samples.iter().zip(decoded) {
+                assert!(
+                    (expected - actual).abs() <= tolerance,
+                    "{format:?}: expected {expected}, got {actual}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_sample_format_known_values() {
+        assert_eq!(16383i16.to_ne_bytes(), *encode(SampleFormat::I16, &[0.5]));
+        assert_eq!(32767i16.to_ne_bytes(), *encode(SampleFormat::I16, &[2.]));
+        assert_eq!((-32767i16).to_ne_bytes(), *encode(SampleFormat::I16, &[-1.]));
+        // 24-bit samples are aligned to the lower bits of their 32-bit word
+        assert_eq!(0x40_0000i32.to_ne_bytes(), *encode(SampleFormat::I24, &[0.5]));
+        assert_eq!((-0x40_0000i32).to_ne_bytes(), *encode(SampleFormat::I24, &[-0.5]));
+        let packed = if cfg!(target_endian = "little") {
+            [0x00, 0x00, 0x40]
+        } else {
+            [0x40, 0x00, 0x00]
+        };
+        assert_eq!(packed, *encode(SampleFormat::I24Packed, &[0.5]));
+        assert_eq!(0x4000_0000i32.to_ne_bytes(), *encode(SampleFormat::I32, &[0.5]));
+        assert_eq!(0.25f32.to_ne_bytes(), *encode(SampleFormat::F32, &[0.25]));
+
+        assert_eq!([0.5], *decode_rounded(SampleFormat::I16, &16384i16.to_ne_bytes()));
+        // The upper byte of 24-bit samples is ignored, as it is not always sign-extended
+        let i24 = [0x40_0000i32.to_ne_bytes(), 0x00C0_0000i32.to_ne_bytes()].concat();
+        assert_eq!([0.5, -0.5], *decode_rounded(SampleFormat::I24, &i24));
+        assert_eq!([0.5], *decode_rounded(SampleFormat::I24Packed, &packed));
+        let i32 = (-0x4000_0000i32).to_ne_bytes();
+        assert_eq!([-0.5], *decode_rounded(SampleFormat::I32, &i32));
+        assert_eq!([0.25], *decode_rounded(SampleFormat::F32, &0.25f32.to_ne_bytes()));
+    }
+
+    #[test]
+    fn test_sample_format_partial() {
+        let mut output = [0u8; 5];
+        assert_eq!(2, SampleFormat::I16.encode(&[0.5; 4], &mut output));
+        let mut output = [0f32; 1];
+        assert_eq!(1, SampleFormat::I16.decode(&[0; 6], &mut output));
+    }
+
+    /// Decode samples, rounded to cancel the asymmetry of integer formats.
+    fn decode_rounded(format: SampleFormat, input: &[u8]) -> Vec<f32> {
+        let samples = decode(format, input);
+        samples.into_iter().map(|x| (x * 1e4).round() / 1e4).collect()
+    }
+}
@@ -5,25 +5,49 @@
 //! (PulseAudio, PipeWire) offer ALSA-compatible APIs so that older software can still access the
 //! audio devices through them.
 
+mod worker;
+
 use core::fmt;
+use core::fmt::Write;
+use std::ffi::{CStr, CString};
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
 use std::thread::JoinHandle;
 use std::time::Duration;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::mem;
 use std::borrow::Cow;
 
-use alsa::{device_name::HintIter, pcm, PCM};
+use alsa::mixer::{MilliBel, Mixer, Selem, SelemChannelId, SelemId};
+use alsa::{device_name::HintIter, pcm, Round, PCM};
 use thiserror::Error;
 
-use crate::audio_buffer::{AudioMut, AudioRef};
-use crate::channel_map::{Bitset, ChannelMap32};
-use crate::timestamp::Timestamp;
+use crate::audio_buffer::{AudioMut, AudioRef, SampleFormat};
+use crate::channel_map::{
+    channel_selection, device_channel_count, scatter_channels, select_channels, Bitset,
+    ChannelMap32, CreateBitset,
+};
+use crate::clock::{callback_deadline, HostTime};
+use crate::control::{control_queue, EjectError, StreamControl, StreamFailure};
+use crate::diagnostics;
+use crate::duplex::AudioDuplexCallback;
+use crate::events::{StreamEvent, StreamEventReceiver, StreamEventSender};
+use crate::layout::ChannelPosition;
+use crate::metering::{StreamLevels, StreamMeter};
+use crate::realtime;
+use crate::timestamp::{display_samplerate, CallbackTracker, Timestamp};
 use crate::{
-    AudioCallbackContext, AudioDevice, AudioDriver, AudioInput, AudioInputCallback,
-    AudioInputDevice, AudioOutput, AudioOutputCallback, AudioOutputDevice, AudioStreamHandle,
-    Channel, DeviceType, StreamConfig,
+    AudioCallbackContext, AudioDevice, AudioDeviceVolume, AudioDriver, AudioDriverEvents,
+    AudioDuplexDevice, AudioInput, AudioInputCallback, AudioInputDevice, AudioOutput,
+    AudioOutputCallback, AudioOutputDevice, AudioStreamHandle, BufferSize, Channel, ChannelTrim,
+    DeviceEvent, DeviceType, SendEverywhereButOnWeb, StreamConfig,
 };
 
+pub use worker::AlsaWorker;
+
 /// Type of errors from using the ALSA backend.
 #[derive(Debug, Error)]
 #[error("ALSA error: ")]
@@ -31,6 +55,25 @@ pub enum AlsaError {
     /// Error originates from ALSA itself.
     #[error("{0}")]
     BackendError(#[from] alsa::Error),
+    /// The device has no mixer control for its volume.
+    #[error("No volume control found for device {0}")]
+    NoVolumeControl(String),
+    /// I/O error from the operating system.
+    #[error("I/O error: {0}")]
+    IoError(#[from] io::Error),
+    /// A device of the wrong type was given, for example an output device where an input
+    /// device was expected.
+    #[error("Expected {expected:?} device, got {actual:?}")]
+    InvalidDeviceType {
+        /// Device type that was expected
+        expected: DeviceType,
+        /// Device type of the given device
+        actual: DeviceType,
+    },
+    /// Exclusive access was requested on a device whose hardware device cannot be found, such as
+    /// a PCM not tied to a sound card.
+    #[error("Cannot find the hardware device of {0} for exclusive access")]
+    NoHardwareDevice(String),
 }
 
 /// ALSA driver type. ALSA is statically available without client configuration, therefore this type
@@ -45,7 +88,9 @@ impl AudioDriver for AlsaDriver {
     const DISPLAY_NAME: &'static str = "ALSA";
 
     fn version(&self) -> Result<Cow<str>, Self::Error> {
-        Ok(Cow::Borrowed("ALSA (version unknown)"))
+        // SAFETY: the function returns a pointer to a static, nul-terminated string
+        let version = unsafe { CStr::from_ptr(alsa_sys::snd_asoundlib_version()) };
+        Ok(version.to_string_lossy())
     }
 
     fn default_device(&self, device_type: DeviceType) -> Result<Option<Self::Device>, Self::Error> {
@@ -58,18 +103,414 @@ impl AudioDriver for AlsaDriver {
     }
 }
 
+/// Device events are detected by listening to the device events of the sound subsystem, as sent
+/// by the kernel and udev, and listing devices again when cards are added or removed. Other
+/// systems than Linux have no such events, and devices are listed again periodically instead.
+/// ALSA has no notion of changing default devices, so [`DeviceEvent::DefaultChanged`] is never
+/// sent.
+///
+/// Devices are not kept open between events, so that they stay available to streams. Devices
+/// given in [`DeviceEvent::DeviceRemoved`] events cannot be opened anymore, and their PCM handle
+/// is the `null` PCM.
+impl AudioDriverEvents for AlsaDriver {
+    type Subscription = AlsaEventSubscription;
+
+    fn subscribe_device_events(
+        &self,
+        mut callback: impl 'static + Send + FnMut(DeviceEvent<Self::Device>),
+    ) -> Result<Self::Subscription, Self::Error> {
+        /// Interval at which the thread checks whether the subscription has been dropped.
+        const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+        /// Cards send a burst of events when plugged in, wait for them all before listing devices.
+        const SETTLE_TIME: Duration = Duration::from_millis(250);
+
+        let watcher = DeviceWatcher::open()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let join_handle = std::thread::spawn({
+            let stop = stop.clone();
+            move || {
+                let mut known_pcms = match pcm_hints() {
+                    Ok(hints) => hints
+                        .into_iter()
+                        .map(|(name, direction)| KnownPcm::probe(name, direction).0)
+                        .collect::<Vec<_>>(),
+                    Err(err) => {
+                        diagnostics::error!("Cannot list ALSA devices: {err}");
+                        return;
+                    }
+                };
+                while !stop.load(Ordering::Relaxed) {
+                    match watcher.wait_sound_event(POLL_TIMEOUT) {
+                        Ok(true) => {}
+                        Ok(false) => continue,
+                        Err(err) => {
+                            diagnostics::error!("Cannot receive device events: {err}");
+                            return;
+                        }
+                    }
+                    while let Ok(true) = watcher.wait_sound_event(SETTLE_TIME) {}
+                    let hints = match pcm_hints() {
+                        Ok(hints) => hints,
+                        Err(err) => {
+                            diagnostics::error!("Cannot list ALSA devices: {err}");
+                            continue;
+                        }
+                    };
+                    known_pcms.retain(|known| {
+                        if hints.iter().any(|(name, dir)| known.is(name, *dir)) {
+                            return true;
+                        }
+                        match known.removed_device() {
+                            Ok(device) => callback(DeviceEvent::DeviceRemoved(device)),
+                            Err(err) => {
+                                log::debug!("Cannot report removal of {}: {err}", known.name)
+                            }
+                        }
+                        false
+                    });
+                    for (name, direction) in hints {
+                        if known_pcms.iter().any(|known| known.is(&name, direction)) {
+                            continue;
+                        }
+                        let (known, device) = KnownPcm::probe(name, direction);
+                        known_pcms.push(known);
+                        if let Some(device) = device {
+                            callback(DeviceEvent::DeviceAdded(device));
+                        }
+                    }
+                }
+            }
+        });
+        Ok(AlsaEventSubscription {
+            stop,
+            join_handle: Some(join_handle),
+        })
+    }
+}
+
+/// Subscription to ALSA device events. Events stop being sent when this is dropped.
+pub struct AlsaEventSubscription {
+    stop: Arc<AtomicBool>,
+    join_handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for AlsaEventSubscription {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(join_handle) = self.join_handle.take() {
+            let _ = join_handle.join();
+        }
+    }
+}
+
+/// Names and directions of the PCMs listed by [`AudioDriver::list_devices`], without opening them.
+fn pcm_hints() -> Result<Vec<(String, alsa::Direction)>, alsa::Error> {
+    Ok(HintIter::new(None, c"pcm")?
+        .filter_map(|hint| Some((hint.name?, hint.direction?)))
+        .collect())
+}
+
+/// PCM known to the device event thread. PCMs are only opened briefly when they appear, so that
+/// they stay available to streams and other applications.
+struct KnownPcm {
+    name: String,
+    direction: alsa::Direction,
+    display_name: String,
+    card: Option<i32>,
+}
+
+impl KnownPcm {
+    /// Open the PCM to learn about it, returning the device when it could be opened.
+    fn probe(name: String, direction: alsa::Direction) -> (Self, Option<AlsaDevice>) {
+        match AlsaDevice::new(&name, direction) {
+            Ok(device) => {
+                let known = Self {
+                    name,
+                    direction,
+                    display_name: device.display_name.clone(),
+                    card: device.card,
+                };
+                (known, Some(device))
+            }
+            Err(err) => {
+                log::debug!("Cannot open {name}: {err}");
+                let known = Self {
+                    display_name: name.clone(),
+                    name,
+                    direction,
+                    card: None,
+                };
+                (known, None)
+            }
+        }
+    }
+
+    fn is(&self, name: &str, direction: alsa::Direction) -> bool {
+        self.name == name && self.direction == direction
+    }
+
+    /// Device standing for this PCM once it has been removed, and cannot be opened anymore.
+    fn removed_device(&self) -> Result<AlsaDevice, alsa::Error> {
+        let mut device = AlsaDevice::new("null", self.direction)?;
+        device.name.clone_from(&self.name);
+        device.display_name.clone_from(&self.display_name);
+        device.card = self.card;
+        Ok(device)
+    }
+}
+
+/// Source of the device events of the sound subsystem.
+#[cfg(target_os = "linux")]
+type DeviceWatcher = UeventSocket;
+
+/// Source of the device events of the sound subsystem, which are approximated by listing devices
+/// again periodically on systems without uevents.
+#[cfg(not(target_os = "linux"))]
+struct DeviceWatcher {
+    last_scan: std::cell::Cell<std::time::Instant>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl DeviceWatcher {
+    /// Interval at which devices are listed again.
+    const RESCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+    fn open() -> io::Result<Self> {
+        Ok(Self {
+            last_scan: std::cell::Cell::new(std::time::Instant::now()),
+        })
+    }
+
+    /// Wait for the given timeout, returning whether devices should be listed again.
+    fn wait_sound_event(&self, timeout: Duration) -> io::Result<bool> {
+        std::thread::sleep(timeout);
+        if self.last_scan.get().elapsed() < Self::RESCAN_INTERVAL {
+            return Ok(false);
+        }
+        self.last_scan.set(std::time::Instant::now());
+        Ok(true)
+    }
+}
+
+/// Netlink socket receiving device events from the kernel and from udev.
+#[cfg(target_os = "linux")]
+struct UeventSocket(OwnedFd);
+
+#[cfg(target_os = "linux")]
+impl UeventSocket {
+    /// Multicast groups of kernel and udev events.
+    const GROUPS: u32 = 0b11;
+
+    fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = Self(unsafe { OwnedFd::from_raw_fd(fd) });
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as _;
+        address.nl_groups = Self::GROUPS;
+        let result = unsafe {
+            libc::bind(
+                fd,
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as _,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    /// Wait for an event, returning whether it concerns the sound subsystem. Returns `false` when
+    /// no event arrived before the timeout.
+    fn wait_sound_event(&self, timeout: Duration) -> io::Result<bool> {
+        let mut pollfd = libc::pollfd {
+            fd: self.0.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let result = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as _) };
+        if result < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::Interrupted => Ok(false),
+                _ => Err(err),
+            };
+        }
+        if result == 0 {
+            return Ok(false);
+        }
+        let mut buffer = [0u8; 8192];
+        let len = unsafe {
+            libc::recv(
+                self.0.as_raw_fd(),
+                buffer.as_mut_ptr().cast(),
+                buffer.len(),
+                0,
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Events are lists of null-terminated `KEY=value` properties. udev events start with a
+        // binary header, which never matches a property.
+        Ok(buffer[..len as usize]
+            .split(|&b| b == 0)
+            .any(|property| property == b"SUBSYSTEM=sound"))
+    }
+}
+
+impl AlsaDriver {
+    /// Pair of the default input and output devices, used together as a duplex device. Returns
+    /// `None` if either default device is missing.
+    pub fn default_duplex_device(&self) -> Result<Option<AlsaDuplexDevice>, AlsaError> {
+        let Some(input) = self.default_device(DeviceType::Input)? else {
+            return Ok(None);
+        };
+        let Some(output) = self.default_device(DeviceType::Output)? else {
+            return Ok(None);
+        };
+        AlsaDuplexDevice::new(input, output).map(Some)
+    }
+
+    /// List the sound cards of the system, along with the PCM devices each of them provides.
+    ///
+    /// Unlike [`AudioDriver::list_devices`], which returns every PCM known to ALSA, this groups
+    /// devices by card and tells which kind of PCM each device is, which helps presenting
+    /// meaningful choices in device pickers.
+    pub fn list_cards(&self) -> Result<Vec<AlsaCard>, AlsaError> {
+        let mut cards = vec![];
+        for card in alsa::card::Iter::new() {
+            let card = card?;
+            let mut devices = vec![];
+            for hint in HintIter::new(Some(&card), c"pcm")? {
+                let Some(name) = hint.name else {
+                    continue;
+                };
+                // Hints without a direction provide both playback and capture
+                let directions = [alsa::Direction::Playback, alsa::Direction::Capture]
+                    .into_iter()
+                    .filter(|direction| hint.direction.map_or(true, |d| d == *direction));
+                for direction in directions {
+                    match AlsaDevice::new(&name, direction) {
+                        Ok(device) => devices.push(AlsaCardDevice {
+                            kind: AlsaPcmKind::from_name(&name),
+                            description: hint.desc.clone(),
+                            device,
+                        }),
+                        Err(err) => log::debug!("Cannot open {name} ({direction:?}): {err}"),
+                    }
+                }
+            }
+            cards.push(AlsaCard {
+                index: card.get_index(),
+                name: card.get_name()?,
+                description: card.get_longname()?,
+                devices,
+            });
+        }
+        Ok(cards)
+    }
+}
+
+/// Sound card, as listed by [`AlsaDriver::list_cards`].
+#[derive(Debug, Clone)]
+pub struct AlsaCard {
+    /// Index of the card in the system.
+    pub index: i32,
+    /// Short name of the card, for example "HDA Intel PCH".
+    pub name: String,
+    /// Longer description of the card, usually including its hardware address.
+    pub description: String,
+    /// PCM devices provided by the card.
+    pub devices: Vec<AlsaCardDevice>,
+}
+
+/// PCM device provided by a sound card.
+#[derive(Debug, Clone)]
+pub struct AlsaCardDevice {
+    /// Device which can be used to open streams.
+    pub device: AlsaDevice,
+    /// Description of the PCM given by ALSA, if any.
+    pub description: Option<String>,
+    /// Kind of PCM this device is.
+    pub kind: AlsaPcmKind,
+}
+
+/// Kinds of PCM devices provided by a sound card.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlsaPcmKind {
+    /// Direct access to the hardware (`hw:`), without any conversion or mixing. Only one stream
+    /// can use the device at a time.
+    Hardware,
+    /// Hardware device with automatic format, channel and sample rate conversions (`plughw:`).
+    Plug,
+    /// Default device of the card (`default:`/`sysdefault:`), which usually allows several
+    /// applications to use the card at the same time.
+    Default,
+    /// Any other PCM, such as speaker-specific outputs (`front:`, `surround51:`, ...) or digital
+    /// outputs (`iec958:`, `hdmi:`).
+    Other,
+}
+
+impl AlsaPcmKind {
+    fn from_name(name: &str) -> Self {
+        match name.split_once(':').map_or(name, |(kind, _)| kind) {
+            "hw" => Self::Hardware,
+            "plughw" => Self::Plug,
+            "default" | "sysdefault" => Self::Default,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Tuning of the buffering of ALSA streams, which trades robustness against xruns for latency.
+/// Values left unset are chosen by ALSA.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AlsaStreamOptions {
+    /// Number of periods in the device buffer.
+    pub periods: Option<u32>,
+    /// Size of a period in frames, which is the amount of frames the stream processes at once.
+    /// The nearest period size supported by the device is used.
+    pub period_size: Option<usize>,
+    /// Number of frames which need to be queued before the device starts. Lower values start the
+    /// stream sooner, at the risk of an xrun right after starting. Defaults to the whole buffer.
+    pub start_threshold: Option<usize>,
+    /// Minimum number of frames available in the device buffer before the stream thread is woken
+    /// up. Defaults to one period.
+    pub avail_min: Option<usize>,
+    /// Number of frames of the buffer left unfilled (for playback) or unread (for capture) at
+    /// which the device stops with an xrun. Values larger than the buffer size keep the device
+    /// running through xruns, playing back stale data instead of stopping. Defaults to the whole
+    /// buffer.
+    pub stop_threshold: Option<usize>,
+}
+
 /// Type of ALSA devices.
 #[derive(Clone)]
 pub struct AlsaDevice {
     pcm: Arc<PCM>,
     name: String,
+    display_name: String,
+    card: Option<i32>,
     direction: alsa::Direction,
+    stream_options: AlsaStreamOptions,
+    worker: Option<AlsaWorker>,
 }
 
 impl fmt::Debug for AlsaDevice {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("AlsaDevice")
             .field("name", &self.name)
+            .field("card", &self.card)
             .field("direction", &format!("{:?}", self.direction))
             .finish_non_exhaustive()
     }
@@ -79,7 +520,7 @@ impl AudioDevice for AlsaDevice {
     type Error = AlsaError;
 
     fn name(&self) -> Cow<str> {
-        Cow::Borrowed(self.name.as_str())
+        Cow::Borrowed(self.display_name.as_str())
     }
 
     fn device_type(&self) -> DeviceType {
@@ -90,21 +531,96 @@ impl AudioDevice for AlsaDevice {
     }
 
     fn channel_map(&self) -> impl IntoIterator<Item = Channel> {
-        []
+        let names = self
+            .channel_names()
+            .inspect_err(|err| diagnostics::error!("Cannot get channel names: {err}"))
+            .unwrap_or_default();
+        names.into_iter().enumerate().map(|(index, name)| Channel {
+            index,
+            position: chmap_position(&name),
+            name: Cow::Owned(name),
+        })
+    }
+
+    /// Exclusive streams open the hardware device directly, which is only possible for devices
+    /// of a sound card that no other application is using.
+    fn supports_exclusive(&self) -> bool {
+        let Some(name) = stream_pcm_name(&self.name, self.card, true) else {
+            return false;
+        };
+        PCM::new(&name, self.direction, true)
+            .inspect_err(|err| log::debug!("Cannot open {name}: {err}"))
+            .is_ok()
     }
 
     fn is_config_supported(&self, config: &StreamConfig) -> bool {
-        self.get_hwp(config)
+        let device = match stream_pcm_name(&self.name, self.card, config.exclusive) {
+            None => return false,
+            Some(Cow::Borrowed(_)) => Cow::Borrowed(self),
+            Some(Cow::Owned(name)) => match AlsaDevice::new(&name, self.direction) {
+                Ok(device) => Cow::Owned(device.with_stream_options(self.stream_options)),
+                Err(err) => {
+                    log::debug!("Cannot open {name}: {err}");
+                    return false;
+                }
+            },
+        };
+        let supported = device
+            .get_hwp(config)
             .inspect_err(|err| {
                 log::debug!("{config:#?}");
                 log::debug!("Configuration unsupported: {err}");
             })
-            .is_ok()
+            .is_ok();
+        supported
     }
 
     fn enumerate_configurations(&self) -> Option<impl IntoIterator<Item = StreamConfig>> {
-        log::info!("TODO: enumerate configurations");
-        None::<[StreamConfig; 0]>
+        const TYPICAL_SAMPLERATES: [u32; 8] =
+            [22050, 32000, 44100, 48000, 88200, 96000, 176400, 192000];
+        let hwp = pcm::HwParams::any(&self.pcm)
+            .inspect_err(|err| diagnostics::error!("Cannot get hardware parameters: {err}"))
+            .ok()?;
+        if negotiate_format(&hwp, None).is_none() {
+            log::debug!("Device does not support any of the sample formats used by streams");
+            return Some(vec![]);
+        }
+        let channels_max = hwp
+            .get_channels_max()
+            .ok()?
+            .min(ChannelMap32::default().capacity() as _);
+        let channel_counts = (hwp.get_channels_min().ok()?..=channels_max)
+            .filter(|&count| hwp.test_channels(count).is_ok())
+            .collect::<Vec<_>>();
+        let period_size = |frames: pcm::Frames| BufferSize::Frames(frames as _);
+        let buffer_size_range = (
+            hwp.get_period_size_min().ok().map(period_size),
+            hwp.get_period_size_max().ok().map(period_size),
+        );
+        let direction = self.direction;
+        let configs = TYPICAL_SAMPLERATES
+            .into_iter()
+            .filter(|&rate| hwp.test_rate(rate).is_ok())
+            .flat_map(|samplerate| {
+                channel_counts.iter().map(move |&count| {
+                    let channels = ChannelMap32::first_n(count as usize);
+                    let (input_channels, output_channels) = direction_channels(direction, channels);
+                    StreamConfig {
+                        samplerate: samplerate as _,
+                        input_channels,
+                        output_channels,
+                        buffer_size_range,
+                        exclusive: false,
+                        fallback_to_shared: false,
+                        sample_format: None,
+                        input_trim: ChannelTrim::default(),
+                        output_trim: ChannelTrim::default(),
+                        metering: false,
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        Some(configs)
     }
 }
 
@@ -120,8 +636,11 @@ impl AudioInputDevice for AlsaDevice {
         stream_config: StreamConfig,
         callback: Callback,
     ) -> Result<Self::StreamHandle<Callback>, Self::Error> {
+        let stream_config = stream_config.resolve_exclusive(|| self.supports_exclusive());
         Ok(AlsaStream::new_input(
-            self.name.clone(),
+            self.stream_pcm_name(stream_config.exclusive)?,
+            self.stream_options,
+            self.worker.as_ref(),
             stream_config,
             callback,
         ))
@@ -140,57 +659,207 @@ impl AudioOutputDevice for AlsaDevice {
         stream_config: StreamConfig,
         callback: Callback,
     ) -> Result<Self::StreamHandle<Callback>, Self::Error> {
+        let stream_config = stream_config.resolve_exclusive(|| self.supports_exclusive());
         Ok(AlsaStream::new_output(
-            self.name.clone(),
+            self.stream_pcm_name(stream_config.exclusive)?,
+            self.stream_options,
+            self.worker.as_ref(),
             stream_config,
             callback,
         ))
     }
 }
 
+/// The volume is read from and written to the mixer of the card the device belongs to, using dB
+/// values when the mixer control supports them so that the volume follows a perceptual scale.
+impl AudioDeviceVolume for AlsaDevice {
+    fn volume(&self) -> Result<f32, Self::Error> {
+        let mixer = Mixer::new(&self.mixer_name(), false)?;
+        Ok(self.mixer_control(&mixer)?.volume()?)
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), Self::Error> {
+        let mixer = Mixer::new(&self.mixer_name(), false)?;
+        Ok(self.mixer_control(&mixer)?.set_volume(volume)?)
+    }
+
+    fn is_muted(&self) -> Result<bool, Self::Error> {
+        let mixer = Mixer::new(&self.mixer_name(), false)?;
+        Ok(self.mixer_control(&mixer)?.is_muted()?)
+    }
+
+    fn set_muted(&self, muted: bool) -> Result<(), Self::Error> {
+        let mixer = Mixer::new(&self.mixer_name(), false)?;
+        Ok(self.mixer_control(&mixer)?.set_muted(muted)?)
+    }
+}
+
 impl AlsaDevice {
     /// Shortcut constructor for getting ALSA devices directly.
+    ///
+    /// This opens the `default` PCM, which follows the user configuration (`~/.asoundrc`, or the
+    /// `ALSA_CARD` environment variable). The card it resolves to is available from
+    /// [`Self::card`], and is included in the device name.
     pub fn default_device(device_type: DeviceType) -> Result<Option<Self>, alsa::Error> {
         let direction = match device_type {
             DeviceType::Input => alsa::Direction::Capture,
             DeviceType::Output => alsa::Direction::Playback,
             _ => return Ok(None),
         };
-        let pcm = Arc::new(PCM::new("default", direction, true)?);
-        Ok(Some(Self {
-            pcm,
-            direction,
-            name: "default".to_string(),
-        }))
+        Self::new("default", direction).map(Some)
+    }
+
+    /// Sample formats natively supported by the device. Streams convert from and to 16, 24 and
+    /// 32-bit integer and 32-bit floating-point samples in either byte order; other formats are
+    /// only available through plugin PCMs (`plughw`, `default`, ...), which convert them.
+    pub fn native_formats(&self) -> Result<Vec<pcm::Format>, AlsaError> {
+        let hwp = pcm::HwParams::any(&self.pcm)?;
+        Ok(pcm::Format::all()
+            .iter()
+            .copied()
+            .filter(|&format| hwp.test_format(format).is_ok())
+            .collect())
+    }
+
+    /// Index of the sound card this device resolves to, if any. Devices not tied to a single card,
+    /// such as PCMs routed to a sound server, return `None`.
+    pub fn card(&self) -> Option<i32> {
+        self.card
+    }
+
+    /// Name of the PCM opened by this device, as given to ALSA. Unlike [`AudioDevice::name`], this
+    /// never includes the name of the card it resolves to.
+    pub fn pcm_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Use the given buffering options for streams created from this device.
+    pub fn with_stream_options(mut self, stream_options: AlsaStreamOptions) -> Self {
+        self.stream_options = stream_options;
+        self
+    }
+
+    /// Run the input and output streams created from this device on the given shared worker,
+    /// instead of spawning an audio thread for each of them. Duplex streams always get their own
+    /// thread.
+    pub fn with_worker(mut self, worker: &AlsaWorker) -> Self {
+        self.worker = Some(worker.clone());
+        self
+    }
+
+    fn stream_pcm_name(&self, exclusive: bool) -> Result<String, AlsaError> {
+        stream_pcm_name(&self.name, self.card, exclusive)
+            .map(Cow::into_owned)
+            .ok_or_else(|| AlsaError::NoHardwareDevice(self.name.clone()))
     }
 
     fn new(name: &str, direction: alsa::Direction) -> Result<Self, alsa::Error> {
         let pcm = PCM::new(name, direction, true)?;
-        let pcm = Arc::new(pcm);
+        let card = resolve_card(&pcm, name);
+        // PCMs which do not name their card (`default`, user-defined aliases, ...) get the name of
+        // the card they resolve to appended, so that they can be told apart
+        let card_name = card
+            .filter(|_| pcm_card_arg(name).is_none())
+            .and_then(|index| alsa::Card::new(index).get_name().ok());
+        let display_name = match card_name {
+            Some(card_name) => format!("{name} ({card_name})"),
+            None => name.to_string(),
+        };
         Ok(Self {
             name: name.to_string(),
+            display_name,
+            card,
             direction,
-            pcm,
+            pcm: Arc::new(pcm),
+            stream_options: AlsaStreamOptions::default(),
+            worker: None,
         })
     }
 
+    /// Name of the mixer controlling the card of this device, falling back to the default mixer
+    /// for devices which are not tied to a card.
+    fn mixer_name(&self) -> String {
+        self.card
+            .map_or_else(|| "default".to_string(), |card| format!("hw:{card}"))
+    }
+
+    /// Names of the device channels, taken from the largest channel map reported by the device
+    /// (e.g. "FL", "FR", "RL", ...). Devices without channel maps get generic names instead.
+    fn channel_names(&self) -> Result<Vec<String>, alsa::Error> {
+        // Channel positions are read from the printed channel map, as the position enum does not
+        // cover driver-specific positions or flags
+        let positions = self
+            .pcm
+            .query_chmaps()
+            .filter_map(|(_, chmap)| {
+                let mut printed = String::new();
+                write!(printed, "{chmap}").ok()?;
+                Some(
+                    printed
+                        .split_whitespace()
+                        .map(str::to_string)
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .max_by_key(Vec::len)
+            .unwrap_or_default();
+        if !positions.is_empty() {
+            return Ok(positions);
+        }
+        let hwp = pcm::HwParams::any(&self.pcm)?;
+        let channels = hwp
+            .get_channels_max()?
+            .min(ChannelMap32::default().capacity() as _);
+        Ok((0..channels)
+            .map(|ch| format!("Channel {}", ch + 1))
+            .collect())
+    }
+
+    fn mixer_control<'a>(&self, mixer: &'a Mixer) -> Result<MixerControl<'a>, AlsaError> {
+        MixerControl::find(mixer, self.direction)
+            .ok_or_else(|| AlsaError::NoVolumeControl(self.name.clone()))
+    }
+
+    /// Channels of the stream configuration opened on this device.
+    fn stream_channels(&self, config: &StreamConfig) -> ChannelMap32 {
+        match self.direction {
+            alsa::Direction::Capture => config.input_channels,
+            alsa::Direction::Playback => config.output_channels,
+        }
+    }
+
     fn get_hwp(&self, config: &StreamConfig) -> Result<pcm::HwParams, alsa::Error> {
         let hwp = pcm::HwParams::any(&self.pcm)?;
-        hwp.set_channels(config.channels as _)?;
+        hwp.set_channels(device_channel_count(self.stream_channels(config)) as _)?;
+        if config.exclusive {
+            // Exclusive streams run at the hardware rate, without software resampling
+            hwp.set_rate_resample(false)?;
+        }
         hwp.set_rate(config.samplerate as _, alsa::ValueOr::Nearest)?;
-        hwp.set_format(pcm::Format::float())?;
+        let format = negotiate_format(&hwp, config.sample_format)
+            .ok_or(alsa::Error::unsupported("snd_pcm_hw_params_set_format"))?;
+        hwp.set_format(format.alsa)?;
         hwp.set_access(pcm::Access::RWInterleaved)?;
+        if let Some(period_size) = self.stream_options.period_size {
+            hwp.set_period_size_near(period_size as _, alsa::ValueOr::Nearest)?;
+        }
+        if let Some(periods) = self.stream_options.periods {
+            hwp.set_periods(periods, alsa::ValueOr::Nearest)?;
+        }
         Ok(hwp)
     }
 
     fn apply_config(
         &self,
         config: &StreamConfig,
-    ) -> Result<(pcm::HwParams, pcm::SwParams, pcm::IO<f32>), alsa::Error> {
+    ) -> Result<(pcm::HwParams, pcm::SwParams, pcm::IO<u8>, DeviceFormat), alsa::Error> {
         let hwp = self.get_hwp(config)?;
         self.pcm.hw_params(&hwp)?;
-        let io = self.pcm.io_f32()?;
+        // Samples are converted from and to the negotiated format in the stream thread
+        let io = self.pcm.io_bytes();
         let hwp = self.pcm.hw_params_current()?;
+        let format = DeviceFormat::from_alsa(hwp.get_format()?)
+            .ok_or(alsa::Error::unsupported("snd_pcm_hw_params_get_format"))?;
         let swp = self.pcm.sw_params_current()?;
 
         log::debug!("Apply config: hwp {hwp:#?}");
@@ -198,166 +867,598 @@ impl AlsaDevice {
 
         // TODO: Forward buffer size hints
 
-        swp.set_start_threshold(hwp.get_buffer_size()?)?;
+        let options = self.stream_options;
+        let boundary = swp.get_boundary()?;
+        let frames =
+            |frames: usize| pcm::Frames::try_from(frames).map_or(boundary, |f| f.min(boundary));
+        swp.set_start_threshold(match options.start_threshold {
+            Some(start_threshold) => frames(start_threshold),
+            None => hwp.get_buffer_size()?,
+        })?;
+        if let Some(avail_min) = options.avail_min {
+            swp.set_avail_min(frames(avail_min))?;
+        }
+        if let Some(stop_threshold) = options.stop_threshold {
+            swp.set_stop_threshold(frames(stop_threshold))?;
+        }
+        // Status timestamps are used for stream timestamps, and need to come from a clock which
+        // doesn't jump
+        swp.set_tstamp_mode(true)?;
+        if let Err(err) = swp.set_tstamp_type(pcm::TstampType::Monotonic) {
+            log::debug!("Cannot use monotonic timestamps: {err}");
+        }
         self.pcm.sw_params(&swp)?;
-        Ok((hwp, swp, io))
+        Ok((hwp, swp, io, format))
     }
 
     fn default_config(&self) -> Result<StreamConfig, AlsaError> {
         let samplerate = 48000.; // Default ALSA sample rate
         let channel_count = 2; // Stereo stream
-        let channels = 1 << (channel_count - 1);
+        let channels = ChannelMap32::first_n(channel_count);
+        let (input_channels, output_channels) = direction_channels(self.direction, channels);
         Ok(StreamConfig {
             samplerate: samplerate as _,
-            channels,
+            input_channels,
+            output_channels,
             buffer_size_range: (None, None),
             exclusive: false,
+            fallback_to_shared: false,
+            sample_format: None,
+            input_trim: ChannelTrim::default(),
+            output_trim: ChannelTrim::default(),
+            metering: false,
+        })
+    }
+}
+
+/// Input and output channel maps of a configuration opening the given channels in one direction.
+fn direction_channels(
+    direction: alsa::Direction,
+    channels: ChannelMap32,
+) -> (ChannelMap32, ChannelMap32) {
+    match direction {
+        alsa::Direction::Capture => (channels, 0),
+        alsa::Direction::Playback => (0, channels),
+    }
+}
+
+/// Pair of ALSA devices used together as a single duplex device.
+///
+/// When both devices belong to the same sound card, their PCMs are linked so that they start
+/// together and run from the same clock. Devices from different cards are run side by side and
+/// drift apart over time.
+#[derive(Debug, Clone)]
+pub struct AlsaDuplexDevice {
+    input: AlsaDevice,
+    output: AlsaDevice,
+}
+
+impl AlsaDuplexDevice {
+    /// Create a duplex device from a capture device and a playback device.
+    pub fn new(input: AlsaDevice, output: AlsaDevice) -> Result<Self, AlsaError> {
+        if input.direction != alsa::Direction::Capture {
+            return Err(AlsaError::InvalidDeviceType {
+                expected: DeviceType::Input,
+                actual: input.device_type(),
+            });
+        }
+        if output.direction != alsa::Direction::Playback {
+            return Err(AlsaError::InvalidDeviceType {
+                expected: DeviceType::Output,
+                actual: output.device_type(),
+            });
+        }
+        Ok(Self { input, output })
+    }
+
+    /// Input device of this pair.
+    pub fn input(&self) -> &AlsaDevice {
+        &self.input
+    }
+
+    /// Output device of this pair.
+    pub fn output(&self) -> &AlsaDevice {
+        &self.output
+    }
+
+    /// Whether both devices belong to the same sound card, in which case their streams are
+    /// linked.
+    pub fn is_same_card(&self) -> bool {
+        self.input.card.is_some() && self.input.card == self.output.card
+    }
+}
+
+impl AudioDevice for AlsaDuplexDevice {
+    type Error = AlsaError;
+
+    fn name(&self) -> Cow<'_, str> {
+        Cow::Owned(format!("{} / {}", self.input.name(), self.output.name()))
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Duplex
+    }
+
+    fn channel_map(&self) -> impl IntoIterator<Item = Channel<'_>> {
+        self.output.channel_map()
+    }
+
+    fn is_config_supported(&self, config: &StreamConfig) -> bool {
+        self.input.is_config_supported(config) && self.output.is_config_supported(config)
+    }
+
+    fn supports_exclusive(&self) -> bool {
+        self.input.supports_exclusive() && self.output.supports_exclusive()
+    }
+
+    fn enumerate_configurations(&self) -> Option<impl IntoIterator<Item = StreamConfig>> {
+        None::<[StreamConfig; 0]>
+    }
+}
+
+impl AudioDuplexDevice for AlsaDuplexDevice {
+    type StreamHandle<Callback: AudioDuplexCallback> = AlsaStream<Callback>;
+
+    fn default_duplex_config(&self) -> Result<StreamConfig, Self::Error> {
+        Ok(StreamConfig {
+            input_channels: self.input.default_config()?.input_channels,
+            ..self.output.default_config()?
         })
     }
+
+    fn create_duplex_stream<Callback: SendEverywhereButOnWeb + AudioDuplexCallback>(
+        &self,
+        stream_config: StreamConfig,
+        callback: Callback,
+    ) -> Result<Self::StreamHandle<Callback>, Self::Error> {
+        let stream_config = stream_config.resolve_exclusive(|| self.supports_exclusive());
+        Ok(AlsaStream::new_duplex(
+            self.input.stream_pcm_name(stream_config.exclusive)?,
+            self.output.stream_pcm_name(stream_config.exclusive)?,
+            self.output.stream_options,
+            stream_config,
+            callback,
+        ))
+    }
 }
 
 /// Type of ALSA streams.
 ///
 /// The audio stream implementation relies on the synchronous API for now, as the [`alsa`] crate
 /// does not seem to wrap the asynchronous API as of now. A separate I/O thread is spawned when
-/// creating a stream, and is stopped when caling [`AudioInputDevice::eject`] /
-/// [`AudioOutputDevice::eject`].
+/// creating a stream, unless it runs on an [`AlsaWorker`], and is stopped when caling
+/// [`AudioInputDevice::eject`] / [`AudioOutputDevice::eject`].
 pub struct AlsaStream<Callback> {
-    eject_signal: Arc<AtomicBool>,
-    join_handle: JoinHandle<Result<Callback, AlsaError>>,
+    control: StreamControl<Callback>,
+    thread: StreamThread,
+    events: StreamEventSender,
+    meter: StreamMeter,
 }
 
 impl<Callback> AudioStreamHandle<Callback> for AlsaStream<Callback> {
-    type Error = AlsaError;
+    type Error = EjectError<Callback, AlsaError>;
 
     fn eject(self) -> Result<Callback, Self::Error> {
-        self.eject_signal.store(true, Ordering::Relaxed);
-        self.join_handle.join().unwrap()
+        self.control.request_eject();
+        let result = self.thread.join();
+        let callback = self.control.collect(result)?;
+        self.events.send(StreamEvent::Ejected);
+        Ok(callback)
+    }
+
+    fn subscribe_events(&self) -> StreamEventReceiver {
+        self.events.subscribe()
+    }
+
+    fn levels(&self) -> Option<StreamLevels> {
+        self.meter.levels()
+    }
+
+    fn control(&self) -> Option<&StreamControl<Callback>> {
+        Some(&self.control)
     }
 }
 
+/// Outcome of a cycle of an input or output stream.
+enum Step {
+    Continue,
+    /// The stream has nothing to process for the given duration, e.g. while the device is paused
+    Sleep(Duration),
+    Stop,
+}
+
+/// I/O of an input or output stream, processed one cycle at a time, either on its own thread or on
+/// a shared worker.
+struct StreamCycle {
+    /// PCM of the stream, polled by workers to know when to process a cycle
+    pcm: Arc<PCM>,
+    step: Box<dyn FnMut() -> Result<Step, AlsaError>>,
+}
+
+/// Opens the device of a stream on the thread processing it, as ALSA devices cannot be shared
+/// between threads.
+type CycleSetup = Box<dyn Send + FnOnce(StreamEventSender) -> Result<StreamCycle, AlsaError>>;
+
+/// Thread processing the I/O of a stream.
+enum StreamThread {
+    Dedicated(JoinHandle<Result<(), AlsaError>>),
+    /// Receives the outcome of the stream once the worker has stopped processing it
+    Worker(mpsc::Receiver<Result<(), StreamFailure<AlsaError>>>),
+}
+
+impl StreamThread {
+    fn spawn(
+        events: &StreamEventSender,
+        worker: Option<&AlsaWorker>,
+        setup: impl 'static + Send + FnOnce(StreamEventSender) -> Result<StreamCycle, AlsaError>,
+    ) -> Self {
+        if let Some(worker) = worker {
+            return Self::Worker(worker.register(events, Box::new(setup)));
+        }
+        Self::Dedicated(spawn_audio_thread(events, move |events| {
+            let mut cycle = setup(events)?;
+            loop {
+                let step = (cycle.step)()
+                    .inspect_err(|err| diagnostics::error!("Audio thread error: {err}"))?;
+                match step {
+                    Step::Continue => {}
+                    Step::Sleep(duration) => std::thread::sleep(duration),
+                    Step::Stop => break Ok(()),
+                }
+            }
+        }))
+    }
+
+    fn join(self) -> Result<(), StreamFailure<AlsaError>> {
+        match self {
+            Self::Dedicated(handle) => match handle.join() {
+                Ok(result) => result.map_err(StreamFailure::Backend),
+                Err(payload) => Err(StreamFailure::panicked(payload)),
+            },
+            Self::Worker(result) => result.recv().unwrap_or_else(|_| {
+                Err(StreamFailure::Panicked("ALSA worker thread has stopped".into()))
+            }),
+        }
+    }
+}
+
+/// Spawn the I/O thread of a stream, which reports the error it stops with, if any. The callback
+/// is given back to the handle when its slot, moved into the thread, is dropped.
+fn spawn_audio_thread(
+    events: &StreamEventSender,
+    run: impl 'static + Send + FnOnce(StreamEventSender) -> Result<(), AlsaError>,
+) -> JoinHandle<Result<(), AlsaError>> {
+    let events = events.clone();
+    std::thread::spawn(move || {
+        run(events.clone())
+            .inspect_err(|err| events.send(StreamEvent::Error(err.to_string().into())))
+    })
+}
+
 impl<Callback: 'static + Send + AudioInputCallback> AlsaStream<Callback> {
-    fn new_input(name: String, stream_config: StreamConfig, mut callback: Callback) -> Self {
-        let eject_signal = Arc::new(AtomicBool::new(false));
-        let join_handle = std::thread::spawn({
-            let eject_signal = eject_signal.clone();
-            move || {
-                let device = AlsaDevice::new(&name, alsa::Direction::Capture)?;
-                let (hwp, _, io) = device.apply_config(&stream_config)?;
+    fn new_input(
+        name: String,
+        stream_options: AlsaStreamOptions,
+        worker: Option<&AlsaWorker>,
+        stream_config: StreamConfig,
+        callback: Callback,
+    ) -> Self {
+        let (control, mut receiver, mut callback) = control_queue(callback);
+        let events = StreamEventSender::new();
+        let (meter, mut input_tap, _) =
+            StreamMeter::new(stream_config.metering, stream_config.input_channels.count(), 0);
+        let thread = StreamThread::spawn(&events, worker, {
+            move |events| {
+                let device = AlsaDevice::new(&name, alsa::Direction::Capture)?
+                    .with_stream_options(stream_options);
+                let (hwp, _, _, format) = device.apply_config(&stream_config)?;
                 let (_, period_size) = device.pcm.get_params()?;
                 let period_size = period_size as usize;
                 log::info!("Period size : {period_size}");
+                // Buffers hold the largest period the device can use, and each iteration
+                // processes at most that many frames
+                let max_frames = hwp.get_period_size_max()?.max(period_size as _) as usize;
                 let num_channels = hwp.get_channels()? as usize;
                 log::info!("Num channels: {num_channels}");
+                log::info!("Format      : {format:?}");
                 let samplerate = hwp.get_rate()? as f64;
-                log::info!("Sample rate : {samplerate}");
+                log::info!("Sample rate : {}", display_samplerate(samplerate));
+                let selection = channel_selection(stream_config.input_channels, num_channels);
                 let stream_config = StreamConfig {
                     samplerate,
-                    channels: ChannelMap32::default()
-                        .with_indices(std::iter::repeat(1).take(num_channels)),
-                    buffer_size_range: (Some(period_size), Some(period_size)),
-                    exclusive: false,
+                    buffer_size_range: (Some(period_size.into()), Some(max_frames.into())),
+                    sample_format: Some(format.sample),
+                    ..stream_config
                 };
-                let mut timestamp = Timestamp::new(samplerate);
-                let mut buffer = vec![0f32; period_size * num_channels];
+                let timestamp = Timestamp::new(samplerate);
+                let mut clock = StreamClock::new(samplerate, alsa::Direction::Capture, &device.pcm);
+                let mut tracker = CallbackTracker::default();
+                let mut buffer = vec![0f32; max_frames * num_channels];
+                let mut raw_buffer = vec![0u8; buffer.len() * format.sample_size()];
+                let mut selected_buffer =
+                    vec![0f32; selection.as_ref().map_or(0, |sel| max_frames * sel.len())];
+                let trim = stream_config
+                    .input_trim
+                    .amplitudes(selection.as_ref().map_or(num_channels, Vec::len));
                 device.pcm.prepare()?;
                 if device.pcm.state() != pcm::State::Running {
                     log::info!("Device not already started, starting now");
                     device.pcm.start()?;
                 }
-                let _try = || loop {
-                    if eject_signal.load(Ordering::Relaxed) {
-                        log::debug!("Eject requested, returning ownership of callback");
-                        break Ok(callback);
+                callback.prepare(AudioCallbackContext {
+                    stream_config,
+                    timestamp,
+                    output_time: None,
+                    deadline: None,
+                    queued_frames: None,
+                    frames_since_last_callback: None,
+                    discontinuity: false,
+                });
+                events.send(StreamEvent::Started);
+                let mut paused = false;
+                // Hardware parameters borrow the device, which is moved into the cycle
+                let can_resume = hwp.can_resume();
+                drop(hwp);
+                let pcm = device.pcm.clone();
+                let step = move || {
+                    let io = device.pcm.io_bytes();
+                    if receiver.process(&mut callback, &events) {
+                        callback.prepare(AudioCallbackContext {
+                            stream_config,
+                            timestamp,
+                            output_time: None,
+                            deadline: None,
+                            queued_frames: None,
+                            frames_since_last_callback: None,
+                            discontinuity: false,
+                        });
                     }
-                    let frames = device.pcm.avail_update()? as usize;
+                    if receiver.eject_requested() {
+                        log::debug!("Eject requested, giving the callback back");
+                        return Ok(Step::Stop);
+                    }
+                    let frames = (device.pcm.avail_update()? as usize).min(max_frames);
                     let len = frames * num_channels;
-                    if let Err(err) = io.readi(&mut buffer[..len]) {
-                        log::warn!("ALSA PCM error, trying to recover ...");
-                        log::debug!("Error: {err}");
-                        device.pcm.try_recover(err, true)?;
+                    let (timestamp, capture_time, queued) = clock.timestamp(&device.pcm)?;
+                    let raw_len = len * format.sample_size();
+                    match io.readi(&mut raw_buffer[..raw_len]) {
+                        Ok(read) => clock.advance(read),
+                        Err(err) => {
+                            diagnostics::warn!("ALSA PCM error, trying to recover ...");
+                            log::debug!("Error: {err}");
+                            device.pcm.try_recover(err, true)?;
+                            clock.mark_xrun();
+                            tracker.mark_discontinuity();
+                            events.send(StreamEvent::XRun);
+                        }
                     }
-                    let buffer = AudioRef::from_interleaved(&buffer[..len], num_channels).unwrap();
-                    let context = AudioCallbackContext {
-                        stream_config,
-                        timestamp,
-                    };
-                    let input = AudioInput { buffer, timestamp };
-                    callback.on_input_data(context, input);
-                    timestamp += frames as u64;
+                    realtime::process_cycle(|| {
+                        format.decode(&mut raw_buffer[..raw_len], &mut buffer[..len]);
+                        let (samples, channels) = match &selection {
+                            Some(selected) => {
+                                let output = &mut selected_buffer[..frames * selected.len()];
+                                select_channels(&buffer[..len], num_channels, selected, output);
+                                (output, selected.len())
+                            }
+                            None => (&mut buffer[..len], num_channels),
+                        };
+                        let mut input_samples =
+                            AudioMut::from_interleaved_mut(samples, channels).unwrap();
+                        if let Some(amplitudes) = &trim {
+                            input_samples.change_channel_amplitudes(amplitudes);
+                        }
+                        receiver.apply_volume(input_samples);
+                        let buffer = AudioRef::from_interleaved(samples, channels).unwrap();
+                        if let Some(tap) = &mut input_tap {
+                            tap.process(buffer);
+                        }
+                        if receiver.is_paused() {
+                            return;
+                        }
+                        let (frames_since_last_callback, discontinuity) =
+                            tracker.next(timestamp, frames);
+                        let context = AudioCallbackContext {
+                            stream_config,
+                            timestamp,
+                            output_time: None,
+                            deadline: Some(callback_deadline(samplerate, frames)),
+                            queued_frames: Some(queued),
+                            frames_since_last_callback,
+                            discontinuity,
+                        };
+                        let input = AudioInput {
+                            buffer,
+                            timestamp,
+                            capture_time: Some(capture_time),
+                        };
+                        callback.on_input_data(context, input);
+                    });
 
                     match device.pcm.state() {
                         pcm::State::Suspended => {
-                            if hwp.can_resume() {
+                            tracker.mark_discontinuity();
+                            paused = true;
+                            events.send(StreamEvent::Paused);
+                            if can_resume {
                                 device.pcm.resume()?;
                             } else {
                                 device.pcm.prepare()?;
                             }
                         }
-                        pcm::State::Paused => std::thread::sleep(Duration::from_secs(1)),
+                        pcm::State::Paused => {
+                            if !paused {
+                                paused = true;
+                                events.send(StreamEvent::Paused);
+                            }
+                            return Ok(Step::Sleep(Duration::from_secs(1)));
+                        }
+                        _ if paused => {
+                            paused = false;
+                            events.send(StreamEvent::Started);
+                        }
                         _ => {}
                     }
+                    Ok(Step::Continue)
                 };
-                _try()
+                Ok(StreamCycle {
+                    pcm,
+                    step: Box::new(step),
+                })
             }
         });
         Self {
-            eject_signal,
-            join_handle,
+            control,
+            thread,
+            events,
+            meter,
         }
     }
 }
 
 impl<Callback: 'static + Send + AudioOutputCallback> AlsaStream<Callback> {
-    fn new_output(name: String, stream_config: StreamConfig, mut callback: Callback) -> Self {
-        let eject_signal = Arc::new(AtomicBool::new(false));
-        let join_handle = std::thread::spawn({
-            let eject_signal = eject_signal.clone();
-            move || {
-                let device = AlsaDevice::new(&name, alsa::Direction::Playback)?;
-                let (hwp, _, io) = device.apply_config(&stream_config)?;
+    fn new_output(
+        name: String,
+        stream_options: AlsaStreamOptions,
+        worker: Option<&AlsaWorker>,
+        stream_config: StreamConfig,
+        callback: Callback,
+    ) -> Self {
+        let (control, mut receiver, mut callback) = control_queue(callback);
+        let events = StreamEventSender::new();
+        let (meter, _, mut output_tap) =
+            StreamMeter::new(stream_config.metering, 0, stream_config.output_channels.count());
+        let thread = StreamThread::spawn(&events, worker, {
+            move |events| {
+                let device = AlsaDevice::new(&name, alsa::Direction::Playback)?
+                    .with_stream_options(stream_options);
+                let (hwp, _, _, format) = device.apply_config(&stream_config)?;
                 let (_, period_size) = device.pcm.get_params()?;
                 let period_size = period_size as usize;
                 log::debug!("Period size : {period_size}");
+                // Buffers hold the largest period the device can use, and each iteration
+                // processes at most that many frames
+                let max_frames = hwp.get_period_size_max()?.max(period_size as _) as usize;
                 let num_channels = hwp.get_channels()? as usize;
                 log::debug!("Num channels: {num_channels}");
+                log::debug!("Format      : {format:?}");
                 let samplerate = hwp.get_rate()? as f64;
-                log::debug!("Sample rate : {samplerate}");
+                log::debug!("Sample rate : {}", display_samplerate(samplerate));
+                let selection = channel_selection(stream_config.output_channels, num_channels);
                 let stream_config = StreamConfig {
                     samplerate,
-                    channels: ChannelMap32::default()
-                        .with_indices(std::iter::repeat(1).take(num_channels)),
-                    buffer_size_range: (Some(period_size), Some(period_size)),
-                    exclusive: false,
+                    buffer_size_range: (Some(period_size.into()), Some(max_frames.into())),
+                    sample_format: Some(format.sample),
+                    ..stream_config
                 };
-                let frames = device.pcm.avail_update()? as usize;
-                let mut timestamp = Timestamp::new(samplerate);
-                let mut buffer = vec![0f32; frames * num_channels];
+                let timestamp = Timestamp::new(samplerate);
+                let mut clock =
+                    StreamClock::new(samplerate, alsa::Direction::Playback, &device.pcm);
+                let mut tracker = CallbackTracker::default();
+                let mut buffer = vec![0f32; max_frames * num_channels];
+                let mut raw_buffer = vec![0u8; buffer.len() * format.sample_size()];
+                let mut selected_buffer =
+                    vec![0f32; selection.as_ref().map_or(0, |sel| max_frames * sel.len())];
+                let trim = stream_config
+                    .output_trim
+                    .amplitudes(selection.as_ref().map_or(num_channels, Vec::len));
                 device.pcm.prepare()?;
                 if device.pcm.state() != pcm::State::Running {
                     device.pcm.start()?;
                 }
-                let _try = || loop {
-                    if eject_signal.load(Ordering::Relaxed) {
-                        break Ok(callback);
+                callback.prepare(AudioCallbackContext {
+                    stream_config,
+                    timestamp,
+                    output_time: None,
+                    deadline: None,
+                    queued_frames: None,
+                    frames_since_last_callback: None,
+                    discontinuity: false,
+                });
+                events.send(StreamEvent::Started);
+                let mut paused = false;
+                // Hardware parameters borrow the device, which is moved into the cycle
+                let can_resume = hwp.can_resume();
+                drop(hwp);
+                let pcm = device.pcm.clone();
+                let step = move || {
+                    let io = device.pcm.io_bytes();
+                    if receiver.process(&mut callback, &events) {
+                        callback.prepare(AudioCallbackContext {
+                            stream_config,
+                            timestamp,
+                            output_time: None,
+                            deadline: None,
+                            queued_frames: None,
+                            frames_since_last_callback: None,
+                            discontinuity: false,
+                        });
+                    }
+                    if receiver.eject_requested() {
+                        return Ok(Step::Stop);
                     }
-                    let frames = device.pcm.avail_update()? as usize;
+                    let frames = (device.pcm.avail_update()? as usize).min(max_frames);
                     let len = frames * num_channels;
-                    let context = AudioCallbackContext {
-                        stream_config,
-                        timestamp,
-                    };
-                    let input = AudioOutput {
-                        buffer: AudioMut::from_interleaved_mut(&mut buffer[..len], num_channels)
-                            .unwrap(),
-                        timestamp,
-                    };
-                    callback.on_output_data(context, input);
-                    timestamp += frames as u64;
-                    if let Err(err) = io.writei(&buffer[..len]) { device.pcm.try_recover(err, true)? }
+                    let (timestamp, output_time, queued) = clock.timestamp(&device.pcm)?;
+                    let raw_len = len * format.sample_size();
+                    realtime::process_cycle(|| {
+                        let mut output = match &selection {
+                            Some(selected) => AudioMut::from_interleaved_mut(
+                                &mut selected_buffer[..frames * selected.len()],
+                                selected.len(),
+                            ),
+                            None => {
+                                AudioMut::from_interleaved_mut(&mut buffer[..len], num_channels)
+                            }
+                        }
+                        .unwrap();
+                        if receiver.is_paused() {
+                            output.as_interleaved_mut().fill(0.);
+                        } else {
+                            let (frames_since_last_callback, discontinuity) =
+                                tracker.next(timestamp, frames);
+                            let context = AudioCallbackContext {
+                                stream_config,
+                                timestamp,
+                                output_time: Some(output_time),
+                                deadline: Some(callback_deadline(samplerate, frames)),
+                                queued_frames: Some(queued),
+                                frames_since_last_callback,
+                                discontinuity,
+                            };
+                            let input = AudioOutput {
+                                buffer: output.as_mut(),
+                                timestamp,
+                            };
+                            callback.on_output_data(context, input);
+                        }
+                        if let Some(tap) = &mut output_tap {
+                            tap.process(output.as_ref());
+                        }
+                        if let Some(amplitudes) = &trim {
+                            output.change_channel_amplitudes(amplitudes);
+                        }
+                        receiver.apply_volume(output.as_mut());
+                        if let Some(selected) = &selection {
+                            let input = &selected_buffer[..frames * selected.len()];
+                            scatter_channels(input, selected, num_channels, &mut buffer[..len]);
+                        }
+                        format.encode(&buffer[..len], &mut raw_buffer[..raw_len]);
+                    });
+                    match io.writei(&raw_buffer[..raw_len]) {
+                        Ok(written) => clock.advance(written),
+                        Err(err) => {
+                            device.pcm.try_recover(err, true)?;
+                            clock.mark_xrun();
+                            tracker.mark_discontinuity();
+                            events.send(StreamEvent::XRun);
+                        }
+                    }
                     match device.pcm.state() {
                         pcm::State::Suspended => {
-                            if hwp.can_resume() {
+                            tracker.mark_discontinuity();
+                            paused = true;
+                            events.send(StreamEvent::Paused);
+                            if can_resume {
                                 log::debug!("Stream suspended, resuming");
                                 device.pcm.resume()?;
                             } else {
@@ -367,16 +1468,719 @@ impl<Callback: 'static + Send + AudioOutputCallback> AlsaStream<Callback> {
                                 device.pcm.prepare()?;
                             }
                         }
-                        pcm::State::Paused => std::thread::sleep(Duration::from_secs(1)),
+                        pcm::State::Paused => {
+                            if !paused {
+                                paused = true;
+                                events.send(StreamEvent::Paused);
+                            }
+                            return Ok(Step::Sleep(Duration::from_secs(1)));
+                        }
+                        _ if paused => {
+                            paused = false;
+                            events.send(StreamEvent::Started);
+                        }
                         _ => {}
                     }
+                    Ok(Step::Continue)
                 };
-                _try().inspect_err(|err| log::error!("Audio thread error: {err}"))
+                Ok(StreamCycle {
+                    pcm,
+                    step: Box::new(step),
+                })
             }
         });
         Self {
-            eject_signal,
-            join_handle,
+            control,
+            thread,
+            events,
+            meter,
+        }
+    }
+}
+
+impl<Callback: 'static + Send + AudioDuplexCallback> AlsaStream<Callback> {
+    fn new_duplex(
+        input_name: String,
+        output_name: String,
+        stream_options: AlsaStreamOptions,
+        stream_config: StreamConfig,
+        callback: Callback,
+    ) -> Self {
+        let (control, mut receiver, mut callback) = control_queue(callback);
+        let events = StreamEventSender::new();
+        let (meter, mut input_tap, mut output_tap) = StreamMeter::new(
+            stream_config.metering,
+            stream_config.input_channels.count(),
+            stream_config.output_channels.count(),
+        );
+        let thread = StreamThread::Dedicated(spawn_audio_thread(&events, {
+            move |events| {
+                let input = AlsaDevice::new(&input_name, alsa::Direction::Capture)?
+                    .with_stream_options(stream_options);
+                let output = AlsaDevice::new(&output_name, alsa::Direction::Playback)?
+                    .with_stream_options(stream_options);
+                let (in_hwp, _, in_io, in_format) = input.apply_config(&stream_config)?;
+                let (out_hwp, _, out_io, out_format) = output.apply_config(&stream_config)?;
+                let (_, period_size) = output.pcm.get_params()?;
+                let period_size = period_size as usize;
+                log::debug!("Period size : {period_size}");
+                let in_channels = in_hwp.get_channels()? as usize;
+                let out_channels = out_hwp.get_channels()? as usize;
+                log::debug!("Num channels: {in_channels} in, {out_channels} out");
+                log::debug!("Format      : {in_format:?} in, {out_format:?} out");
+                let samplerate = out_hwp.get_rate()? as f64;
+                log::debug!("Sample rate : {}", display_samplerate(samplerate));
+                if in_hwp.get_rate()? as f64 != samplerate {
+                    diagnostics::warn!("Input and output run at different sample rates");
+                }
+                // Linked PCMs are started, stopped and prepared together, and PCMs of the same
+                // card share its clock, so that input and output never drift apart
+                let linked = input.card.is_some()
+                    && input.card == output.card
+                    && input
+                        .pcm
+                        .link(&output.pcm)
+                        .inspect_err(|err| diagnostics::warn!("Cannot link PCMs: {err}"))
+                        .is_ok();
+                if !linked {
+                    diagnostics::warn!("Input and output are not linked, and will drift apart");
+                }
+                let in_selected = stream_config
+                    .input_channels
+                    .indices()
+                    .into_iter()
+                    .collect::<Vec<_>>();
+                let out_selected = stream_config
+                    .output_channels
+                    .indices()
+                    .into_iter()
+                    .collect::<Vec<_>>();
+                // Input and output only report a sample format when they share it
+                let sample_format =
+                    (in_format.sample == out_format.sample).then_some(out_format.sample);
+                let stream_config = StreamConfig {
+                    samplerate,
+                    buffer_size_range: (Some(period_size.into()), Some(period_size.into())),
+                    sample_format,
+                    ..stream_config
+                };
+                let timestamp = Timestamp::new(samplerate);
+                let mut clock =
+                    StreamClock::new(samplerate, alsa::Direction::Playback, &output.pcm);
+                let mut in_clock =
+                    StreamClock::new(samplerate, alsa::Direction::Capture, &input.pcm);
+                let mut tracker = CallbackTracker::default();
+                let mut in_buffer = vec![0f32; period_size * in_channels];
+                let mut in_raw_buffer = vec![0u8; in_buffer.len() * in_format.sample_size()];
+                let mut out_buffer = vec![0f32; period_size * out_channels];
+                let mut out_raw_buffer = vec![0u8; out_buffer.len() * out_format.sample_size()];
+                let mut input_buffer = vec![0f32; period_size * in_selected.len()];
+                let mut output_buffer = vec![0f32; period_size * out_selected.len()];
+                let in_trim = stream_config.input_trim.amplitudes(in_selected.len());
+                let out_trim = stream_config.output_trim.amplitudes(out_selected.len());
+                input.pcm.prepare()?;
+                output.pcm.prepare()?;
+                // A period of silence gives the output time to receive the first processed period
+                out_io.writei(&out_raw_buffer)?;
+                clock.advance(period_size);
+                output.pcm.start()?;
+                if !linked {
+                    input.pcm.start()?;
+                }
+                callback.prepare(AudioCallbackContext {
+                    stream_config,
+                    timestamp,
+                    output_time: None,
+                    deadline: None,
+                    queued_frames: None,
+                    frames_since_last_callback: None,
+                    discontinuity: false,
+                });
+                events.send(StreamEvent::Started);
+                let mut paused = false;
+                let mut _try = || loop {
+                    if receiver.process(&mut callback, &events) {
+                        callback.prepare(AudioCallbackContext {
+                            stream_config,
+                            timestamp,
+                            output_time: None,
+                            deadline: None,
+                            queued_frames: None,
+                            frames_since_last_callback: None,
+                            discontinuity: false,
+                        });
+                    }
+                    if receiver.eject_requested() {
+                        break Ok(());
+                    }
+                    if !input.pcm.wait(Some(100))? {
+                        continue;
+                    }
+                    let frames = (input.pcm.avail_update()? as usize).min(period_size);
+                    let (timestamp, output_time, queued) = clock.timestamp(&output.pcm)?;
+                    let (in_timestamp, capture_time, _) = in_clock.timestamp(&input.pcm)?;
+                    let in_len = frames * in_channels;
+                    let in_raw_len = in_len * in_format.sample_size();
+                    // A failed capture still runs the callback on silence, so that the output
+                    // keeps being fed while the input recovers
+                    let captured = match in_io.readi(&mut in_raw_buffer[..in_raw_len]) {
+                        Ok(read) => {
+                            in_clock.advance(read);
+                            true
+                        }
+                        Err(err) => {
+                            diagnostics::warn!("ALSA PCM error, trying to recover ...");
+                            log::debug!("Error: {err}");
+                            input.pcm.try_recover(err, true)?;
+                            in_clock.mark_xrun();
+                            tracker.mark_discontinuity();
+                            events.send(StreamEvent::XRun);
+                            false
+                        }
+                    };
+                    let out_len = frames * out_channels;
+                    let out_raw_len = out_len * out_format.sample_size();
+                    realtime::process_cycle(|| {
+                        if captured {
+                            in_format
+                                .decode(&mut in_raw_buffer[..in_raw_len], &mut in_buffer[..in_len]);
+                        } else {
+                            in_buffer[..in_len].fill(0.);
+                        }
+                        let input_samples = &mut input_buffer[..frames * in_selected.len()];
+                        select_channels(
+                            &in_buffer[..in_len],
+                            in_channels,
+                            &in_selected,
+                            input_samples,
+                        );
+                        if let Some(amplitudes) = &in_trim {
+                            AudioMut::from_interleaved_mut(input_samples, in_selected.len())
+                                .unwrap()
+                                .change_channel_amplitudes(amplitudes);
+                        }
+                        let in_audio =
+                            AudioRef::from_interleaved(input_samples, in_selected.len()).unwrap();
+                        if let Some(tap) = &mut input_tap {
+                            tap.process(in_audio);
+                        }
+                        let mut output_samples = AudioMut::from_interleaved_mut(
+                            &mut output_buffer[..frames * out_selected.len()],
+                            out_selected.len(),
+                        )
+                        .unwrap();
+                        if receiver.is_paused() {
+                            output_samples.as_interleaved_mut().fill(0.);
+                        } else {
+                            let (frames_since_last_callback, discontinuity) =
+                                tracker.next(timestamp, frames);
+                            let context = AudioCallbackContext {
+                                stream_config,
+                                timestamp,
+                                output_time: Some(output_time),
+                                deadline: Some(callback_deadline(samplerate, frames)),
+                                queued_frames: Some(queued),
+                                frames_since_last_callback,
+                                discontinuity,
+                            };
+                            let input_audio = AudioInput {
+                                buffer: in_audio,
+                                timestamp: in_timestamp,
+                                capture_time: Some(capture_time),
+                            };
+                            let output_audio = AudioOutput {
+                                buffer: output_samples.as_mut(),
+                                timestamp,
+                            };
+                            callback.on_audio_data(context, input_audio, output_audio);
+                        }
+                        if let Some(tap) = &mut output_tap {
+                            tap.process(output_samples.as_ref());
+                        }
+                        if let Some(amplitudes) = &out_trim {
+                            output_samples.change_channel_amplitudes(amplitudes);
+                        }
+                        receiver.apply_volume(output_samples);
+                        let out_samples = &mut out_buffer[..out_len];
+                        let output_samples = &output_buffer[..frames * out_selected.len()];
+                        scatter_channels(output_samples, &out_selected, out_channels, out_samples);
+                        out_format.encode(out_samples, &mut out_raw_buffer[..out_raw_len]);
+                    });
+                    match out_io.writei(&out_raw_buffer[..out_raw_len]) {
+                        Ok(written) => clock.advance(written),
+                        Err(err) => {
+                            diagnostics::warn!("ALSA PCM error, trying to recover ...");
+                            log::debug!("Error: {err}");
+                            output.pcm.try_recover(err, true)?;
+                            clock.mark_xrun();
+                            tracker.mark_discontinuity();
+                            events.send(StreamEvent::XRun);
+                        }
+                    }
+                    match output.pcm.state() {
+                        pcm::State::Suspended => {
+                            tracker.mark_discontinuity();
+                            paused = true;
+                            events.send(StreamEvent::Paused);
+                            if out_hwp.can_resume() {
+                                output.pcm.resume()?;
+                            } else {
+                                output.pcm.prepare()?;
+                            }
+                        }
+                        pcm::State::Paused => {
+                            if !paused {
+                                paused = true;
+                                events.send(StreamEvent::Paused);
+                            }
+                            std::thread::sleep(Duration::from_secs(1))
+                        }
+                        _ if paused => {
+                            paused = false;
+                            events.send(StreamEvent::Started);
+                        }
+                        _ => {}
+                    }
+                };
+                _try().inspect_err(|err| diagnostics::error!("Audio thread error: {err}"))
+            }
+        }));
+        Self {
+            control,
+            thread,
+            events,
+            meter,
         }
     }
 }
+
+/// Counts the frames of a stream for its timestamps, and derives the host time of the frames
+/// from the PCM status, which tells when the status was taken and how many frames are queued in
+/// the device.
+///
+/// Timestamps advance by the frames transferred with the device, so that consecutive buffers are
+/// exactly their length apart, including across pauses. Frames lost on xruns are added to the
+/// count once the stream has recovered, measured from the host time of the frames.
+struct StreamClock {
+    samplerate: f64,
+    direction: alsa::Direction,
+    monotonic: bool,
+    /// Frames transferred with the device, and lost on xruns
+    position: u64,
+    /// Position and host time of the last frame measured, to count the frames lost on xruns
+    last: Option<(u64, HostTime)>,
+    /// Whether an xrun happened since the last measure
+    xrun: bool,
+}
+
+impl StreamClock {
+    fn new(samplerate: f64, direction: alsa::Direction, pcm: &PCM) -> Self {
+        let monotonic = pcm
+            .sw_params_current()
+            .and_then(|swp| swp.get_tstamp_type())
+            .is_ok_and(|tstamp| tstamp == pcm::TstampType::Monotonic);
+        Self {
+            samplerate,
+            direction,
+            monotonic,
+            position: 0,
+            last: None,
+            xrun: false,
+        }
+    }
+
+    /// Timestamp of the next frame transferred with the device, along with the host time of that
+    /// frame. For capture streams, this is the time at which the frame was captured; for playback
+    /// streams, the time at which the frame will be played. When the driver cannot give status
+    /// timestamps on the monotonic clock, the current host time is used instead. The number of
+    /// frames queued in the device, which separate the next frame from the device, is returned
+    /// last.
+    fn timestamp(&mut self, pcm: &PCM) -> Result<(Timestamp, HostTime, usize), alsa::Error> {
+        let status = pcm.status()?;
+        let queued = status.get_delay().max(0) as usize;
+        let delay = Duration::from_secs_f64(queued as f64 / self.samplerate);
+        let host_now = if self.monotonic {
+            let htstamp = status.get_htstamp();
+            HostTime::from_duration(Duration::new(htstamp.tv_sec as _, htstamp.tv_nsec as _))
+        } else {
+            HostTime::now()
+        };
+        let device_time = match self.direction {
+            alsa::Direction::Playback => host_now + delay,
+            alsa::Direction::Capture => host_now.saturating_sub(delay),
+        };
+        if std::mem::take(&mut self.xrun) {
+            if let Some((position, time)) = self.last {
+                let frames = self.position - position;
+                let expected = time + Timestamp::from_count(self.samplerate, frames).as_duration();
+                let lost = device_time.saturating_duration_since(expected);
+                self.position += (lost.as_secs_f64() * self.samplerate).round() as u64;
+            }
+        }
+        self.last = Some((self.position, device_time));
+        Ok((
+            Timestamp::from_count(self.samplerate, self.position),
+            device_time,
+            queued,
+        ))
+    }
+
+    /// Count frames transferred with the device.
+    fn advance(&mut self, frames: usize) {
+        self.position += frames as u64;
+    }
+
+    /// Count the frames lost on an xrun the next time the timestamp is measured, once the stream
+    /// has recovered.
+    fn mark_xrun(&mut self) {
+        self.xrun = true;
+    }
+}
+
+/// Simple mixer element controlling the volume of a device.
+struct MixerControl<'a> {
+    selem: Selem<'a>,
+    direction: alsa::Direction,
+}
+
+impl<'a> MixerControl<'a> {
+    /// Names of the elements usually controlling the volume of a card, in order of preference.
+    const PLAYBACK_ELEMENTS: [&'static str; 3] = ["Master", "PCM", "Speaker"];
+    const CAPTURE_ELEMENTS: [&'static str; 2] = ["Capture", "Mic"];
+
+    /// Find the element controlling the volume in the given direction, falling back to the first
+    /// element with a volume in that direction.
+    fn find(mixer: &'a Mixer, direction: alsa::Direction) -> Option<Self> {
+        let (names, has_volume): (&[&str], fn(&Selem<'a>) -> bool) = match direction {
+            alsa::Direction::Playback => (&Self::PLAYBACK_ELEMENTS, Selem::has_playback_volume),
+            alsa::Direction::Capture => (&Self::CAPTURE_ELEMENTS, Selem::has_capture_volume),
+        };
+        names
+            .iter()
+            .filter_map(|name| mixer.find_selem(&SelemId::new(name, 0)))
+            .chain(mixer.iter().filter_map(Selem::new))
+            .find(has_volume)
+            .map(|selem| Self { selem, direction })
+    }
+
+    fn volume(&self) -> Result<f32, alsa::Error> {
+        let channel = SelemChannelId::mono();
+        let (db, value) = match self.direction {
+            alsa::Direction::Playback => (
+                self.selem.get_playback_vol_db(channel),
+                self.selem.get_playback_volume(channel)?,
+            ),
+            alsa::Direction::Capture => (
+                self.selem.get_capture_vol_db(channel),
+                self.selem.get_capture_volume(channel)?,
+            ),
+        };
+        let (min_db, max_db) = self.db_range();
+        if let (Ok(db), true) = (db, min_db < max_db) {
+            if db <= min_db {
+                return Ok(0.0);
+            }
+            return Ok(10f32.powf((db - max_db).to_db() / 20.0));
+        }
+        let (min, max) = self.volume_range();
+        Ok((value - min) as f32 / (max - min).max(1) as f32)
+    }
+
+    fn set_volume(&self, volume: f32) -> Result<(), alsa::Error> {
+        let volume = volume.clamp(0.0, 1.0);
+        let (min_db, max_db) = self.db_range();
+        if min_db < max_db {
+            let db = if volume > 0.0 {
+                (MilliBel::from_db(20.0 * volume.log10()) + max_db).max(min_db)
+            } else {
+                min_db
+            };
+            return match self.direction {
+                alsa::Direction::Playback => self.selem.set_playback_db_all(db, Round::Floor),
+                alsa::Direction::Capture => self.selem.set_capture_db_all(db, Round::Floor),
+            };
+        }
+        let (min, max) = self.volume_range();
+        let value = min + ((max - min) as f32 * volume).round() as i64;
+        match self.direction {
+            alsa::Direction::Playback => self.selem.set_playback_volume_all(value),
+            alsa::Direction::Capture => self.selem.set_capture_volume_all(value),
+        }
+    }
+
+    fn is_muted(&self) -> Result<bool, alsa::Error> {
+        // Switches are on when the element is *not* muted
+        let channel = SelemChannelId::mono();
+        Ok(match self.direction {
+            alsa::Direction::Playback if self.selem.has_playback_switch() => {
+                self.selem.get_playback_switch(channel)? == 0
+            }
+            alsa::Direction::Capture if self.selem.has_capture_switch() => {
+                self.selem.get_capture_switch(channel)? == 0
+            }
+            _ => false,
+        })
+    }
+
+    fn set_muted(&self, muted: bool) -> Result<(), alsa::Error> {
+        let value = (!muted) as i32;
+        match self.direction {
+            alsa::Direction::Playback if self.selem.has_playback_switch() => {
+                self.selem.set_playback_switch_all(value)
+            }
+            alsa::Direction::Capture if self.selem.has_capture_switch() => {
+                self.selem.set_capture_switch_all(value)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn db_range(&self) -> (MilliBel, MilliBel) {
+        match self.direction {
+            alsa::Direction::Playback => self.selem.get_playback_db_range(),
+            alsa::Direction::Capture => self.selem.get_capture_db_range(),
+        }
+    }
+
+    fn volume_range(&self) -> (i64, i64) {
+        match self.direction {
+            alsa::Direction::Playback => self.selem.get_playback_volume_range(),
+            alsa::Direction::Capture => self.selem.get_capture_volume_range(),
+        }
+    }
+}
+
+/// Speaker position of a channel from its name in a printed channel map. Names are matched
+/// textually, as the channel position enum of the [`alsa`] crate is missing some positions.
+fn chmap_position(name: &str) -> Option<ChannelPosition> {
+    Some(match name {
+        "FL" => ChannelPosition::FrontLeft,
+        "FR" => ChannelPosition::FrontRight,
+        "FC" | "MONO" => ChannelPosition::FrontCenter,
+        "LFE" => ChannelPosition::LowFrequency,
+        "RL" => ChannelPosition::BackLeft,
+        "RR" => ChannelPosition::BackRight,
+        "FLC" => ChannelPosition::FrontLeftOfCenter,
+        "FRC" => ChannelPosition::FrontRightOfCenter,
+        "RC" => ChannelPosition::BackCenter,
+        "SL" => ChannelPosition::SideLeft,
+        "SR" => ChannelPosition::SideRight,
+        "TC" => ChannelPosition::TopCenter,
+        "TFL" => ChannelPosition::TopFrontLeft,
+        "TFC" => ChannelPosition::TopFrontCenter,
+        "TFR" => ChannelPosition::TopFrontRight,
+        "TRL" => ChannelPosition::TopBackLeft,
+        "TRC" => ChannelPosition::TopBackCenter,
+        "TRR" => ChannelPosition::TopBackRight,
+        _ => return None,
+    })
+}
+
+/// Card given in the arguments of a PCM name (e.g. `hw:0`, `default:CARD=PCH`), if any.
+fn pcm_card_arg(pcm_name: &str) -> Option<&str> {
+    let args = pcm_name.split_once(':').map_or("", |(_, args)| args);
+    args.split(',')
+        .next()
+        .map(|arg| arg.strip_prefix("CARD=").unwrap_or(arg))
+        .filter(|card| !card.is_empty() && !card.contains('='))
+}
+
+/// Index of the card a PCM resolves to. The PCM itself is asked first, which accounts for aliases
+/// defined in the user configuration; the card given in the PCM name is used otherwise.
+fn resolve_card(pcm: &PCM, name: &str) -> Option<i32> {
+    let info_card = pcm.info().ok().map(|info| info.get_card());
+    if let Some(card) = info_card.filter(|&card| card >= 0) {
+        return Some(card);
+    }
+    let card = CString::new(pcm_card_arg(name)?).ok()?;
+    alsa::Card::from_str(&card)
+        .ok()
+        .map(|card| card.get_index())
+}
+
+/// Sample format of the data exchanged with a device, which can use the opposite byte order of
+/// the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DeviceFormat {
+    alsa: pcm::Format,
+    sample: SampleFormat,
+    big_endian: bool,
+}
+
+impl DeviceFormat {
+    /// Formats streams can use, by order of preference of their sample format. Streams use
+    /// floating-point samples when the device supports them, and convert from and to integer
+    /// samples otherwise.
+    const ALL: [Self; 10] = [
+        Self::new(pcm::Format::FloatLE, SampleFormat::F32, false),
+        Self::new(pcm::Format::FloatBE, SampleFormat::F32, true),
+        Self::new(pcm::Format::S32LE, SampleFormat::I32, false),
+        Self::new(pcm::Format::S32BE, SampleFormat::I32, true),
+        Self::new(pcm::Format::S24LE, SampleFormat::I24, false),
+        Self::new(pcm::Format::S24BE, SampleFormat::I24, true),
+        Self::new(pcm::Format::S243LE, SampleFormat::I24Packed, false),
+        Self::new(pcm::Format::S243BE, SampleFormat::I24Packed, true),
+        Self::new(pcm::Format::S16LE, SampleFormat::I16, false),
+        Self::new(pcm::Format::S16BE, SampleFormat::I16, true),
+    ];
+
+    const fn new(alsa: pcm::Format, sample: SampleFormat, big_endian: bool) -> Self {
+        Self {
+            alsa,
+            sample,
+            big_endian,
+        }
+    }
+
+    fn from_alsa(format: pcm::Format) -> Option<Self> {
+        Self::ALL.into_iter().find(|f| f.alsa == format)
+    }
+
+    /// Whether samples need their bytes swapped to be read on the host.
+    fn swap_bytes(&self) -> bool {
+        self.big_endian != cfg!(target_endian = "big")
+    }
+
+    fn sample_size(&self) -> usize {
+        self.sample.sample_size()
+    }
+
+    /// Decode device samples into floating-point samples. Samples in the opposite byte order are
+    /// swapped in place first.
+    fn decode(&self, input: &mut [u8], output: &mut [f32]) -> usize {
+        if self.swap_bytes() {
+            input
+                .chunks_exact_mut(self.sample_size())
+                .for_each(<[u8]>::reverse);
+        }
+        self.sample.decode(input, output)
+    }
+
+    /// Encode floating-point samples into device samples.
+    fn encode(&self, input: &[f32], output: &mut [u8]) -> usize {
+        let len = self.sample.encode(input, output);
+        if self.swap_bytes() {
+            output[..len * self.sample_size()]
+                .chunks_exact_mut(self.sample_size())
+                .for_each(<[u8]>::reverse);
+        }
+        len
+    }
+}
+
+/// First sample format supported by the hardware parameters, restricted to the requested sample
+/// format if any. Formats in the byte order of the host are preferred, as they need no byte
+/// swapping.
+fn negotiate_format(
+    hwp: &pcm::HwParams,
+    sample_format: Option<SampleFormat>,
+) -> Option<DeviceFormat> {
+    let (native, swapped): (Vec<_>, Vec<_>) = DeviceFormat::ALL
+        .into_iter()
+        .filter(|format| sample_format.map_or(true, |sample| format.sample == sample))
+        .partition(|format| !format.swap_bytes());
+    native
+        .into_iter()
+        .chain(swapped)
+        .find(|format| hwp.test_format(format.alsa).is_ok())
+}
+
+/// Name of the PCM to open for a stream on the given device, which belongs to the given card.
+/// Exclusive streams open the hardware device directly, bypassing software mixing
+/// (`dmix`/`dsnoop`) and conversions (`plug`), while shared streams go through the plugin layer.
+///
+/// Returns `None` when the hardware device of an exclusive stream cannot be found.
+fn stream_pcm_name(name: &str, card: Option<i32>, exclusive: bool) -> Option<Cow<'_, str>> {
+    let (kind, args) = name.split_once(':').unwrap_or((name, ""));
+    match (exclusive, kind) {
+        (false, "hw") => Some(Cow::Owned(format!("plughw:{args}"))),
+        (false, _) | (true, "hw") => Some(Cow::Borrowed(name)),
+        (true, "plughw") => Some(Cow::Owned(format!("hw:{args}"))),
+        (true, _) => {
+            // Other PCMs (default, sysdefault, front, dmix, ...) map to the hardware device of
+            // their card, keeping the device number the `hw` PCM understands
+            let card = match card {
+                Some(card) => format!("CARD={card}"),
+                None => args.split(',').find(|arg| arg.starts_with("CARD="))?.to_owned(),
+            };
+            let hw_name = match args.split(',').find(|arg| arg.starts_with("DEV=")) {
+                Some(device) => format!("hw:{card},{device}"),
+                None => format!("hw:{card}"),
+            };
+            Some(Cow::Owned(hw_name))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{pcm, stream_pcm_name, DeviceFormat};
+    use std::borrow::Cow;
+
+    fn format(alsa: pcm::Format) -> DeviceFormat {
+        DeviceFormat::from_alsa(alsa).unwrap()
+    }
+
+    fn encode(format: DeviceFormat, input: &[f32]) -> Vec<u8> {
+        let mut output = vec![0; input.len() * format.sample_size()];
+        assert_eq!(input.len(), format.encode(input, &mut output));
+        output
+    }
+
+    #[test]
+    fn test_device_format_byte_order() {
+        assert_eq!([0x3F, 0xFF], *encode(format(pcm::Format::S16BE), &[0.5]));
+        assert_eq!([0xFF, 0x3F], *encode(format(pcm::Format::S16LE), &[0.5]));
+        let s24be = encode(format(pcm::Format::S24BE), &[0.5]);
+        assert_eq!([0x00, 0x40, 0x00, 0x00], *s24be);
+        let s24le = encode(format(pcm::Format::S24LE), &[0.5]);
+        assert_eq!([0x00, 0x00, 0x40, 0x00], *s24le);
+        assert_eq!([0x40, 0x00, 0x00], *encode(format(pcm::Format::S243BE), &[0.5]));
+        assert_eq!([0x00, 0x00, 0x40], *encode(format(pcm::Format::S243LE), &[0.5]));
+        assert_eq!([0x40, 0x00, 0x00, 0x00], *encode(format(pcm::Format::S32BE), &[0.5]));
+        assert_eq!(0.5f32.to_be_bytes(), *encode(format(pcm::Format::FloatBE), &[0.5]));
+        assert_eq!(0.5f32.to_le_bytes(), *encode(format(pcm::Format::FloatLE), &[0.5]));
+    }
+
+    #[test]
+    fn test_device_format_swapped_roundtrip() {
+        let samples = [-1., -0.5, 0., 0.25, 0.5];
+        for pair in DeviceFormat::ALL.chunks_exact(2) {
+            let [little, big] = [pair[0], pair[1]];
+            assert!(!little.big_endian && big.big_endian);
+            assert_ne!(little.swap_bytes(), big.swap_bytes());
+            // Both byte orders hold the same samples, with their bytes reversed
+            let expected = encode(little, &samples);
+            let mut swapped = encode(big, &samples);
+            let reversed = swapped
+                .chunks_exact(big.sample_size())
+                .flat_map(|sample| sample.iter().rev().copied())
+                .collect::<Vec<_>>();
+            assert_eq!(expected, reversed, "{:?}", big.alsa);
+
+            let mut decoded = [0.; 5];
+            assert_eq!(5, big.decode(&mut swapped, &mut decoded));
+            let mut native = expected.clone();
+            let mut expected_decoded = [0.; 5];
+            little.decode(&mut native, &mut expected_decoded);
+            assert_eq!(expected_decoded, decoded, "{:?}", big.alsa);
+            for (sample, decoded) in samples.iter().zip(decoded) {
+                assert!((sample - decoded).abs() < 1e-4, "{:?}", big.alsa);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stream_pcm_name() {
+        let name =
+            |name, card, exclusive| stream_pcm_name(name, card, exclusive).map(Cow::into_owned);
+        assert_eq!(Some("plughw:0,0".into()), name("hw:0,0", Some(0), false));
+        assert_eq!(Some("default".into()), name("default", Some(0), false));
+        assert_eq!(Some("hw:0,0".into()), name("hw:0,0", Some(0), true));
+        assert_eq!(Some("hw:1,0".into()), name("plughw:1,0", Some(1), true));
+        assert_eq!(Some("hw:CARD=1".into()), name("default", Some(1), true));
+        assert_eq!(Some("hw:CARD=0,DEV=3".into()), name("hdmi:CARD=PCH,DEV=3", Some(0), true));
+        assert_eq!(Some("hw:CARD=PCH".into()), name("front:CARD=PCH", None, true));
+        assert_eq!(None, name("pulse", None, true));
+    }
+}
@@ -0,0 +1,262 @@
+//! Shared audio thread, processing the I/O of several ALSA streams.
+
+use std::io;
+use std::ops::Range;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::time::Instant;
+
+use alsa::poll::{pollfd, Descriptors};
+
+use super::{AlsaError, CycleSetup, Step, StreamCycle};
+use crate::control::StreamFailure;
+use crate::diagnostics;
+use crate::events::{StreamEvent, StreamEventSender};
+
+/// Timeout of the polling of the streams, bounding the latency of stream registration and
+/// commands on streams which have no I/O to process.
+const POLL_TIMEOUT_MS: i32 = 10;
+
+/// `SCHED_FIFO` priority requested for worker threads, lowered to the maximum priority of the
+/// system if needed.
+const REALTIME_PRIORITY: libc::c_int = 70;
+
+/// Outcome of a stream processed by a worker, sent to its handle when the worker stops
+/// processing it.
+type StreamResult = Result<(), StreamFailure<AlsaError>>;
+
+/// Audio thread shared by several ALSA streams.
+///
+/// Each ALSA stream spawns its own audio thread by default. Applications opening many streams
+/// with few channels, such as soft-phones with many endpoints, can instead run them all on a
+/// single worker thread, which waits on all their PCMs at once and processes those that are
+/// ready. Streams are run on a worker by creating them from a device configured with
+/// [`AlsaDevice::with_worker`](super::AlsaDevice::with_worker).
+///
+/// Callbacks of streams sharing a worker are called one after the other, so that a slow callback
+/// delays all the other streams of the worker.
+///
+/// The worker thread requests realtime scheduling (`SCHED_FIFO`), and keeps running with the
+/// default scheduling when the system does not allow it, e.g. without the `CAP_SYS_NICE`
+/// capability or an `rtprio` limit.
+///
+/// Workers are only available for ALSA streams; streams of the other backends each run on their
+/// own audio thread.
+///
+/// The worker thread stops once all the handles of the worker have been dropped and all of its
+/// streams have been ejected.
+///
+/// ```no_run
+/// use interflow::backends::alsa::{AlsaDriver, AlsaWorker};
+/// use interflow::prelude::*;
+/// use interflow::signals::Sine;
+/// let worker = AlsaWorker::new();
+/// let device = default_output_device_from(&AlsaDriver).with_worker(&worker);
+/// let low = device.default_output_stream(Sine::new(440.)).unwrap();
+/// let high = device.default_output_stream(Sine::new(880.)).unwrap();
+/// std::thread::sleep(std::time::Duration::from_secs(1));
+/// low.eject().unwrap();
+/// high.eject().unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct AlsaWorker {
+    registrations: mpsc::Sender<Registration>,
+}
+
+impl Default for AlsaWorker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AlsaWorker {
+    /// Spawn a new worker thread.
+    pub fn new() -> Self {
+        let (registrations, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("interflow_alsa_worker".into())
+            .spawn(move || {
+                request_realtime_scheduling();
+                run(receiver)
+            })
+            .expect("Cannot spawn ALSA worker thread");
+        Self { registrations }
+    }
+
+    /// Open a stream on the worker thread and process it there. The returned receiver gets the
+    /// outcome of the stream once the worker stops processing it.
+    pub(super) fn register(
+        &self,
+        events: &StreamEventSender,
+        setup: CycleSetup,
+    ) -> mpsc::Receiver<StreamResult> {
+        let (result, receiver) = mpsc::channel();
+        let registration = Registration {
+            setup,
+            events: events.clone(),
+            result,
+        };
+        // When the worker thread has stopped, the registration is dropped along with its result
+        // sender, and the handle reports the failure
+        if self.registrations.send(registration).is_err() {
+            diagnostics::error!("ALSA worker thread has stopped");
+        }
+        receiver
+    }
+}
+
+/// Stream waiting to be opened by the worker.
+struct Registration {
+    setup: CycleSetup,
+    events: StreamEventSender,
+    result: mpsc::Sender<StreamResult>,
+}
+
+/// Stream processed by the worker.
+struct WorkerStream {
+    cycle: StreamCycle,
+    events: StreamEventSender,
+    result: mpsc::Sender<StreamResult>,
+    /// Time until which the stream has nothing to process
+    sleep_until: Option<Instant>,
+    /// Poll descriptors of the stream among those of all the streams, or `None` when they could
+    /// not be queried
+    fds: Option<Range<usize>>,
+}
+
+impl WorkerStream {
+    /// Whether the stream has I/O to process, after polling the descriptors of all the streams.
+    /// Sleeping streams are woken up once their sleep is over, and streams without descriptors
+    /// are always processed.
+    fn is_ready(&self, fds: &[pollfd]) -> bool {
+        if let Some(until) = self.sleep_until {
+            return Instant::now() >= until;
+        }
+        let Some(range) = self.fds.clone() else {
+            return true;
+        };
+        self.cycle
+            .pcm
+            .revents(&fds[range])
+            .map_or(true, |flags| !flags.is_empty())
+    }
+
+    /// Process a cycle of the stream, returning its outcome once it stops.
+    fn step(&mut self) -> Option<StreamResult> {
+        self.sleep_until = None;
+        match catch_unwind(AssertUnwindSafe(|| (self.cycle.step)())) {
+            Ok(Ok(Step::Continue)) => None,
+            Ok(Ok(Step::Sleep(duration))) => {
+                self.sleep_until = Some(Instant::now() + duration);
+                None
+            }
+            Ok(Ok(Step::Stop)) => Some(Ok(())),
+            Ok(Err(err)) => {
+                diagnostics::error!("Audio thread error: {err}");
+                self.events.send(StreamEvent::Error(err.to_string().into()));
+                Some(Err(StreamFailure::Backend(err)))
+            }
+            Err(payload) => Some(Err(StreamFailure::panicked(payload))),
+        }
+    }
+}
+
+/// Request realtime scheduling for the current thread, reporting a warning when it is denied.
+fn request_realtime_scheduling() {
+    // Safety: the scheduling parameters are valid for the lifetime of the calls
+    let result = unsafe {
+        let max = libc::sched_get_priority_max(libc::SCHED_FIFO);
+        let param = libc::sched_param {
+            sched_priority: REALTIME_PRIORITY.min(max),
+        };
+        libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param)
+    };
+    if result != 0 {
+        let err = io::Error::from_raw_os_error(result);
+        diagnostics::warn!("Cannot run the ALSA worker with realtime scheduling: {err}");
+    }
+}
+
+fn run(registrations: mpsc::Receiver<Registration>) {
+    let mut streams: Vec<WorkerStream> = Vec::new();
+    let mut fds: Vec<pollfd> = Vec::new();
+    let mut changed = false;
+    loop {
+        if streams.is_empty() {
+            match registrations.recv() {
+                Ok(registration) => changed |= open(registration, &mut streams),
+                Err(_) => break,
+            }
+        }
+        for registration in registrations.try_iter() {
+            changed |= open(registration, &mut streams);
+        }
+        if changed {
+            fds.clear();
+            for stream in &mut streams {
+                stream.fds = match stream.cycle.pcm.get() {
+                    Ok(descriptors) => {
+                        let start = fds.len();
+                        fds.extend(descriptors);
+                        Some(start..fds.len())
+                    }
+                    Err(err) => {
+                        diagnostics::warn!("Cannot get poll descriptors: {err}");
+                        None
+                    }
+                };
+            }
+            changed = false;
+        }
+        if let Err(err) = alsa::poll::poll(&mut fds, POLL_TIMEOUT_MS) {
+            diagnostics::warn!("Cannot poll streams: {err}");
+        }
+        // Only streams whose descriptors are ready are processed, so that idle streams are not
+        // given empty buffers
+        streams.retain_mut(|stream| {
+            if !stream.is_ready(&fds) {
+                return true;
+            }
+            match stream.step() {
+                None => true,
+                Some(result) => {
+                    // The handle may have been dropped without ejecting the stream
+                    let _ = stream.result.send(result);
+                    changed = true;
+                    false
+                }
+            }
+        });
+    }
+    log::debug!("ALSA worker stopped");
+}
+
+/// Open a registered stream, adding it to the streams processed by the worker. Returns whether it
+/// was added.
+fn open(registration: Registration, streams: &mut Vec<WorkerStream>) -> bool {
+    let Registration {
+        setup,
+        events,
+        result,
+    } = registration;
+    let cycle = match catch_unwind(AssertUnwindSafe(|| setup(events.clone()))) {
+        Ok(Ok(cycle)) => cycle,
+        Ok(Err(err)) => {
+            events.send(StreamEvent::Error(err.to_string().into()));
+            let _ = result.send(Err(StreamFailure::Backend(err)));
+            return false;
+        }
+        Err(payload) => {
+            let _ = result.send(Err(StreamFailure::panicked(payload)));
+            return false;
+        }
+    };
+    streams.push(WorkerStream {
+        cycle,
+        events,
+        result,
+        sleep_until: None,
+        fds: None,
+    });
+    true
+}
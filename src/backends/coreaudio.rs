@@ -2,29 +2,86 @@
 //!
 //! CoreAudio is the audio backend for macOS and iOS devices.
 
+mod aggregate;
+
 use std::borrow::Cow;
 use std::convert::Infallible;
+use std::ffi::{c_void, CStr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{mem, ptr, slice};
 
+use core_foundation_sys::base::CFRelease;
+use core_foundation_sys::string::{
+    kCFStringEncodingUTF8, CFStringGetCString, CFStringGetLength,
+    CFStringGetMaximumSizeForEncoding, CFStringRef,
+};
 use coreaudio::audio_unit::audio_format::LinearPcmFlags;
 use coreaudio::audio_unit::macos_helpers::{
-    audio_unit_from_device_id, get_audio_device_ids_for_scope, get_default_device_id,
-    get_device_name, get_supported_physical_stream_formats,
+    audio_unit_from_device_id, get_audio_device_ids, get_audio_device_supports_scope,
+    get_default_device_id, get_device_name, get_supported_physical_stream_formats,
 };
 use coreaudio::audio_unit::render_callback::{data, Args};
 use coreaudio::audio_unit::{AudioUnit, Element, SampleFormat, Scope, StreamFormat};
 use coreaudio::sys::{
-    kAudioUnitProperty_SampleRate, kAudioUnitProperty_StreamFormat, AudioDeviceID,
+    kAudioChannelLabel_Center, kAudioChannelLabel_CenterSurround, kAudioChannelLabel_LFEScreen,
+    kAudioChannelLabel_Left, kAudioChannelLabel_LeftCenter, kAudioChannelLabel_LeftSurround,
+    kAudioChannelLabel_LeftSurroundDirect, kAudioChannelLabel_Mono,
+    kAudioChannelLabel_RearSurroundLeft, kAudioChannelLabel_RearSurroundRight,
+    kAudioChannelLabel_Right, kAudioChannelLabel_RightCenter, kAudioChannelLabel_RightSurround,
+    kAudioChannelLabel_RightSurroundDirect, kAudioChannelLabel_TopBackCenter,
+    kAudioChannelLabel_TopBackLeft, kAudioChannelLabel_TopBackRight,
+    kAudioChannelLabel_TopCenterSurround, kAudioChannelLabel_VerticalHeightCenter,
+    kAudioChannelLabel_VerticalHeightLeft, kAudioChannelLabel_VerticalHeightRight,
+    kAudioChannelLayoutTag_UseChannelBitmap, kAudioChannelLayoutTag_UseChannelDescriptions,
+    kAudioDevicePropertyAvailableNominalSampleRates, kAudioDevicePropertyBufferFrameSize,
+    kAudioDevicePropertyBufferFrameSizeRange, kAudioDevicePropertyDeviceUID,
+    kAudioDevicePropertyNominalSampleRate, kAudioDevicePropertyPreferredChannelLayout,
+    kAudioDevicePropertyStreams, kAudioDeviceProcessorOverload,
+    kAudioHardwarePropertyDefaultInputDevice,
+    kAudioHardwarePropertyDefaultOutputDevice, kAudioHardwarePropertyDevices,
+    kAudioHardwarePropertyTranslateUIDToDevice, kAudioObjectPropertyElementMaster,
+    kAudioObjectPropertyElementName, kAudioObjectPropertyScopeGlobal,
+    kAudioObjectPropertyScopeInput, kAudioObjectPropertyScopeOutput, kAudioObjectSystemObject,
+    kAudioObjectUnknown, kAudioOutputUnitProperty_ChannelMap,
+    kAudioOutputUnitProperty_CurrentDevice, kAudioStreamPropertyLatency,
+    kAudioStreamPropertyTerminalType, kAudioStreamPropertyVirtualFormat,
+    kAudioStreamTerminalTypeDigitalAudioInterface, kAudioStreamTerminalTypeDisplayPort,
+    kAudioStreamTerminalTypeHDMI, kAudioStreamTerminalTypeHeadphones,
+    kAudioStreamTerminalTypeHeadsetMicrophone, kAudioStreamTerminalTypeLFESpeaker,
+    kAudioStreamTerminalTypeLine, kAudioStreamTerminalTypeMicrophone,
+    kAudioStreamTerminalTypeReceiverMicrophone, kAudioStreamTerminalTypeReceiverSpeaker,
+    kAudioStreamTerminalTypeSpeaker, kAudioTimeStampHostTimeValid, kAudioUnitProperty_StreamFormat,
+    AudioConvertHostTimeToNanos, AudioDeviceID, AudioObjectAddPropertyListener,
+    AudioObjectGetPropertyData, AudioObjectGetPropertyDataSize, AudioObjectID,
+    AudioObjectPropertyAddress, AudioObjectPropertyScope, AudioObjectPropertySelector,
+    AudioObjectRemovePropertyListener, AudioObjectSetPropertyData, AudioStreamBasicDescription,
+    AudioStreamID, AudioTimeStamp, AudioUnitSetProperty, AudioValueRange, OSStatus,
 };
 use thiserror::Error;
 
-use crate::audio_buffer::{AudioBuffer, Sample};
-use crate::channel_map::Bitset;
+use aggregate::CfObject;
+pub use aggregate::CoreAudioAggregateDevice;
+
+use crate::audio_buffer::{AudioBuffer, AudioMut, AudioRef, SampleFormat as WireFormat};
+use crate::channel_map::{Bitset, CreateBitset};
+use crate::clock::{callback_deadline, HostTime};
+use crate::control::{control_queue, EjectError, StreamControl};
+use crate::diagnostics;
+use crate::duplex::AudioDuplexCallback;
+use crate::events::{StreamEvent, StreamEventReceiver, StreamEventSender};
+use crate::layout::{ChannelPosition, SpeakerLayout};
+use crate::metering::{StreamLevels, StreamMeter};
+use crate::realtime;
+use crate::resample::{LinearResampler, Resampler};
 use crate::prelude::ChannelMap32;
-use crate::timestamp::Timestamp;
+use crate::timestamp::{CallbackTracker, Timestamp};
 use crate::{
-    AudioCallbackContext, AudioDevice, AudioDriver, AudioInput, AudioInputCallback,
-    AudioInputDevice, AudioOutput, AudioOutputCallback, AudioOutputDevice, AudioStreamHandle,
-    Channel, DeviceType, SendEverywhereButOnWeb, StreamConfig,
+    AudioCallbackContext, AudioDevice, AudioDriver, AudioDriverEvents, AudioDuplexDevice,
+    AudioInput, AudioInputCallback, AudioInputDevice, AudioOutput, AudioOutputCallback,
+    AudioOutputDevice, AudioStreamHandle, BufferSize, Channel, ChannelTrim, DeviceEvent,
+    DeviceType, SendEverywhereButOnWeb, StreamConfig, StreamLatency,
 };
 
 /// Type of errors from the CoreAudio backend
@@ -37,6 +94,33 @@ pub enum CoreAudioError {
     /// The scope given to an audio device is invalid.
     #[error("Invalid scope {0:?}")]
     InvalidScope(Scope),
+    /// A device of the wrong type was given, for example an output device where an input
+    /// device was expected.
+    #[error("Expected {expected:?} device, got {actual:?}")]
+    InvalidDeviceType {
+        /// Device type that was expected
+        expected: DeviceType,
+        /// Device type of the given device
+        actual: DeviceType,
+    },
+    /// The device does not support running at the requested sample rate.
+    #[error("Sample rate {0} Hz is not supported by the device")]
+    UnsupportedSamplerate(f64),
+    /// The device did not switch to the requested sample rate in time.
+    #[error("Timed out waiting for the device to switch to {0} Hz")]
+    SamplerateChangeTimeout(f64),
+    /// The device does not report a unique identifier.
+    #[error("Device has no unique identifier")]
+    MissingDeviceUid,
+    /// The device with the given unique identifier is not part of the aggregate device.
+    #[error("Device {0:?} is not a sub-device of this aggregate device")]
+    NotASubDevice(String),
+    /// Streams cannot exchange samples in the requested format.
+    #[error("Sample format {0:?} is not supported")]
+    UnsupportedSampleFormat(WireFormat),
+    /// CoreAudio does not report its version.
+    #[error("CoreAudio version is not available")]
+    VersionUnavailable,
 }
 
 /// The CoreAudio driver.
@@ -49,34 +133,127 @@ impl AudioDriver for CoreAudioDriver {
     const DISPLAY_NAME: &'static str = "CoreAudio";
 
     fn version(&self) -> Result<Cow<str>, Self::Error> {
-        Ok(Cow::Borrowed("CoreAudio (version unknown)"))
+        Err(CoreAudioError::VersionUnavailable)
     }
 
     fn default_device(&self, device_type: DeviceType) -> Result<Option<Self::Device>, Self::Error> {
+        // There is no default duplex device, use the default output device if it also has inputs
         let is_input = matches!(device_type, DeviceType::Input);
         let Some(device_id) = get_default_device_id(is_input) else {
             return Ok(None);
         };
-        Ok(Some(CoreAudioDevice {
-            device_id,
-            device_type,
+        let device = CoreAudioDevice::from_id(device_id)?;
+        Ok(device.filter(|device| {
+            device_type != DeviceType::Duplex || device.device_type == DeviceType::Duplex
         }))
     }
 
     fn list_devices(&self) -> Result<impl IntoIterator<Item = Self::Device>, Self::Error> {
-        let per_scope = [Scope::Input, Scope::Output]
+        let devices = get_audio_device_ids()?
             .into_iter()
-            .map(|scope| {
-                let audio_ids = get_audio_device_ids_for_scope(scope)?;
-                Ok::<_, CoreAudioError>(
-                    audio_ids
-                        .into_iter()
-                        .map(|id| CoreAudioDevice::from_id(scope, id))
-                        .collect::<Result<Vec<_>, _>>()?,
-                )
-            })
+            .map(CoreAudioDevice::from_id)
             .collect::<Result<Vec<_>, _>>()?;
-        Ok(per_scope.into_iter().flatten())
+        Ok(devices.into_iter().flatten())
+    }
+
+    fn device_by_id(&self, id: &str) -> Result<Option<Self::Device>, Self::Error> {
+        let uid = CfObject::string(id);
+        let uid: CFStringRef = uid.0.cast();
+        let mut device_id: AudioDeviceID = kAudioObjectUnknown;
+        let mut data_size = mem::size_of::<AudioDeviceID>() as u32;
+        let status = unsafe {
+            AudioObjectGetPropertyData(
+                kAudioObjectSystemObject,
+                &AudioObjectPropertyAddress {
+                    mSelector: kAudioHardwarePropertyTranslateUIDToDevice,
+                    mScope: kAudioObjectPropertyScopeGlobal,
+                    mElement: kAudioObjectPropertyElementMaster,
+                },
+                mem::size_of::<CFStringRef>() as u32,
+                &uid as *const CFStringRef as *const c_void,
+                &mut data_size,
+                &mut device_id as *mut AudioDeviceID as *mut c_void,
+            )
+        };
+        coreaudio::Error::from_os_status(status)?;
+        if device_id == kAudioObjectUnknown {
+            return Ok(None);
+        }
+        CoreAudioDevice::from_id(device_id)
+    }
+}
+
+impl AudioDriverEvents for CoreAudioDriver {
+    type Subscription = CoreAudioEventSubscription;
+
+    fn subscribe_device_events(
+        &self,
+        mut callback: impl 'static + Send + FnMut(DeviceEvent<Self::Device>),
+    ) -> Result<Self::Subscription, Self::Error> {
+        let mut known_devices = self.list_devices()?.into_iter().collect::<Vec<_>>();
+        let listener = PropertyListener::new(
+            kAudioObjectSystemObject,
+            &[
+                kAudioHardwarePropertyDevices,
+                kAudioHardwarePropertyDefaultInputDevice,
+                kAudioHardwarePropertyDefaultOutputDevice,
+            ],
+            move |selector| {
+                if selector == kAudioHardwarePropertyDevices {
+                    let devices = match CoreAudioDriver.list_devices() {
+                        Ok(devices) => devices.into_iter().collect::<Vec<_>>(),
+                        Err(err) => {
+                            diagnostics::error!("Cannot list CoreAudio devices: {err}");
+                            return;
+                        }
+                    };
+                    for device in &known_devices {
+                        if !devices.iter().any(|d| d.is_same_endpoint(device)) {
+                            callback(DeviceEvent::DeviceRemoved(*device));
+                        }
+                    }
+                    for device in &devices {
+                        if !known_devices.iter().any(|d| d.is_same_endpoint(device)) {
+                            callback(DeviceEvent::DeviceAdded(*device));
+                        }
+                    }
+                    known_devices = devices;
+                    return;
+                }
+                let Some(device_type) = default_device_selector_type(selector) else {
+                    return;
+                };
+                let is_input = matches!(device_type, DeviceType::Input);
+                let device = get_default_device_id(is_input)
+                    .and_then(|device_id| CoreAudioDevice::from_id(device_id).ok().flatten());
+                callback(DeviceEvent::DefaultChanged {
+                    device_type,
+                    device,
+                });
+            },
+        )?;
+        Ok(CoreAudioEventSubscription {
+            _listener: listener,
+        })
+    }
+}
+
+/// Subscription to CoreAudio device events. Notifications stop when this is dropped.
+pub struct CoreAudioEventSubscription {
+    _listener: PropertyListener,
+}
+
+impl CoreAudioDriver {
+    /// Pair of the default input and output devices, used together as a duplex device. Returns
+    /// `None` if either default device is missing.
+    pub fn default_duplex_device(&self) -> Result<Option<CoreAudioDuplexDevice>, CoreAudioError> {
+        let Some(input) = self.default_device(DeviceType::Input)? else {
+            return Ok(None);
+        };
+        let Some(output) = self.default_device(DeviceType::Output)? else {
+            return Ok(None);
+        };
+        CoreAudioDuplexDevice::new(input, output).map(Some)
     }
 }
 
@@ -85,24 +262,80 @@ impl AudioDriver for CoreAudioDriver {
 pub struct CoreAudioDevice {
     device_id: AudioDeviceID,
     device_type: DeviceType,
+    follow_default: bool,
+    switch_samplerate: bool,
 }
 
 impl CoreAudioDevice {
-    fn from_id(scope: Scope, device_id: AudioDeviceID) -> Result<Self, CoreAudioError> {
-        let device_type =
-            Self::scope_to_valid_device_type(scope).ok_or(CoreAudioError::InvalidScope(scope))?;
-        Ok(Self {
+    fn new(device_id: AudioDeviceID, device_type: DeviceType) -> Self {
+        Self {
             device_id,
             device_type,
-        })
+            follow_default: false,
+            switch_samplerate: false,
+        }
+    }
+
+    /// Create a device from its ID, detecting whether it has inputs, outputs, or both. Returns
+    /// `None` for devices without any audio stream.
+    fn from_id(device_id: AudioDeviceID) -> Result<Option<Self>, CoreAudioError> {
+        let has_input = get_audio_device_supports_scope(device_id, Scope::Input)?;
+        let has_output = get_audio_device_supports_scope(device_id, Scope::Output)?;
+        let device_type = match (has_input, has_output) {
+            (true, true) => DeviceType::Duplex,
+            (true, false) => DeviceType::Input,
+            (false, true) => DeviceType::Output,
+            (false, false) => return Ok(None),
+        };
+        Ok(Some(Self::new(device_id, device_type)))
+    }
+
+    fn has_input(&self) -> bool {
+        matches!(self.device_type, DeviceType::Input | DeviceType::Duplex)
+    }
+
+    fn has_output(&self) -> bool {
+        matches!(self.device_type, DeviceType::Output | DeviceType::Duplex)
+    }
+
+    /// Whether both values refer to the same device, in the same direction.
+    fn is_same_endpoint(&self, other: &Self) -> bool {
+        self.device_id == other.device_id && self.device_type == other.device_type
     }
 
-    fn scope_to_valid_device_type(scope: Scope) -> Option<DeviceType> {
-        match scope {
-            Scope::Input => Some(DeviceType::Input),
-            Scope::Output => Some(DeviceType::Output),
-            _ => None,
+    /// Make streams opened from this device follow the system default device: whenever the
+    /// default device of the same type changes, running streams migrate to the new default
+    /// device.
+    pub fn with_follow_default(mut self, enabled: bool) -> Self {
+        self.follow_default = enabled;
+        self
+    }
+
+    /// Switch the device itself to the sample rate requested by streams, instead of letting the
+    /// audio unit resample between the stream and the device rates.
+    ///
+    /// Note that this changes the sample rate for all applications using the device.
+    pub fn with_samplerate_switching(mut self, enabled: bool) -> Self {
+        self.switch_samplerate = enabled;
+        self
+    }
+
+    /// Prepare the device for opening a stream with the given configuration, returning the
+    /// configuration the stream is opened with.
+    fn prepare_stream(&self, stream_config: StreamConfig) -> Result<StreamConfig, CoreAudioError> {
+        // Audio units always exchange floating-point samples with streams, and convert from and
+        // to the physical format of the device themselves
+        match stream_config.sample_format {
+            None | Some(WireFormat::F32) => {}
+            Some(format) => return Err(CoreAudioError::UnsupportedSampleFormat(format)),
+        }
+        if self.switch_samplerate {
+            set_nominal_samplerate(self.device_id, stream_config.samplerate)?;
         }
+        Ok(StreamConfig {
+            sample_format: Some(WireFormat::F32),
+            ..stream_config
+        })
     }
 }
 
@@ -113,7 +346,7 @@ impl AudioDevice for CoreAudioDevice {
         match get_device_name(self.device_id) {
             Ok(std) => Cow::Owned(std),
             Err(err) => {
-                eprintln!("Cannot get audio device name: {err}");
+                diagnostics::warn!("Cannot get audio device name: {err}");
                 Cow::Borrowed("<unknown>")
             }
         }
@@ -123,11 +356,18 @@ impl AudioDevice for CoreAudioDevice {
         self.device_type
     }
 
+    /// Unique identifier of the device, which unlike the numeric device ID stays the same across
+    /// reboots.
+    fn device_id(&self) -> Result<Option<Cow<'_, str>>, Self::Error> {
+        Ok(Some(Cow::Owned(object_uid(self.device_id)?)))
+    }
+
     fn channel_map(&self) -> impl IntoIterator<Item = Channel> {
+        // Duplex devices report their output channels
         let is_input = matches!(self.device_type, DeviceType::Input);
         let channels = match audio_unit_from_device_id(self.device_id, is_input) {
             Err(err) => {
-                eprintln!("CoreAudio error getting audio unit: {err}");
+                diagnostics::warn!("CoreAudio error getting audio unit: {err}");
                 0
             }
             Ok(audio_unit) => {
@@ -139,22 +379,49 @@ impl AudioDevice for CoreAudioDevice {
                 stream_format.channels as usize
             }
         };
-        (0..channels).map(|ch| Channel {
-            index: ch,
-            name: Cow::Owned(format!("Channel {}", ch)),
+        let scope = if is_input {
+            kAudioObjectPropertyScopeInput
+        } else {
+            kAudioObjectPropertyScopeOutput
+        };
+        let terminal_types = get_channel_terminal_types(self.device_id, scope)
+            .inspect_err(|err| diagnostics::warn!("Cannot get stream terminal types: {err}"))
+            .unwrap_or_default();
+        let positions = get_channel_positions(self.device_id, scope)
+            .inspect_err(|err| diagnostics::warn!("Cannot get channel layout: {err}"))
+            .unwrap_or_default();
+        let device_id = self.device_id;
+        (0..channels).map(move |ch| {
+            let position = positions.get(ch).copied().flatten();
+            let name = get_channel_name(device_id, scope, ch)
+                .or_else(|| Some(position?.short_name().to_string()))
+                .or_else(|| {
+                    let (terminal_type, index) = terminal_types.get(ch)?;
+                    let label = terminal_type_label(*terminal_type)?;
+                    Some(format!("{label} {}", index + 1))
+                })
+                .unwrap_or_else(|| format!("Channel {}", ch));
+            Channel {
+                index: ch,
+                name: Cow::Owned(name),
+                position,
+            }
         })
     }
 
-    fn is_config_supported(&self, _config: &StreamConfig) -> bool {
-        true
+    fn is_config_supported(&self, config: &StreamConfig) -> bool {
+        config
+            .sample_format
+            .map_or(true, |format| format == WireFormat::F32)
     }
 
     fn enumerate_configurations(&self) -> Option<impl IntoIterator<Item = StreamConfig>> {
         const TYPICAL_SAMPLERATES: [f64; 5] = [44100., 48000., 96000., 128000., 192000.];
         let supported_list = get_supported_physical_stream_formats(self.device_id)
-            .inspect_err(|err| eprintln!("Error getting stream formats: {err}"))
+            .inspect_err(|err| diagnostics::warn!("Error getting stream formats: {err}"))
             .ok()?;
-        Some(supported_list.into_iter().flat_map(|asbd| {
+        let device_type = self.device_type;
+        Some(supported_list.into_iter().flat_map(move |asbd| {
             let samplerate_range = asbd.mSampleRateRange.mMinimum..asbd.mSampleRateRange.mMaximum;
             TYPICAL_SAMPLERATES
                 .iter()
@@ -166,25 +433,505 @@ impl AudioDevice for CoreAudioDevice {
                         .map(move |exclusive| (sr, exclusive))
                 })
                 .map(move |(samplerate, exclusive)| {
-                    let channels = 1 << asbd.mFormat.mChannelsPerFrame as u32 - 1;
+                    let channels = ChannelMap32::first_n(asbd.mFormat.mChannelsPerFrame as usize);
+                    let input = matches!(device_type, DeviceType::Input | DeviceType::Duplex);
+                    let output = matches!(device_type, DeviceType::Output | DeviceType::Duplex);
                     StreamConfig {
                         samplerate,
-                        channels,
+                        input_channels: if input { channels } else { 0 },
+                        output_channels: if output { channels } else { 0 },
                         buffer_size_range: (None, None),
                         exclusive,
+                        fallback_to_shared: false,
+                        sample_format: None,
+                        input_trim: ChannelTrim::default(),
+                        output_trim: ChannelTrim::default(),
+                        metering: false,
                     }
                 })
         }))
     }
 }
 
-fn input_stream_format(sample_rate: f64) -> StreamFormat {
+/// Reads a fixed-size property of a CoreAudio object.
+fn get_object_property<T: Copy>(
+    object_id: AudioObjectID,
+    address: &AudioObjectPropertyAddress,
+) -> Result<T, coreaudio::Error> {
+    // Property values are plain C data, for which all zeroes is a valid value
+    let mut value: T = unsafe { mem::zeroed() };
+    let mut data_size = mem::size_of::<T>() as u32;
+    let status = unsafe {
+        AudioObjectGetPropertyData(
+            object_id,
+            address,
+            0,
+            ptr::null(),
+            &mut data_size,
+            &mut value as *mut T as *mut c_void,
+        )
+    };
+    coreaudio::Error::from_os_status(status)?;
+    Ok(value)
+}
+
+/// Reads an array property of a CoreAudio object.
+fn get_object_property_array<T: Copy>(
+    object_id: AudioObjectID,
+    address: &AudioObjectPropertyAddress,
+) -> Result<Vec<T>, coreaudio::Error> {
+    let mut data_size = 0;
+    let status = unsafe {
+        AudioObjectGetPropertyDataSize(object_id, address, 0, ptr::null(), &mut data_size)
+    };
+    coreaudio::Error::from_os_status(status)?;
+    let mut values = vec![unsafe { mem::zeroed::<T>() }; data_size as usize / mem::size_of::<T>()];
+    let status = unsafe {
+        AudioObjectGetPropertyData(
+            object_id,
+            address,
+            0,
+            ptr::null(),
+            &mut data_size,
+            values.as_mut_ptr() as *mut c_void,
+        )
+    };
+    coreaudio::Error::from_os_status(status)?;
+    values.truncate(data_size as usize / mem::size_of::<T>());
+    Ok(values)
+}
+
+/// Unique identifier of a CoreAudio object, which persists across reboots.
+fn object_uid(object_id: AudioObjectID) -> Result<String, CoreAudioError> {
+    let uid: CFStringRef = get_object_property(
+        object_id,
+        &AudioObjectPropertyAddress {
+            mSelector: kAudioDevicePropertyDeviceUID,
+            mScope: kAudioObjectPropertyScopeGlobal,
+            mElement: kAudioObjectPropertyElementMaster,
+        },
+    )?;
+    if uid.is_null() {
+        return Err(CoreAudioError::MissingDeviceUid);
+    }
+    let uid = CfObject(uid.cast());
+    cfstring_to_string(uid.0.cast()).ok_or(CoreAudioError::MissingDeviceUid)
+}
+
+/// Writes a property of a CoreAudio object.
+fn set_object_property<T: Copy>(
+    object_id: AudioObjectID,
+    address: &AudioObjectPropertyAddress,
+    value: &T,
+) -> Result<(), coreaudio::Error> {
+    let status = unsafe {
+        AudioObjectSetPropertyData(
+            object_id,
+            address,
+            0,
+            ptr::null(),
+            mem::size_of::<T>() as u32,
+            value as *const T as *const c_void,
+        )
+    };
+    coreaudio::Error::from_os_status(status)
+}
+
+/// Switches the nominal sample rate of the device, and waits for the change to take effect.
+fn set_nominal_samplerate(device_id: AudioDeviceID, samplerate: f64) -> Result<(), CoreAudioError> {
+    /// Devices usually take a few hundred milliseconds to switch their sample rate.
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    let address = AudioObjectPropertyAddress {
+        mSelector: kAudioDevicePropertyNominalSampleRate,
+        mScope: kAudioObjectPropertyScopeGlobal,
+        mElement: kAudioObjectPropertyElementMaster,
+    };
+    let current: f64 = get_object_property(device_id, &address)?;
+    if current == samplerate {
+        return Ok(());
+    }
+    let ranges: Vec<AudioValueRange> = get_object_property_array(
+        device_id,
+        &AudioObjectPropertyAddress {
+            mSelector: kAudioDevicePropertyAvailableNominalSampleRates,
+            ..address
+        },
+    )?;
+    if !ranges
+        .iter()
+        .any(|range| (range.mMinimum..=range.mMaximum).contains(&samplerate))
+    {
+        return Err(CoreAudioError::UnsupportedSamplerate(samplerate));
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let _listener = PropertyListener::new(
+        device_id,
+        &[kAudioDevicePropertyNominalSampleRate],
+        move |_| {
+            let _ = tx.send(());
+        },
+    )?;
+    set_object_property(device_id, &address, &samplerate)?;
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let current: f64 = get_object_property(device_id, &address)?;
+        if current == samplerate {
+            return Ok(());
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(CoreAudioError::SamplerateChangeTimeout(samplerate));
+        }
+        // Also re-checks when the timeout expires, in case the notification got lost
+        let _ = rx.recv_timeout(remaining);
+    }
+}
+
+/// Keeps track of the buffer size of a device, which other applications or the user can change
+/// at any time while streams are running.
+struct BufferSizeWatch {
+    /// Current buffer size of the device, in frames.
+    frame_size: Arc<AtomicUsize>,
+    /// Largest buffer size the device supports, in frames, which callback buffers are allocated
+    /// for so that buffer size changes never require allocating in the audio thread.
+    max_frame_count: usize,
+    listener: PropertyListener,
+}
+
+impl BufferSizeWatch {
+    fn new(device_id: AudioDeviceID) -> Result<Self, CoreAudioError> {
+        let address = AudioObjectPropertyAddress {
+            mSelector: kAudioDevicePropertyBufferFrameSize,
+            mScope: kAudioObjectPropertyScopeGlobal,
+            mElement: kAudioObjectPropertyElementMaster,
+        };
+        let current: u32 = get_object_property(device_id, &address)?;
+        let range: AudioValueRange = get_object_property(
+            device_id,
+            &AudioObjectPropertyAddress {
+                mSelector: kAudioDevicePropertyBufferFrameSizeRange,
+                ..address
+            },
+        )?;
+        let frame_size = Arc::new(AtomicUsize::new(current as _));
+        let listener = PropertyListener::new(device_id, &[kAudioDevicePropertyBufferFrameSize], {
+            let frame_size = frame_size.clone();
+            move |_| match get_object_property::<u32>(device_id, &address) {
+                Ok(value) => frame_size.store(value as _, Ordering::Relaxed),
+                Err(err) => diagnostics::warn!("Cannot get device buffer size: {err}"),
+            }
+        })?;
+        Ok(Self {
+            frame_size,
+            max_frame_count: (range.mMaximum as usize).max(current as _),
+            listener,
+        })
+    }
+}
+
+/// Updates the stream configuration passed to the callback when the buffer size of the device
+/// changes, returning `true` if the callback needs to be prepared again.
+fn update_buffer_size(stream_config: &mut StreamConfig, frame_size: &AtomicUsize) -> bool {
+    let frame_size = Some(BufferSize::Frames(frame_size.load(Ordering::Relaxed)));
+    if stream_config.buffer_size_range == (frame_size, frame_size) {
+        return false;
+    }
+    stream_config.buffer_size_range = (frame_size, frame_size);
+    true
+}
+
+/// Name given by the driver to a channel of the device, if any.
+fn get_channel_name(
+    device_id: AudioDeviceID,
+    scope: AudioObjectPropertyScope,
+    channel: usize,
+) -> Option<String> {
+    let address = AudioObjectPropertyAddress {
+        mSelector: kAudioObjectPropertyElementName,
+        mScope: scope,
+        // Element 0 is the main element, channels start at 1
+        mElement: channel as u32 + 1,
+    };
+    let name: CFStringRef = get_object_property(device_id, &address).ok()?;
+    if name.is_null() {
+        return None;
+    }
+    let string = cfstring_to_string(name);
+    unsafe { CFRelease(name.cast()) };
+    string.filter(|name| !name.is_empty())
+}
+
+/// Terminal type of the stream each channel belongs to, along with the index of the channel
+/// within that stream.
+fn get_channel_terminal_types(
+    device_id: AudioDeviceID,
+    scope: AudioObjectPropertyScope,
+) -> Result<Vec<(u32, usize)>, coreaudio::Error> {
+    let streams: Vec<AudioStreamID> = get_object_property_array(
+        device_id,
+        &AudioObjectPropertyAddress {
+            mSelector: kAudioDevicePropertyStreams,
+            mScope: scope,
+            mElement: kAudioObjectPropertyElementMaster,
+        },
+    )?;
+    let mut terminal_types = vec![];
+    for stream_id in streams {
+        let global_address = |selector| AudioObjectPropertyAddress {
+            mSelector: selector,
+            mScope: kAudioObjectPropertyScopeGlobal,
+            mElement: kAudioObjectPropertyElementMaster,
+        };
+        let format: AudioStreamBasicDescription = get_object_property(
+            stream_id,
+            &global_address(kAudioStreamPropertyVirtualFormat),
+        )?;
+        let terminal_type: u32 =
+            get_object_property(stream_id, &global_address(kAudioStreamPropertyTerminalType))?;
+        terminal_types.extend((0..format.mChannelsPerFrame as usize).map(|i| (terminal_type, i)));
+    }
+    Ok(terminal_types)
+}
+
+/// Latency of the device currently used by the audio unit in the given scope, in frames. This
+/// is the sum of the device and stream latencies and of the safety offset of the device.
+fn audio_unit_latency(
+    audio_unit: &AudioUnit,
+    scope: AudioObjectPropertyScope,
+) -> Result<usize, coreaudio::Error> {
+    let device_id: AudioDeviceID = audio_unit.get_property(
+        kAudioOutputUnitProperty_CurrentDevice,
+        Scope::Global,
+        Element::Output,
+    )?;
+    let address = |selector| AudioObjectPropertyAddress {
+        mSelector: selector,
+        mScope: scope,
+        mElement: kAudioObjectPropertyElementMaster,
+    };
+    let device_latency: u32 =
+        get_object_property(device_id, &address(kAudioDevicePropertyLatency))?;
+    let safety_offset: u32 =
+        get_object_property(device_id, &address(kAudioDevicePropertySafetyOffset))?;
+    let streams: Vec<AudioStreamID> =
+        get_object_property_array(device_id, &address(kAudioDevicePropertyStreams))?;
+    // All streams of a device are clocked together, the first one is representative
+    let stream_latency: u32 = match streams.first() {
+        Some(&stream_id) => get_object_property(
+            stream_id,
+            &AudioObjectPropertyAddress {
+                mSelector: kAudioStreamPropertyLatency,
+                mScope: kAudioObjectPropertyScopeGlobal,
+                mElement: kAudioObjectPropertyElementMaster,
+            },
+        )?,
+        None => 0,
+    };
+    Ok((device_latency + safety_offset + stream_latency) as usize)
+}
+
+/// Time on the host clock of a callback timestamp, if the timestamp has a valid host time.
+///
+/// Callback timestamps give the time at which their first frame enters or leaves the audio unit,
+/// so device latency has to be accounted for to get the capture or presentation time.
+fn host_time(time_stamp: &AudioTimeStamp) -> Option<HostTime> {
+    if time_stamp.mFlags & kAudioTimeStampHostTimeValid == 0 {
+        return None;
+    }
+    let nanos = unsafe { AudioConvertHostTimeToNanos(time_stamp.mHostTime) };
+    Some(HostTime::from_duration(Duration::from_nanos(nanos)))
+}
+
+/// Speaker position of each channel, from the preferred channel layout of the device. Layouts
+/// only given as a predefined layout tag are not expanded, and give no positions.
+#[allow(non_upper_case_globals)]
+fn get_channel_positions(
+    device_id: AudioDeviceID,
+    scope: AudioObjectPropertyScope,
+) -> Result<Vec<Option<ChannelPosition>>, coreaudio::Error> {
+    // `AudioChannelLayout` is a variable-sized structure made only of 32-bit fields, read as such
+    let layout: Vec<u32> = get_object_property_array(
+        device_id,
+        &AudioObjectPropertyAddress {
+            mSelector: kAudioDevicePropertyPreferredChannelLayout,
+            mScope: scope,
+            mElement: kAudioObjectPropertyElementMaster,
+        },
+    )?;
+    let [tag, bitmap, count, descriptions @ ..] = layout.as_slice() else {
+        return Ok(vec![]);
+    };
+    Ok(match *tag {
+        // Each `AudioChannelDescription` is made of a label, flags and 3 coordinates
+        kAudioChannelLayoutTag_UseChannelDescriptions => descriptions
+            .chunks_exact(5)
+            .take(*count as usize)
+            .map(|description| channel_label_position(description[0]))
+            .collect(),
+        kAudioChannelLayoutTag_UseChannelBitmap => SpeakerLayout::from_mask(*bitmap)
+            .positions()
+            .map(Some)
+            .collect(),
+        _ => vec![],
+    })
+}
+
+#[allow(non_upper_case_globals)]
+fn channel_label_position(label: u32) -> Option<ChannelPosition> {
+    Some(match label {
+        kAudioChannelLabel_Left => ChannelPosition::FrontLeft,
+        kAudioChannelLabel_Right => ChannelPosition::FrontRight,
+        kAudioChannelLabel_Center | kAudioChannelLabel_Mono => ChannelPosition::FrontCenter,
+        kAudioChannelLabel_LFEScreen => ChannelPosition::LowFrequency,
+        kAudioChannelLabel_LeftSurround | kAudioChannelLabel_RearSurroundLeft => {
+            ChannelPosition::BackLeft
+        }
+        kAudioChannelLabel_RightSurround | kAudioChannelLabel_RearSurroundRight => {
+            ChannelPosition::BackRight
+        }
+        kAudioChannelLabel_LeftCenter => ChannelPosition::FrontLeftOfCenter,
+        kAudioChannelLabel_RightCenter => ChannelPosition::FrontRightOfCenter,
+        kAudioChannelLabel_CenterSurround => ChannelPosition::BackCenter,
+        kAudioChannelLabel_LeftSurroundDirect => ChannelPosition::SideLeft,
+        kAudioChannelLabel_RightSurroundDirect => ChannelPosition::SideRight,
+        kAudioChannelLabel_TopCenterSurround => ChannelPosition::TopCenter,
+        kAudioChannelLabel_VerticalHeightLeft => ChannelPosition::TopFrontLeft,
+        kAudioChannelLabel_VerticalHeightCenter => ChannelPosition::TopFrontCenter,
+        kAudioChannelLabel_VerticalHeightRight => ChannelPosition::TopFrontRight,
+        kAudioChannelLabel_TopBackLeft => ChannelPosition::TopBackLeft,
+        kAudioChannelLabel_TopBackCenter => ChannelPosition::TopBackCenter,
+        kAudioChannelLabel_TopBackRight => ChannelPosition::TopBackRight,
+        _ => return None,
+    })
+}
+
+#[allow(non_upper_case_globals)]
+fn terminal_type_label(terminal_type: u32) -> Option<&'static str> {
+    Some(match terminal_type {
+        kAudioStreamTerminalTypeLine => "Line",
+        kAudioStreamTerminalTypeDigitalAudioInterface => "Digital",
+        kAudioStreamTerminalTypeSpeaker => "Speaker",
+        kAudioStreamTerminalTypeHeadphones => "Headphones",
+        kAudioStreamTerminalTypeLFESpeaker => "LFE",
+        kAudioStreamTerminalTypeReceiverSpeaker => "Receiver",
+        kAudioStreamTerminalTypeMicrophone => "Microphone",
+        kAudioStreamTerminalTypeHeadsetMicrophone => "Headset Microphone",
+        kAudioStreamTerminalTypeReceiverMicrophone => "Receiver Microphone",
+        kAudioStreamTerminalTypeHDMI => "HDMI",
+        kAudioStreamTerminalTypeDisplayPort => "DisplayPort",
+        _ => return None,
+    })
+}
+
+fn cfstring_to_string(string: CFStringRef) -> Option<String> {
+    unsafe {
+        let length = CFStringGetLength(string);
+        let capacity = CFStringGetMaximumSizeForEncoding(length, kCFStringEncodingUTF8) + 1;
+        let mut buffer = vec![0u8; capacity as usize];
+        let success = CFStringGetCString(
+            string,
+            buffer.as_mut_ptr().cast(),
+            capacity,
+            kCFStringEncodingUTF8,
+        );
+        if success == 0 {
+            return None;
+        }
+        let c_str = CStr::from_bytes_until_nul(&buffer).ok()?;
+        Some(c_str.to_string_lossy().into_owned())
+    }
+}
+
+/// Client-side format of input streams. The audio unit only supports multichannel input in
+/// interleaved form, and converts from the native format of the device to 32-bit floats without
+/// loss of precision.
+fn input_stream_format(sample_rate: f64, channels: ChannelMap32) -> StreamFormat {
     StreamFormat {
         sample_rate,
-        sample_format: SampleFormat::I16,
-        flags: LinearPcmFlags::IS_NON_INTERLEAVED | LinearPcmFlags::IS_SIGNED_INTEGER,
-        channels: 1,
+        sample_format: SampleFormat::F32,
+        flags: LinearPcmFlags::IS_FLOAT | LinearPcmFlags::IS_PACKED,
+        channels: channels.count() as _,
+    }
+}
+
+/// Native format of the device an input audio unit is connected to.
+fn device_input_format(
+    audio_unit: &AudioUnit,
+) -> Result<AudioStreamBasicDescription, CoreAudioError> {
+    Ok(audio_unit.get_property(
+        kAudioUnitProperty_StreamFormat,
+        Scope::Input,
+        Element::Input,
+    )?)
+}
+
+/// Restricts the requested input configuration to what the device can provide.
+fn negotiate_input_config(
+    audio_unit: &AudioUnit,
+    mut stream_config: StreamConfig,
+) -> Result<StreamConfig, CoreAudioError> {
+    let device_channels = device_input_format(audio_unit)?.mChannelsPerFrame as usize;
+    let requested = stream_config.input_channels;
+    let available = requested
+        .indices()
+        .into_iter()
+        .filter(|&i| i < device_channels);
+    stream_config.input_channels = 0u32.with_indices(available);
+    if stream_config.input_channels == 0 {
+        stream_config.input_channels =
+            ChannelMap32::first_n(requested.count().min(device_channels));
     }
+    Ok(stream_config)
+}
+
+/// Routes the selected device channels to the channels of the audio unit, when they are not the
+/// first channels of the device.
+///
+/// The channel map of an input unit gives the device channel of each stream channel, while the
+/// channel map of an output unit gives the stream channel of each device channel, with `-1`
+/// leaving the device channel silent.
+fn set_channel_map(
+    audio_unit: &mut AudioUnit,
+    element: Element,
+    channels: ChannelMap32,
+) -> Result<(), CoreAudioError> {
+    if channels == ChannelMap32::first_n(channels.count()) {
+        return Ok(());
+    }
+    let selected = channels.indices().into_iter().collect::<Vec<_>>();
+    let (scope, map) = match element {
+        Element::Input => {
+            let map = selected.iter().map(|&channel| channel as i32).collect();
+            (Scope::Output, map)
+        }
+        Element::Output => {
+            let device_format: AudioStreamBasicDescription = audio_unit.get_property(
+                kAudioUnitProperty_StreamFormat,
+                Scope::Output,
+                Element::Output,
+            )?;
+            let mut map = vec![-1i32; device_format.mChannelsPerFrame as usize];
+            for (i, &channel) in selected.iter().enumerate() {
+                if let Some(entry) = map.get_mut(channel) {
+                    *entry = i as i32;
+                }
+            }
+            (Scope::Input, map)
+        }
+    };
+    let status = unsafe {
+        AudioUnitSetProperty(
+            *audio_unit.as_ref(),
+            kAudioOutputUnitProperty_ChannelMap,
+            scope as _,
+            element as _,
+            map.as_ptr().cast(),
+            mem::size_of_val(map.as_slice()) as _,
+        )
+    };
+    Ok(coreaudio::Error::from_os_status(status)?)
 }
 
 impl AudioInputDevice for CoreAudioDevice {
@@ -192,16 +939,18 @@ impl AudioInputDevice for CoreAudioDevice {
 
     fn default_input_config(&self) -> Result<StreamConfig, Self::Error> {
         let audio_unit = audio_unit_from_device_id(self.device_id, true)?;
-        let samplerate = audio_unit.get_property::<f64>(
-            kAudioUnitProperty_SampleRate,
-            Scope::Input,
-            Element::Input,
-        )?;
+        let format = device_input_format(&audio_unit)?;
         Ok(StreamConfig {
-            channels: 0b1, // Hardcoded to mono on non-interleaved inputs
-            samplerate,
+            input_channels: ChannelMap32::first_n(format.mChannelsPerFrame as usize),
+            output_channels: 0,
+            samplerate: format.mSampleRate,
             buffer_size_range: (None, None),
             exclusive: false,
+            fallback_to_shared: false,
+            sample_format: None,
+            input_trim: ChannelTrim::default(),
+            output_trim: ChannelTrim::default(),
+            metering: false,
         })
     }
 
@@ -210,7 +959,9 @@ impl AudioInputDevice for CoreAudioDevice {
         stream_config: StreamConfig,
         callback: Callback,
     ) -> Result<Self::StreamHandle<Callback>, Self::Error> {
-        CoreAudioStream::new_input(self.device_id, stream_config, callback)
+        let stream_config = stream_config.resolve_exclusive(|| self.supports_exclusive());
+        let stream_config = self.prepare_stream(stream_config)?;
+        CoreAudioStream::new_input(self.device_id, self.follow_default, stream_config, callback)
     }
 }
 
@@ -219,7 +970,7 @@ fn output_stream_format(sample_rate: f64, channels: ChannelMap32) -> StreamForma
         sample_rate,
         sample_format: SampleFormat::F32,
         flags: LinearPcmFlags::IS_NON_INTERLEAVED | LinearPcmFlags::IS_FLOAT,
-        channels,
+        channels: channels.count() as _,
     }
 }
 
@@ -232,8 +983,14 @@ impl AudioOutputDevice for CoreAudioDevice {
         Ok(StreamConfig {
             samplerate,
             buffer_size_range: (None, None),
-            channels: 0b11,
+            input_channels: 0,
+            output_channels: 0b11,
             exclusive: false,
+            fallback_to_shared: false,
+            sample_format: None,
+            input_trim: ChannelTrim::default(),
+            output_trim: ChannelTrim::default(),
+            metering: false,
         })
     }
 
@@ -242,84 +999,230 @@ impl AudioOutputDevice for CoreAudioDevice {
         stream_config: StreamConfig,
         callback: Callback,
     ) -> Result<Self::StreamHandle<Callback>, Self::Error> {
-        CoreAudioStream::new_output(self.device_id, stream_config, callback)
+        let stream_config = stream_config.resolve_exclusive(|| self.supports_exclusive());
+        let stream_config = self.prepare_stream(stream_config)?;
+        CoreAudioStream::new_output(self.device_id, self.follow_default, stream_config, callback)
     }
 }
 
+/// Time given to audio units to give the callback back when their stream is ejected, before
+/// their callbacks are freed.
+const EJECT_TIMEOUT: Duration = Duration::from_millis(500);
+
 pub struct CoreAudioStream<Callback> {
+    listeners: Vec<PropertyListener>,
+    scope: AudioObjectPropertyScope,
     audio_unit: AudioUnit,
-    callback_retrieve: oneshot::Sender<oneshot::Sender<Callback>>,
+    control: StreamControl<Callback>,
+    events: StreamEventSender,
+    meter: StreamMeter,
 }
 
 impl<Callback> AudioStreamHandle<Callback> for CoreAudioStream<Callback> {
-    type Error = Infallible;
+    type Error = EjectError<Callback, Infallible>;
 
     fn eject(mut self) -> Result<Callback, Self::Error> {
-        let (tx, rx) = oneshot::channel();
-        self.callback_retrieve.send(tx).unwrap();
-        let callback = rx.recv().unwrap();
+        // Stop reacting to device changes before tearing down the audio unit
+        self.listeners.clear();
+        self.control.request_eject();
+        let ejected = self.control.wait_ejected_timeout(EJECT_TIMEOUT);
+        // Audio units of devices which have disappeared do not call back anymore; freeing the
+        // callbacks then drops the callback slot, giving the callback back
         self.audio_unit.free_input_callback();
         self.audio_unit.free_render_callback();
+        let callback = match ejected {
+            Some(callback) => callback,
+            None => self.control.collect(Ok(()))?,
+        };
+        self.events.send(StreamEvent::Ejected);
         Ok(callback)
     }
+
+    fn subscribe_events(&self) -> StreamEventReceiver {
+        self.events.subscribe()
+    }
+
+    fn levels(&self) -> Option<StreamLevels> {
+        self.meter.levels()
+    }
+
+    fn control(&self) -> Option<&StreamControl<Callback>> {
+        Some(&self.control)
+    }
+
+    fn latency(&self) -> StreamLatency {
+        let latency = audio_unit_latency(&self.audio_unit, self.scope)
+            .inspect_err(|err| diagnostics::warn!("Cannot get stream latency: {err}"))
+            .ok();
+        if self.scope == kAudioObjectPropertyScopeInput {
+            StreamLatency {
+                input: latency,
+                output: None,
+            }
+        } else {
+            StreamLatency {
+                input: None,
+                output: latency,
+            }
+        }
+    }
 }
 
 impl<Callback: 'static + Send + AudioInputCallback> CoreAudioStream<Callback> {
     fn new_input(
         device_id: AudioDeviceID,
+        follow_default: bool,
         stream_config: StreamConfig,
-        callback: Callback,
+        mut callback: Callback,
     ) -> Result<Self, CoreAudioError> {
         let mut audio_unit = audio_unit_from_device_id(device_id, true)?;
-        let asbd = input_stream_format(stream_config.samplerate).to_asbd();
+        let stream_config = negotiate_input_config(&audio_unit, stream_config)?;
+        let asbd =
+            input_stream_format(stream_config.samplerate, stream_config.input_channels).to_asbd();
         audio_unit.set_property(
             kAudioUnitProperty_StreamFormat,
             Scope::Output,
             Element::Input,
             Some(&asbd),
         )?;
-        let mut buffer = AudioBuffer::zeroed(1, stream_config.samplerate as _);
+        set_channel_map(
+            &mut audio_unit,
+            Element::Input,
+            stream_config.input_channels,
+        )?;
+        let channels = stream_config.input_channels.count();
+        let trim = stream_config.input_trim.amplitudes(channels);
+        let (meter, mut input_tap, _) = StreamMeter::new(stream_config.metering, channels, 0);
+        let input_latency = audio_unit_latency(&audio_unit, kAudioObjectPropertyScopeInput)
+            .inspect_err(|err| diagnostics::warn!("Cannot get stream latency: {err}"))
+            .unwrap_or(0);
+        let input_latency =
+            Timestamp::from_count(stream_config.samplerate, input_latency as _).as_duration();
+        let buffer_size = BufferSizeWatch::new(device_id)?;
+        let frame_size = buffer_size.frame_size.clone();
+        let mut stream_config = stream_config;
+        update_buffer_size(&mut stream_config, &frame_size);
+
+        callback.prepare(AudioCallbackContext {
+            stream_config,
+            timestamp: Timestamp::new(stream_config.samplerate),
+            output_time: None,
+            deadline: None,
+            queued_frames: None,
+            frames_since_last_callback: None,
+            discontinuity: false,
+        });
 
-        // Set up the callback retrieval process, without needing to make the callback `Sync`
-        let (tx, rx) = oneshot::channel::<oneshot::Sender<Callback>>();
+        // Commands are applied by the audio unit callback, without needing to make the callback
+        // `Sync`
+        let (control, mut receiver, callback) = control_queue(callback);
+        let events = StreamEventSender::new();
+        let audio_events = events.clone();
         let mut callback = Some(callback);
-        audio_unit.set_input_callback(move |mut args: Args<data::NonInterleaved<i16>>| {
-            if let Ok(sender) = rx.try_recv() {
-                sender.send(callback.take().unwrap()).unwrap();
-                return Err(());
-            }
-            let mut buffer = buffer.slice_mut(..args.num_frames);
-            for (input, mut inner) in args.data.channels().zip(buffer.channels_mut()) {
-                for (s1, s2) in input.into_iter().zip(inner.iter_mut()) {
-                    *s2 = s1.into_float();
-                }
-            }
-            let timestamp =
-                Timestamp::from_count(stream_config.samplerate, args.time_stamp.mSampleTime as _);
-            let input = AudioInput {
-                buffer: buffer.as_ref(),
-                timestamp,
-            };
-            if let Some(callback) = &mut callback {
-                callback.on_input_data(
-                    AudioCallbackContext {
+        let mut timeline = Timestamp::new(stream_config.samplerate);
+        let mut tracker = CallbackTracker::default();
+        audio_unit.set_input_callback(move |mut args: Args<data::Interleaved<f32>>| {
+            realtime::process_cycle(|| {
+                let Some(inner) = &mut callback else {
+                    return Ok(());
+                };
+                if receiver.process(inner, &audio_events) {
+                    inner.prepare(AudioCallbackContext {
                         stream_config,
-                        timestamp,
-                    },
-                    input,
-                );
-                for (input, inner) in args.data.channels_mut().zip(buffer.channels()) {
-                    for (s1, s2) in input.into_iter().zip(inner.iter()) {
-                        *s1 = i16::from_float(*s2);
+                        timestamp: timeline,
+                        output_time: None,
+                        deadline: None,
+                        queued_frames: None,
+                        frames_since_last_callback: None,
+                        discontinuity: false,
+                    });
+                }
+                if receiver.eject_requested() {
+                    // Dropping the slot gives the callback back to the handle
+                    drop(callback.take());
+                    return Err(());
+                }
+                timeline.advance_to(args.time_stamp.mSampleTime as _);
+                let timestamp = timeline;
+                let deadline = callback_deadline(stream_config.samplerate, args.num_frames);
+                if update_buffer_size(&mut stream_config, &frame_size) {
+                    if let Some(callback) = &mut callback {
+                        callback.prepare(AudioCallbackContext {
+                            stream_config,
+                            timestamp,
+                            output_time: None,
+                            deadline: None,
+                            queued_frames: None,
+                            frames_since_last_callback: None,
+                            discontinuity: false,
+                        });
                     }
                 }
-            }
-            Ok(())
+                // The samples are already in the right format, pass them through without copying
+                let data = &mut args.data.buffer[..args.num_frames * channels];
+                if let Some(mut buffer) = AudioMut::from_interleaved_mut(data, channels) {
+                    if let Some(amplitudes) = &trim {
+                        buffer.change_channel_amplitudes(amplitudes);
+                    }
+                    receiver.apply_volume(buffer);
+                }
+                let Some(buffer) = AudioRef::from_interleaved(data, channels) else {
+                    return Ok(());
+                };
+                if let Some(tap) = &mut input_tap {
+                    tap.process(buffer);
+                }
+                if receiver.is_paused() {
+                    return Ok(());
+                }
+                let capture_time =
+                    host_time(&args.time_stamp).map(|time| time.saturating_sub(input_latency));
+                // Frames captured since the first frame of the buffer, which are not in the buffer
+                let queued_frames = capture_time.map(|time| {
+                    let frames = time.elapsed().as_secs_f64() * stream_config.samplerate;
+                    (frames as usize).saturating_sub(args.num_frames)
+                });
+                let input = AudioInput {
+                    buffer,
+                    timestamp,
+                    capture_time,
+                };
+                let (frames_since_last_callback, discontinuity) =
+                    tracker.next(timestamp, args.num_frames);
+                if let Some(callback) = &mut callback {
+                    callback.on_input_data(
+                        AudioCallbackContext {
+                            stream_config,
+                            timestamp,
+                            output_time: None,
+                            deadline: Some(deadline),
+                            queued_frames,
+                            frames_since_last_callback,
+                            discontinuity,
+                        },
+                        input,
+                    );
+                }
+                Ok(())
+            })
         })?;
         audio_unit.start()?;
+        events.send(StreamEvent::Started);
+        let mut listeners = vec![buffer_size.listener, overload_listener(device_id, &events)?];
+        if follow_default {
+            listeners.push(follow_default_device(
+                &audio_unit,
+                DeviceType::Input,
+                &events,
+            )?);
+        }
         Ok(Self {
+            listeners,
+            scope: kAudioObjectPropertyScopeInput,
             audio_unit,
-            callback_retrieve: tx,
+            control,
+            events,
+            meter,
         })
     }
 }
@@ -327,55 +1230,760 @@ impl<Callback: 'static + Send + AudioInputCallback> CoreAudioStream<Callback> {
 impl<Callback: 'static + Send + AudioOutputCallback> CoreAudioStream<Callback> {
     fn new_output(
         device_id: AudioDeviceID,
+        follow_default: bool,
         stream_config: StreamConfig,
-        callback: Callback,
+        mut callback: Callback,
     ) -> Result<Self, CoreAudioError> {
         let mut audio_unit = audio_unit_from_device_id(device_id, false)?;
-        let asbd = output_stream_format(stream_config.samplerate, stream_config.channels).to_asbd();
+        let asbd =
+            output_stream_format(stream_config.samplerate, stream_config.output_channels).to_asbd();
         audio_unit.set_property(
             kAudioUnitProperty_StreamFormat,
             Scope::Input,
             Element::Output,
             Some(&asbd),
         )?;
+        set_channel_map(
+            &mut audio_unit,
+            Element::Output,
+            stream_config.output_channels,
+        )?;
+        let buffer_size = BufferSizeWatch::new(device_id)?;
+        let frame_size = buffer_size.frame_size.clone();
+        let mut stream_config = stream_config;
+        update_buffer_size(&mut stream_config, &frame_size);
         let mut buffer = AudioBuffer::zeroed(
-            stream_config.channels.count(),
-            stream_config.samplerate as _,
+            stream_config.output_channels.count(),
+            buffer_size.max_frame_count,
         );
+        let trim = stream_config
+            .output_trim
+            .amplitudes(stream_config.output_channels.count());
+        let (meter, _, mut output_tap) = StreamMeter::new(
+            stream_config.metering,
+            0,
+            stream_config.output_channels.count(),
+        );
+        let output_latency = audio_unit_latency(&audio_unit, kAudioObjectPropertyScopeOutput)
+            .inspect_err(|err| diagnostics::warn!("Cannot get stream latency: {err}"))
+            .unwrap_or(0);
+
+        callback.prepare(AudioCallbackContext {
+            stream_config,
+            timestamp: Timestamp::new(stream_config.samplerate),
+            output_time: None,
+            deadline: None,
+            queued_frames: None,
+            frames_since_last_callback: None,
+            discontinuity: false,
+        });
 
-        // Set up the callback retrieval process, without needing to make the callback `Sync`
-        let (tx, rx) = oneshot::channel::<oneshot::Sender<Callback>>();
+        // Commands are applied by the audio unit callback, without needing to make the callback
+        // `Sync`
+        let (control, mut receiver, callback) = control_queue(callback);
+        let events = StreamEventSender::new();
+        let audio_events = events.clone();
         let mut callback = Some(callback);
+        let mut timeline = Timestamp::new(stream_config.samplerate);
+        let mut tracker = CallbackTracker::default();
         audio_unit.set_render_callback(move |mut args: Args<data::NonInterleaved<f32>>| {
-            if let Ok(sender) = rx.try_recv() {
-                sender.send(callback.take().unwrap()).unwrap();
-                return Err(());
-            }
-            let mut buffer = buffer.slice_mut(..args.num_frames);
-            let timestamp =
-                Timestamp::from_count(stream_config.samplerate, args.time_stamp.mSampleTime as _);
-            let output = AudioOutput {
-                buffer: buffer.as_mut(),
-                timestamp,
-            };
-            if let Some(callback) = &mut callback {
-                callback.on_output_data(
-                    AudioCallbackContext {
+            realtime::process_cycle(|| {
+                let Some(inner) = &mut callback else {
+                    return Ok(());
+                };
+                if receiver.process(inner, &audio_events) {
+                    inner.prepare(AudioCallbackContext {
+                        stream_config,
+                        timestamp: timeline,
+                        output_time: None,
+                        deadline: None,
+                        queued_frames: None,
+                        frames_since_last_callback: None,
+                        discontinuity: false,
+                    });
+                }
+                if receiver.eject_requested() {
+                    // Dropping the slot gives the callback back to the handle
+                    drop(callback.take());
+                    return Err(());
+                }
+                let Some(callback) = &mut callback else {
+                    return Ok(());
+                };
+                timeline.advance_to(args.time_stamp.mSampleTime as _);
+                let mut timestamp = timeline;
+                let deadline = callback_deadline(stream_config.samplerate, args.num_frames);
+                // Frames played before the first frame of the buffer reaches the device
+                let queued_frames = host_time(&args.time_stamp).map(|time| {
+                    let ahead = time.saturating_duration_since(HostTime::now());
+                    output_latency + (ahead.as_secs_f64() * stream_config.samplerate) as usize
+                });
+                if update_buffer_size(&mut stream_config, &frame_size) {
+                    callback.prepare(AudioCallbackContext {
                         stream_config,
                         timestamp,
-                    },
-                    output,
-                );
-                for (output, inner) in args.data.channels_mut().zip(buffer.channels()) {
-                    output.copy_from_slice(inner.as_slice().unwrap());
+                        output_time: None,
+                        deadline: None,
+                        queued_frames: None,
+                        frames_since_last_callback: None,
+                        discontinuity: false,
+                    });
                 }
-            }
-            Ok(())
+                // Blocks larger than the preallocated buffer are rendered in several chunks
+                let mut offset = 0;
+                while offset < args.num_frames {
+                    let len = (args.num_frames - offset).min(buffer.num_samples());
+                    let mut buffer = buffer.slice_mut(..len);
+                    if receiver.is_paused() {
+                        buffer.as_interleaved_mut().fill(0.);
+                    } else {
+                        let (frames_since_last_callback, discontinuity) =
+                            tracker.next(timestamp, len);
+                        callback.on_output_data(
+                            AudioCallbackContext {
+                                stream_config,
+                                timestamp,
+                                output_time: host_time(&args.time_stamp).map(|time| {
+                                    let frames = (output_latency + offset) as u64;
+                                    time + Timestamp::from_count(stream_config.samplerate, frames)
+                                        .as_duration()
+                                }),
+                                deadline: Some(deadline),
+                                queued_frames,
+                                frames_since_last_callback,
+                                discontinuity,
+                            },
+                            AudioOutput {
+                                buffer: buffer.as_mut(),
+                                timestamp,
+                            },
+                        );
+                    }
+                    if let Some(tap) = &mut output_tap {
+                        tap.process(buffer.as_ref());
+                    }
+                    if let Some(amplitudes) = &trim {
+                        buffer.change_channel_amplitudes(amplitudes);
+                    }
+                    receiver.apply_volume(buffer.as_mut());
+                    for (output, inner) in args.data.channels_mut().zip(buffer.channels()) {
+                        output[offset..offset + len].copy_from_slice(inner.as_slice().unwrap());
+                    }
+                    timestamp += len as u64;
+                    offset += len;
+                }
+                Ok(())
+            })
         })?;
         audio_unit.start()?;
+        events.send(StreamEvent::Started);
+        let mut listeners = vec![buffer_size.listener, overload_listener(device_id, &events)?];
+        if follow_default {
+            listeners.push(follow_default_device(
+                &audio_unit,
+                DeviceType::Output,
+                &events,
+            )?);
+        }
         Ok(Self {
+            listeners,
+            scope: kAudioObjectPropertyScopeOutput,
             audio_unit,
-            callback_retrieve: tx,
+            control,
+            events,
+            meter,
         })
     }
 }
+
+/// Pointer to an audio unit which is moved into property listeners. The audio unit is owned by
+/// the stream, which drops the listener before the audio unit.
+struct AudioUnitPtr(coreaudio::sys::AudioUnit);
+
+unsafe impl Send for AudioUnitPtr {}
+
+/// Listens for changes of the default device of the given type, and moves the audio unit over to
+/// the new default device.
+fn follow_default_device(
+    audio_unit: &AudioUnit,
+    device_type: DeviceType,
+    events: &StreamEventSender,
+) -> Result<PropertyListener, CoreAudioError> {
+    let is_input = matches!(device_type, DeviceType::Input);
+    let selector = if is_input {
+        kAudioHardwarePropertyDefaultInputDevice
+    } else {
+        kAudioHardwarePropertyDefaultOutputDevice
+    };
+    let audio_unit = AudioUnitPtr(*audio_unit.as_ref());
+    let events = events.clone();
+    PropertyListener::new(kAudioObjectSystemObject, &[selector], move |_| {
+        let Some(device_id) = get_default_device_id(is_input) else {
+            return;
+        };
+        match set_current_device(&audio_unit, device_id) {
+            Ok(()) => events.send(StreamEvent::DeviceChanged),
+            Err(err) => {
+                diagnostics::error!("Cannot move stream to new default device: {err}");
+                events.send(StreamEvent::Error(err.to_string().into()));
+            }
+        }
+    })
+}
+
+/// Report the processing overloads of the device, where it ran out of audio, as xruns.
+fn overload_listener(
+    device_id: AudioDeviceID,
+    events: &StreamEventSender,
+) -> Result<PropertyListener, CoreAudioError> {
+    let events = events.clone();
+    PropertyListener::new(device_id, &[kAudioDeviceProcessorOverload], move |_| {
+        events.send(StreamEvent::XRun)
+    })
+}
+
+fn set_current_device(
+    audio_unit: &AudioUnitPtr,
+    device_id: AudioDeviceID,
+) -> Result<(), coreaudio::Error> {
+    use coreaudio::sys;
+    let audio_unit = audio_unit.0;
+    // The current device of an audio unit can only be changed while it is uninitialized
+    unsafe {
+        coreaudio::Error::from_os_status(sys::AudioOutputUnitStop(audio_unit))?;
+        coreaudio::Error::from_os_status(sys::AudioUnitUninitialize(audio_unit))?;
+    }
+    coreaudio::audio_unit::set_property(
+        audio_unit,
+        kAudioOutputUnitProperty_CurrentDevice,
+        Scope::Global,
+        Element::Output,
+        Some(&device_id),
+    )?;
+    unsafe {
+        coreaudio::Error::from_os_status(sys::AudioUnitInitialize(audio_unit))?;
+        coreaudio::Error::from_os_status(sys::AudioOutputUnitStart(audio_unit))?;
+    }
+    Ok(())
+}
+
+#[allow(non_upper_case_globals)]
+fn default_device_selector_type(selector: AudioObjectPropertySelector) -> Option<DeviceType> {
+    match selector {
+        kAudioHardwarePropertyDefaultInputDevice => Some(DeviceType::Input),
+        kAudioHardwarePropertyDefaultOutputDevice => Some(DeviceType::Output),
+        _ => None,
+    }
+}
+
+type PropertyCallback = Mutex<Box<dyn Send + FnMut(AudioObjectPropertySelector)>>;
+
+/// Closure registered as a listener of properties of a CoreAudio object. The listener is removed
+/// when this value is dropped.
+struct PropertyListener {
+    object_id: AudioObjectID,
+    addresses: Vec<AudioObjectPropertyAddress>,
+    callback: Box<PropertyCallback>,
+}
+
+impl PropertyListener {
+    fn new(
+        object_id: AudioObjectID,
+        selectors: &[AudioObjectPropertySelector],
+        callback: impl 'static + Send + FnMut(AudioObjectPropertySelector),
+    ) -> Result<Self, CoreAudioError> {
+        let mut listener = Self {
+            object_id,
+            addresses: Vec::with_capacity(selectors.len()),
+            callback: Box::new(Mutex::new(Box::new(callback))),
+        };
+        for &selector in selectors {
+            let address = AudioObjectPropertyAddress {
+                mSelector: selector,
+                mScope: kAudioObjectPropertyScopeGlobal,
+                mElement: kAudioObjectPropertyElementMaster,
+            };
+            let status = unsafe {
+                AudioObjectAddPropertyListener(
+                    object_id,
+                    &address,
+                    Some(property_listener_proc),
+                    listener.client_data(),
+                )
+            };
+            // Listeners registered so far are removed when `listener` is dropped
+            coreaudio::Error::from_os_status(status)?;
+            listener.addresses.push(address);
+        }
+        Ok(listener)
+    }
+
+    fn client_data(&self) -> *mut c_void {
+        &*self.callback as *const PropertyCallback as *mut c_void
+    }
+}
+
+impl Drop for PropertyListener {
+    fn drop(&mut self) {
+        for address in &self.addresses {
+            unsafe {
+                AudioObjectRemovePropertyListener(
+                    self.object_id,
+                    address,
+                    Some(property_listener_proc),
+                    self.client_data(),
+                );
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn property_listener_proc(
+    _object_id: AudioObjectID,
+    num_addresses: u32,
+    addresses: *const AudioObjectPropertyAddress,
+    client_data: *mut c_void,
+) -> OSStatus {
+    let callback = &*(client_data as *const PropertyCallback);
+    let addresses = slice::from_raw_parts(addresses, num_addresses as usize);
+    let Ok(mut callback) = callback.lock() else {
+        return 0;
+    };
+    for address in addresses {
+        callback(address.mSelector);
+    }
+    0
+}
+
+/// Pair of CoreAudio devices used together as a single duplex device, for example a USB
+/// microphone and the built-in speakers.
+///
+/// The two devices run from their own clocks, which drift apart over time. The input is
+/// therefore resampled to follow the output device, with the resampling ratio continuously
+/// adjusted to keep the latency between input and output constant.
+#[derive(Debug, Clone, Copy)]
+pub struct CoreAudioDuplexDevice {
+    input: CoreAudioDevice,
+    output: CoreAudioDevice,
+}
+
+impl CoreAudioDuplexDevice {
+    /// Create a duplex device from an input device and an output device. Duplex devices can be
+    /// used on either side.
+    pub fn new(input: CoreAudioDevice, output: CoreAudioDevice) -> Result<Self, CoreAudioError> {
+        if !input.has_input() {
+            return Err(CoreAudioError::InvalidDeviceType {
+                expected: DeviceType::Input,
+                actual: input.device_type,
+            });
+        }
+        if !output.has_output() {
+            return Err(CoreAudioError::InvalidDeviceType {
+                expected: DeviceType::Output,
+                actual: output.device_type,
+            });
+        }
+        Ok(Self { input, output })
+    }
+
+    /// Input device of this pair.
+    pub fn input(&self) -> CoreAudioDevice {
+        self.input
+    }
+
+    /// Output device of this pair.
+    pub fn output(&self) -> CoreAudioDevice {
+        self.output
+    }
+}
+
+impl AudioDevice for CoreAudioDuplexDevice {
+    type Error = CoreAudioError;
+
+    fn name(&self) -> Cow<str> {
+        Cow::Owned(format!("{} / {}", self.input.name(), self.output.name()))
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Duplex
+    }
+
+    fn channel_map(&self) -> impl IntoIterator<Item = Channel> {
+        self.output.channel_map()
+    }
+
+    fn is_config_supported(&self, config: &StreamConfig) -> bool {
+        self.input.is_config_supported(config) && self.output.is_config_supported(config)
+    }
+
+    fn enumerate_configurations(&self) -> Option<impl IntoIterator<Item = StreamConfig>> {
+        None::<[StreamConfig; 0]>
+    }
+}
+
+impl AudioDuplexDevice for CoreAudioDuplexDevice {
+    type StreamHandle<Callback: AudioDuplexCallback> = CoreAudioDuplexStream<Callback>;
+
+    fn default_duplex_config(&self) -> Result<StreamConfig, Self::Error> {
+        Ok(StreamConfig {
+            input_channels: self.input.default_input_config()?.input_channels,
+            ..self.output.default_output_config()?
+        })
+    }
+
+    fn create_duplex_stream<Callback: SendEverywhereButOnWeb + AudioDuplexCallback>(
+        &self,
+        stream_config: StreamConfig,
+        callback: Callback,
+    ) -> Result<Self::StreamHandle<Callback>, Self::Error> {
+        let stream_config = stream_config.resolve_exclusive(|| self.supports_exclusive());
+        let stream_config = self.input.prepare_stream(stream_config)?;
+        let stream_config = self.output.prepare_stream(stream_config)?;
+        CoreAudioDuplexStream::new(
+            self.input.device_id,
+            self.output.device_id,
+            stream_config,
+            callback,
+        )
+    }
+}
+
+/// Duplex stream running over two CoreAudio devices.
+pub struct CoreAudioDuplexStream<Callback> {
+    buffer_size_listener: Option<PropertyListener>,
+    overload_listener: Option<PropertyListener>,
+    input_unit: AudioUnit,
+    output_unit: AudioUnit,
+    control: StreamControl<Callback>,
+    events: StreamEventSender,
+    meter: StreamMeter,
+}
+
+impl<Callback> AudioStreamHandle<Callback> for CoreAudioDuplexStream<Callback> {
+    type Error = EjectError<Callback, Infallible>;
+
+    fn eject(mut self) -> Result<Callback, Self::Error> {
+        self.buffer_size_listener = None;
+        self.overload_listener = None;
+        self.control.request_eject();
+        let ejected = self.control.wait_ejected_timeout(EJECT_TIMEOUT);
+        self.output_unit.free_render_callback();
+        self.input_unit.free_input_callback();
+        let callback = match ejected {
+            Some(callback) => callback,
+            None => self.control.collect(Ok(()))?,
+        };
+        self.events.send(StreamEvent::Ejected);
+        Ok(callback)
+    }
+
+    fn subscribe_events(&self) -> StreamEventReceiver {
+        self.events.subscribe()
+    }
+
+    fn levels(&self) -> Option<StreamLevels> {
+        self.meter.levels()
+    }
+
+    fn control(&self) -> Option<&StreamControl<Callback>> {
+        Some(&self.control)
+    }
+
+    fn latency(&self) -> StreamLatency {
+        let input = audio_unit_latency(&self.input_unit, kAudioObjectPropertyScopeInput);
+        let output = audio_unit_latency(&self.output_unit, kAudioObjectPropertyScopeOutput);
+        StreamLatency {
+            input: input
+                .inspect_err(|err| diagnostics::warn!("Cannot get input latency: {err}"))
+                .ok(),
+            output: output
+                .inspect_err(|err| diagnostics::warn!("Cannot get output latency: {err}"))
+                .ok(),
+        }
+    }
+}
+
+impl<Callback: 'static + Send + AudioDuplexCallback> CoreAudioDuplexStream<Callback> {
+    fn new(
+        input_id: AudioDeviceID,
+        output_id: AudioDeviceID,
+        stream_config: StreamConfig,
+        mut callback: Callback,
+    ) -> Result<Self, CoreAudioError> {
+        let samplerate = stream_config.samplerate;
+        let in_channels = stream_config.input_channels.count();
+        let out_channels = stream_config.output_channels.count();
+        let mut input_unit = audio_unit_from_device_id(input_id, true)?;
+        input_unit.set_property(
+            kAudioUnitProperty_StreamFormat,
+            Scope::Output,
+            Element::Input,
+            Some(&input_stream_format(samplerate, stream_config.input_channels).to_asbd()),
+        )?;
+        set_channel_map(
+            &mut input_unit,
+            Element::Input,
+            stream_config.input_channels,
+        )?;
+        let mut output_unit = audio_unit_from_device_id(output_id, false)?;
+        output_unit.set_property(
+            kAudioUnitProperty_StreamFormat,
+            Scope::Input,
+            Element::Output,
+            Some(&output_stream_format(samplerate, stream_config.output_channels).to_asbd()),
+        )?;
+        set_channel_map(
+            &mut output_unit,
+            Element::Output,
+            stream_config.output_channels,
+        )?;
+
+        // One second of interleaved input, which is much more than what drift compensation needs
+        let (mut producer, consumer) =
+            rtrb::RingBuffer::new(in_channels * stream_config.samplerate as usize);
+        input_unit.set_input_callback(move |args: Args<data::Interleaved<f32>>| {
+            realtime::process_cycle(|| {
+                // Only write whole frames, dropping the rest of the block on overflow
+                let num_frames = args.num_frames.min(producer.slots() / in_channels);
+                for &sample in &args.data.buffer[..num_frames * in_channels] {
+                    let _ = producer.push(sample);
+                }
+                Ok(())
+            })
+        })?;
+
+        // The output device drives the callback, so its buffer size is the one reported
+        let buffer_size = BufferSizeWatch::new(output_id)?;
+        let frame_size = buffer_size.frame_size.clone();
+        let mut stream_config = stream_config;
+        update_buffer_size(&mut stream_config, &frame_size);
+        let mut resampler = DriftResampler::new(consumer, in_channels);
+        let mut input_buffer = AudioBuffer::zeroed(in_channels, buffer_size.max_frame_count);
+        let mut output_buffer = AudioBuffer::zeroed(out_channels, buffer_size.max_frame_count);
+        let in_trim = stream_config.input_trim.amplitudes(in_channels);
+        let out_trim = stream_config.output_trim.amplitudes(out_channels);
+        let (meter, mut input_tap, mut output_tap) =
+            StreamMeter::new(stream_config.metering, in_channels, out_channels);
+        let output_latency = audio_unit_latency(&output_unit, kAudioObjectPropertyScopeOutput)
+            .inspect_err(|err| diagnostics::warn!("Cannot get output latency: {err}"))
+            .unwrap_or(0);
+
+        callback.prepare(AudioCallbackContext {
+            stream_config,
+            timestamp: Timestamp::new(stream_config.samplerate),
+            output_time: None,
+            deadline: None,
+            queued_frames: None,
+            frames_since_last_callback: None,
+            discontinuity: false,
+        });
+
+        // Commands are applied by the audio unit callback, without needing to make the callback
+        // `Sync`
+        let (control, mut receiver, callback) = control_queue(callback);
+        let events = StreamEventSender::new();
+        let audio_events = events.clone();
+        let mut callback = Some(callback);
+        let mut timeline = Timestamp::new(stream_config.samplerate);
+        let mut tracker = CallbackTracker::default();
+        output_unit.set_render_callback(move |mut args: Args<data::NonInterleaved<f32>>| {
+            realtime::process_cycle(|| {
+                let Some(inner) = &mut callback else {
+                    return Ok(());
+                };
+                if receiver.process(inner, &audio_events) {
+                    inner.prepare(AudioCallbackContext {
+                        stream_config,
+                        timestamp: timeline,
+                        output_time: None,
+                        deadline: None,
+                        queued_frames: None,
+                        frames_since_last_callback: None,
+                        discontinuity: false,
+                    });
+                }
+                if receiver.eject_requested() {
+                    // Dropping the slot gives the callback back to the handle
+                    drop(callback.take());
+                    return Err(());
+                }
+                let Some(callback) = &mut callback else {
+                    return Ok(());
+                };
+                timeline.advance_to(args.time_stamp.mSampleTime as _);
+                let mut timestamp = timeline;
+                let deadline = callback_deadline(stream_config.samplerate, args.num_frames);
+                // Frames played before the first frame of the buffer reaches the device
+                let queued_frames = host_time(&args.time_stamp).map(|time| {
+                    let ahead = time.saturating_duration_since(HostTime::now());
+                    output_latency + (ahead.as_secs_f64() * stream_config.samplerate) as usize
+                });
+                if update_buffer_size(&mut stream_config, &frame_size) {
+                    callback.prepare(AudioCallbackContext {
+                        stream_config,
+                        timestamp,
+                        output_time: None,
+                        deadline: None,
+                        queued_frames: None,
+                        frames_since_last_callback: None,
+                        discontinuity: false,
+                    });
+                }
+                // Blocks larger than the preallocated buffers are processed in several chunks
+                let mut offset = 0;
+                while offset < args.num_frames {
+                    let len = (args.num_frames - offset).min(output_buffer.num_samples());
+                    let mut input_buffer = input_buffer.slice_mut(..len);
+                    resampler.process(input_buffer.as_mut());
+                    if let Some(amplitudes) = &in_trim {
+                        input_buffer.change_channel_amplitudes(amplitudes);
+                    }
+                    if let Some(tap) = &mut input_tap {
+                        tap.process(input_buffer.as_ref());
+                    }
+                    let mut output_buffer = output_buffer.slice_mut(..len);
+                    if receiver.is_paused() {
+                        output_buffer.as_interleaved_mut().fill(0.);
+                    } else {
+                        let (frames_since_last_callback, discontinuity) =
+                            tracker.next(timestamp, len);
+                        callback.on_audio_data(
+                            AudioCallbackContext {
+                                stream_config,
+                                timestamp,
+                                output_time: host_time(&args.time_stamp).map(|time| {
+                                    let frames = (output_latency + offset) as u64;
+                                    time + Timestamp::from_count(stream_config.samplerate, frames)
+                                        .as_duration()
+                                }),
+                                deadline: Some(deadline),
+                                queued_frames,
+                                frames_since_last_callback,
+                                discontinuity,
+                            },
+                            AudioInput {
+                                buffer: input_buffer.as_ref(),
+                                // Drift compensation resamples the input onto the output
+                                // timeline, and loses the time at which it was captured
+                                timestamp,
+                                capture_time: None,
+                            },
+                            AudioOutput {
+                                buffer: output_buffer.as_mut(),
+                                timestamp,
+                            },
+                        );
+                    }
+                    if let Some(tap) = &mut output_tap {
+                        tap.process(output_buffer.as_ref());
+                    }
+                    if let Some(amplitudes) = &out_trim {
+                        output_buffer.change_channel_amplitudes(amplitudes);
+                    }
+                    receiver.apply_volume(output_buffer.as_mut());
+                    for (output, inner) in args.data.channels_mut().zip(output_buffer.channels()) {
+                        output[offset..offset + len].copy_from_slice(inner.as_slice().unwrap());
+                    }
+                    timestamp += len as u64;
+                    offset += len;
+                }
+                Ok(())
+            })
+        })?;
+        input_unit.start()?;
+        output_unit.start()?;
+        events.send(StreamEvent::Started);
+        Ok(Self {
+            buffer_size_listener: Some(buffer_size.listener),
+            overload_listener: Some(overload_listener(output_id, &events)?),
+            input_unit,
+            output_unit,
+            control,
+            events,
+            meter,
+        })
+    }
+}
+
+/// Resamples the input of a duplex stream to follow the clock of the output device.
+///
+/// The resampling ratio is derived from the fill level of the ring buffer between the two
+/// devices: when input accumulates, it is consumed slightly faster, and vice versa. Samples are
+/// converted with a [`LinearResampler`], which is transparent enough for ratios this close to 1.
+struct DriftResampler {
+    consumer: rtrb::Consumer<f32>,
+    resampler: LinearResampler,
+    average_fill: f64,
+    target_fill: usize,
+    primed: bool,
+}
+
+impl DriftResampler {
+    /// Maximum deviation of the resampling ratio from 1. Clock drift between devices is usually
+    /// in the order of 100 ppm, this leaves plenty of headroom while keeping pitch changes
+    /// inaudible.
+    const MAX_DEVIATION: f64 = 0.005;
+    /// Ratio deviation applied per unit of relative fill error.
+    const GAIN: f64 = 0.01;
+    /// Smoothing factor of the fill level estimate, applied once per output callback.
+    const SMOOTHING: f64 = 0.05;
+
+    fn new(consumer: rtrb::Consumer<f32>, channels: usize) -> Self {
+        Self {
+            consumer,
+            resampler: LinearResampler::new(channels, 1.0, 1.0),
+            average_fill: 0.0,
+            target_fill: 0,
+            primed: false,
+        }
+    }
+
+    fn process(&mut self, mut output: AudioMut<f32>) {
+        let channels = self.resampler.channels();
+        let num_frames = output.num_samples();
+        // Keep enough input buffered to cover both the output and input callback sizes
+        self.target_fill = self.target_fill.max(2 * num_frames);
+        let fill = self.consumer.slots() / channels.max(1);
+        if !self.primed {
+            if fill < self.target_fill {
+                Self::silence(output);
+                return;
+            }
+            self.primed = true;
+            self.average_fill = fill as f64;
+        }
+        self.average_fill += Self::SMOOTHING * (fill as f64 - self.average_fill);
+        let error = (self.average_fill - self.target_fill as f64) / self.target_fill as f64;
+        // Input frames consumed per output frame
+        let step = 1.0 + (Self::GAIN * error).clamp(-Self::MAX_DEVIATION, Self::MAX_DEVIATION);
+        self.resampler.set_ratio(step.recip());
+
+        let Ok(chunk) = self.consumer.read_chunk(fill * channels) else {
+            unreachable!("Ring buffer holds the frames");
+        };
+        // The ring buffer only holds whole frames, so that both slices do as well
+        let (first, second) = chunk.as_slices();
+        let mut consumed = 0;
+        let mut written = 0;
+        for samples in [first, second] {
+            let Some(input) = AudioRef::from_interleaved(samples, channels) else {
+                continue;
+            };
+            let (count, frames) = self.resampler.process(input, output.slice_mut(written..));
+            consumed += count;
+            written += frames;
+        }
+        chunk.commit(consumed * channels);
+        if written < num_frames {
+            // Input underrun, wait for the buffer to fill up again
+            self.primed = false;
+            Self::silence(output.slice_mut(written..));
+        }
+    }
+
+    fn silence(mut output: AudioMut<f32>) {
+        for mut channel in output.channels_mut() {
+            channel.fill(0.0);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! Management of CoreAudio aggregate devices.
+//!
+//! Aggregate devices combine several devices into a single one, which lets applications open
+//! multiple audio interfaces as if they were one, with CoreAudio taking care of clock drift
+//! between them.
+
+use std::borrow::Cow;
+use std::ffi::c_void;
+
+use core_foundation_sys::array::{
+    kCFTypeArrayCallBacks, CFArrayCreate, CFArrayGetCount, CFArrayGetValueAtIndex, CFArrayRef,
+};
+use core_foundation_sys::base::{kCFAllocatorDefault, CFRelease, CFTypeRef};
+use core_foundation_sys::dictionary::{
+    kCFTypeDictionaryKeyCallBacks, kCFTypeDictionaryValueCallBacks, CFDictionaryCreate,
+};
+use core_foundation_sys::number::{kCFNumberSInt32Type, CFNumberCreate};
+use core_foundation_sys::string::{kCFStringEncodingUTF8, CFStringCreateWithBytes, CFStringRef};
+use coreaudio::audio_unit::macos_helpers::{get_audio_device_ids, get_device_name};
+use coreaudio::sys::{
+    kAudioAggregateDevicePropertyFullSubDeviceList, kAudioAggregateDevicePropertyMasterSubDevice,
+    kAudioDevicePropertyTransportType, kAudioDeviceTransportTypeAggregate,
+    kAudioObjectPropertyClass, kAudioObjectPropertyElementMaster, kAudioObjectPropertyOwnedObjects,
+    kAudioObjectPropertyScopeGlobal, kAudioSubDeviceClassID,
+    kAudioSubDevicePropertyDriftCompensation, AudioDeviceID, AudioHardwareCreateAggregateDevice,
+    AudioHardwareDestroyAggregateDevice, AudioObjectID, AudioObjectPropertyAddress,
+    AudioObjectPropertySelector,
+};
+
+use super::{
+    cfstring_to_string, get_object_property, get_object_property_array, object_uid,
+    set_object_property, CoreAudioDevice, CoreAudioError,
+};
+use crate::diagnostics;
+use crate::DeviceType;
+
+/// Aggregate device, combining several CoreAudio devices into a single one.
+///
+/// One of the sub-devices acts as the clock source of the aggregate device; the others can have
+/// drift compensation enabled so that CoreAudio resamples them to follow that clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoreAudioAggregateDevice {
+    device_id: AudioDeviceID,
+}
+
+impl CoreAudioAggregateDevice {
+    /// Create a new aggregate device made of the given devices, which is then visible to all
+    /// applications until it is [destroyed](Self::destroy).
+    ///
+    /// The `uid` must be unique across the system, and can be used to find the device again
+    /// later. The first device is used as the clock source, and drift compensation is enabled
+    /// for all other devices.
+    pub fn create(
+        name: &str,
+        uid: &str,
+        sub_devices: &[CoreAudioDevice],
+    ) -> Result<Self, CoreAudioError> {
+        let mut sub_device_uids = Vec::<String>::with_capacity(sub_devices.len());
+        for device in sub_devices {
+            let uid = object_uid(device.device_id)?;
+            if !sub_device_uids.contains(&uid) {
+                sub_device_uids.push(uid);
+            }
+        }
+        let sub_device_list = sub_device_uids
+            .iter()
+            .enumerate()
+            .map(|(i, uid)| {
+                CfObject::dictionary(&[
+                    (SUB_DEVICE_UID_KEY, CfObject::string(uid)),
+                    (SUB_DEVICE_DRIFT_KEY, CfObject::number((i > 0) as i32)),
+                ])
+            })
+            .collect::<Vec<_>>();
+        let mut description = vec![
+            (AGGREGATE_NAME_KEY, CfObject::string(name)),
+            (AGGREGATE_UID_KEY, CfObject::string(uid)),
+            (AGGREGATE_SUB_DEVICES_KEY, CfObject::array(&sub_device_list)),
+        ];
+        if let Some(clock_uid) = sub_device_uids.first() {
+            description.push((AGGREGATE_CLOCK_KEY, CfObject::string(clock_uid)));
+        }
+        let description = CfObject::dictionary(&description);
+
+        let mut device_id = 0;
+        let status =
+            unsafe { AudioHardwareCreateAggregateDevice(description.0.cast(), &mut device_id) };
+        coreaudio::Error::from_os_status(status)?;
+        Ok(Self { device_id })
+    }
+
+    /// List the aggregate devices currently present on the system.
+    pub fn list() -> Result<Vec<Self>, CoreAudioError> {
+        let mut aggregates = vec![];
+        for device_id in get_audio_device_ids()? {
+            let transport_type: u32 = get_object_property(
+                device_id,
+                &global_address(kAudioDevicePropertyTransportType),
+            )?;
+            if transport_type == kAudioDeviceTransportTypeAggregate {
+                aggregates.push(Self { device_id });
+            }
+        }
+        Ok(aggregates)
+    }
+
+    /// Aggregate device display name.
+    pub fn name(&self) -> Cow<str> {
+        match get_device_name(self.device_id) {
+            Ok(name) => Cow::Owned(name),
+            Err(err) => {
+                diagnostics::warn!("Cannot get audio device name: {err}");
+                Cow::Borrowed("<unknown>")
+            }
+        }
+    }
+
+    /// Aggregate device used as a device of the given type, to open streams on.
+    pub fn device(&self, device_type: DeviceType) -> CoreAudioDevice {
+        CoreAudioDevice::new(self.device_id, device_type)
+    }
+
+    /// Unique identifiers of the devices making up this aggregate device.
+    pub fn sub_device_uids(&self) -> Result<Vec<String>, CoreAudioError> {
+        let list: CFArrayRef = get_object_property(
+            self.device_id,
+            &global_address(kAudioAggregateDevicePropertyFullSubDeviceList),
+        )?;
+        let list = CfObject(list.cast());
+        let uids = unsafe {
+            (0..CFArrayGetCount(list.0.cast()))
+                .filter_map(|i| cfstring_to_string(CFArrayGetValueAtIndex(list.0.cast(), i).cast()))
+                .collect()
+        };
+        Ok(uids)
+    }
+
+    /// Add a device to this aggregate device. Adding a device that is already part of the
+    /// aggregate device does nothing.
+    pub fn add_sub_device(&self, device: &CoreAudioDevice) -> Result<(), CoreAudioError> {
+        let mut uids = self.sub_device_uids()?;
+        let uid = object_uid(device.device_id)?;
+        if uids.contains(&uid) {
+            return Ok(());
+        }
+        uids.push(uid);
+        self.set_sub_devices(&uids)
+    }
+
+    /// Remove a device from this aggregate device. Removing a device that is not part of the
+    /// aggregate device does nothing.
+    pub fn remove_sub_device(&self, device: &CoreAudioDevice) -> Result<(), CoreAudioError> {
+        let mut uids = self.sub_device_uids()?;
+        let uid = object_uid(device.device_id)?;
+        let len = uids.len();
+        uids.retain(|other| *other != uid);
+        if uids.len() == len {
+            return Ok(());
+        }
+        self.set_sub_devices(&uids)
+    }
+
+    /// Use the given sub-device as the clock source of this aggregate device.
+    pub fn set_clock_device(&self, device: &CoreAudioDevice) -> Result<(), CoreAudioError> {
+        let uid = CfObject::string(&object_uid(device.device_id)?);
+        let uid: CFStringRef = uid.0.cast();
+        Ok(set_object_property(
+            self.device_id,
+            &global_address(kAudioAggregateDevicePropertyMasterSubDevice),
+            &uid,
+        )?)
+    }
+
+    /// Enable or disable drift compensation for the given sub-device. Drift compensation should
+    /// be enabled on all sub-devices which are not the clock source, unless they share the same
+    /// hardware clock.
+    pub fn set_drift_compensation(
+        &self,
+        device: &CoreAudioDevice,
+        enabled: bool,
+    ) -> Result<(), CoreAudioError> {
+        let uid = object_uid(device.device_id)?;
+        let owned_objects: Vec<AudioObjectID> = get_object_property_array(
+            self.device_id,
+            &global_address(kAudioObjectPropertyOwnedObjects),
+        )?;
+        for object_id in owned_objects {
+            let class: u32 =
+                get_object_property(object_id, &global_address(kAudioObjectPropertyClass))?;
+            if class != kAudioSubDeviceClassID || !object_uid(object_id).is_ok_and(|id| id == uid) {
+                continue;
+            }
+            return Ok(set_object_property(
+                object_id,
+                &global_address(kAudioSubDevicePropertyDriftCompensation),
+                &(enabled as u32),
+            )?);
+        }
+        Err(CoreAudioError::NotASubDevice(uid))
+    }
+
+    /// Remove this aggregate device from the system.
+    pub fn destroy(self) -> Result<(), CoreAudioError> {
+        let status = unsafe { AudioHardwareDestroyAggregateDevice(self.device_id) };
+        Ok(coreaudio::Error::from_os_status(status)?)
+    }
+
+    fn set_sub_devices(&self, uids: &[String]) -> Result<(), CoreAudioError> {
+        let list = uids
+            .iter()
+            .map(|uid| CfObject::string(uid))
+            .collect::<Vec<_>>();
+        let list = CfObject::array(&list);
+        let list: CFArrayRef = list.0.cast();
+        Ok(set_object_property(
+            self.device_id,
+            &global_address(kAudioAggregateDevicePropertyFullSubDeviceList),
+            &list,
+        )?)
+    }
+}
+
+fn global_address(selector: AudioObjectPropertySelector) -> AudioObjectPropertyAddress {
+    AudioObjectPropertyAddress {
+        mSelector: selector,
+        mScope: kAudioObjectPropertyScopeGlobal,
+        mElement: kAudioObjectPropertyElementMaster,
+    }
+}
+
+// Keys of the aggregate device description dictionary, from `AudioHardware.h`
+const AGGREGATE_NAME_KEY: &str = "name";
+const AGGREGATE_UID_KEY: &str = "uid";
+const AGGREGATE_SUB_DEVICES_KEY: &str = "subdevices";
+const AGGREGATE_CLOCK_KEY: &str = "master";
+const SUB_DEVICE_UID_KEY: &str = "uid";
+const SUB_DEVICE_DRIFT_KEY: &str = "drift";
+
+/// Owned reference to a Core Foundation object, released when dropped.
+pub(super) struct CfObject(pub(super) CFTypeRef);
+
+impl CfObject {
+    pub(super) fn string(value: &str) -> Self {
+        Self(unsafe {
+            CFStringCreateWithBytes(
+                kCFAllocatorDefault,
+                value.as_ptr(),
+                value.len() as _,
+                kCFStringEncodingUTF8,
+                false as _,
+            )
+            .cast()
+        })
+    }
+
+    fn number(value: i32) -> Self {
+        Self(unsafe {
+            CFNumberCreate(
+                kCFAllocatorDefault,
+                kCFNumberSInt32Type,
+                &value as *const i32 as *const c_void,
+            )
+            .cast()
+        })
+    }
+
+    fn array(values: &[CfObject]) -> Self {
+        let values = values.iter().map(|value| value.0).collect::<Vec<_>>();
+        Self(unsafe {
+            CFArrayCreate(
+                kCFAllocatorDefault,
+                values.as_ptr(),
+                values.len() as _,
+                &kCFTypeArrayCallBacks,
+            )
+            .cast()
+        })
+    }
+
+    fn dictionary(entries: &[(&str, CfObject)]) -> Self {
+        // Keys only need to live until the dictionary has retained them
+        let keys = entries
+            .iter()
+            .map(|(key, _)| CfObject::string(key))
+            .collect::<Vec<_>>();
+        let keys = keys.iter().map(|key| key.0).collect::<Vec<_>>();
+        let values = entries.iter().map(|(_, value)| value.0).collect::<Vec<_>>();
+        let dictionary = unsafe {
+            CFDictionaryCreate(
+                kCFAllocatorDefault,
+                keys.as_ptr(),
+                values.as_ptr(),
+                keys.len() as _,
+                &kCFTypeDictionaryKeyCallBacks,
+                &kCFTypeDictionaryValueCallBacks,
+            )
+        };
+        Self(dictionary.cast())
+    }
+}
+
+impl Drop for CfObject {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { CFRelease(self.0) };
+        }
+    }
+}
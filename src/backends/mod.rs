@@ -5,6 +5,10 @@
 //! Each backend is provided in its own submodule. Types should be public so that the user isn't
 //! limited to going through the main API if they want to choose a specific backend.
 
+use std::fmt;
+
+use thiserror::Error;
+
 use crate::{
     AudioDriver, AudioInputDevice, AudioOutputDevice, DeviceType,
 };
@@ -21,6 +25,72 @@ pub mod coreaudio;
 #[cfg(os_wasapi)]
 pub mod wasapi;
 
+/// Kinds of backends supported by the library.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum BackendKind {
+    /// ALSA, on Linux and BSD systems.
+    Alsa,
+    /// CoreAudio, on macOS and iOS.
+    CoreAudio,
+    /// WASAPI, on Windows.
+    Wasapi,
+}
+
+impl BackendKind {
+    /// Display name of the backend.
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Alsa => "ALSA",
+            Self::CoreAudio => "CoreAudio",
+            Self::Wasapi => "WASAPI",
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Information about a driver, e.g. to be included in logs and bug reports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DriverInfo {
+    /// Backend of the driver.
+    pub kind: BackendKind,
+    /// Display name of the driver, as given by [`AudioDriver::DISPLAY_NAME`].
+    pub name: &'static str,
+    /// Runtime version of the driver, as given by [`AudioDriver::version`], or `None` when it
+    /// cannot be queried.
+    pub version: Option<String>,
+}
+
+impl DriverInfo {
+    /// Information about the given driver, of the given backend kind.
+    pub fn new<Driver: AudioDriver>(kind: BackendKind, driver: &Driver) -> Self {
+        let version = driver
+            .version()
+            .inspect_err(|err| log::debug!("Cannot get driver version: {err}"))
+            .ok()
+            .map(|version| version.into_owned());
+        Self {
+            kind,
+            name: Driver::DISPLAY_NAME,
+            version,
+        }
+    }
+}
+
+impl fmt::Display for DriverInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{} ({version})", self.name),
+            None => f.write_str(self.name),
+        }
+    }
+}
+
 /// Returns the default driver.
 ///
 /// "Default" here means that it is a supported driver that is available on the platform.
@@ -47,18 +117,73 @@ pub fn default_driver() -> impl AudioDriver {
     return wasapi::WasapiDriver;
 }
 
+/// Returns information about the driver selected by [`default_driver`], which its `impl
+/// AudioDriver` return type does not tell.
+///
+/// ```no_run
+/// use interflow::prelude::*;
+/// let info = default_driver_info();
+/// println!("Running on {info} via interflow");
+/// ```
+#[cfg(any(os_alsa, os_coreaudio, os_wasapi))]
+#[allow(clippy::needless_return)]
+pub fn default_driver_info() -> DriverInfo {
+    #[cfg(os_alsa)]
+    return DriverInfo::new(BackendKind::Alsa, &alsa::AlsaDriver);
+    #[cfg(os_coreaudio)]
+    return DriverInfo::new(BackendKind::CoreAudio, &coreaudio::CoreAudioDriver);
+    #[cfg(os_wasapi)]
+    return DriverInfo::new(BackendKind::Wasapi, &wasapi::WasapiDriver);
+}
+
+/// Type of errors from getting the default device of a driver.
+#[derive(Debug, Error)]
+pub enum DefaultDeviceError<DriverError> {
+    /// The driver failed to get its default device.
+    #[error("Audio driver error: {0}")]
+    DriverError(#[source] DriverError),
+    /// The driver has no default device of the requested type, e.g. on systems without audio
+    /// hardware.
+    #[error("No default {0:?} device found")]
+    NoDefaultDevice(DeviceType),
+}
+
+/// Returns the default device of the given type for the given audio driver, or an error when
+/// there is none.
+fn try_default_device_from<Driver: AudioDriver>(
+    driver: &Driver,
+    device_type: DeviceType,
+) -> Result<Driver::Device, DefaultDeviceError<Driver::Error>> {
+    driver
+        .default_device(device_type)
+        .map_err(DefaultDeviceError::DriverError)?
+        .ok_or(DefaultDeviceError::NoDefaultDevice(device_type))
+}
+
 /// Returns the default input device for the given audio driver.
 ///
 /// The default device is usually the one the user has selected in its system settings.
+///
+/// # Panics
+///
+/// Panics when the driver fails, or has no default input device. Use
+/// [`try_default_input_device_from`] to handle these cases.
 pub fn default_input_device_from<Driver: AudioDriver>(driver: &Driver) -> Driver::Device
 where
     Driver::Device: Clone + AudioInputDevice,
 {
-    driver
-        .default_device(DeviceType::Input)
-        .expect("Audio driver error")
-        .expect("No default device found")
-        .clone()
+    try_default_input_device_from(driver).unwrap_or_else(|err| panic!("{err}"))
+}
+
+/// Returns the default input device for the given audio driver, or an error when the driver
+/// fails or has no default input device.
+pub fn try_default_input_device_from<Driver: AudioDriver>(
+    driver: &Driver,
+) -> Result<Driver::Device, DefaultDeviceError<Driver::Error>>
+where
+    Driver::Device: AudioInputDevice,
+{
+    try_default_device_from(driver, DeviceType::Input)
 }
 
 /// Default input device from the default driver for this platform.
@@ -66,6 +191,11 @@ where
 /// "Default" here means both in terms of platform support but also can include runtime selection.
 /// Therefore, it is better to use this method directly rather than first getting the default
 /// driver from [`default_driver`].
+///
+/// # Panics
+///
+/// Panics when there is no default input device. Use [`try_default_input_device`] to handle this
+/// case.
 #[cfg(any(os_alsa, os_coreaudio, os_wasapi))]
 #[allow(clippy::needless_return)]
 pub fn default_input_device() -> impl AudioInputDevice {
@@ -77,18 +207,52 @@ pub fn default_input_device() -> impl AudioInputDevice {
     return default_input_device_from(&wasapi::WasapiDriver);
 }
 
-/// Returns the default input device for the given audio driver.
+/// Default input device from the default driver for this platform, or an error when there is
+/// none, e.g. on systems without audio hardware.
+///
+/// ```no_run
+/// use interflow::prelude::*;
+/// match try_default_input_device() {
+///     Ok(device) => println!("Recording from {}", device.name()),
+///     Err(err) => eprintln!("Recording is unavailable: {err}"),
+/// }
+/// ```
+#[cfg(any(os_alsa, os_coreaudio, os_wasapi))]
+#[allow(clippy::needless_return)]
+pub fn try_default_input_device(
+) -> Result<impl AudioInputDevice, DefaultDeviceError<impl std::error::Error>> {
+    #[cfg(os_alsa)]
+    return try_default_input_device_from(&alsa::AlsaDriver);
+    #[cfg(os_coreaudio)]
+    return try_default_input_device_from(&coreaudio::CoreAudioDriver);
+    #[cfg(os_wasapi)]
+    return try_default_input_device_from(&wasapi::WasapiDriver);
+}
+
+/// Returns the default output device for the given audio driver.
 ///
 /// The default device is usually the one the user has selected in its system settings.
+///
+/// # Panics
+///
+/// Panics when the driver fails, or has no default output device. Use
+/// [`try_default_output_device_from`] to handle these cases.
 pub fn default_output_device_from<Driver: AudioDriver>(driver: &Driver) -> Driver::Device
 where
     Driver::Device: Clone + AudioOutputDevice,
 {
-    driver
-        .default_device(DeviceType::Output)
-        .expect("Audio driver error")
-        .expect("No default device found")
-        .clone()
+    try_default_output_device_from(driver).unwrap_or_else(|err| panic!("{err}"))
+}
+
+/// Returns the default output device for the given audio driver, or an error when the driver
+/// fails or has no default output device.
+pub fn try_default_output_device_from<Driver: AudioDriver>(
+    driver: &Driver,
+) -> Result<Driver::Device, DefaultDeviceError<Driver::Error>>
+where
+    Driver::Device: AudioOutputDevice,
+{
+    try_default_device_from(driver, DeviceType::Output)
 }
 
 /// Default output device from the default driver for this platform.
@@ -96,6 +260,11 @@ where
 /// "Default" here means both in terms of platform support but also can include runtime selection.
 /// Therefore, it is better to use this method directly rather than first getting the default
 /// driver from [`default_driver`].
+///
+/// # Panics
+///
+/// Panics when there is no default output device. Use [`try_default_output_device`] to handle
+/// this case.
 #[cfg(any(os_alsa, os_coreaudio, os_wasapi))]
 #[allow(clippy::needless_return)]
 pub fn default_output_device() -> impl AudioOutputDevice {
@@ -106,3 +275,17 @@ pub fn default_output_device() -> impl AudioOutputDevice {
     #[cfg(os_wasapi)]
     return default_output_device_from(&wasapi::WasapiDriver);
 }
+
+/// Default output device from the default driver for this platform, or an error when there is
+/// none, e.g. on systems without audio hardware.
+#[cfg(any(os_alsa, os_coreaudio, os_wasapi))]
+#[allow(clippy::needless_return)]
+pub fn try_default_output_device(
+) -> Result<impl AudioOutputDevice, DefaultDeviceError<impl std::error::Error>> {
+    #[cfg(os_alsa)]
+    return try_default_output_device_from(&alsa::AlsaDriver);
+    #[cfg(os_coreaudio)]
+    return try_default_output_device_from(&coreaudio::CoreAudioDriver);
+    #[cfg(os_wasapi)]
+    return try_default_output_device_from(&wasapi::WasapiDriver);
+}
@@ -1,9 +1,13 @@
 use super::{error, stream};
-use crate::backends::wasapi::stream::WasapiStream;
-use crate::channel_map::Bitset;
+use crate::backends::wasapi::meter::WasapiMeter;
+use crate::backends::wasapi::stream::{StreamOptions, WasapiRecoveryPolicy, WasapiStream};
+use crate::channel_map::{ChannelMap32, CreateBitset};
+use crate::diagnostics;
+use crate::layout::{ChannelPosition, SpeakerLayout};
 use crate::prelude::wasapi::util::WasapiMMDevice;
-use crate::{AudioDevice, AudioInputCallback, AudioInputDevice, AudioOutputCallback, AudioOutputDevice, Channel, DeviceType, StreamConfig};
+use crate::{AudioDevice, AudioInputCallback, AudioInputDevice, AudioOutputCallback, AudioOutputDevice, BufferSize, Channel, ChannelTrim, DeviceType, StreamConfig};
 use std::borrow::Cow;
+use windows::core::imp::CoTaskMemFree;
 use windows::Win32::Media::Audio;
 
 /// Type of devices available from the WASAPI driver.
@@ -11,6 +15,7 @@ use windows::Win32::Media::Audio;
 pub struct WasapiDevice {
     device: WasapiMMDevice,
     device_type: DeviceType,
+    stream_options: StreamOptions,
 }
 
 impl WasapiDevice {
@@ -18,8 +23,61 @@ impl WasapiDevice {
         WasapiDevice {
             device: WasapiMMDevice::new(device),
             device_type,
+            stream_options: StreamOptions::default(),
         }
     }
+
+    pub(crate) fn mm_device(&self) -> &WasapiMMDevice {
+        &self.device
+    }
+
+    /// Speaker positions of the channels of the device mix format. Channels which are not
+    /// assigned to a speaker have no position.
+    fn channel_positions(&self) -> Result<Vec<Option<ChannelPosition>>, error::WasapiError> {
+        let audio_client = self.device.activate::<Audio::IAudioClient>()?;
+        let (channels, mask) = unsafe {
+            let format = audio_client.GetMixFormat()?;
+            let channels = format.read_unaligned().nChannels as usize;
+            let mask = stream::channel_mask(format);
+            CoTaskMemFree(format.cast());
+            (channels, mask)
+        };
+        let layout = SpeakerLayout::from_mask(mask.unwrap_or(0));
+        let mut positions = layout.positions().map(Some).collect::<Vec<_>>();
+        positions.resize(channels, None);
+        Ok(positions)
+    }
+
+    /// Returns whether this endpoint supports hardware-offloaded streams, where long-running
+    /// media playback is processed by the audio hardware directly for power savings.
+    pub fn is_offload_capable(&self) -> Result<bool, error::WasapiError> {
+        let audio_client = self.device.activate::<Audio::IAudioClient2>()?;
+        let capable = unsafe { audio_client.IsOffloadCapable(Audio::AudioCategory_Media) }?;
+        Ok(capable.as_bool())
+    }
+
+    /// Requests output streams created from this device to be offloaded to the audio hardware.
+    ///
+    /// Offloading is only attempted on shared-mode streams of offload-capable endpoints (see
+    /// [`Self::is_offload_capable`]); streams transparently fall back to regular processing
+    /// otherwise.
+    pub fn with_hardware_offload(mut self, enabled: bool) -> Self {
+        self.stream_options.hardware_offload = enabled;
+        self
+    }
+
+    /// Sets what streams created from this device do when their endpoint becomes invalid, for
+    /// example when a USB audio interface is unplugged. See [`WasapiRecoveryPolicy`] for details.
+    pub fn with_recovery_policy(mut self, policy: WasapiRecoveryPolicy) -> Self {
+        self.stream_options.recovery_policy = policy;
+        self
+    }
+
+    /// Returns a peak meter over the whole endpoint, that is all audio sessions playing to or
+    /// recording from this device.
+    pub fn meter(&self) -> Result<WasapiMeter, error::WasapiError> {
+        Ok(WasapiMeter::new(self.device.activate()?))
+    }
 }
 
 impl AudioDevice for WasapiDevice {
@@ -29,7 +87,7 @@ impl AudioDevice for WasapiDevice {
         match self.device.name() {
             Some(std) => Cow::Owned(std),
             None => {
-                eprintln!("Cannot get audio device name");
+                diagnostics::warn!("Cannot get audio device name");
                 Cow::Borrowed("<unknown>")
             }
         }
@@ -40,7 +98,21 @@ impl AudioDevice for WasapiDevice {
     }
 
     fn channel_map(&self) -> impl IntoIterator<Item = Channel> {
-        []
+        let positions = self
+            .channel_positions()
+            .inspect_err(|err| diagnostics::warn!("Cannot get channel positions: {err}"))
+            .unwrap_or_default();
+        positions
+            .into_iter()
+            .enumerate()
+            .map(|(index, position)| Channel {
+                index,
+                name: match position {
+                    Some(position) => Cow::Borrowed(position.short_name()),
+                    None => Cow::Owned(format!("Channel {}", index + 1)),
+                },
+                position,
+            })
     }
 
     fn is_config_supported(&self, config: &StreamConfig) -> bool {
@@ -55,6 +127,49 @@ impl AudioDevice for WasapiDevice {
     fn enumerate_configurations(&self) -> Option<impl IntoIterator<Item = StreamConfig>> {
         None::<[StreamConfig; 0]>
     }
+
+    /// Exclusive access is supported when the endpoint accepts its mix format in exclusive mode,
+    /// which fails when exclusive mode is disabled in the endpoint settings.
+    fn supports_exclusive(&self) -> bool {
+        let try_ = || unsafe {
+            let audio_client = self.device.activate::<Audio::IAudioClient>()?;
+            let mix_format = audio_client.GetMixFormat()?;
+            let mix = mix_format.read_unaligned();
+            CoTaskMemFree(mix_format.cast());
+            let format = stream::config_to_waveformatextensible(
+                mix.nSamplesPerSec as _,
+                mix.nChannels as _,
+            );
+            let result = audio_client.IsFormatSupported(
+                Audio::AUDCLNT_SHAREMODE_EXCLUSIVE,
+                &format.Format,
+                None,
+            );
+            Ok::<_, error::WasapiError>(result.is_ok())
+        };
+        try_()
+            .inspect_err(|err| diagnostics::warn!("Cannot check for exclusive mode support: {err}"))
+            .unwrap_or(false)
+    }
+
+    /// Streams open the endpoint in shared mode with `IAudioClient::Initialize`, which processes
+    /// audio once per period of the audio engine. Buffers are at least as large as this period;
+    /// larger buffers are accepted, up to a limit which the endpoint does not report.
+    fn buffer_size_range(&self) -> Result<(Option<usize>, Option<usize>), Self::Error> {
+        let audio_client = self.device.activate::<Audio::IAudioClient>()?;
+        unsafe {
+            let format = audio_client.GetMixFormat()?;
+            let sample_rate = format.read_unaligned().nSamplesPerSec;
+            CoTaskMemFree(format.cast());
+            // The minimum period only applies to exclusive mode streams
+            let mut default_period = 0i64;
+            audio_client.GetDevicePeriod(Some(&mut default_period as *mut _), None)?;
+            Ok((
+                Some(stream::duration_to_buffer_size(default_period, sample_rate)),
+                None,
+            ))
+        }
+    }
 }
 
 
@@ -67,10 +182,19 @@ impl AudioInputDevice for WasapiDevice {
             audio_client.GetMixFormat()?.read_unaligned() };
         let frame_size = unsafe { audio_client.GetBufferSize() }.map(|i| i as usize).ok();
         Ok(StreamConfig {
-            channels: 0u32.with_indices(0..format.nChannels as _),
+            input_channels: ChannelMap32::first_n(format.nChannels as _),
+            output_channels: 0,
             exclusive: false,
+            fallback_to_shared: false,
+            sample_format: None,
+            input_trim: ChannelTrim::default(),
+            output_trim: ChannelTrim::default(),
+            metering: false,
             samplerate: format.nSamplesPerSec as _,
-            buffer_size_range: (frame_size, frame_size),
+            buffer_size_range: (
+                frame_size.map(BufferSize::Frames),
+                frame_size.map(BufferSize::Frames),
+            ),
         })
     }
 
@@ -79,9 +203,11 @@ impl AudioInputDevice for WasapiDevice {
         stream_config: StreamConfig,
         callback: Callback,
     ) -> Result<Self::StreamHandle<Callback>, Self::Error> {
+        let stream_config = stream_config.resolve_exclusive(|| self.supports_exclusive());
         Ok(WasapiStream::new_input(
             self.device.clone(),
             stream_config,
+            self.stream_options,
             callback,
         ))
     }
@@ -96,10 +222,19 @@ impl AudioOutputDevice for WasapiDevice {
             audio_client.GetMixFormat()?.read_unaligned() };
         let frame_size = unsafe { audio_client.GetBufferSize() }.map(|i| i as usize).ok();
         Ok(StreamConfig {
-            channels: 0u32.with_indices(0..format.nChannels as _),
+            input_channels: 0,
+            output_channels: ChannelMap32::first_n(format.nChannels as _),
             exclusive: false,
+            fallback_to_shared: false,
+            sample_format: None,
+            input_trim: ChannelTrim::default(),
+            output_trim: ChannelTrim::default(),
+            metering: false,
             samplerate: format.nSamplesPerSec as _,
-            buffer_size_range: (frame_size, frame_size),
+            buffer_size_range: (
+                frame_size.map(BufferSize::Frames),
+                frame_size.map(BufferSize::Frames),
+            ),
         })
     }
 
@@ -108,9 +243,11 @@ impl AudioOutputDevice for WasapiDevice {
         stream_config: StreamConfig,
         callback: Callback,
     ) -> Result<Self::StreamHandle<Callback>, Self::Error> {
+        let stream_config = stream_config.resolve_exclusive(|| self.supports_exclusive());
         Ok(WasapiStream::new_output(
             self.device.clone(),
             stream_config,
+            self.stream_options,
             callback,
         ))
     }
@@ -1,8 +1,10 @@
 use std::borrow::Cow;
+use windows::core::HSTRING;
 use windows::Win32::System::Com;
 use windows::Win32::Media::Audio;
 use std::sync::OnceLock;
 use crate::backends::wasapi::device::{WasapiDevice, WasapiDeviceList};
+use crate::backends::wasapi::util::WasapiMMDevice;
 
 use super::{error, util};
 
@@ -19,7 +21,7 @@ impl AudioDriver for WasapiDriver {
     const DISPLAY_NAME: &'static str = "WASAPI";
 
     fn version(&self) -> Result<Cow<str>, Self::Error> {
-        Ok(Cow::Borrowed("unknown"))
+        Err(error::WasapiError::VersionUnavailable)
     }
 
     fn default_device(&self, device_type: DeviceType) -> Result<Option<Self::Device>, Self::Error> {
@@ -56,7 +58,7 @@ pub struct AudioDeviceEnumerator(Audio::IMMDeviceEnumerator);
 
 impl AudioDeviceEnumerator {
     // Returns the default output device.
-    fn get_default_device(
+    pub(crate) fn get_default_device(
         &self,
         device_type: DeviceType,
     ) -> Result<Option<WasapiDevice>, error::WasapiError> {
@@ -73,6 +75,12 @@ impl AudioDeviceEnumerator {
         }
     }
 
+    // Returns the device with the given endpoint ID.
+    pub(crate) fn get_device_by_id(&self, id: &str) -> Result<WasapiMMDevice, error::WasapiError> {
+        let device = unsafe { self.0.GetDevice(&HSTRING::from(id)) }?;
+        Ok(WasapiMMDevice::new(device))
+    }
+
     // Returns a chained iterator of output and input devices.
     fn get_device_list(&self) -> Result<impl IntoIterator<Item = WasapiDevice>, error::WasapiError> {
         // Create separate collections for output and input devices and then chain them.
@@ -13,4 +13,7 @@ pub enum WasapiError {
     /// Windows Foundation error
     #[error("Win32 error: {0}")]
     FoundationError(String),
+    /// WASAPI does not report its version.
+    #[error("WASAPI version is not available")]
+    VersionUnavailable,
 }
\ No newline at end of file
@@ -0,0 +1,43 @@
+use super::error;
+use windows::Win32::Media::Audio::Endpoints;
+
+/// Peak meter backed by WASAPI's `IAudioMeterInformation` interface.
+///
+/// Meters are computed by the Windows audio engine, which means levels can be read from any
+/// thread (for example a UI thread) without adding a software tap into the audio callback.
+///
+/// A meter can either be obtained from a [`WasapiDevice`](super::WasapiDevice), in which case it
+/// reports the levels of the whole endpoint, or from a [`WasapiStream`](super::WasapiStream), in
+/// which case only the audio session of that stream is metered.
+#[derive(Debug, Clone)]
+pub struct WasapiMeter(Endpoints::IAudioMeterInformation);
+
+unsafe impl Send for WasapiMeter {}
+
+unsafe impl Sync for WasapiMeter {}
+
+impl WasapiMeter {
+    pub(crate) fn new(meter: Endpoints::IAudioMeterInformation) -> Self {
+        Self(meter)
+    }
+
+    /// Peak value over all channels, as a linear amplitude between 0 and 1.
+    pub fn peak(&self) -> Result<f32, error::WasapiError> {
+        Ok(unsafe { self.0.GetPeakValue() }?)
+    }
+
+    /// Number of channels measured by this meter.
+    pub fn channel_count(&self) -> Result<usize, error::WasapiError> {
+        Ok(unsafe { self.0.GetMeteringChannelCount() }? as usize)
+    }
+
+    /// Writes the peak values of each channel into `peaks`, as linear amplitudes between 0 and 1.
+    ///
+    /// Returns the number of channels written, which is the minimum between the number of
+    /// metered channels and the length of `peaks`.
+    pub fn channel_peaks(&self, peaks: &mut [f32]) -> Result<usize, error::WasapiError> {
+        let count = self.channel_count()?.min(peaks.len());
+        unsafe { self.0.GetChannelsPeakValues(&mut peaks[..count]) }?;
+        Ok(count)
+    }
+}
@@ -4,6 +4,7 @@ mod error;
 
 pub(crate) mod driver;
 mod device;
+mod meter;
 mod stream;
 pub mod prelude;
 
@@ -2,5 +2,6 @@ pub use super::{
     device::WasapiDevice,
     driver::WasapiDriver,
     error::WasapiError,
-    stream::WasapiStream,
+    meter::WasapiMeter,
+    stream::{WasapiRecoveryPolicy, WasapiStream},
 };
@@ -1,17 +1,30 @@
 use super::error;
-use crate::audio_buffer::AudioMut;
+use crate::audio_buffer::{AudioMut, SampleFormat};
+use crate::backends::wasapi::driver::audio_device_enumerator;
+use crate::backends::wasapi::meter::WasapiMeter;
 use crate::backends::wasapi::util::WasapiMMDevice;
-use crate::channel_map::Bitset;
+use crate::channel_map::{
+    channel_selection, device_channel_count, scatter_channels, select_channels, Bitset,
+    ChannelMap32, CreateBitset,
+};
+use crate::clock::{callback_deadline, HostTime};
+use crate::control::{
+    control_queue, CallbackSlot, ControlReceiver, EjectError, StreamControl, StreamFailure,
+};
+use crate::diagnostics;
+use crate::events::{StreamEvent, StreamEventReceiver, StreamEventSender};
+use crate::metering::{LevelTap, StreamLevels, StreamMeter};
+use crate::realtime;
 use crate::prelude::{AudioRef, Timestamp};
+use crate::timestamp::CallbackTracker;
 use crate::{
     AudioCallbackContext, AudioInput, AudioInputCallback, AudioOutput, AudioOutputCallback,
-    AudioStreamHandle, StreamConfig,
+    AudioStreamHandle, DeviceType, StreamConfig,
 };
 use duplicate::duplicate_item;
 use std::marker::PhantomData;
 use std::ptr::NonNull;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::thread::JoinHandle;
 use std::time::Duration;
 use std::{ops, ptr, slice};
@@ -22,7 +35,37 @@ use windows::Win32::Foundation::{CloseHandle, HANDLE};
 use windows::Win32::Media::{Audio, KernelStreaming, Multimedia};
 use windows::Win32::System::Threading;
 
-type EjectSignal = Arc<AtomicBool>;
+/// Maximum time to wait for the audio engine to signal a new buffer, after which the stream
+/// checks for ejection or device errors.
+const EVENT_TIMEOUT_MS: u32 = 1000;
+
+/// Interval between attempts at reopening a device when recovering a stream.
+const RECOVERY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Policy applied by WASAPI streams when their endpoint becomes invalid
+/// (`AUDCLNT_E_DEVICE_INVALIDATED`), for example when a USB audio interface is unplugged.
+///
+/// When the stream recovers, the callback is notified through its `prepare` method with the new
+/// stream configuration. Timestamps then restart from zero in a new
+/// [`generation`](crate::timestamp::Timestamp::generation).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WasapiRecoveryPolicy {
+    /// The stream stops with an error.
+    #[default]
+    Disabled,
+    /// Wait for the same endpoint to reappear, and resume the stream on it.
+    SameDevice,
+    /// Resume the stream on the default endpoint as soon as there is one, which may or may not
+    /// be the same endpoint.
+    FollowDefault,
+}
+
+/// Backend-specific stream options, set through builder methods on [`super::WasapiDevice`].
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct StreamOptions {
+    pub(crate) hardware_offload: bool,
+    pub(crate) recovery_policy: WasapiRecoveryPolicy,
+}
 
 #[duplicate_item(
 name                 ty;
@@ -65,17 +108,13 @@ impl<'a, T> ops::DerefMut for name<'a, T> {
 
 impl<T> Drop for AudioCaptureBuffer<'_, T> {
     fn drop(&mut self) {
-        unsafe { self.interface.ReleaseBuffer(self.frame_size as _).unwrap() };
+        let _ = unsafe { self.interface.ReleaseBuffer(self.frame_size as _) };
     }
 }
 
 impl<T> Drop for AudioRenderBuffer<'_, T> {
     fn drop(&mut self) {
-        unsafe {
-            self.interface
-                .ReleaseBuffer(self.frame_size as _, 0)
-                .unwrap();
-        }
+        let _ = unsafe { self.interface.ReleaseBuffer(self.frame_size as _, 0) };
     }
 }
 
@@ -97,105 +136,140 @@ impl<'a, T> AudioRenderBuffer<'a, T> {
     }
 }
 impl<'a, T> AudioCaptureBuffer<'a, T> {
+    /// Get the next packet of captured frames, along with the time at which its first frame was
+    /// recorded by the device, and whether the device reported a discontinuity before it.
     fn from_client(
         capture_client: &'a Audio::IAudioCaptureClient,
         channels: usize,
-    ) -> Result<Option<Self>, error::WasapiError> {
+    ) -> Result<Option<(Self, HostTime, bool)>, error::WasapiError> {
         let mut buf_ptr = ptr::null_mut();
         let mut frame_size = 0;
         let mut flags = 0;
+        let mut qpc_position: u64 = 0;
         unsafe {
-            capture_client.GetBuffer(&mut buf_ptr, &mut frame_size, &mut flags, None, None)
+            capture_client.GetBuffer(
+                &mut buf_ptr,
+                &mut frame_size,
+                &mut flags,
+                None,
+                Some(&mut qpc_position),
+            )
         }?;
         let Some(data) = NonNull::new(buf_ptr as _) else { return Ok(None); };
-        Ok(Some(Self {
+        let buffer = Self {
             interface: capture_client,
             data,
             frame_size: frame_size as _,
             channels,
             __type: PhantomData,
-        }))
+        };
+        // The `qpc_position` is in 100 nanosecond units
+        let capture_time = HostTime::from_duration(Duration::from_nanos(qpc_position * 100));
+        let discontinuity = flags & Audio::AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY.0 as u32 != 0;
+        Ok(Some((buffer, capture_time, discontinuity)))
     }
 }
 
 struct AudioThread<Callback, Interface> {
+    device: WasapiMMDevice,
+    requested_config: StreamConfig,
+    options: StreamOptions,
     audio_client: Audio::IAudioClient,
     interface: Interface,
     audio_clock: Audio::IAudioClock,
     stream_config: StreamConfig,
-    eject_signal: EjectSignal,
+    control: ControlReceiver<Callback>,
     frame_size: usize,
-    callback: Callback,
+    device_channels: usize,
+    selection: Option<Vec<usize>>,
+    selection_buffer: Vec<f32>,
+    trim: Option<Vec<f32>>,
+    callback: CallbackSlot<Callback>,
     event_handle: HANDLE,
-    clock_start: Duration,
+    clock_start: HostTime,
+    generation: u32,
+    tracker: CallbackTracker,
+    events: StreamEventSender,
+    level_tap: Option<LevelTap>,
 }
 
 impl<Callback, Interface> AudioThread<Callback, Interface> {
-    fn finalize(self) -> Result<Callback, error::WasapiError> {
+    fn finalize(self) -> Result<(), error::WasapiError> {
         if !self.event_handle.is_invalid() {
             unsafe { CloseHandle(self.event_handle) }?;
         }
         let _ = unsafe {
             self.audio_client
                 .Stop()
-                .inspect_err(|err| eprintln!("Cannot stop audio thread: {err}"))
+                .inspect_err(|err| diagnostics::warn!("Cannot stop audio thread: {err}"))
         };
-        Ok(self.callback)
+        Ok(())
     }
 }
 
 impl<Callback, Iface: Interface> AudioThread<Callback, Iface> {
     fn new(
         device: WasapiMMDevice,
-        eject_signal: EjectSignal,
+        device_type: DeviceType,
+        control: ControlReceiver<Callback>,
+        events: StreamEventSender,
         mut stream_config: StreamConfig,
-        callback: Callback,
+        options: StreamOptions,
+        callback: CallbackSlot<Callback>,
     ) -> Result<Self, error::WasapiError> {
+        let requested_config = stream_config;
         unsafe {
-            let audio_client: Audio::IAudioClient = device.activate()?;
+            let mut audio_client: Audio::IAudioClient = device.activate()?;
             let sharemode = if stream_config.exclusive {
                 Audio::AUDCLNT_SHAREMODE_EXCLUSIVE
             } else {
                 Audio::AUDCLNT_SHAREMODE_SHARED
             };
-            let format = {
-                let mut format = config_to_waveformatextensible(&stream_config);
-                let mut actual_format = ptr::null_mut();
-                audio_client
-                    .IsFormatSupported(
-                        sharemode,
-                        &format.Format,
-                        (!stream_config.exclusive).then_some(&mut actual_format),
-                    )
-                    .ok()?;
-                if !stream_config.exclusive {
-                    assert!(!actual_format.is_null());
-                    format.Format = actual_format.read_unaligned();
-                    CoTaskMemFree(actual_format.cast());
-                    let sample_rate = format.Format.nSamplesPerSec;
-                    stream_config.channels = 0u32.with_indices(0..format.Format.nChannels as _);
-                    stream_config.samplerate = sample_rate as _;
-                }
-                format
-            };
-            let frame_size = stream_config
-                .buffer_size_range
-                .0
-                .or(stream_config.buffer_size_range.1);
+            // Open all device channels up to the last requested one, and pick the requested
+            // channels out of them
+            let requested_channels = *stream_channels(&mut stream_config, device_type);
+            *stream_channels(&mut stream_config, device_type) =
+                ChannelMap32::first_n(device_channel_count(requested_channels));
+            let format =
+                negotiate_format(&audio_client, sharemode, &mut stream_config, device_type)?;
+            let device_channels = stream_channels(&mut stream_config, device_type).count();
+            let selection = channel_selection(requested_channels, device_channels)
+                .filter(|selected| selected.iter().all(|&channel| channel < device_channels));
+            if selection.is_some() {
+                *stream_channels(&mut stream_config, device_type) = requested_channels;
+            }
+            let (min_frames, max_frames) = stream_config.buffer_frames_range();
+            let frame_size = min_frames.or(max_frames);
             let buffer_duration = frame_size
                 .map(|frame_size| {
                     buffer_size_to_duration(frame_size, stream_config.samplerate as _)
                 })
                 .unwrap_or(0);
-            audio_client.Initialize(
-                sharemode,
-                Audio::AUDCLNT_STREAMFLAGS_EVENTCALLBACK
-                    | Audio::AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM,
-                buffer_duration,
-                0,
-                &format.Format,
-                None,
-            )?;
+            let initialize = |audio_client: &Audio::IAudioClient, buffer_duration| {
+                audio_client.Initialize(
+                    sharemode,
+                    Audio::AUDCLNT_STREAMFLAGS_EVENTCALLBACK
+                        | Audio::AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM,
+                    buffer_duration,
+                    0,
+                    &format.Format,
+                    None,
+                )
+            };
+            if options.hardware_offload
+                && !stream_config.exclusive
+                && enable_hardware_offload(&audio_client)
+            {
+                let offload_duration =
+                    offload_buffer_duration(&audio_client, &format.Format, buffer_duration);
+                if let Err(err) = initialize(&audio_client, offload_duration) {
+                    diagnostics::warn!("Cannot initialize offloaded stream, falling back: {err}");
+                    audio_client = device.activate()?;
+                    initialize(&audio_client, buffer_duration)?;
+                }
+            } else {
+                initialize(&audio_client, buffer_duration)?;
+            }
             let buffer_size = audio_client.GetBufferSize()? as usize;
             let event_handle = {
                 let event_handle =
@@ -206,18 +280,38 @@ impl<Callback, Iface: Interface> AudioThread<Callback, Iface> {
             let interface = audio_client.GetService::<Iface>()?;
             let audio_clock = audio_client.GetService::<Audio::IAudioClock>()?;
             let frame_size = buffer_size;
+            let selection_buffer = selection
+                .as_ref()
+                .map_or(vec![], |selected| vec![0.0; frame_size * selected.len()]);
+            let stream_channels = selection.as_ref().map_or(device_channels, Vec::len);
+            let trim = match device_type {
+                DeviceType::Input => stream_config.input_trim,
+                _ => stream_config.output_trim,
+            }
+            .amplitudes(stream_channels);
             Ok(Self {
+                device,
+                requested_config,
+                options,
                 audio_client,
                 interface,
                 audio_clock,
                 event_handle,
                 frame_size,
-                eject_signal,
+                device_channels,
+                selection,
+                selection_buffer,
+                trim,
+                control,
                 stream_config: StreamConfig {
-                    buffer_size_range: (Some(frame_size), Some(frame_size)),
+                    buffer_size_range: (Some(frame_size.into()), Some(frame_size.into())),
                     ..stream_config
                 },
-                clock_start: Duration::ZERO,
+                clock_start: HostTime::default(),
+                generation: 0,
+                tracker: CallbackTracker::default(),
+                events,
+                level_tap: None,
                 callback,
             })
         }
@@ -225,7 +319,7 @@ impl<Callback, Iface: Interface> AudioThread<Callback, Iface> {
 
     fn await_frame(&mut self) -> Result<(), error::WasapiError> {
         let _ = unsafe {
-            let result = Threading::WaitForSingleObject(self.event_handle, Threading::INFINITE);
+            let result = Threading::WaitForSingleObject(self.event_handle, EVENT_TIMEOUT_MS);
             if result == Foundation::WAIT_FAILED {
                 let err = Foundation::GetLastError();
                 let description = format!("Waiting for event handle failed: {:?}", err);
@@ -236,31 +330,166 @@ impl<Callback, Iface: Interface> AudioThread<Callback, Iface> {
         Ok(())
     }
 
+    fn session_meter(&self) -> Result<WasapiMeter, error::WasapiError> {
+        let session = unsafe { self.audio_client.GetService::<Audio::IAudioSessionControl>() }?;
+        Ok(WasapiMeter::new(session.cast()?))
+    }
+
     fn output_timestamp(&self) -> Result<Timestamp, error::WasapiError> {
         let clock = stream_instant(&self.audio_clock)?;
-        let diff = clock - self.clock_start;
-        Ok(Timestamp::from_duration(
-            self.stream_config.samplerate,
-            diff,
-        ))
+        let diff = clock.saturating_duration_since(self.clock_start);
+        Ok(Timestamp {
+            generation: self.generation,
+            ..Timestamp::from_duration(self.stream_config.samplerate, diff)
+        })
+    }
+
+    fn context(&self) -> Result<AudioCallbackContext, error::WasapiError> {
+        Ok(AudioCallbackContext {
+            stream_config: self.stream_config,
+            timestamp: self.output_timestamp()?,
+            output_time: None,
+            deadline: None,
+            queued_frames: None,
+            frames_since_last_callback: None,
+            discontinuity: false,
+        })
+    }
+
+    fn can_recover(&self, err: &error::WasapiError) -> bool {
+        let error::WasapiError::BackendError(err) = err else {
+            return false;
+        };
+        err.code() == Audio::AUDCLNT_E_DEVICE_INVALIDATED
+            && self.options.recovery_policy != WasapiRecoveryPolicy::Disabled
+    }
+
+    /// Waits for an endpoint to become available according to the recovery policy, and
+    /// re-initializes the audio client on it. Returns `false` if the stream has been ejected
+    /// while waiting.
+    fn recover(&mut self, device_type: DeviceType) -> Result<bool, error::WasapiError> {
+        diagnostics::warn!("Audio device invalidated, waiting for it to come back");
+        self.generation = self.generation.wrapping_add(1);
+        self.tracker.mark_discontinuity();
+        if !self.event_handle.is_invalid() {
+            unsafe { CloseHandle(self.event_handle) }?;
+            self.event_handle = HANDLE::default();
+        }
+        loop {
+            // Callbacks replaced while waiting are prepared once the stream has recovered
+            self.control.process(&mut self.callback, &self.events);
+            if self.control.eject_requested() {
+                return Ok(false);
+            }
+            std::thread::sleep(RECOVERY_POLL_INTERVAL);
+            let Some(device) = self.recovery_device(device_type) else {
+                continue;
+            };
+            let (_, control, callback) = control_queue(());
+            let result = AudioThread::<(), Iface>::new(
+                device,
+                device_type,
+                control,
+                self.events.clone(),
+                self.requested_config,
+                self.options,
+                callback,
+            );
+            let thread = match result {
+                Ok(thread) => thread,
+                Err(err) => {
+                    diagnostics::warn!("Cannot reopen audio device: {err}");
+                    continue;
+                }
+            };
+            // The device can disappear again before the stream starts, which is waited for in
+            // the same way
+            let clock_start = unsafe { thread.audio_client.Start() }
+                .map_err(error::WasapiError::from)
+                .and_then(|()| stream_instant(&thread.audio_clock));
+            let clock_start = match clock_start {
+                Ok(clock_start) => clock_start,
+                Err(err) => {
+                    diagnostics::warn!("Cannot restart audio device: {err}");
+                    let _ = thread.finalize();
+                    continue;
+                }
+            };
+            self.device = thread.device;
+            self.audio_client = thread.audio_client;
+            self.interface = thread.interface;
+            self.audio_clock = thread.audio_clock;
+            self.stream_config = thread.stream_config;
+            self.frame_size = thread.frame_size;
+            self.device_channels = thread.device_channels;
+            self.selection = thread.selection;
+            self.selection_buffer = thread.selection_buffer;
+            self.trim = thread.trim;
+            self.event_handle = thread.event_handle;
+            self.clock_start = clock_start;
+            diagnostics::warn!("Audio stream recovered");
+            self.events.send(StreamEvent::DeviceChanged);
+            return Ok(true);
+        }
+    }
+
+    fn recovery_device(&self, device_type: DeviceType) -> Option<WasapiMMDevice> {
+        match self.options.recovery_policy {
+            WasapiRecoveryPolicy::Disabled => None,
+            WasapiRecoveryPolicy::SameDevice => {
+                let id = self.device.id()?;
+                audio_device_enumerator().get_device_by_id(&id).ok()
+            }
+            WasapiRecoveryPolicy::FollowDefault => audio_device_enumerator()
+                .get_default_device(device_type)
+                .ok()
+                .flatten()
+                .map(|device| device.mm_device().clone()),
+        }
     }
 }
 
 impl<Callback: AudioInputCallback> AudioThread<Callback, Audio::IAudioCaptureClient> {
-    fn run(mut self) -> Result<Callback, error::WasapiError> {
+    fn run(mut self) -> Result<(), error::WasapiError> {
         set_thread_priority();
+        self.callback.prepare(AudioCallbackContext {
+            stream_config: self.stream_config,
+            timestamp: Timestamp::new(self.stream_config.samplerate),
+            output_time: None,
+            deadline: None,
+            queued_frames: None,
+            frames_since_last_callback: None,
+            discontinuity: false,
+        });
         unsafe {
             self.audio_client.Start()?;
         }
         self.clock_start = stream_instant(&self.audio_clock)?;
+        let events = self.events.clone();
+        events.send(StreamEvent::Started);
         loop {
-            if self.eject_signal.load(Ordering::Relaxed) {
+            if self.control.process(&mut self.callback, &self.events) {
+                let context = self.context()?;
+                self.callback.prepare(context);
+            }
+            if self.control.eject_requested() {
                 break self.finalize();
             }
             self.await_frame()?;
-            self.process()?;
+            if let Err(err) = realtime::process_cycle(|| self.process()) {
+                if !self.can_recover(&err) {
+                    break Err(err);
+                }
+                if self.recover(DeviceType::Input)? {
+                    let context = self.context()?;
+                    self.callback.prepare(context);
+                }
+            }
         }
-        .inspect_err(|err| eprintln!("Render thread process error: {err}"))
+        .inspect_err(|err| {
+            diagnostics::error!("Render thread process error: {err}");
+            events.send(StreamEvent::Error(err.to_string().into()));
+        })
     }
 
     fn process(&mut self) -> Result<(), error::WasapiError> {
@@ -268,90 +497,213 @@ impl<Callback: AudioInputCallback> AudioThread<Callback, Audio::IAudioCaptureCli
         if frames_available == 0 {
             return Ok(());
         }
-        let Some(mut buffer) = AudioCaptureBuffer::<f32>::from_client(
-            &self.interface,
-            self.stream_config.channels.count(),
-        )?
+        let padding = unsafe { self.audio_client.GetCurrentPadding()? as usize };
+        let Some((mut buffer, capture_time, discontinuity)) =
+            AudioCaptureBuffer::<f32>::from_client(&self.interface, self.device_channels)?
         else {
-            eprintln!("Null buffer from WASAPI");
+            diagnostics::warn!("Null buffer from WASAPI");
             return Ok(());
         };
         let timestamp = self.output_timestamp()?;
+        let frames = buffer.len() / self.device_channels;
+        if discontinuity {
+            self.tracker.mark_discontinuity();
+            self.events.send(StreamEvent::XRun);
+        }
+        let (samples, channels) = match &self.selection {
+            Some(selected) => {
+                let samples = &mut self.selection_buffer[..frames * selected.len()];
+                select_channels(&buffer, self.device_channels, selected, samples);
+                (samples, selected.len())
+            }
+            None => (&mut buffer[..], self.device_channels),
+        };
+        let mut input_samples = AudioMut::from_interleaved_mut(samples, channels).unwrap();
+        if let Some(amplitudes) = &self.trim {
+            input_samples.change_channel_amplitudes(amplitudes);
+        }
+        self.control.apply_volume(input_samples);
+        let buffer = AudioRef::from_interleaved(samples, channels).unwrap();
+        if let Some(tap) = &mut self.level_tap {
+            tap.process(buffer);
+        }
+        if self.control.is_paused() {
+            return Ok(());
+        }
+        let (frames_since_last_callback, discontinuity) = self.tracker.next(timestamp, frames);
         let context = AudioCallbackContext {
             stream_config: self.stream_config,
             timestamp,
+            output_time: None,
+            deadline: Some(callback_deadline(self.stream_config.samplerate, frames)),
+            // The padding of capture streams includes the packet being read
+            queued_frames: Some(padding.saturating_sub(frames)),
+            frames_since_last_callback,
+            discontinuity,
+        };
+        let output = AudioInput {
+            timestamp,
+            capture_time: Some(capture_time),
+            buffer,
         };
-        let buffer =
-            AudioRef::from_interleaved(&mut buffer, self.stream_config.channels.count()).unwrap();
-        let output = AudioInput { timestamp, buffer };
         self.callback.on_input_data(context, output);
         Ok(())
     }
 }
 
 impl<Callback: AudioOutputCallback> AudioThread<Callback, Audio::IAudioRenderClient> {
-    fn run(mut self) -> Result<Callback, error::WasapiError> {
+    fn run(mut self) -> Result<(), error::WasapiError> {
         set_thread_priority();
+        self.callback.prepare(AudioCallbackContext {
+            stream_config: self.stream_config,
+            timestamp: Timestamp::new(self.stream_config.samplerate),
+            output_time: None,
+            deadline: None,
+            queued_frames: None,
+            frames_since_last_callback: None,
+            discontinuity: false,
+        });
         unsafe {
             self.audio_client.Start()?;
         }
         self.clock_start = stream_instant(&self.audio_clock)?;
+        let events = self.events.clone();
+        events.send(StreamEvent::Started);
         loop {
-            if self.eject_signal.load(Ordering::Relaxed) {
+            if self.control.process(&mut self.callback, &self.events) {
+                let context = self.context()?;
+                self.callback.prepare(context);
+            }
+            if self.control.eject_requested() {
                 break self.finalize();
             }
             self.await_frame()?;
-            self.process()?;
+            if let Err(err) = realtime::process_cycle(|| self.process()) {
+                if !self.can_recover(&err) {
+                    break Err(err);
+                }
+                if self.recover(DeviceType::Output)? {
+                    let context = self.context()?;
+                    self.callback.prepare(context);
+                }
+            }
         }
-        .inspect_err(|err| eprintln!("Render thread process error: {err}"))
+        .inspect_err(|err| {
+            diagnostics::error!("Render thread process error: {err}");
+            events.send(StreamEvent::Error(err.to_string().into()));
+        })
     }
 
     fn process(&mut self) -> Result<(), error::WasapiError> {
-        let frames_available = unsafe {
-            let padding = self.audio_client.GetCurrentPadding()? as usize;
-            self.frame_size - padding
-        };
+        let padding = unsafe { self.audio_client.GetCurrentPadding()? as usize };
+        let frames_available = self.frame_size - padding;
         if frames_available == 0 {
             return Ok(());
         }
-        let frames_requested = if let Some(max_frames) = self.stream_config.buffer_size_range.1 {
+        let (_, max_frames) = self.stream_config.buffer_frames_range();
+        let frames_requested = if let Some(max_frames) = max_frames {
             frames_available.min(max_frames)
         } else {
             frames_available
         };
         let mut buffer = AudioRenderBuffer::<f32>::from_client(
             &self.interface,
-            self.stream_config.channels.count(),
+            self.device_channels,
             frames_requested,
         )?;
         let timestamp = self.output_timestamp()?;
-        let context = AudioCallbackContext {
-            stream_config: self.stream_config,
-            timestamp,
+        // Frames still queued in the endpoint buffer are played before the ones written now
+        let output_time = stream_instant(&self.audio_clock)?
+            + Duration::from_secs_f64(padding as f64 / self.stream_config.samplerate);
+        let samples = match &self.selection {
+            Some(selected) => &mut self.selection_buffer[..frames_requested * selected.len()],
+            None => &mut buffer[..],
         };
-        let buffer =
-            AudioMut::from_interleaved_mut(&mut buffer, self.stream_config.channels.count())
+        let mut samples =
+            AudioMut::from_interleaved_mut(samples, self.stream_config.output_channels.count())
                 .unwrap();
-        let output = AudioOutput { timestamp, buffer };
-        self.callback.on_output_data(context, output);
+        if self.control.is_paused() {
+            samples.as_interleaved_mut().fill(0.);
+        } else {
+            let (frames_since_last_callback, discontinuity) =
+                self.tracker.next(timestamp, frames_requested);
+            let context = AudioCallbackContext {
+                stream_config: self.stream_config,
+                timestamp,
+                output_time: Some(output_time),
+                deadline: Some(callback_deadline(self.stream_config.samplerate, frames_requested)),
+                queued_frames: Some(padding),
+                frames_since_last_callback,
+                discontinuity,
+            };
+            let output = AudioOutput {
+                timestamp,
+                buffer: samples.as_mut(),
+            };
+            self.callback.on_output_data(context, output);
+        }
+        if let Some(tap) = &mut self.level_tap {
+            tap.process(samples.as_ref());
+        }
+        if let Some(amplitudes) = &self.trim {
+            samples.change_channel_amplitudes(amplitudes);
+        }
+        self.control.apply_volume(samples);
+        if let Some(selected) = &self.selection {
+            scatter_channels(
+                &self.selection_buffer[..frames_requested * selected.len()],
+                selected,
+                self.device_channels,
+                &mut buffer,
+            );
+        }
         Ok(())
     }
 }
 
 /// Type representing a WASAPI audio stream.
 pub struct WasapiStream<Callback> {
-    join_handle: JoinHandle<Result<Callback, error::WasapiError>>,
-    eject_signal: EjectSignal,
+    join_handle: JoinHandle<Result<(), error::WasapiError>>,
+    control: StreamControl<Callback>,
+    meter: Arc<OnceLock<WasapiMeter>>,
+    events: StreamEventSender,
+    levels: StreamMeter,
+}
+
+impl<Callback> WasapiStream<Callback> {
+    /// Returns a peak meter over the audio session of this stream only.
+    ///
+    /// Returns `None` when the stream has not finished initializing yet, or if the session meter
+    /// is not available.
+    pub fn meter(&self) -> Option<WasapiMeter> {
+        self.meter.get().cloned()
+    }
 }
 
 impl<Callback> AudioStreamHandle<Callback> for WasapiStream<Callback> {
-    type Error = error::WasapiError;
+    type Error = EjectError<Callback, error::WasapiError>;
 
     fn eject(self) -> Result<Callback, Self::Error> {
-        self.eject_signal.store(true, Ordering::Relaxed);
-        self.join_handle
-            .join()
-            .expect("Audio output thread panicked")
+        self.control.request_eject();
+        let result = match self.join_handle.join() {
+            Ok(result) => result.map_err(StreamFailure::Backend),
+            Err(payload) => Err(StreamFailure::panicked(payload)),
+        };
+        let callback = self.control.collect(result)?;
+        self.events.send(StreamEvent::Ejected);
+        Ok(callback)
+    }
+
+    fn subscribe_events(&self) -> StreamEventReceiver {
+        self.events.subscribe()
+    }
+
+    fn levels(&self) -> Option<StreamLevels> {
+        self.levels.levels()
+    }
+
+    fn control(&self) -> Option<&StreamControl<Callback>> {
+        Some(&self.control)
     }
 }
 
@@ -359,26 +711,51 @@ impl<Callback: 'static + Send + AudioInputCallback> WasapiStream<Callback> {
     pub(crate) fn new_input(
         device: WasapiMMDevice,
         stream_config: StreamConfig,
+        options: StreamOptions,
         callback: Callback,
     ) -> Self {
-        let eject_signal = EjectSignal::default();
+        let (control, receiver, callback) = control_queue(callback);
+        let meter = Arc::new(OnceLock::new());
+        let events = StreamEventSender::new();
+        let (levels, level_tap, _) = StreamMeter::new(
+            stream_config.metering,
+            stream_config.input_channels.count(),
+            0,
+        );
         let join_handle = std::thread::Builder::new()
             .name("interflow_wasapi_output_stream".to_string())
             .spawn({
-                let eject_signal = eject_signal.clone();
+                let meter = meter.clone();
+                let events = events.clone();
                 move || {
-                    let inner: AudioThread<Callback, Audio::IAudioCaptureClient> =
-                        AudioThread::new(device, eject_signal, stream_config, callback)
-                            .inspect_err(|err| {
-                                eprintln!("Failed to create render thread: {err}")
-                            })?;
+                    let mut inner: AudioThread<Callback, Audio::IAudioCaptureClient> =
+                        AudioThread::new(
+                            device,
+                            DeviceType::Input,
+                            receiver,
+                            events.clone(),
+                            stream_config,
+                            options,
+                            callback,
+                        )
+                        .inspect_err(|err| {
+                            diagnostics::error!("Failed to create render thread: {err}");
+                            events.send(StreamEvent::Error(err.to_string().into()));
+                        })?;
+                    inner.level_tap = level_tap;
+                    if let Ok(session_meter) = inner.session_meter() {
+                        let _ = meter.set(session_meter);
+                    }
                     inner.run()
                 }
             })
             .expect("Cannot spawn audio output thread");
         Self {
             join_handle,
-            eject_signal,
+            control,
+            meter,
+            events,
+            levels,
         }
     }
 }
@@ -387,26 +764,51 @@ impl<Callback: 'static + Send + AudioOutputCallback> WasapiStream<Callback> {
     pub(crate) fn new_output(
         device: WasapiMMDevice,
         stream_config: StreamConfig,
+        options: StreamOptions,
         callback: Callback,
     ) -> Self {
-        let eject_signal = EjectSignal::default();
+        let (control, receiver, callback) = control_queue(callback);
+        let meter = Arc::new(OnceLock::new());
+        let events = StreamEventSender::new();
+        let (levels, _, level_tap) = StreamMeter::new(
+            stream_config.metering,
+            0,
+            stream_config.output_channels.count(),
+        );
         let join_handle = std::thread::Builder::new()
             .name("interflow_wasapi_output_stream".to_string())
             .spawn({
-                let eject_signal = eject_signal.clone();
+                let meter = meter.clone();
+                let events = events.clone();
                 move || {
-                    let inner: AudioThread<Callback, Audio::IAudioRenderClient> =
-                        AudioThread::new(device, eject_signal, stream_config, callback)
-                            .inspect_err(|err| {
-                                eprintln!("Failed to create render thread: {err}")
-                            })?;
+                    let mut inner: AudioThread<Callback, Audio::IAudioRenderClient> =
+                        AudioThread::new(
+                            device,
+                            DeviceType::Output,
+                            receiver,
+                            events.clone(),
+                            stream_config,
+                            options,
+                            callback,
+                        )
+                        .inspect_err(|err| {
+                            diagnostics::error!("Failed to create render thread: {err}");
+                            events.send(StreamEvent::Error(err.to_string().into()));
+                        })?;
+                    inner.level_tap = level_tap;
+                    if let Ok(session_meter) = inner.session_meter() {
+                        let _ = meter.set(session_meter);
+                    }
                     inner.run()
                 }
             })
             .expect("Cannot spawn audio output thread");
         Self {
             join_handle,
-            eject_signal,
+            control,
+            meter,
+            events,
+            levels,
         }
     }
 }
@@ -422,11 +824,67 @@ fn set_thread_priority() {
     }
 }
 
+/// Tries to enable hardware offloading on the audio client, returning whether the client has been
+/// configured for it. This needs to be called before the client is initialized.
+fn enable_hardware_offload(audio_client: &Audio::IAudioClient) -> bool {
+    let try_ = || unsafe {
+        let audio_client = audio_client.cast::<Audio::IAudioClient2>()?;
+        if !audio_client
+            .IsOffloadCapable(Audio::AudioCategory_Media)?
+            .as_bool()
+        {
+            return Ok(false);
+        }
+        let properties = Audio::AudioClientProperties {
+            cbSize: size_of::<Audio::AudioClientProperties>() as _,
+            bIsOffload: Foundation::TRUE,
+            eCategory: Audio::AudioCategory_Media,
+            Options: Audio::AUDCLNT_STREAMOPTIONS_NONE,
+        };
+        audio_client.SetClientProperties(&properties)?;
+        Ok::<_, error::WasapiError>(true)
+    };
+    try_()
+        .inspect_err(|err| diagnostics::warn!("Cannot enable hardware offload: {err}"))
+        .unwrap_or(false)
+}
+
+/// Clamps the requested buffer duration (in 100 ns units) to the limits supported by offloaded
+/// streams for the given format.
+fn offload_buffer_duration(
+    audio_client: &Audio::IAudioClient,
+    format: &Audio::WAVEFORMATEX,
+    buffer_duration: i64,
+) -> i64 {
+    let Ok(audio_client) = audio_client.cast::<Audio::IAudioClient2>() else {
+        return buffer_duration;
+    };
+    let mut min_duration = 0;
+    let mut max_duration = 0;
+    let result = unsafe {
+        audio_client.GetBufferSizeLimits(
+            format,
+            Foundation::TRUE,
+            &mut min_duration,
+            &mut max_duration,
+        )
+    };
+    match result {
+        Ok(()) => buffer_duration.clamp(min_duration, max_duration),
+        Err(_) => buffer_duration,
+    }
+}
+
 pub fn buffer_size_to_duration(buffer_size: usize, sample_rate: u32) -> i64 {
     (buffer_size as i64 / sample_rate as i64) * (1_000_000_000 / 100)
 }
 
-fn stream_instant(audio_clock: &Audio::IAudioClock) -> Result<Duration, error::WasapiError> {
+/// Converts a duration in 100 ns units into a number of frames at the given sample rate.
+pub(crate) fn duration_to_buffer_size(duration: i64, sample_rate: u32) -> usize {
+    (duration as u64 * sample_rate as u64 / 10_000_000) as usize
+}
+
+fn stream_instant(audio_clock: &Audio::IAudioClock) -> Result<HostTime, error::WasapiError> {
     let mut position: u64 = 0;
     let mut qpc_position: u64 = 0;
     unsafe {
@@ -434,14 +892,22 @@ fn stream_instant(audio_clock: &Audio::IAudioClock) -> Result<Duration, error::W
     };
     // The `qpc_position` is in 100 nanosecond units. Convert it to nanoseconds.
     let qpc_nanos = qpc_position * 100;
-    let instant = Duration::from_nanos(qpc_nanos);
+    let instant = HostTime::from_duration(Duration::from_nanos(qpc_nanos));
     Ok(instant)
 }
 
-pub(crate) fn config_to_waveformatextensible(config: &StreamConfig) -> Audio::WAVEFORMATEXTENSIBLE {
+/// Builds the stream format for the given configuration.
+///
+/// The channel mask is derived from the number of requested channels (see
+/// [`channel_mask_for_count`]); interleaved samples are laid out in ascending speaker bit order of
+/// that mask, as specified by `WAVEFORMATEXTENSIBLE`.
+pub(crate) fn config_to_waveformatextensible(
+    samplerate: f64,
+    channels: usize,
+) -> Audio::WAVEFORMATEXTENSIBLE {
     let format_tag = KernelStreaming::WAVE_FORMAT_EXTENSIBLE;
-    let channels = config.channels as u16;
-    let sample_rate = config.samplerate as u32;
+    let channels = channels as u16;
+    let sample_rate = samplerate as u32;
     let sample_bytes = size_of::<f32>() as u16;
     let avg_bytes_per_sec = u32::from(channels) * sample_rate * u32::from(sample_bytes);
     let block_align = channels * sample_bytes;
@@ -463,7 +929,7 @@ pub(crate) fn config_to_waveformatextensible(config: &StreamConfig) -> Audio::WA
         cbSize: cb_size,
     };
 
-    let channel_mask = KernelStreaming::KSAUDIO_SPEAKER_DIRECTOUT;
+    let channel_mask = channel_mask_for_count(channels as usize);
 
     let sub_format = Multimedia::KSDATAFORMAT_SUBTYPE_IEEE_FLOAT;
 
@@ -479,42 +945,129 @@ pub(crate) fn config_to_waveformatextensible(config: &StreamConfig) -> Audio::WA
     waveformatextensible
 }
 
+/// Standard speaker mask for the given number of channels, following the default Windows speaker
+/// configurations. Channel counts without a standard layout are opened without speaker
+/// assignment (`KSAUDIO_SPEAKER_DIRECTOUT`).
+pub(crate) fn channel_mask_for_count(channels: usize) -> u32 {
+    use KernelStreaming::*;
+    const STEREO: u32 = SPEAKER_FRONT_LEFT | SPEAKER_FRONT_RIGHT;
+    const QUAD: u32 = STEREO | SPEAKER_BACK_LEFT | SPEAKER_BACK_RIGHT;
+    const SURROUND_5_0: u32 =
+        STEREO | SPEAKER_FRONT_CENTER | SPEAKER_SIDE_LEFT | SPEAKER_SIDE_RIGHT;
+    match channels {
+        1 => KSAUDIO_SPEAKER_MONO,
+        2 => STEREO,
+        3 => STEREO | SPEAKER_FRONT_CENTER,
+        4 => QUAD,
+        5 => SURROUND_5_0,
+        6 => SURROUND_5_0 | SPEAKER_LOW_FREQUENCY,
+        7 => SURROUND_5_0 | SPEAKER_LOW_FREQUENCY | SPEAKER_BACK_CENTER,
+        8 => SURROUND_5_0 | SPEAKER_LOW_FREQUENCY | SPEAKER_BACK_LEFT | SPEAKER_BACK_RIGHT,
+        _ => KSAUDIO_SPEAKER_DIRECTOUT,
+    }
+}
+
+/// Speaker mask of a format, when it is given as a `WAVEFORMATEXTENSIBLE` structure.
+pub(crate) unsafe fn channel_mask(format: *const Audio::WAVEFORMATEX) -> Option<u32> {
+    let header = format.read_unaligned();
+    let extensible_size =
+        size_of::<Audio::WAVEFORMATEXTENSIBLE>() - size_of::<Audio::WAVEFORMATEX>();
+    (u32::from(header.wFormatTag) == KernelStreaming::WAVE_FORMAT_EXTENSIBLE
+        && header.cbSize as usize >= extensible_size)
+        .then(|| {
+            format
+                .cast::<Audio::WAVEFORMATEXTENSIBLE>()
+                .read_unaligned()
+                .dwChannelMask
+        })
+}
+
+/// Channels of the configuration opened by a stream of the given type.
+fn stream_channels(config: &mut StreamConfig, device_type: DeviceType) -> &mut ChannelMap32 {
+    match device_type {
+        DeviceType::Input => &mut config.input_channels,
+        _ => &mut config.output_channels,
+    }
+}
+
+/// Validates the requested configuration against the device, and returns the format to open the
+/// stream with.
+///
+/// In shared mode, the audio engine may propose a closest match, in which case the stream
+/// configuration is updated to reflect the sample rate and channels the stream will actually be
+/// opened with. Samples are always exchanged as 32-bit floats, and requesting any other sample
+/// format fails.
+unsafe fn negotiate_format(
+    audio_client: &Audio::IAudioClient,
+    sharemode: Audio::AUDCLNT_SHAREMODE,
+    stream_config: &mut StreamConfig,
+    device_type: DeviceType,
+) -> Result<Audio::WAVEFORMATEXTENSIBLE, error::WasapiError> {
+    if stream_config
+        .sample_format
+        .is_some_and(|format| format != SampleFormat::F32)
+    {
+        return Err(error::WasapiError::ConfigurationNotAvailable);
+    }
+    stream_config.sample_format = Some(SampleFormat::F32);
+    let channels = stream_channels(stream_config, device_type).count();
+    let format = config_to_waveformatextensible(stream_config.samplerate, channels);
+    let mut closest_match = ptr::null_mut();
+    let result = audio_client.IsFormatSupported(
+        sharemode,
+        &format.Format,
+        (!stream_config.exclusive).then_some(&mut closest_match),
+    );
+    if result == Audio::AUDCLNT_E_UNSUPPORTED_FORMAT {
+        return Err(error::WasapiError::ConfigurationNotAvailable);
+    }
+    result.ok()?;
+    // A null closest match means the requested format is supported as-is
+    if closest_match.is_null() {
+        return Ok(format);
+    }
+
+    let closest = closest_match.read_unaligned();
+    let closest_mask = channel_mask(closest_match);
+    CoTaskMemFree(closest_match.cast());
+
+    *stream_channels(stream_config, device_type) = ChannelMap32::first_n(closest.nChannels as _);
+    stream_config.samplerate = closest.nSamplesPerSec as _;
+    let mut format =
+        config_to_waveformatextensible(stream_config.samplerate, closest.nChannels as _);
+    // Keep the device's speaker assignment when it describes the proposed channels
+    if let Some(mask) = closest_mask.filter(|mask| mask.count_ones() == closest.nChannels as u32) {
+        format.dwChannelMask = mask;
+    }
+    Ok(format)
+}
+
 pub(crate) fn is_output_config_supported(
     device: WasapiMMDevice,
     stream_config: &StreamConfig,
 ) -> bool {
-    let mut try_ = || unsafe {
+    let try_ = || unsafe {
         let audio_client: Audio::IAudioClient = device.activate()?;
         let sharemode = if stream_config.exclusive {
             Audio::AUDCLNT_SHAREMODE_EXCLUSIVE
         } else {
             Audio::AUDCLNT_SHAREMODE_SHARED
         };
-        let mut format = config_to_waveformatextensible(&stream_config);
-        let mut actual_format = ptr::null_mut();
-        audio_client
-            .IsFormatSupported(
-                sharemode,
-                &format.Format,
-                (!stream_config.exclusive).then_some(&mut actual_format),
-            )
-            .ok()?;
-        if !stream_config.exclusive {
-            assert!(!actual_format.is_null());
-            format.Format = actual_format.read_unaligned();
-            CoTaskMemFree(actual_format.cast());
-            let sample_rate = format.Format.nSamplesPerSec;
-            let new_channels = 0u32.with_indices(0..format.Format.nChannels as _);
-            let new_samplerate = sample_rate as f64;
-            if stream_config.samplerate != new_samplerate
-                || stream_config.channels.count() != new_channels.count()
-            {
-                return Ok(false);
-            }
-        }
-        Ok::<_, error::WasapiError>(true)
+        let device_channels = device_channel_count(stream_config.output_channels);
+        let mut negotiated = *stream_config;
+        negotiated.output_channels = ChannelMap32::first_n(device_channels);
+        negotiate_format(&audio_client, sharemode, &mut negotiated, DeviceType::Output)?;
+        Ok::<_, error::WasapiError>(
+            stream_config.samplerate == negotiated.samplerate
+                && device_channels == negotiated.output_channels.count(),
+        )
     };
-    try_()
-        .inspect_err(|err| eprintln!("Error while checking configuration is valid: {err}"))
-        .unwrap_or(false)
+    match try_() {
+        Ok(supported) => supported,
+        Err(error::WasapiError::ConfigurationNotAvailable) => false,
+        Err(err) => {
+            diagnostics::warn!("Error while checking configuration is valid: {err}");
+            false
+        }
+    }
 }
@@ -1,5 +1,6 @@
 use crate::prelude::wasapi::error;
 use std::marker::PhantomData;
+use windows::core::imp::CoTaskMemFree;
 use windows::core::Interface;
 use windows::Win32::Foundation::RPC_E_CHANGED_MODE;
 use windows::Win32::Media::Audio;
@@ -81,6 +82,16 @@ impl WasapiMMDevice {
     pub(crate) fn name(&self) -> Option<String> {
         get_device_name(&self.0)
     }
+
+    /// Endpoint ID string of this device, which identifies it across the whole system.
+    pub(crate) fn id(&self) -> Option<String> {
+        unsafe {
+            let id = self.0.GetId().ok()?;
+            let result = id.to_string().ok();
+            CoTaskMemFree(id.0.cast());
+            result
+        }
+    }
 }
 
 fn get_device_name(device: &Audio::IMMDevice) -> Option<String> {
@@ -0,0 +1,249 @@
+//! Consumption of captured audio as an asynchronous [`Stream`].
+//!
+//! [`capture_stream`] creates an input callback along with a [`CaptureStream`], which yields the
+//! audio captured by the callback in chunks of a fixed size. The callback and stream are connected
+//! by a bounded queue; what happens when the stream is not consumed fast enough is configured with
+//! [`Backpressure`].
+//!
+//! ```no_run
+//! use futures::StreamExt;
+//! use interflow::channel_map::Bitset;
+//! use interflow::capture::{capture_stream, CaptureConfig};
+//! use interflow::prelude::*;
+//! let device = default_input_device();
+//! let config = device.default_input_config().unwrap();
+//! let (callback, mut audio) =
+//!     capture_stream(config.input_channels.count(), CaptureConfig::default());
+//! let stream = device.create_input_stream(config, callback).unwrap();
+//! futures::executor::block_on(async {
+//!     while let Some(buffer) = audio.next().await {
+//!         println!("Captured {} frames", buffer.num_samples());
+//!     }
+//! });
+//! ```
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures_core::Stream;
+
+use crate::audio_buffer::{AudioBuffer, AudioRef};
+use crate::{AudioCallbackContext, AudioInput, AudioInputCallback};
+
+/// What happens to captured audio when the queue of a [`CaptureStream`] is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Captured audio which does not fit in the queue is dropped, and counted in
+    /// [`CaptureStream::dropped_frames`].
+    #[default]
+    DropNewest,
+    /// The stream ends once the audio queued before the overflow has been consumed. This suits
+    /// consumers which cannot tolerate gaps, and would rather start over.
+    EndStream,
+}
+
+/// Configuration of a [`CaptureStream`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureConfig {
+    /// Number of frames in each buffer yielded by the stream.
+    pub chunk_frames: usize,
+    /// Maximum number of chunks queued between the callback and the stream.
+    pub capacity: usize,
+    /// Behavior when the queue is full.
+    pub backpressure: Backpressure,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            chunk_frames: 1024,
+            capacity: 16,
+            backpressure: Backpressure::DropNewest,
+        }
+    }
+}
+
+/// Create an input callback capturing audio with the given number of channels, and the stream
+/// yielding it.
+pub fn capture_stream(channels: usize, config: CaptureConfig) -> (CaptureCallback, CaptureStream) {
+    let channels = channels.max(1);
+    let chunk_len = config.chunk_frames.max(1) * channels;
+    let (producer, consumer) = rtrb::RingBuffer::new(chunk_len * config.capacity.max(1));
+    let shared = Arc::new(Shared::default());
+    let callback = CaptureCallback {
+        producer,
+        chunk_len,
+        backpressure: config.backpressure,
+        shared: shared.clone(),
+    };
+    let stream = CaptureStream {
+        consumer,
+        channels,
+        chunk_len,
+        shared,
+    };
+    (callback, stream)
+}
+
+/// Input callback feeding a [`CaptureStream`], created by [`capture_stream`].
+///
+/// The callback wakes the task consuming the stream whenever a chunk becomes available, which
+/// runs the waking code of the executor on the audio thread. The callback never blocks: it skips
+/// waking the task while the stream is registering it, as the stream then checks the queue again.
+pub struct CaptureCallback {
+    producer: rtrb::Producer<f32>,
+    chunk_len: usize,
+    backpressure: Backpressure,
+    shared: Arc<Shared>,
+}
+
+/// Asynchronous stream of captured audio, created by [`capture_stream`].
+///
+/// The stream ends when its callback is dropped, for instance when its audio stream is ejected,
+/// after yielding the audio still queued, the last buffer possibly being shorter than a chunk.
+pub struct CaptureStream {
+    consumer: rtrb::Consumer<f32>,
+    channels: usize,
+    chunk_len: usize,
+    shared: Arc<Shared>,
+}
+
+impl CaptureStream {
+    /// Number of channels of the yielded buffers.
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Number of captured frames dropped because the queue was full.
+    pub fn dropped_frames(&self) -> u64 {
+        self.shared.dropped_frames.load(Ordering::Relaxed)
+    }
+
+    /// Pop a buffer of `len` samples from the queue.
+    fn pop_buffer(&mut self, len: usize) -> Option<AudioBuffer<f32>> {
+        let chunk = self.consumer.read_chunk(len).ok()?;
+        let (first, second) = chunk.as_slices();
+        let mut samples = Vec::with_capacity(len);
+        samples.extend_from_slice(first);
+        samples.extend_from_slice(second);
+        chunk.commit_all();
+        AudioRef::from_interleaved(&samples, self.channels).map(|buffer| buffer.to_owned())
+    }
+
+    fn try_next(&mut self) -> Poll<Option<AudioBuffer<f32>>> {
+        // Checked before looking at the queue, so that audio queued right before the callback was
+        // dropped or overflowed is still yielded
+        let ended = self.shared.closed.load(Ordering::Acquire)
+            || self.shared.overflowed.load(Ordering::Acquire);
+        let available = self.consumer.slots();
+        if available >= self.chunk_len {
+            return Poll::Ready(self.pop_buffer(self.chunk_len));
+        }
+        if !ended {
+            return Poll::Pending;
+        }
+        let len = available - available % self.channels;
+        if len == 0 {
+            return Poll::Ready(None);
+        }
+        Poll::Ready(self.pop_buffer(len))
+    }
+}
+
+impl Stream for CaptureStream {
+    type Item = AudioBuffer<f32>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Poll::Ready(item) = self.try_next() {
+            return Poll::Ready(item);
+        }
+        self.shared.register(cx.waker());
+        // Audio captured while registering may not have woken this task
+        self.try_next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.consumer.slots() / self.chunk_len, None)
+    }
+}
+
+impl AudioInputCallback for CaptureCallback {
+    fn on_input_data(&mut self, _context: AudioCallbackContext, input: AudioInput<f32>) {
+        if self.shared.overflowed.load(Ordering::Relaxed) {
+            return;
+        }
+        let frames = input.buffer.num_samples();
+        let len = frames * input.buffer.num_channels();
+        let queued = self.producer.buffer().capacity() - self.producer.slots();
+        match self.producer.write_chunk_uninit(len) {
+            Ok(chunk) => {
+                chunk.fill_from_iter(input.buffer.as_interleaved().iter().copied());
+            }
+            Err(_) => {
+                match self.backpressure {
+                    Backpressure::DropNewest => {
+                        self.shared
+                            .dropped_frames
+                            .fetch_add(frames as u64, Ordering::Relaxed);
+                    }
+                    Backpressure::EndStream => {
+                        self.shared.overflowed.store(true, Ordering::Release);
+                        self.shared.try_wake();
+                    }
+                }
+                return;
+            }
+        }
+        // Only wake the task when a new chunk is complete
+        if queued / self.chunk_len != (queued + len) / self.chunk_len {
+            self.shared.try_wake();
+        }
+    }
+}
+
+impl Drop for CaptureCallback {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+        let waker = self
+            .shared
+            .waker
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// State shared between a capture callback and its stream.
+#[derive(Debug, Default)]
+struct Shared {
+    waker: Mutex<Option<Waker>>,
+    dropped_frames: AtomicU64,
+    overflowed: AtomicBool,
+    /// Whether the callback has been dropped
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn register(&self, waker: &Waker) {
+        let mut slot = self.waker.lock().unwrap_or_else(|err| err.into_inner());
+        match &mut *slot {
+            Some(current) if current.will_wake(waker) => {}
+            slot => *slot = Some(waker.clone()),
+        }
+    }
+
+    /// Wake the consuming task, unless it is being registered, in which case the stream checks
+    /// the queue again right after.
+    fn try_wake(&self) {
+        if let Ok(slot) = self.waker.try_lock() {
+            if let Some(waker) = &*slot {
+                waker.wake_by_ref();
+            }
+        }
+    }
+}
@@ -1,4 +1,9 @@
+use core::fmt;
+use core::ops::Range;
 use core::panic;
+use core::str::FromStr;
+
+use thiserror::Error;
 
 /// Trait for types which can represent bitsets.
 ///
@@ -25,6 +30,40 @@ pub trait Bitset: Sized {
         self.indices().into_iter().count()
     }
 
+    /// Sets the value for all indices in the given range. Implementations should panic when the
+    /// range is out of bounds.
+    fn set_range(&mut self, range: Range<usize>, value: bool) {
+        for index in range {
+            self.set_index(index, value);
+        }
+    }
+
+    /// Returns an iterator over the contiguous runs of indices set `true`, in increasing order.
+    ///
+    /// For example, a bitset with indices 0, 1, 4, 6 and 7 set gives the ranges `0..2`, `4..5`
+    /// and `6..8`.
+    fn iter_ranges(&self) -> impl Iterator<Item = Range<usize>> {
+        let mut indices = self.indices().into_iter().peekable();
+        core::iter::from_fn(move || {
+            let start = indices.next()?;
+            let mut end = start + 1;
+            while indices.next_if_eq(&end).is_some() {
+                end += 1;
+            }
+            Some(start..end)
+        })
+    }
+
+    /// Display the set indices with the compact syntax used by CLI tools and configuration
+    /// files, where channels are numbered from 1 and contiguous channels are given as ranges,
+    /// for example `1-2,5,7-8`.
+    ///
+    /// Channel maps can be parsed back from this syntax with [`parse_channel_map`], or with
+    /// [`ChannelMapDyn::from_str`].
+    fn display(&self) -> DisplayChannels<'_, Self> {
+        DisplayChannels(self)
+    }
+
     /// Builder-like method for setting a value at a specific index.
     fn with_index(&mut self, index: usize, value: bool) -> &mut Self {
         self.set_index(index, value);
@@ -66,6 +105,22 @@ impl Bitset for ty {
         }
     }
 
+    fn set_range(&mut self, range: Range<usize>, value: bool) {
+        if range.is_empty() {
+            return;
+        }
+        assert!(
+            range.end <= ty::BITS as usize,
+            "Range {range:?} out of bounds"
+        );
+        let mask = (ty::MAX >> (ty::BITS as usize - range.len())) << range.start;
+        if value {
+            *self |= mask;
+        } else {
+            *self &= !mask;
+        }
+    }
+
     fn count(&self) -> usize {
         self.count_ones() as _
     }
@@ -112,12 +167,336 @@ pub type ChannelMap64 = u64;
 /// Type alias for a bitset with a capacity of 128 slots.
 pub type ChannelMap128 = u128;
 
+/// Trait for bitsets which can be created empty, given the number of indices they need to hold.
+pub trait CreateBitset: Bitset {
+    /// Create an empty bitset able to hold at least `capacity` indices. Fixed-size bitsets ignore
+    /// the requested capacity.
+    fn with_capacity(capacity: usize) -> Self;
+
+    /// Create a bitset with all provided indices set.
+    fn from_indices(indices: impl IntoIterator<Item = usize>) -> Self {
+        Self::with_capacity(0).with_indices(indices)
+    }
+
+    /// Create a bitset with the first `n` indices set, which is how streams usually select their
+    /// channels.
+    fn first_n(n: usize) -> Self {
+        let mut bitset = Self::with_capacity(n);
+        bitset.set_range(0..n, true);
+        bitset
+    }
+}
+
+#[duplicate::duplicate_item(
+    ty;
+    [u8];
+    [u16];
+    [u32];
+    [u64];
+    [u128];
+)]
+impl CreateBitset for ty {
+    fn with_capacity(_capacity: usize) -> Self {
+        0
+    }
+}
+
+/// Heap-allocated bitset, for channel sets larger than fit in [`ChannelMap128`], such as the
+/// channels of MADI or Dante interfaces.
+///
+/// Streams still open at most 32 channels: configurations using this map are converted to a
+/// [`ChannelMap32`] to open streams, which fails when channels past the first 32 are selected.
+///
+/// Unlike fixed-size bitsets, setting an index past the current capacity grows the bitset
+/// instead of panicking.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ChannelMapDyn {
+    // Trailing blocks are never zero, so that equal sets compare equal
+    blocks: Vec<u64>,
+}
+
+impl ChannelMapDyn {
+    const BLOCK_BITS: usize = u64::BITS as usize;
+
+    /// Number of channels that can be given when parsing channel maps from strings. Channels
+    /// past this limit are rejected, so that untrusted input cannot make the channel map
+    /// allocate without bounds.
+    pub const MAX_PARSED_CHANNELS: usize = 65536;
+
+    /// Create an empty channel map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns true when no index is set.
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+impl Bitset for ChannelMapDyn {
+    fn capacity(&self) -> usize {
+        self.blocks.len() * Self::BLOCK_BITS
+    }
+
+    fn get_index(&self, index: usize) -> bool {
+        self.blocks
+            .get(index / Self::BLOCK_BITS)
+            .is_some_and(|block| block.get_index(index % Self::BLOCK_BITS))
+    }
+
+    fn set_index(&mut self, index: usize, value: bool) {
+        let block = index / Self::BLOCK_BITS;
+        if value && block >= self.blocks.len() {
+            self.blocks.resize(block + 1, 0);
+        }
+        if let Some(bits) = self.blocks.get_mut(block) {
+            bits.set_index(index % Self::BLOCK_BITS, value);
+        }
+        while self.blocks.last() == Some(&0) {
+            self.blocks.pop();
+        }
+    }
+
+    fn set_range(&mut self, range: Range<usize>, value: bool) {
+        if range.is_empty() {
+            return;
+        }
+        let end_block = (range.end - 1) / Self::BLOCK_BITS;
+        if value && end_block >= self.blocks.len() {
+            self.blocks.resize(end_block + 1, 0);
+        }
+        let start_block = range.start / Self::BLOCK_BITS;
+        let end_block = end_block.min(self.blocks.len().saturating_sub(1));
+        for block in start_block..=end_block {
+            // Bits of the range within this block
+            let first = range.start.max(block * Self::BLOCK_BITS) - block * Self::BLOCK_BITS;
+            let last = (range.end - block * Self::BLOCK_BITS).min(Self::BLOCK_BITS);
+            let mask = (u64::MAX >> (Self::BLOCK_BITS - (last - first))) << first;
+            let Some(bits) = self.blocks.get_mut(block) else {
+                break;
+            };
+            if value {
+                *bits |= mask;
+            } else {
+                *bits &= !mask;
+            }
+        }
+        while self.blocks.last() == Some(&0) {
+            self.blocks.pop();
+        }
+    }
+
+    fn indices(&self) -> impl IntoIterator<Item = usize> {
+        self.blocks.iter().enumerate().flat_map(|(i, block)| {
+            block
+                .indices()
+                .into_iter()
+                .map(move |index| i * Self::BLOCK_BITS + index)
+        })
+    }
+
+    fn count(&self) -> usize {
+        self.blocks
+            .iter()
+            .map(|block| block.count_ones() as usize)
+            .sum()
+    }
+}
+
+impl CreateBitset for ChannelMapDyn {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            blocks: Vec::with_capacity(capacity.div_ceil(Self::BLOCK_BITS)),
+        }
+    }
+}
+
+/// Error returned when a channel map does not fit in a fixed-size channel map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("Channel {0} does not fit in the channel map")]
+pub struct ChannelOutOfRange(pub usize);
+
+#[duplicate::duplicate_item(
+    ty;
+    [u8];
+    [u16];
+    [u32];
+    [u64];
+    [u128];
+)]
+impl From<ty> for ChannelMapDyn {
+    fn from(value: ty) -> Self {
+        Self::from_indices(value.indices())
+    }
+}
+
+#[duplicate::duplicate_item(
+    ty;
+    [u8];
+    [u16];
+    [u32];
+    [u64];
+    [u128];
+)]
+impl TryFrom<&ChannelMapDyn> for ty {
+    type Error = ChannelOutOfRange;
+
+    fn try_from(value: &ChannelMapDyn) -> Result<Self, Self::Error> {
+        let mut bitset: ty = 0;
+        for index in value.indices() {
+            if index >= ty::BITS as usize {
+                return Err(ChannelOutOfRange(index));
+            }
+            bitset.set_index(index, true);
+        }
+        Ok(bitset)
+    }
+}
+
+/// Displays the indices of a bitset as a list of channels. See [`Bitset::display`].
+#[derive(Debug, Clone, Copy)]
+pub struct DisplayChannels<'a, B>(&'a B);
+
+impl<B: Bitset> fmt::Display for DisplayChannels<'_, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, range) in self.0.iter_ranges().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            if range.len() == 1 {
+                write!(f, "{}", range.end)?;
+            } else {
+                write!(f, "{}-{}", range.start + 1, range.end)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ChannelMapDyn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.display().fmt(f)
+    }
+}
+
+/// Error returned when parsing a channel map from a string fails.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ParseChannelMapError {
+    /// The channel is not a number, or is 0 (channels are numbered from 1).
+    #[error("Invalid channel {0:?}")]
+    InvalidChannel(String),
+    /// The range ends before it starts.
+    #[error("Invalid channel range {0:?}")]
+    InvalidRange(String),
+    /// The channel does not fit in the channel map.
+    #[error(transparent)]
+    OutOfRange(#[from] ChannelOutOfRange),
+}
+
+/// Parses channel maps from the syntax given by [`Bitset::display`], for example `1-2,5,7-8`.
+/// Whitespace around channels is ignored, and an empty string gives an empty channel map.
+/// Channels past [`ChannelMapDyn::MAX_PARSED_CHANNELS`] are rejected.
+impl FromStr for ChannelMapDyn {
+    type Err = ParseChannelMapError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bitset = Self::new();
+        if s.trim().is_empty() {
+            return Ok(bitset);
+        }
+        for item in s.split(',') {
+            let range = match item.split_once('-') {
+                Some((start, end)) => parse_channel(start)?..parse_channel(end)? + 1,
+                None => {
+                    let index = parse_channel(item)?;
+                    index..index + 1
+                }
+            };
+            if range.is_empty() {
+                return Err(ParseChannelMapError::InvalidRange(item.trim().to_string()));
+            }
+            if range.end > Self::MAX_PARSED_CHANNELS {
+                return Err(ChannelOutOfRange(range.end - 1).into());
+            }
+            bitset.set_range(range, true);
+        }
+        Ok(bitset)
+    }
+}
+
+/// Index of a channel numbered from 1.
+fn parse_channel(channel: &str) -> Result<usize, ParseChannelMapError> {
+    let channel = channel.trim();
+    match channel.parse::<usize>() {
+        // Bounded so that ranges ending at this channel do not overflow
+        Ok(number) if number > 0 && number < usize::MAX => Ok(number - 1),
+        _ => Err(ParseChannelMapError::InvalidChannel(channel.to_string())),
+    }
+}
+
+/// Parses a fixed-size channel map from the syntax given by [`Bitset::display`]. See
+/// [`ChannelMapDyn::from_str`].
+pub fn parse_channel_map<T>(s: &str) -> Result<T, ParseChannelMapError>
+where
+    T: for<'a> TryFrom<&'a ChannelMapDyn, Error = ChannelOutOfRange>,
+{
+    Ok(T::try_from(&s.parse::<ChannelMapDyn>()?)?)
+}
+
+/// Number of channels to open on the device so that all requested channels are available.
+#[cfg(any(os_alsa, os_wasapi))]
+pub(crate) fn device_channel_count(channels: ChannelMap32) -> usize {
+    channels.iter_ranges().last().map_or(0, |range| range.end)
+}
+
+/// Requested channels, when they differ from the channels opened on the device and have to be
+/// picked out of the device buffers.
+#[cfg(any(os_alsa, os_wasapi))]
+pub(crate) fn channel_selection(channels: ChannelMap32, num_channels: usize) -> Option<Vec<usize>> {
+    let selected = channels.indices().into_iter().collect::<Vec<_>>();
+    (selected.len() != num_channels).then_some(selected)
+}
+
+/// Copies the selected channels out of interleaved device samples.
+#[cfg(any(os_alsa, os_wasapi))]
+pub(crate) fn select_channels(
+    device: &[f32],
+    num_channels: usize,
+    selected: &[usize],
+    output: &mut [f32],
+) {
+    let frames = device.chunks_exact(num_channels);
+    for (frame, out) in frames.zip(output.chunks_exact_mut(selected.len())) {
+        for (sample, &channel) in out.iter_mut().zip(selected) {
+            *sample = frame[channel];
+        }
+    }
+}
+
+/// Writes the selected channels into interleaved device samples, silencing the other channels.
+#[cfg(any(os_alsa, os_wasapi))]
+pub(crate) fn scatter_channels(
+    input: &[f32],
+    selected: &[usize],
+    num_channels: usize,
+    device: &mut [f32],
+) {
+    device.fill(0.0);
+    let frames = device.chunks_exact_mut(num_channels);
+    for (frame, samples) in frames.zip(input.chunks_exact(selected.len())) {
+        for (&sample, &channel) in samples.iter().zip(selected) {
+            frame[channel] = sample;
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::collections::HashSet;
     use std::hash::RandomState;
 
-    use crate::channel_map::Bitset;
+    use crate::channel_map::{parse_channel_map, Bitset, ChannelMapDyn, CreateBitset};
 
     #[test]
     fn test_getset_index() {
@@ -163,4 +542,66 @@ mod test {
         let result = HashSet::<_, RandomState>::from_iter(bitrate.indices());
         assert_eq!(HashSet::from_iter([0, 2, 5, 12, 14, 16]), result);
     }
+
+    #[test]
+    fn test_dyn_getset() {
+        let mut bitset = ChannelMapDyn::from_indices([3, 70, 200]);
+        assert_eq!(3, bitset.count());
+        assert!(bitset.get_index(200));
+        assert!(!bitset.get_index(1000));
+        assert_eq!(vec![3, 70, 200], Vec::from_iter(bitset.indices()));
+
+        bitset.set_index(200, false);
+        assert_eq!(ChannelMapDyn::from_indices([3, 70]), bitset);
+        assert!(u32::try_from(&bitset).is_err());
+        assert_eq!(Ok(1 << 3 | 1 << 70), u128::try_from(&bitset));
+
+        let mut bitset = ChannelMapDyn::new();
+        bitset.set_range(60..200, true);
+        assert_eq!(ChannelMapDyn::from_indices(60..200), bitset);
+        bitset.set_range(64..200, false);
+        assert_eq!(ChannelMapDyn::from_indices(60..64), bitset);
+        bitset.set_range(0..64, true);
+        assert_eq!(ChannelMapDyn::from_indices(0..64), bitset);
+        bitset.set_range(300..400, false);
+        assert_eq!(1, bitset.blocks.len());
+    }
+
+    #[test]
+    fn test_ranges() {
+        let mut bitset = u16::first_n(3);
+        assert_eq!(0b111, bitset);
+        bitset.set_range(5..9, true);
+        bitset.set_range(1..2, false);
+        assert_eq!(0b1_1110_0101, bitset);
+        assert_eq!(vec![0..1, 2..3, 5..9], Vec::from_iter(bitset.iter_ranges()));
+
+        let bitset = ChannelMapDyn::first_n(70);
+        assert_eq!(70, bitset.count());
+        assert_eq!(vec![0..70], Vec::from_iter(bitset.iter_ranges()));
+        assert_eq!(u8::MAX, u8::first_n(8));
+    }
+
+    #[test]
+    fn test_parse_display() {
+        let bitset = 0b1101_0011u32;
+        assert_eq!("1-2,5,7-8", bitset.display().to_string());
+        assert_eq!(Ok(bitset), parse_channel_map::<u32>("1-2, 5,7-8"));
+        assert_eq!(Ok(0), parse_channel_map::<u8>(""));
+
+        let bitset = ChannelMapDyn::from_indices([0, 99, 100]);
+        assert_eq!("1,100-101", bitset.to_string());
+        assert_eq!(Ok(bitset), "1,100-101".parse());
+
+        assert!(parse_channel_map::<u8>("0").is_err());
+        assert!(parse_channel_map::<u8>("3-1").is_err());
+        assert!(parse_channel_map::<u8>("9").is_err());
+        assert!(parse_channel_map::<u8>("1,,2").is_err());
+        assert!(parse_channel_map::<u8>("1-4000000000").is_err());
+        assert!("18446744073709551615".parse::<ChannelMapDyn>().is_err());
+        let limit = ChannelMapDyn::MAX_PARSED_CHANNELS;
+        let all = format!("1-{limit}").parse::<ChannelMapDyn>().unwrap();
+        assert_eq!(limit, all.count());
+        assert!(format!("1-{}", limit + 1).parse::<ChannelMapDyn>().is_err());
+    }
 }
@@ -0,0 +1,160 @@
+//! Monotonic clock shared by all audio streams of the process.
+//!
+//! Streams report the times at which buffers are captured and played as [`HostTime`] values,
+//! which all come from the same clock regardless of the device or driver producing them. These
+//! times can be compared between streams, and against [`HostTime::now`].
+//!
+//! The clock is the one the audio APIs of the platform report their times with:
+//!
+//! - ALSA: `CLOCK_MONOTONIC`
+//! - CoreAudio: the host time (`mach_absolute_time`)
+//! - WASAPI: `QueryPerformanceCounter`
+//!
+//! On other platforms, a monotonic clock started with the process is used.
+
+use std::ops;
+use std::time::Duration;
+
+/// Instant on the audio host clock, stored as the time elapsed since the clock origin.
+///
+/// ```rust
+/// use std::time::Duration;
+/// use interflow::clock::HostTime;
+/// let now = HostTime::now();
+/// let later = now + Duration::from_millis(10);
+/// assert!(later > now);
+/// assert_eq!(later.saturating_duration_since(now), Duration::from_millis(10));
+/// assert_eq!(now.saturating_duration_since(later), Duration::ZERO);
+/// ```
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HostTime(Duration);
+
+impl HostTime {
+    /// Create a host time from the time elapsed since the clock origin.
+    pub const fn from_duration(duration: Duration) -> Self {
+        Self(duration)
+    }
+
+    /// Time elapsed since the clock origin.
+    pub const fn as_duration(&self) -> Duration {
+        self.0
+    }
+
+    /// Current time of the host clock.
+    pub fn now() -> Self {
+        Self(source::now())
+    }
+
+    /// Time elapsed since this instant, or zero if this instant is in the future.
+    pub fn elapsed(&self) -> Duration {
+        Self::now().saturating_duration_since(*self)
+    }
+
+    /// Time elapsed from an earlier instant to this one, or `None` if `earlier` is later than
+    /// this instant.
+    pub fn checked_duration_since(&self, earlier: Self) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    /// Time elapsed from an earlier instant to this one, or zero if `earlier` is later than this
+    /// instant.
+    pub fn saturating_duration_since(&self, earlier: Self) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    /// Instant a given duration before this one, or `None` if it would be before the clock
+    /// origin.
+    pub fn checked_sub(&self, duration: Duration) -> Option<Self> {
+        self.0.checked_sub(duration).map(Self)
+    }
+
+    /// Instant a given duration before this one, clamped to the clock origin.
+    pub fn saturating_sub(&self, duration: Duration) -> Self {
+        Self(self.0.saturating_sub(duration))
+    }
+}
+
+/// Deadline of a callback called now to process buffers of the given number of frames.
+pub(crate) fn callback_deadline(samplerate: f64, frames: usize) -> HostTime {
+    HostTime::now() + Duration::from_secs_f64(frames as f64 / samplerate)
+}
+
+impl ops::Add<Duration> for HostTime {
+    type Output = Self;
+
+    fn add(self, rhs: Duration) -> Self {
+        Self(self.0 + rhs)
+    }
+}
+
+impl ops::AddAssign<Duration> for HostTime {
+    fn add_assign(&mut self, rhs: Duration) {
+        self.0 += rhs;
+    }
+}
+
+impl ops::Sub<Duration> for HostTime {
+    type Output = Self;
+
+    fn sub(self, rhs: Duration) -> Self {
+        Self(self.0 - rhs)
+    }
+}
+
+#[cfg(os_alsa)]
+mod source {
+    use std::time::Duration;
+
+    pub(super) fn now() -> Duration {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // Cannot fail with a valid clock ID and pointer
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+        Duration::new(ts.tv_sec as _, ts.tv_nsec as _)
+    }
+}
+
+#[cfg(os_coreaudio)]
+mod source {
+    use coreaudio::sys::{AudioConvertHostTimeToNanos, AudioGetCurrentHostTime};
+    use std::time::Duration;
+
+    pub(super) fn now() -> Duration {
+        let nanos = unsafe { AudioConvertHostTimeToNanos(AudioGetCurrentHostTime()) };
+        Duration::from_nanos(nanos)
+    }
+}
+
+#[cfg(os_wasapi)]
+mod source {
+    use std::sync::OnceLock;
+    use std::time::Duration;
+    use windows::Win32::System::Performance::{QueryPerformanceCounter, QueryPerformanceFrequency};
+
+    pub(super) fn now() -> Duration {
+        static FREQUENCY: OnceLock<u64> = OnceLock::new();
+        // Both calls cannot fail on Windows XP and later
+        let frequency = *FREQUENCY.get_or_init(|| {
+            let mut frequency = 0;
+            let _ = unsafe { QueryPerformanceFrequency(&mut frequency) };
+            frequency.max(1) as u64
+        });
+        let mut counter = 0;
+        let _ = unsafe { QueryPerformanceCounter(&mut counter) };
+        let nanos = counter as u128 * 1_000_000_000 / frequency as u128;
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
+#[cfg(unsupported)]
+mod source {
+    use std::sync::OnceLock;
+    use std::time::{Duration, Instant};
+
+    pub(super) fn now() -> Duration {
+        static ORIGIN: OnceLock<Instant> = OnceLock::new();
+        ORIGIN.get_or_init(Instant::now).elapsed()
+    }
+}
@@ -0,0 +1,571 @@
+//! Composable wrappers around callbacks.
+//!
+//! [`CallbackExt`] is implemented for all types, and provides methods wrapping callbacks to chain
+//! them, apply a gain, meter them, process their buffers with a closure, or bound the size of
+//! their buffers. The wrappers
+//! implement [`AudioInputCallback`], [`AudioOutputCallback`] and [`AudioDuplexCallback`]
+//! whenever the callbacks they wrap do.
+//!
+//! ```rust
+//! use interflow::combinators::CallbackExt;
+//! use interflow::signals::Sine;
+//! use interflow::AudioOutputCallback;
+//! fn assert_output_callback(_: impl AudioOutputCallback) {}
+//!
+//! let (tx, _rx) = rtrb::RingBuffer::new(64);
+//! let callback = Sine::new(440.)
+//!     .map_buffer(|mut buffer| buffer.change_amplitude(0.5))
+//!     .with_gain(-6.)
+//!     .with_meter(tx);
+//! assert_output_callback(callback);
+//! ```
+
+use std::time::Duration;
+
+use crate::audio_buffer::{AudioBuffer, AudioMut, AudioRef};
+use crate::channel_map::Bitset;
+use crate::duplex::AudioDuplexCallback;
+use crate::timestamp::Timestamp;
+use crate::{
+    AudioCallbackContext, AudioInput, AudioInputCallback, AudioOutput, AudioOutputCallback,
+    BufferSize,
+};
+
+/// Extension methods wrapping callbacks into combinators.
+pub trait CallbackExt: Sized {
+    /// Run `other` after this callback, on the same buffers. Output callbacks chained this way
+    /// process the audio written by the callbacks before them, as in an effects chain.
+    fn chain<Other>(self, other: Other) -> Chain<Self, Other> {
+        Chain {
+            first: self,
+            second: other,
+        }
+    }
+
+    /// Apply a gain, in decibels, to the output of this callback, or to the input given to it.
+    fn with_gain(self, gain: f32) -> WithGain<Self> {
+        WithGain {
+            callback: self,
+            amplitude: 10f32.powf(gain / 20.),
+            scratch: InputScratch::default(),
+        }
+    }
+
+    /// Send the levels of the output of this callback, or of the input given to it, through the
+    /// given queue. Readings are dropped when the queue is full.
+    fn with_meter(self, tx: rtrb::Producer<MeterReading>) -> WithMeter<Self> {
+        WithMeter { callback: self, tx }
+    }
+
+    /// Process the output of this callback, or the input given to it, with the given closure.
+    fn map_buffer<F: FnMut(AudioMut<f32>)>(self, f: F) -> MapBuffer<Self, F> {
+        MapBuffer {
+            callback: self,
+            f,
+            scratch: InputScratch::default(),
+        }
+    }
+
+    /// Split the buffers given by the driver into chunks of at most `max_size`, calling this
+    /// callback once per chunk. Each chunk comes with its own timestamps, as if the driver had
+    /// delivered it on its own.
+    ///
+    /// This bounds the amount of work done per call for callbacks which cannot handle large
+    /// buffers, for instance when drivers change their buffer size while running.
+    fn with_max_buffer_size(self, max_size: impl Into<BufferSize>) -> Chunked<Self> {
+        Chunked {
+            callback: self,
+            max_size: max_size.into(),
+        }
+    }
+}
+
+impl<T> CallbackExt for T {}
+
+/// Levels of one channel of a buffer, sent by [`CallbackExt::with_meter`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeterReading {
+    /// Timestamp of the buffer.
+    pub timestamp: Timestamp,
+    /// Index of the channel in the buffer.
+    pub channel: usize,
+    /// Peak linear amplitude of the channel over the buffer.
+    pub peak: f32,
+    /// RMS linear amplitude of the channel over the buffer.
+    pub rms: f32,
+}
+
+/// Two callbacks run one after the other, created by [`CallbackExt::chain`].
+#[derive(Debug, Clone)]
+pub struct Chain<First, Second> {
+    first: First,
+    second: Second,
+}
+
+impl<First, Second> Chain<First, Second> {
+    /// Return both chained callbacks.
+    pub fn into_inner(self) -> (First, Second) {
+        (self.first, self.second)
+    }
+}
+
+impl<First: AudioInputCallback, Second: AudioInputCallback> AudioInputCallback
+    for Chain<First, Second>
+{
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.first.prepare(context);
+        self.second.prepare(context);
+    }
+
+    fn on_input_data(&mut self, context: AudioCallbackContext, input: AudioInput<f32>) {
+        self.first.on_input_data(context, reborrow_input(&input));
+        self.second.on_input_data(context, input);
+    }
+}
+
+impl<First: AudioOutputCallback, Second: AudioOutputCallback> AudioOutputCallback
+    for Chain<First, Second>
+{
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.first.prepare(context);
+        self.second.prepare(context);
+    }
+
+    fn on_output_data(&mut self, context: AudioCallbackContext, mut output: AudioOutput<f32>) {
+        self.first
+            .on_output_data(context, reborrow_output(&mut output));
+        self.second.on_output_data(context, output);
+    }
+}
+
+impl<First: AudioDuplexCallback, Second: AudioDuplexCallback> AudioDuplexCallback
+    for Chain<First, Second>
+{
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.first.prepare(context);
+        self.second.prepare(context);
+    }
+
+    fn on_audio_data(
+        &mut self,
+        context: AudioCallbackContext,
+        input: AudioInput<f32>,
+        mut output: AudioOutput<f32>,
+    ) {
+        self.first.on_audio_data(
+            context,
+            reborrow_input(&input),
+            reborrow_output(&mut output),
+        );
+        self.second.on_audio_data(context, input, output);
+    }
+}
+
+/// Callback with a gain applied, created by [`CallbackExt::with_gain`].
+pub struct WithGain<Callback> {
+    callback: Callback,
+    amplitude: f32,
+    scratch: InputScratch,
+}
+
+impl<Callback> WithGain<Callback> {
+    /// Change the gain, in decibels.
+    pub fn set_gain(&mut self, gain: f32) {
+        self.amplitude = 10f32.powf(gain / 20.);
+    }
+
+    /// Return the wrapped callback.
+    pub fn into_inner(self) -> Callback {
+        self.callback
+    }
+}
+
+impl<Callback: AudioInputCallback> AudioInputCallback for WithGain<Callback> {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.scratch.prepare(&context);
+        self.callback.prepare(context);
+    }
+
+    fn on_input_data(&mut self, context: AudioCallbackContext, input: AudioInput<f32>) {
+        let mut buffer = self.scratch.copy_from(input.buffer);
+        buffer.change_amplitude(self.amplitude);
+        let buffer = self.scratch.get(input.buffer.num_samples());
+        self.callback
+            .on_input_data(context, AudioInput { buffer, ..input });
+    }
+}
+
+impl<Callback: AudioOutputCallback> AudioOutputCallback for WithGain<Callback> {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.callback.prepare(context);
+    }
+
+    fn on_output_data(&mut self, context: AudioCallbackContext, mut output: AudioOutput<f32>) {
+        self.callback
+            .on_output_data(context, reborrow_output(&mut output));
+        output.buffer.change_amplitude(self.amplitude);
+    }
+}
+
+impl<Callback: AudioDuplexCallback> AudioDuplexCallback for WithGain<Callback> {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.callback.prepare(context);
+    }
+
+    /// The gain is applied to the output.
+    fn on_audio_data(
+        &mut self,
+        context: AudioCallbackContext,
+        input: AudioInput<f32>,
+        mut output: AudioOutput<f32>,
+    ) {
+        self.callback
+            .on_audio_data(context, input, reborrow_output(&mut output));
+        output.buffer.change_amplitude(self.amplitude);
+    }
+}
+
+/// Metered callback, created by [`CallbackExt::with_meter`].
+pub struct WithMeter<Callback> {
+    callback: Callback,
+    tx: rtrb::Producer<MeterReading>,
+}
+
+impl<Callback> WithMeter<Callback> {
+    /// Return the wrapped callback.
+    pub fn into_inner(self) -> Callback {
+        self.callback
+    }
+
+    fn meter(&mut self, timestamp: Timestamp, buffer: AudioRef<f32>) {
+        for (channel, samples) in buffer.channels().enumerate() {
+            let mut peak = 0f32;
+            let mut sum = 0f32;
+            for sample in samples.iter() {
+                peak = peak.max(sample.abs());
+                sum += sample * sample;
+            }
+            let rms = (sum / samples.len().max(1) as f32).sqrt();
+            let _ = self.tx.push(MeterReading {
+                timestamp,
+                channel,
+                peak,
+                rms,
+            });
+        }
+    }
+}
+
+impl<Callback: AudioInputCallback> AudioInputCallback for WithMeter<Callback> {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.callback.prepare(context);
+    }
+
+    fn on_input_data(&mut self, context: AudioCallbackContext, input: AudioInput<f32>) {
+        self.meter(input.timestamp, input.buffer);
+        self.callback.on_input_data(context, input);
+    }
+}
+
+impl<Callback: AudioOutputCallback> AudioOutputCallback for WithMeter<Callback> {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.callback.prepare(context);
+    }
+
+    fn on_output_data(&mut self, context: AudioCallbackContext, mut output: AudioOutput<f32>) {
+        self.callback
+            .on_output_data(context, reborrow_output(&mut output));
+        self.meter(output.timestamp, output.buffer.as_ref());
+    }
+}
+
+impl<Callback: AudioDuplexCallback> AudioDuplexCallback for WithMeter<Callback> {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.callback.prepare(context);
+    }
+
+    /// The output is metered.
+    fn on_audio_data(
+        &mut self,
+        context: AudioCallbackContext,
+        input: AudioInput<f32>,
+        mut output: AudioOutput<f32>,
+    ) {
+        self.callback
+            .on_audio_data(context, input, reborrow_output(&mut output));
+        self.meter(output.timestamp, output.buffer.as_ref());
+    }
+}
+
+/// Callback whose buffers are processed by a closure, created by [`CallbackExt::map_buffer`].
+pub struct MapBuffer<Callback, F> {
+    callback: Callback,
+    f: F,
+    scratch: InputScratch,
+}
+
+impl<Callback, F> MapBuffer<Callback, F> {
+    /// Return the wrapped callback.
+    pub fn into_inner(self) -> Callback {
+        self.callback
+    }
+}
+
+impl<Callback: AudioInputCallback, F: FnMut(AudioMut<f32>)> AudioInputCallback
+    for MapBuffer<Callback, F>
+{
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.scratch.prepare(&context);
+        self.callback.prepare(context);
+    }
+
+    fn on_input_data(&mut self, context: AudioCallbackContext, input: AudioInput<f32>) {
+        (self.f)(self.scratch.copy_from(input.buffer));
+        let buffer = self.scratch.get(input.buffer.num_samples());
+        self.callback
+            .on_input_data(context, AudioInput { buffer, ..input });
+    }
+}
+
+impl<Callback: AudioOutputCallback, F: FnMut(AudioMut<f32>)> AudioOutputCallback
+    for MapBuffer<Callback, F>
+{
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.callback.prepare(context);
+    }
+
+    fn on_output_data(&mut self, context: AudioCallbackContext, mut output: AudioOutput<f32>) {
+        self.callback
+            .on_output_data(context, reborrow_output(&mut output));
+        (self.f)(output.buffer);
+    }
+}
+
+impl<Callback: AudioDuplexCallback, F: 'static + Send + FnMut(AudioMut<f32>)> AudioDuplexCallback
+    for MapBuffer<Callback, F>
+{
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.callback.prepare(context);
+    }
+
+    /// The closure processes the output.
+    fn on_audio_data(
+        &mut self,
+        context: AudioCallbackContext,
+        input: AudioInput<f32>,
+        mut output: AudioOutput<f32>,
+    ) {
+        self.callback
+            .on_audio_data(context, input, reborrow_output(&mut output));
+        (self.f)(output.buffer);
+    }
+}
+
+/// Callback called on chunks of bounded size, created by [`CallbackExt::with_max_buffer_size`].
+pub struct Chunked<Callback> {
+    callback: Callback,
+    max_size: BufferSize,
+}
+
+impl<Callback> Chunked<Callback> {
+    /// Return the wrapped callback.
+    pub fn into_inner(self) -> Callback {
+        self.callback
+    }
+
+    fn max_frames(&self, context: &AudioCallbackContext) -> usize {
+        self.max_size
+            .frames(context.stream_config.samplerate)
+            .max(1)
+    }
+
+    /// Context given to the wrapped callback, whose buffer size range is bounded by the chunk
+    /// size.
+    fn bounded_context(&self, mut context: AudioCallbackContext) -> AudioCallbackContext {
+        let samplerate = context.stream_config.samplerate;
+        let max_frames = self.max_frames(&context);
+        let bound = |size: BufferSize| BufferSize::Frames(size.frames(samplerate).min(max_frames));
+        let (min, max) = context.stream_config.buffer_size_range;
+        context.stream_config.buffer_size_range = (
+            min.map(bound),
+            Some(max.map_or(BufferSize::Frames(max_frames), bound)),
+        );
+        context
+    }
+
+    /// Ranges of the chunks of a buffer of `frames` frames.
+    fn chunks(
+        &self,
+        context: &AudioCallbackContext,
+        frames: usize,
+    ) -> impl Iterator<Item = (usize, usize)> {
+        let max_frames = self.max_frames(context);
+        (0..frames)
+            .step_by(max_frames)
+            .map(move |start| (start, (start + max_frames).min(frames)))
+    }
+}
+
+/// Context of the chunk starting `start` frames into the buffer. `output` tells whether queued
+/// frames are counted on the output side, where the previous chunks have been queued.
+fn chunk_context(
+    context: AudioCallbackContext,
+    start: usize,
+    previous_frames: usize,
+    output: bool,
+) -> AudioCallbackContext {
+    if start == 0 {
+        return context;
+    }
+    let offset = frames_duration(&context, start);
+    AudioCallbackContext {
+        timestamp: context.timestamp + start as u64,
+        output_time: context.output_time.map(|time| time + offset),
+        queued_frames: match output {
+            true => context.queued_frames.map(|queued| queued + start),
+            false => context.queued_frames,
+        },
+        frames_since_last_callback: Some(previous_frames as u64),
+        discontinuity: false,
+        ..context
+    }
+}
+
+fn frames_duration(context: &AudioCallbackContext, frames: usize) -> Duration {
+    Duration::from_secs_f64(frames as f64 / context.stream_config.samplerate)
+}
+
+impl<Callback: AudioInputCallback> AudioInputCallback for Chunked<Callback> {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        let context = self.bounded_context(context);
+        self.callback.prepare(context);
+    }
+
+    fn on_input_data(&mut self, context: AudioCallbackContext, input: AudioInput<f32>) {
+        let context = self.bounded_context(context);
+        let mut previous_frames = 0;
+        for (start, end) in self.chunks(&context, input.buffer.num_samples()) {
+            let input = AudioInput {
+                timestamp: input.timestamp + start as u64,
+                capture_time: input
+                    .capture_time
+                    .map(|time| time + frames_duration(&context, start)),
+                buffer: input.buffer.slice(start..end),
+            };
+            let context = chunk_context(context, start, previous_frames, false);
+            self.callback.on_input_data(context, input);
+            previous_frames = end - start;
+        }
+    }
+}
+
+impl<Callback: AudioOutputCallback> AudioOutputCallback for Chunked<Callback> {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        let context = self.bounded_context(context);
+        self.callback.prepare(context);
+    }
+
+    fn on_output_data(&mut self, context: AudioCallbackContext, mut output: AudioOutput<f32>) {
+        let context = self.bounded_context(context);
+        let mut previous_frames = 0;
+        for (start, end) in self.chunks(&context, output.buffer.num_samples()) {
+            let output = AudioOutput {
+                timestamp: output.timestamp + start as u64,
+                buffer: output.buffer.slice_mut(start..end),
+            };
+            let context = chunk_context(context, start, previous_frames, true);
+            self.callback.on_output_data(context, output);
+            previous_frames = end - start;
+        }
+    }
+}
+
+impl<Callback: AudioDuplexCallback> AudioDuplexCallback for Chunked<Callback> {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        let context = self.bounded_context(context);
+        self.callback.prepare(context);
+    }
+
+    /// Input and output buffers are split at the same frames.
+    fn on_audio_data(
+        &mut self,
+        context: AudioCallbackContext,
+        input: AudioInput<f32>,
+        mut output: AudioOutput<f32>,
+    ) {
+        let context = self.bounded_context(context);
+        let input_frames = input.buffer.num_samples();
+        let mut previous_frames = 0;
+        for (start, end) in self.chunks(&context, output.buffer.num_samples()) {
+            let input = AudioInput {
+                timestamp: input.timestamp + start as u64,
+                capture_time: input
+                    .capture_time
+                    .map(|time| time + frames_duration(&context, start)),
+                buffer: input
+                    .buffer
+                    .slice(start.min(input_frames)..end.min(input_frames)),
+            };
+            let output = AudioOutput {
+                timestamp: output.timestamp + start as u64,
+                buffer: output.buffer.slice_mut(start..end),
+            };
+            let context = chunk_context(context, start, previous_frames, true);
+            self.callback.on_audio_data(context, input, output);
+            previous_frames = end - start;
+        }
+    }
+}
+
+/// Copy of the input buffers, for wrappers which process the input before passing it on.
+///
+/// The copy is allocated when preparing the callback, for the largest buffers of the stream. It
+/// is only reallocated on the audio thread when the driver gives larger buffers than announced.
+#[derive(Default)]
+struct InputScratch {
+    buffer: AudioBuffer<f32>,
+}
+
+impl InputScratch {
+    /// Number of frames to allocate for when the stream does not tell its maximum buffer size.
+    const DEFAULT_FRAMES: usize = 4096;
+
+    fn prepare(&mut self, context: &AudioCallbackContext) {
+        let config = &context.stream_config;
+        let (min, max) = config.buffer_size_range;
+        let frames = max
+            .or(min)
+            .map_or(Self::DEFAULT_FRAMES, |size| size.frames(config.samplerate));
+        self.buffer = AudioBuffer::zeroed(config.input_channels.count(), frames);
+    }
+
+    fn copy_from(&mut self, input: AudioRef<f32>) -> AudioMut<'_, f32> {
+        let frames = input.num_samples();
+        if self.buffer.num_channels() != input.num_channels() || self.buffer.num_samples() < frames
+        {
+            self.buffer = AudioBuffer::zeroed(input.num_channels(), frames);
+        }
+        let mut buffer = self.buffer.slice_mut(..frames);
+        buffer.as_interleaved_mut().assign(&input.as_interleaved());
+        buffer
+    }
+
+    fn get(&self, frames: usize) -> AudioRef<'_, f32> {
+        self.buffer.slice(..frames)
+    }
+}
+
+fn reborrow_input<'a>(input: &AudioInput<'a, f32>) -> AudioInput<'a, f32> {
+    AudioInput {
+        timestamp: input.timestamp,
+        capture_time: input.capture_time,
+        buffer: input.buffer,
+    }
+}
+
+fn reborrow_output<'a>(output: &'a mut AudioOutput<f32>) -> AudioOutput<'a, f32> {
+    AudioOutput {
+        timestamp: output.timestamp,
+        buffer: output.buffer.as_mut(),
+    }
+}
@@ -0,0 +1,488 @@
+//! API shaped after [cpal](https://docs.rs/cpal), on top of the default driver of the platform.
+//!
+//! Code written against cpal can switch to this module with few changes, and then move to the
+//! interflow API at its own pace; [`Device::as_inner`] gives access to the underlying interflow
+//! device. Only `f32` samples are supported, and all operations share the error type of the
+//! driver, [`Error`].
+//!
+//! ```no_run
+//! use interflow::compat::cpal;
+//! let host = cpal::default_host();
+//! let device = host.default_output_device().expect("no output device available");
+//! let config = device.default_output_config().unwrap().config();
+//! let stream = device
+//!     .build_output_stream(
+//!         &config,
+//!         |data: &mut [f32], _: &cpal::OutputCallbackInfo| data.fill(0.),
+//!         |err| eprintln!("an error occurred on stream: {err}"),
+//!         None,
+//!     )
+//!     .unwrap();
+//! stream.play().unwrap();
+//! ```
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use thiserror::Error;
+
+#[cfg(os_alsa)]
+use crate::backends::alsa::AlsaDriver as Driver;
+#[cfg(os_coreaudio)]
+use crate::backends::coreaudio::CoreAudioDriver as Driver;
+#[cfg(os_wasapi)]
+use crate::backends::wasapi::WasapiDriver as Driver;
+use crate::channel_map::{Bitset, CreateBitset};
+use crate::clock::HostTime;
+use crate::diagnostics;
+use crate::events::{StreamEvent, StreamEventReceiver};
+use crate::timestamp::Timestamp;
+use crate::{
+    AudioCallbackContext, AudioDevice, AudioDriver, AudioInput, AudioInputCallback,
+    AudioInputDevice, AudioOutput, AudioOutputCallback, AudioOutputDevice, AudioStreamHandle,
+    BufferSize as InterflowBufferSize, DeviceType, StreamConfig as InterflowStreamConfig,
+};
+
+type BackendDevice = <Driver as AudioDriver>::Device;
+
+/// Error of the driver of the platform, returned by all fallible operations of this module.
+pub type Error = <Driver as AudioDriver>::Error;
+
+/// Number of frames the scratch buffers of streams are allocated for, when the buffers given by
+/// the driver are not interleaved.
+const SCRATCH_FRAMES: usize = 4096;
+
+/// Interval at which the events of streams are checked for errors to report.
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Host giving access to the devices of the default driver of the platform.
+pub struct Host {
+    driver: Driver,
+}
+
+/// Return the host of the default driver of the platform.
+pub fn default_host() -> Host {
+    Host { driver: Driver }
+}
+
+impl Host {
+    /// Display name of the driver.
+    pub fn name(&self) -> &'static str {
+        Driver::DISPLAY_NAME
+    }
+
+    /// All devices of the host.
+    pub fn devices(&self) -> Result<impl Iterator<Item = Device> + '_, Error> {
+        Ok(self
+            .driver
+            .list_devices()?
+            .into_iter()
+            .map(|inner| Device { inner }))
+    }
+
+    /// Devices of the host which can capture audio.
+    pub fn input_devices(&self) -> Result<impl Iterator<Item = Device> + '_, Error> {
+        Ok(self
+            .devices()?
+            .filter(|device| device.inner.device_type() != DeviceType::Output))
+    }
+
+    /// Devices of the host which can play audio.
+    pub fn output_devices(&self) -> Result<impl Iterator<Item = Device> + '_, Error> {
+        Ok(self
+            .devices()?
+            .filter(|device| device.inner.device_type() != DeviceType::Input))
+    }
+
+    /// Default input device of the host, if there is one.
+    pub fn default_input_device(&self) -> Option<Device> {
+        self.default_device(DeviceType::Input)
+    }
+
+    /// Default output device of the host, if there is one.
+    pub fn default_output_device(&self) -> Option<Device> {
+        self.default_device(DeviceType::Output)
+    }
+
+    fn default_device(&self, device_type: DeviceType) -> Option<Device> {
+        self.driver
+            .default_device(device_type)
+            .inspect_err(|err| diagnostics::error!("Cannot get default device: {err}"))
+            .ok()
+            .flatten()
+            .map(|inner| Device { inner })
+    }
+}
+
+/// Audio device of the host.
+#[derive(Clone)]
+pub struct Device {
+    inner: BackendDevice,
+}
+
+impl Device {
+    /// Display name of the device.
+    pub fn name(&self) -> Result<String, Error> {
+        Ok(self.inner.name().into_owned())
+    }
+
+    /// Underlying interflow device.
+    pub fn as_inner(&self) -> &BackendDevice {
+        &self.inner
+    }
+
+    /// Return the underlying interflow device.
+    pub fn into_inner(self) -> BackendDevice {
+        self.inner
+    }
+
+    /// Default configuration of input streams on this device.
+    pub fn default_input_config(&self) -> Result<SupportedStreamConfig, Error> {
+        let config = self.inner.default_input_config()?;
+        Ok(SupportedStreamConfig::new(
+            config,
+            config.input_channels.count(),
+        ))
+    }
+
+    /// Default configuration of output streams on this device.
+    pub fn default_output_config(&self) -> Result<SupportedStreamConfig, Error> {
+        let config = self.inner.default_output_config()?;
+        Ok(SupportedStreamConfig::new(
+            config,
+            config.output_channels.count(),
+        ))
+    }
+
+    /// Open an input stream, calling `data_callback` with interleaved samples as they are
+    /// captured.
+    ///
+    /// `error_callback` is called, from another thread, with the errors which stop the stream,
+    /// such as the device disappearing, and with errors happening when the stream is stopped.
+    /// The timeout is ignored.
+    pub fn build_input_stream<D, E>(
+        &self,
+        config: &StreamConfig,
+        data_callback: D,
+        error_callback: E,
+        _timeout: Option<Duration>,
+    ) -> Result<Stream, Error>
+    where
+        D: FnMut(&[f32], &InputCallbackInfo) + Send + 'static,
+        E: FnMut(StreamError) + Send + 'static,
+    {
+        let mut stream_config = self.inner.default_input_config()?;
+        config.apply(&mut stream_config);
+        stream_config.input_channels = CreateBitset::first_n(config.channels as usize);
+        let paused = Arc::new(AtomicBool::new(false));
+        let callback = InputCallback {
+            data_callback,
+            paused: paused.clone(),
+            scratch: Vec::with_capacity(SCRATCH_FRAMES * config.channels as usize),
+        };
+        let handle = self.inner.create_input_stream(stream_config, callback)?;
+        Ok(Stream::new(handle, paused, error_callback))
+    }
+
+    /// Open an output stream, calling `data_callback` to fill interleaved buffers of samples to
+    /// play.
+    ///
+    /// `error_callback` is called, from another thread, with the errors which stop the stream,
+    /// such as the device disappearing, and with errors happening when the stream is stopped.
+    /// The timeout is ignored.
+    pub fn build_output_stream<D, E>(
+        &self,
+        config: &StreamConfig,
+        data_callback: D,
+        error_callback: E,
+        _timeout: Option<Duration>,
+    ) -> Result<Stream, Error>
+    where
+        D: FnMut(&mut [f32], &OutputCallbackInfo) + Send + 'static,
+        E: FnMut(StreamError) + Send + 'static,
+    {
+        let mut stream_config = self.inner.default_output_config()?;
+        config.apply(&mut stream_config);
+        stream_config.output_channels = CreateBitset::first_n(config.channels as usize);
+        let paused = Arc::new(AtomicBool::new(false));
+        let callback = OutputCallback {
+            data_callback,
+            paused: paused.clone(),
+            scratch: Vec::with_capacity(SCRATCH_FRAMES * config.channels as usize),
+        };
+        let handle = self.inner.create_output_stream(stream_config, callback)?;
+        Ok(Stream::new(handle, paused, error_callback))
+    }
+}
+
+/// Running stream, which stops when dropped.
+pub struct Stream {
+    paused: Arc<AtomicBool>,
+    stop: Option<Box<dyn FnOnce()>>,
+}
+
+impl Stream {
+    /// Wrap a stream handle, reporting the errors of the stream to the error callback.
+    fn new<Callback, Handle, E>(handle: Handle, paused: Arc<AtomicBool>, error_callback: E) -> Self
+    where
+        Handle: 'static + AudioStreamHandle<Callback>,
+        E: FnMut(StreamError) + Send + 'static,
+    {
+        let error_callback = Arc::new(Mutex::new(error_callback));
+        let stop_events = Arc::new(AtomicBool::new(false));
+        let forwarder = forward_errors(
+            handle.subscribe_events(),
+            error_callback.clone(),
+            stop_events.clone(),
+        );
+        Self {
+            paused,
+            stop: Some(Box::new(move || {
+                stop_events.store(true, Ordering::Relaxed);
+                // Errors which stopped the stream are also returned when ejecting it
+                let reported = forwarder.join().unwrap_or(false);
+                if let Err(err) = handle.eject() {
+                    if !reported {
+                        let mut error_callback =
+                            error_callback.lock().unwrap_or_else(|err| err.into_inner());
+                        error_callback(StreamError::BackendSpecific(err.to_string()));
+                    }
+                }
+            })),
+        }
+    }
+
+    /// Resume a paused stream. Streams start playing as soon as they are built.
+    pub fn play(&self) -> Result<(), Error> {
+        self.paused.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Pause the stream. The device keeps running, but input is discarded and silence is played
+    /// until the stream is resumed.
+    pub fn pause(&self) -> Result<(), Error> {
+        self.paused.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            stop();
+        }
+    }
+}
+
+/// Spawn a thread calling the error callback with the errors sent as events by the stream, until
+/// stopped. Returns whether an error was reported.
+fn forward_errors<E: FnMut(StreamError) + Send + 'static>(
+    mut events: StreamEventReceiver,
+    error_callback: Arc<Mutex<E>>,
+    stop: Arc<AtomicBool>,
+) -> JoinHandle<bool> {
+    std::thread::spawn(move || {
+        let mut reported = false;
+        while !stop.load(Ordering::Relaxed) && !events.is_disconnected() {
+            for event in events.try_iter() {
+                if let StreamEvent::Error(message) = event {
+                    let mut error_callback =
+                        error_callback.lock().unwrap_or_else(|err| err.into_inner());
+                    error_callback(StreamError::BackendSpecific(message.to_string()));
+                    reported = true;
+                }
+            }
+            std::thread::sleep(EVENT_POLL_INTERVAL);
+        }
+        reported
+    })
+}
+
+/// Error reported to the error callback of a stream.
+#[derive(Debug, Clone, Error)]
+pub enum StreamError {
+    /// Error of the driver, described by its message.
+    #[error("{0}")]
+    BackendSpecific(String),
+}
+
+/// Sample rate, in Hz.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SampleRate(pub u32);
+
+/// Buffer size requested for a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BufferSize {
+    /// Let the driver choose the buffer size.
+    #[default]
+    Default,
+    /// Request buffers of the given number of frames.
+    Fixed(u32),
+}
+
+/// Configuration of a stream, given when building it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamConfig {
+    /// Number of channels, opened from the first channel of the device.
+    pub channels: u16,
+    /// Sample rate of the stream.
+    pub sample_rate: SampleRate,
+    /// Requested buffer size.
+    pub buffer_size: BufferSize,
+}
+
+impl StreamConfig {
+    fn apply(&self, config: &mut InterflowStreamConfig) {
+        config.samplerate = self.sample_rate.0 as f64;
+        if let BufferSize::Fixed(frames) = self.buffer_size {
+            let size = Some(InterflowBufferSize::Frames(frames as usize));
+            config.buffer_size_range = (size, size);
+        }
+    }
+}
+
+/// Configuration supported by a device, from which a [`StreamConfig`] can be made.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SupportedStreamConfig {
+    channels: u16,
+    sample_rate: SampleRate,
+    inner: InterflowStreamConfig,
+}
+
+impl SupportedStreamConfig {
+    fn new(inner: InterflowStreamConfig, channels: usize) -> Self {
+        Self {
+            channels: channels as u16,
+            sample_rate: SampleRate(inner.samplerate as u32),
+            inner,
+        }
+    }
+
+    /// Number of channels.
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// Sample rate.
+    pub fn sample_rate(&self) -> SampleRate {
+        self.sample_rate
+    }
+
+    /// Stream configuration to build a stream with.
+    pub fn config(&self) -> StreamConfig {
+        StreamConfig {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            buffer_size: BufferSize::Default,
+        }
+    }
+
+    /// Underlying interflow stream configuration.
+    pub fn as_inner(&self) -> &InterflowStreamConfig {
+        &self.inner
+    }
+}
+
+impl From<SupportedStreamConfig> for StreamConfig {
+    fn from(config: SupportedStreamConfig) -> Self {
+        config.config()
+    }
+}
+
+/// Timing information given to input data callbacks.
+#[derive(Debug, Clone, Copy)]
+pub struct InputCallbackInfo {
+    timestamp: Timestamp,
+    capture_time: Option<HostTime>,
+}
+
+impl InputCallbackInfo {
+    /// Position of the first frame of the buffer in the stream.
+    pub fn timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
+
+    /// Time at which the first frame of the buffer was captured, if known.
+    pub fn capture_time(&self) -> Option<HostTime> {
+        self.capture_time
+    }
+}
+
+/// Timing information given to output data callbacks.
+#[derive(Debug, Clone, Copy)]
+pub struct OutputCallbackInfo {
+    timestamp: Timestamp,
+    playback_time: Option<HostTime>,
+}
+
+impl OutputCallbackInfo {
+    /// Position of the first frame of the buffer in the stream.
+    pub fn timestamp(&self) -> Timestamp {
+        self.timestamp
+    }
+
+    /// Time at which the first frame of the buffer is played, if known.
+    pub fn playback_time(&self) -> Option<HostTime> {
+        self.playback_time
+    }
+}
+
+struct InputCallback<D> {
+    data_callback: D,
+    paused: Arc<AtomicBool>,
+    scratch: Vec<f32>,
+}
+
+impl<D: FnMut(&[f32], &InputCallbackInfo)> AudioInputCallback for InputCallback<D> {
+    fn on_input_data(&mut self, _context: AudioCallbackContext, input: AudioInput<f32>) {
+        if self.paused.load(Ordering::Relaxed) {
+            return;
+        }
+        let info = InputCallbackInfo {
+            timestamp: input.timestamp,
+            capture_time: input.capture_time,
+        };
+        let interleaved = input.buffer.as_interleaved();
+        match interleaved.as_slice() {
+            Some(data) => (self.data_callback)(data, &info),
+            None => {
+                self.scratch.clear();
+                self.scratch.extend(interleaved.iter().copied());
+                (self.data_callback)(&self.scratch, &info);
+            }
+        }
+    }
+}
+
+struct OutputCallback<D> {
+    data_callback: D,
+    paused: Arc<AtomicBool>,
+    scratch: Vec<f32>,
+}
+
+impl<D: FnMut(&mut [f32], &OutputCallbackInfo)> AudioOutputCallback for OutputCallback<D> {
+    fn on_output_data(&mut self, context: AudioCallbackContext, mut output: AudioOutput<f32>) {
+        let mut interleaved = output.buffer.as_interleaved_mut();
+        if self.paused.load(Ordering::Relaxed) {
+            interleaved.fill(0.);
+            return;
+        }
+        let info = OutputCallbackInfo {
+            timestamp: output.timestamp,
+            playback_time: context.output_time,
+        };
+        match interleaved.as_slice_mut() {
+            Some(data) => (self.data_callback)(data, &info),
+            None => {
+                self.scratch.clear();
+                self.scratch.resize(interleaved.len(), 0.);
+                (self.data_callback)(&mut self.scratch, &info);
+                for (sample, value) in interleaved.iter_mut().zip(&self.scratch) {
+                    *sample = *value;
+                }
+            }
+        }
+    }
+}
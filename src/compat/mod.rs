@@ -0,0 +1,8 @@
+//! # Compatibility layers
+//!
+//! APIs shaped after other audio libraries, to ease migrating code written against them to
+//! interflow. They only cover the common subset of those libraries, and expose the underlying
+//! interflow types so that code can be migrated gradually.
+
+#[cfg(any(os_alsa, os_coreaudio, os_wasapi))]
+pub mod cpal;
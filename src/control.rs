@@ -0,0 +1,430 @@
+//! Control of running streams from other threads.
+//!
+//! Stream handles send [`StreamCommand`]s to their audio thread through a lock-free
+//! single-producer single-consumer queue, created with [`control_queue`]. Backends apply the
+//! pending commands at the top of every process cycle with [`ControlReceiver::process`], which
+//! never blocks nor allocates. Callbacks replaced by new ones are sent back to the handle, so
+//! that they are not dropped on the audio thread.
+//!
+//! Audio threads hold their callback through a [`CallbackSlot`], which gives it back to the
+//! handle when dropped. The callback is then recovered whether the audio thread stops because
+//! the stream is ejected, because of an error or because it panicked, and handles report
+//! failures with an [`EjectError`] carrying the callback when it could be recovered.
+//!
+//! Applications control streams through the [`StreamControl`] given by
+//! [`AudioStreamHandle::control`](crate::AudioStreamHandle::control):
+//!
+//! ```no_run
+//! use interflow::prelude::*;
+//! use interflow::signals::Sine;
+//! let device = default_output_device();
+//! let stream = device.default_output_stream(Sine::new(440.)).unwrap();
+//! let control = stream.control().expect("Stream cannot be controlled");
+//! control.set_volume(0.5);
+//! control.pause();
+//! control.replace_callback(Sine::new(880.)).ok();
+//! control.resume();
+//! ```
+
+use std::any::Any;
+use std::fmt;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::audio_buffer::AudioMut;
+use crate::events::{StreamEvent, StreamEventSender};
+
+/// Maximum number of commands queued for an audio thread.
+pub const COMMAND_QUEUE_CAPACITY: usize = 32;
+
+/// Interval at which ejecting handles check whether the audio thread has given the callback back.
+const EJECT_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Command sent to the audio thread of a stream.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum StreamCommand<Callback> {
+    /// Stop calling the callback, producing silence, until the stream is resumed.
+    Pause,
+    /// Call the callback again after a pause.
+    Resume,
+    /// Set the linear amplitude applied to the audio exchanged with the device.
+    SetVolume(f32),
+    /// Replace the callback with a new one, which is prepared before processing audio.
+    ReplaceCallback(Callback),
+    /// Stop the stream and give the callback back.
+    Eject,
+}
+
+/// Reason a stream stopped, other than being ejected.
+#[derive(Debug, Error)]
+pub enum StreamFailure<BackendError> {
+    /// The backend stopped the stream because of an error.
+    #[error(transparent)]
+    Backend(BackendError),
+    /// The audio thread panicked, with the given message.
+    #[error("Audio thread panicked: {0}")]
+    Panicked(String),
+    /// The audio thread stopped without giving the callback back.
+    #[error("Audio thread stopped without giving the callback back")]
+    CallbackLost,
+}
+
+impl<BackendError> StreamFailure<BackendError> {
+    /// Failure of an audio thread which panicked, from the payload of the panic.
+    pub fn panicked(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => payload
+                .downcast_ref::<&str>()
+                .map_or("unknown panic", |message| message)
+                .to_string(),
+        };
+        Self::Panicked(message)
+    }
+}
+
+/// Error of stream handles whose stream has failed, returned by
+/// [`AudioStreamHandle::eject`](crate::AudioStreamHandle::eject).
+#[derive(Error)]
+#[error("{failure}")]
+pub struct EjectError<Callback, BackendError> {
+    /// Reason the stream stopped.
+    pub failure: StreamFailure<BackendError>,
+    /// Callback of the stream, when it could be recovered from the audio thread. Callbacks
+    /// recovered after a panic may be left in an inconsistent state.
+    pub callback: Option<Callback>,
+}
+
+impl<Callback, BackendError: fmt::Debug> fmt::Debug for EjectError<Callback, BackendError> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EjectError")
+            .field("failure", &self.failure)
+            .field("callback_recovered", &self.callback.is_some())
+            .finish()
+    }
+}
+
+/// Create the control queue of a stream, returning the handle side, the audio thread side, and
+/// the slot through which the audio thread holds the callback.
+pub fn control_queue<Callback>(
+    callback: Callback,
+) -> (
+    StreamControl<Callback>,
+    ControlReceiver<Callback>,
+    CallbackSlot<Callback>,
+) {
+    let (commands, command_rx) = rtrb::RingBuffer::new(COMMAND_QUEUE_CAPACITY);
+    // Room for every queued command to give a callback back
+    let (returned, returned_rx) = rtrb::RingBuffer::new(COMMAND_QUEUE_CAPACITY);
+    let (parked, parked_rx) = rtrb::RingBuffer::new(1);
+    let control = StreamControl {
+        inner: Mutex::new(ControlInner {
+            commands,
+            returned: returned_rx,
+            parked: parked_rx,
+        }),
+    };
+    let receiver = ControlReceiver {
+        commands: command_rx,
+        returned,
+        paused: false,
+        volume: 1.,
+        eject_requested: false,
+    };
+    let slot = CallbackSlot {
+        callback: ManuallyDrop::new(callback),
+        parked,
+    };
+    (control, receiver, slot)
+}
+
+/// Callback held by the audio thread of a stream, which is given back to the stream handle when
+/// the slot is dropped. It dereferences to the callback.
+pub struct CallbackSlot<Callback> {
+    callback: ManuallyDrop<Callback>,
+    parked: rtrb::Producer<Callback>,
+}
+
+impl<Callback> Deref for CallbackSlot<Callback> {
+    type Target = Callback;
+
+    fn deref(&self) -> &Callback {
+        &self.callback
+    }
+}
+
+impl<Callback> DerefMut for CallbackSlot<Callback> {
+    fn deref_mut(&mut self) -> &mut Callback {
+        &mut self.callback
+    }
+}
+
+impl<Callback> Drop for CallbackSlot<Callback> {
+    fn drop(&mut self) {
+        // Safety: the callback is not accessed anymore once the slot is dropped
+        let callback = unsafe { ManuallyDrop::take(&mut self.callback) };
+        // The queue only ever receives this callback
+        let _ = self.parked.push(callback);
+    }
+}
+
+/// Handle side of the control queue of a stream.
+pub struct StreamControl<Callback> {
+    inner: Mutex<ControlInner<Callback>>,
+}
+
+struct ControlInner<Callback> {
+    commands: rtrb::Producer<StreamCommand<Callback>>,
+    returned: rtrb::Consumer<Callback>,
+    /// Callback given back when the audio thread dropped its slot
+    parked: rtrb::Consumer<Callback>,
+}
+
+impl<Callback> StreamControl<Callback> {
+    /// Pause the stream. Paused streams keep running, but output silence and drop their input
+    /// instead of calling the callback. Returns `false` when the command queue is full.
+    pub fn pause(&self) -> bool {
+        self.send(StreamCommand::Pause).is_ok()
+    }
+
+    /// Resume a paused stream. Returns `false` when the command queue is full.
+    pub fn resume(&self) -> bool {
+        self.send(StreamCommand::Resume).is_ok()
+    }
+
+    /// Set the volume of the stream, as a linear amplitude applied to the output of output and
+    /// duplex streams, and to the input of input streams. Returns `false` when the command queue
+    /// is full.
+    pub fn set_volume(&self, volume: f32) -> bool {
+        self.send(StreamCommand::SetVolume(volume)).is_ok()
+    }
+
+    /// Replace the callback of the stream. The new callback is prepared on the audio thread
+    /// before processing audio, and the previous one can then be taken back with
+    /// [`Self::take_replaced`]. The callback is given back when the command queue is full.
+    pub fn replace_callback(&self, callback: Callback) -> Result<(), Callback> {
+        self.send(StreamCommand::ReplaceCallback(callback))
+            .map_err(|command| match command {
+                StreamCommand::ReplaceCallback(callback) => callback,
+                _ => unreachable!(),
+            })
+    }
+
+    /// Take a callback replaced with [`Self::replace_callback`], if any.
+    pub fn take_replaced(&self) -> Option<Callback> {
+        self.lock().returned.pop().ok()
+    }
+
+    /// Ask the audio thread to stop. Used by stream handles when they are ejected; this command
+    /// is always accepted.
+    pub fn request_eject(&self) {
+        // The other commands leave a slot for this one
+        let _ = self.lock().commands.push(StreamCommand::Eject);
+    }
+
+    /// Wait for the audio thread to give the callback back by dropping its [`CallbackSlot`].
+    /// Returns `None` when the slot was lost without giving the callback back.
+    pub fn wait_ejected(&self) -> Option<Callback> {
+        self.wait_parked(None)
+    }
+
+    /// Wait for the audio thread to give the callback back, for at most the given duration.
+    /// Returns `None` when the callback has not been given back in time.
+    pub fn wait_ejected_timeout(&self, timeout: Duration) -> Option<Callback> {
+        self.wait_parked(Some(Instant::now() + timeout))
+    }
+
+    /// Take the callback back from an audio thread which has stopped, along with the result it
+    /// stopped with. Handles of backends running their own audio threads return this from
+    /// [`AudioStreamHandle::eject`](crate::AudioStreamHandle::eject) once the thread is joined.
+    pub fn collect<BackendError>(
+        &self,
+        result: Result<(), StreamFailure<BackendError>>,
+    ) -> Result<Callback, EjectError<Callback, BackendError>> {
+        let callback = self.wait_ejected();
+        match (result, callback) {
+            (Ok(()), Some(callback)) => Ok(callback),
+            (Ok(()), None) => Err(EjectError {
+                failure: StreamFailure::CallbackLost,
+                callback: None,
+            }),
+            (Err(failure), callback) => Err(EjectError { failure, callback }),
+        }
+    }
+
+    fn wait_parked(&self, deadline: Option<Instant>) -> Option<Callback> {
+        loop {
+            let mut inner = self.lock();
+            if let Ok(callback) = inner.parked.pop() {
+                return Some(callback);
+            }
+            if inner.parked.is_abandoned() {
+                // The slot may have been dropped right after the previous check
+                return inner.parked.pop().ok();
+            }
+            drop(inner);
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return None;
+            }
+            std::thread::sleep(EJECT_POLL_INTERVAL);
+        }
+    }
+
+    fn send(&self, command: StreamCommand<Callback>) -> Result<(), StreamCommand<Callback>> {
+        let mut inner = self.lock();
+        // Keep a slot for the eject command, and room for all the callbacks which can be given
+        // back, so that the audio thread never has to drop them
+        let queued = COMMAND_QUEUE_CAPACITY - inner.commands.slots();
+        if inner.commands.slots() <= 1 || queued + inner.returned.slots() >= COMMAND_QUEUE_CAPACITY
+        {
+            return Err(command);
+        }
+        inner
+            .commands
+            .push(command)
+            .map_err(|rtrb::PushError::Full(command)| command)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ControlInner<Callback>> {
+        self.inner.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Audio thread side of the control queue of a stream.
+pub struct ControlReceiver<Callback> {
+    commands: rtrb::Consumer<StreamCommand<Callback>>,
+    returned: rtrb::Producer<Callback>,
+    paused: bool,
+    volume: f32,
+    eject_requested: bool,
+}
+
+impl<Callback> ControlReceiver<Callback> {
+    /// Apply the pending commands, at the top of a process cycle. Pausing and resuming are
+    /// reported to the event sender.
+    ///
+    /// Returns `true` when the callback has been replaced, in which case the backend has to
+    /// prepare it before giving it audio.
+    pub fn process(&mut self, callback: &mut Callback, events: &StreamEventSender) -> bool {
+        let mut replaced = false;
+        while let Ok(command) = self.commands.pop() {
+            match command {
+                StreamCommand::Pause if !self.paused => {
+                    self.paused = true;
+                    events.send(StreamEvent::Paused);
+                }
+                StreamCommand::Resume if self.paused => {
+                    self.paused = false;
+                    events.send(StreamEvent::Started);
+                }
+                StreamCommand::Pause | StreamCommand::Resume => {}
+                StreamCommand::SetVolume(volume) => self.volume = volume,
+                StreamCommand::ReplaceCallback(new) => {
+                    let previous = std::mem::replace(callback, new);
+                    // The handle only accepts commands when there is room for this
+                    let _ = self.returned.push(previous);
+                    replaced = true;
+                }
+                StreamCommand::Eject => self.eject_requested = true,
+            }
+        }
+        replaced
+    }
+
+    /// Whether the stream is paused, and the callback should not be called.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Volume of the stream, as a linear amplitude.
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    /// Apply the volume of the stream to a buffer.
+    pub fn apply_volume(&self, mut buffer: AudioMut<f32>) {
+        if self.volume != 1. {
+            buffer.change_amplitude(self.volume);
+        }
+    }
+
+    /// Whether the handle asked for the stream to stop.
+    pub fn eject_requested(&self) -> bool {
+        self.eject_requested
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{control_queue, StreamFailure, COMMAND_QUEUE_CAPACITY};
+    use crate::events::{StreamEvent, StreamEventSender};
+
+    #[test]
+    fn test_commands_in_order() {
+        let events = StreamEventSender::new();
+        let mut receiver_events = events.subscribe();
+        let (control, mut receiver, mut slot) = control_queue(0);
+        assert!(control.pause());
+        assert!(control.set_volume(0.5));
+        assert!(control.resume());
+        assert!(control.pause());
+        assert!(!receiver.process(&mut slot, &events));
+        assert!(receiver.is_paused());
+        assert_eq!(0.5, receiver.volume());
+        let received = receiver_events.try_iter().collect::<Vec<_>>();
+        assert_eq!(
+            [StreamEvent::Paused, StreamEvent::Started, StreamEvent::Paused],
+            *received
+        );
+    }
+
+    #[test]
+    fn test_replaced_callbacks_in_order() {
+        let events = StreamEventSender::new();
+        let (control, mut receiver, mut slot) = control_queue(1);
+        assert!(control.replace_callback(2).is_ok());
+        assert!(control.replace_callback(3).is_ok());
+        assert!(receiver.process(&mut slot, &events));
+        assert_eq!(3, *slot);
+        assert_eq!(Some(1), control.take_replaced());
+        assert_eq!(Some(2), control.take_replaced());
+        assert_eq!(None, control.take_replaced());
+    }
+
+    #[test]
+    fn test_eject_after_commands() {
+        let events = StreamEventSender::new();
+        let (control, mut receiver, mut slot) = control_queue(1);
+        // Fill the queue; the eject command is still accepted afterwards
+        let mut sent = 0;
+        while control.set_volume(sent as f32) {
+            sent += 1;
+        }
+        assert!(sent < COMMAND_QUEUE_CAPACITY);
+        control.request_eject();
+        assert!(!receiver.eject_requested());
+        receiver.process(&mut slot, &events);
+        assert!(receiver.eject_requested());
+        // Commands sent before the eject are all applied
+        assert_eq!((sent - 1) as f32, receiver.volume());
+
+        drop(slot);
+        assert_eq!(Some(1), control.wait_ejected());
+    }
+
+    #[test]
+    fn test_collect_failure() {
+        let (control, _receiver, slot) = control_queue(1);
+        drop(slot);
+        let err = control
+            .collect::<()>(Err(StreamFailure::Backend(())))
+            .unwrap_err();
+        assert!(matches!(err.failure, StreamFailure::Backend(())));
+        assert_eq!(Some(1), err.callback);
+    }
+}
@@ -0,0 +1,97 @@
+//! Interoperability with the [`dasp`](::dasp) crate.
+//!
+//! [`SignalCallback`] plays a [`Signal`] as an output callback, and [`frames`] and
+//! [`frames_mut`] expose callback buffers as slices of dasp frames, so that existing dasp
+//! processing can be used as-is.
+//!
+//! ```rust
+//! use interflow::audio_buffer::AudioRef;
+//! use interflow::dasp::frames;
+//! let data = [0.1f32, 0.2, 0.3, 0.4];
+//! let buffer = AudioRef::from_interleaved(&data, 2).unwrap();
+//! let stereo: &[[f32; 2]] = frames(&buffer).unwrap();
+//! assert_eq!(stereo, [[0.1, 0.2], [0.3, 0.4]]);
+//! ```
+
+use ::dasp::sample::ToSample;
+use ::dasp::slice::{FromSampleSlice, FromSampleSliceMut};
+use ::dasp::{Frame, Sample, Signal};
+
+use crate::audio_buffer::{AudioMut, AudioRef};
+use crate::{AudioCallbackContext, AudioOutput, AudioOutputCallback};
+
+/// View an audio buffer as a slice of dasp frames. Returns `None` when the buffer is not stored
+/// interleaved (as when it was created from interleaved data), or when the frame type does not
+/// have as many channels as the buffer.
+pub fn frames<'a, F>(buffer: &'a AudioRef<f32>) -> Option<&'a [F]>
+where
+    &'a [F]: FromSampleSlice<'a, f32>,
+{
+    let samples = buffer.as_interleaved().to_slice()?;
+    ::dasp::slice::from_sample_slice(samples).filter(|frames: &&[F]| {
+        // Slices of a single channel convert to any frame type whose size divides their length
+        frames.len() == buffer.num_samples()
+    })
+}
+
+/// View a mutable audio buffer as a mutable slice of dasp frames. Returns `None` under the same
+/// conditions as [`frames`].
+pub fn frames_mut<'a, F>(buffer: &'a mut AudioMut<f32>) -> Option<&'a mut [F]>
+where
+    &'a mut [F]: FromSampleSliceMut<'a, f32>,
+{
+    let num_samples = buffer.num_samples();
+    let samples = buffer.as_interleaved_mut().into_slice()?;
+    ::dasp::slice::from_sample_slice_mut(samples)
+        .filter(|frames: &&mut [F]| frames.len() == num_samples)
+}
+
+/// Output callback playing a dasp [`Signal`].
+///
+/// Each frame of the signal is written to the output channels in order. Output channels past the
+/// channels of the signal are left silent, and channels of the signal past the output channels
+/// are dropped.
+///
+/// ```rust
+/// use dasp::signal::{self, Signal};
+/// use interflow::dasp::SignalCallback;
+/// let sine = signal::rate(48000.).const_hz(440.).sine().map(|x| [x * 0.5; 2]);
+/// let callback = SignalCallback::new(sine);
+/// ```
+#[derive(Debug, Clone)]
+pub struct SignalCallback<S> {
+    signal: S,
+}
+
+impl<S> SignalCallback<S> {
+    /// Play the given signal.
+    pub fn new(signal: S) -> Self {
+        Self { signal }
+    }
+
+    /// Signal played by this callback.
+    pub fn signal(&self) -> &S {
+        &self.signal
+    }
+
+    /// Return the signal played by this callback.
+    pub fn into_inner(self) -> S {
+        self.signal
+    }
+}
+
+impl<S: Signal> AudioOutputCallback for SignalCallback<S>
+where
+    <S::Frame as Frame>::Sample: ToSample<f32>,
+{
+    fn on_output_data(&mut self, _context: AudioCallbackContext, mut output: AudioOutput<f32>) {
+        for i in 0..output.buffer.num_samples() {
+            let frame = self.signal.next();
+            for (ch, sample) in output.buffer.get_frame_mut(i).iter_mut().enumerate() {
+                *sample = frame
+                    .channel(ch)
+                    .map_or(0., |sample| sample.to_sample::<f32>());
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! Diagnostics reported by the library.
+//!
+//! Failures which the library recovers from, or which cannot be returned to the caller (e.g.
+//! from device listeners or audio threads), are reported as warnings and errors through the
+//! [`log`] crate. Failures of running streams are additionally sent to their event channel as
+//! [`StreamEvent::Error`](crate::events::StreamEvent::Error).
+//!
+//! Applications which do not install a logger can receive the same diagnostics by installing a
+//! sink with [`set_diagnostics_sink`]:
+//!
+//! ```
+//! use interflow::diagnostics::{remove_diagnostics_sink, set_diagnostics_sink};
+//! set_diagnostics_sink(|diagnostic| {
+//!     eprintln!("[{}] {}: {}", diagnostic.level, diagnostic.module, diagnostic.message);
+//! });
+//! // ...
+//! remove_diagnostics_sink();
+//! ```
+
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+pub use log::Level;
+
+/// Diagnostic reported by the library.
+#[derive(Debug, Clone, Copy)]
+pub struct Diagnostic<'a> {
+    /// Severity of the diagnostic, either [`Level::Warn`] or [`Level::Error`].
+    pub level: Level,
+    /// Path of the module reporting the diagnostic, e.g. `interflow::backends::alsa`.
+    pub module: &'static str,
+    /// Description of the diagnostic.
+    pub message: fmt::Arguments<'a>,
+}
+
+type Sink = Arc<dyn Fn(&Diagnostic) + Send + Sync>;
+
+static SINK: RwLock<Option<Sink>> = RwLock::new(None);
+
+/// Install a sink receiving all the diagnostics reported by the library, replacing the previous
+/// one. Diagnostics are still logged through the [`log`] crate.
+///
+/// The sink may be called from any thread, including audio threads when streams fail, and should
+/// return quickly.
+pub fn set_diagnostics_sink(sink: impl 'static + Send + Sync + Fn(&Diagnostic)) {
+    *SINK.write().unwrap_or_else(|err| err.into_inner()) = Some(Arc::new(sink));
+}
+
+/// Remove the diagnostics sink, if any.
+pub fn remove_diagnostics_sink() {
+    *SINK.write().unwrap_or_else(|err| err.into_inner()) = None;
+}
+
+/// Log a diagnostic, and send it to the installed sink. Use the [`warn`] and [`error`] macros
+/// instead.
+pub(crate) fn report(level: Level, module: &'static str, message: fmt::Arguments) {
+    log::log!(target: module, level, "{message}");
+    let sink = SINK.read().unwrap_or_else(|err| err.into_inner()).clone();
+    if let Some(sink) = sink {
+        sink(&Diagnostic {
+            level,
+            module,
+            message,
+        });
+    }
+}
+
+/// Report a warning, in the same way as [`log::warn`].
+macro_rules! report_warn {
+    ($($arg:tt)+) => {
+        $crate::diagnostics::report(
+            $crate::diagnostics::Level::Warn,
+            module_path!(),
+            format_args!($($arg)+),
+        )
+    };
+}
+
+/// Report an error, in the same way as [`log::error`].
+macro_rules! report_error {
+    ($($arg:tt)+) => {
+        $crate::diagnostics::report(
+            $crate::diagnostics::Level::Error,
+            module_path!(),
+            format_args!($($arg)+),
+        )
+    };
+}
+
+// Re-exported under another name, as `warn` conflicts with the built-in attribute
+pub(crate) use {report_error as error, report_warn as warn};
@@ -1,17 +1,44 @@
-use crate::audio_buffer::AudioBuffer;
+//! Duplex streams made of separate input and output streams.
+//!
+//! [`create_duplex_stream`] runs an [`AudioDuplexCallback`] from an input stream and an output
+//! stream, possibly on different devices. The input stream runs an [`InputProxy`], which resamples
+//! the input to the sample rate of the output stream with a [`LinearResampler`] and queues it in
+//! a ring buffer. The output
+//! stream runs a [`DuplexCallback`], which pops the queued input and calls the duplex callback with
+//! it.
+//!
+//! The whole data path is realtime-safe: all buffers are allocated when creating the callbacks,
+//! with [`duplex_callbacks`], and neither callback allocates, locks nor blocks afterwards. The
+//! output sample rate, the capture time of the input and input overruns are shared between the
+//! callbacks through atomics.
+
+use crate::audio_buffer::{AudioBuffer, AudioRef};
 use crate::channel_map::Bitset;
+use crate::clock::HostTime;
+use crate::resample::{LinearResampler, Resampler};
+use crate::timestamp::Timestamp;
 use crate::{
     AudioCallbackContext, AudioInput, AudioInputCallback, AudioInputDevice, AudioOutput,
     AudioOutputCallback, AudioOutputDevice, AudioStreamHandle, SendEverywhereButOnWeb,
     StreamConfig,
 };
-use ndarray::{ArrayView1, ArrayViewMut1};
 use std::error::Error;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 
 pub trait AudioDuplexCallback: 'static + SendEverywhereButOnWeb {
+    /// Prepare the callback for processing. See [`AudioOutputCallback::prepare`] for details.
+    #[allow(unused_variables)]
+    fn prepare(&mut self, context: AudioCallbackContext) {}
+
+    /// Process input audio and fill the output buffer. Input and output each come with their own
+    /// timing: `input` carries the timestamp and capture time of its first frame, while `output`
+    /// and `context` carry the timestamp and presentation time
+    /// ([`AudioCallbackContext::output_time`]) of the output buffer. The difference between the
+    /// capture and presentation times is the latency to compensate for when aligning recorded
+    /// audio with what is being played.
     fn on_audio_data(
         &mut self,
         context: AudioCallbackContext,
@@ -24,56 +51,70 @@ pub struct DuplexStream<Callback, Error> {
     input_stream: Box<dyn AudioStreamHandle<InputProxy, Error = Error>>,
     output_stream: Box<dyn AudioStreamHandle<DuplexCallback<Callback>, Error = Error>>,
 }
+
+/// Number of frames the input is resampled into at a time before being queued.
+const RESAMPLE_CHUNK_FRAMES: usize = 512;
+
+/// Input side of a duplex stream, resampling its input to the sample rate of the output stream and
+/// queueing it for the [`DuplexCallback`].
 pub struct InputProxy {
     buffer: rtrb::Producer<f32>,
+    resampler: LinearResampler,
+    resampled: AudioBuffer<f32>,
     output_sample_rate: Arc<AtomicU64>,
+    capture_end: Arc<AtomicU64>,
+    overrun: Arc<AtomicBool>,
 }
 
 impl AudioInputCallback for InputProxy {
     fn on_input_data(&mut self, context: AudioCallbackContext, input: AudioInput<f32>) {
-        if self.buffer.slots() < input.buffer.num_samples() * input.buffer.num_channels() {
-            eprintln!("Not enough slots to buffer input");
-        }
-        let mut scratch = [0f32; 32];
-        let rate = self.output_sample_rate.load(Ordering::SeqCst) as f64
-            / context.stream_config.samplerate;
-        let out_len = (input.buffer.num_samples() as f64 * rate) as usize;
-        let mut scratch =
-            ArrayViewMut1::from(&mut scratch[..context.stream_config.channels.count()]);
-        let rate_recip = rate.recip();
-        for i in 0..out_len {
-            let in_ix = i as f64 / rate_recip;
-            let i = in_ix.floor() as usize;
-            let j = i + 1;
-            if j == out_len {
-                scratch.assign(&input.buffer.get_frame(i));
-            } else {
-                lerp(
-                    in_ix.fract() as _,
-                    input.buffer.get_frame(i),
-                    input.buffer.get_frame(j),
-                    scratch.view_mut(),
-                );
-            }
-            for sample in scratch.iter().copied() {
-                let _ = self.buffer.push(sample);
-            }
+        // Nothing is queued until the output stream has run and shared its sample rate
+        let output_sample_rate = self.output_sample_rate.load(Ordering::SeqCst);
+        if output_sample_rate != 0 {
+            self.resampler
+                .set_ratio(output_sample_rate as f64 / context.stream_config.samplerate);
+            self.queue(input.buffer);
         }
+        // Host time right after the last frame pushed, in nanoseconds, or 0 when unknown
+        let capture_end = input.capture_time.map_or(0, |time| {
+            let duration = Timestamp::from_count(
+                context.stream_config.samplerate,
+                input.buffer.num_samples() as _,
+            )
+            .as_duration();
+            (time + duration).as_duration().as_nanos() as u64
+        });
+        self.capture_end.store(capture_end, Ordering::SeqCst);
     }
 }
 
-fn lerp(x: f32, a: ArrayView1<f32>, b: ArrayView1<f32>, mut out: ArrayViewMut1<f32>) {
-    assert_eq!(out.len(), a.len());
-    assert_eq!(out.len(), b.len());
-    for i in 0..out.len() {
-        out[i] = lerpf(x, a[i], b[i]);
+impl InputProxy {
+    /// Resample the input and queue it, chunk by chunk.
+    fn queue(&mut self, input: AudioRef<f32>) {
+        let channels = self.resampled.num_channels();
+        let mut offset = 0;
+        loop {
+            let (consumed, written) = self
+                .resampler
+                .process(input.slice(offset..), self.resampled.as_mut());
+            offset += consumed;
+            // Only whole frames are queued, so that channels stay interleaved in order. Frames
+            // which do not fit are dropped; the output side reports the gap as a discontinuity.
+            let frames = written.min(self.buffer.slots() / channels.max(1));
+            if frames < written {
+                self.overrun.store(true, Ordering::Relaxed);
+            }
+            if let Ok(chunk) = self.buffer.write_chunk_uninit(frames * channels) {
+                let resampled = self.resampled.slice(..frames);
+                chunk.fill_from_iter(resampled.as_interleaved().iter().copied());
+            }
+            if written < self.resampled.num_samples() {
+                break;
+            }
+        }
     }
 }
 
-fn lerpf(x: f32, a: f32, b: f32) -> f32 {
-    a + (b - a) * x
-}
-
 #[derive(Debug, Error)]
 #[error(transparent)]
 pub enum DuplexCallbackError<InputError, OutputError> {
@@ -82,11 +123,16 @@ pub enum DuplexCallbackError<InputError, OutputError> {
     Other(Box<dyn Error>),
 }
 
+/// Output side of a duplex stream, calling the duplex callback with the input queued by the
+/// [`InputProxy`].
 pub struct DuplexCallback<Callback> {
     input: rtrb::Consumer<f32>,
     callback: Callback,
     storage: AudioBuffer<f32>,
     output_sample_rate: Arc<AtomicU64>,
+    capture_end: Arc<AtomicU64>,
+    overrun: Arc<AtomicBool>,
+    input_timestamp: Option<Timestamp>,
 }
 
 impl<Callback> DuplexCallback<Callback> {
@@ -96,19 +142,46 @@ impl<Callback> DuplexCallback<Callback> {
 }
 
 impl<Callback: AudioDuplexCallback> AudioOutputCallback for DuplexCallback<Callback> {
-    fn on_output_data(&mut self, context: AudioCallbackContext, output: AudioOutput<f32>) {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.callback.prepare(context);
+    }
+
+    fn on_output_data(&mut self, mut context: AudioCallbackContext, output: AudioOutput<f32>) {
+        context.discontinuity |= self.overrun.swap(false, Ordering::Relaxed);
         self.output_sample_rate
             .store(context.stream_config.samplerate as _, Ordering::SeqCst);
         let num_channels = self.storage.num_channels();
-        for i in 0..output.buffer.num_samples() {
-            let mut frame = self.storage.get_frame_mut(i);
-            for ch in 0..num_channels {
-                frame[ch] = self.input.pop().unwrap_or(0.0);
+        let samplerate = context.stream_config.samplerate;
+        // Input samples were resampled to the output sample rate, and are counted at that rate
+        let timestamp = *self
+            .input_timestamp
+            .get_or_insert_with(|| Timestamp::new(samplerate));
+        // Frames still queued in the ring buffer were captured after the ones popped now
+        let queued = self.input.slots() / num_channels.max(1);
+        let capture_time = match self.capture_end.load(Ordering::SeqCst) {
+            0 => None,
+            nanos => {
+                let queued = Timestamp::from_count(samplerate, queued as _).as_duration();
+                HostTime::from_duration(Duration::from_nanos(nanos)).checked_sub(queued)
+            }
+        };
+        // Only whole frames are read, and missing frames are silent
+        let num_samples = output.buffer.num_samples();
+        let frames = queued.min(num_samples);
+        let mut storage = self.storage.slice_mut(..num_samples);
+        storage.as_interleaved_mut().fill(0.);
+        if let Ok(chunk) = self.input.read_chunk(frames * num_channels) {
+            for (sample, value) in storage.as_interleaved_mut().iter_mut().zip(chunk) {
+                *sample = value;
             }
         }
+        if let Some(timestamp) = &mut self.input_timestamp {
+            *timestamp += num_samples as u64;
+        }
         let input = AudioInput {
-            timestamp: context.timestamp,
-            buffer: self.storage.slice(..output.buffer.num_samples()),
+            timestamp,
+            capture_time,
+            buffer: self.storage.slice(..num_samples),
         };
         self.callback.on_audio_data(context, input, output);
     }
@@ -152,29 +225,50 @@ pub fn create_duplex_stream<
     >,
     DuplexCallbackError<InputDevice::Error, OutputDevice::Error>,
 > {
-    let (producer, consumer) = rtrb::RingBuffer::new(input_config.samplerate as _);
-    let output_sample_rate = Arc::new(AtomicU64::new(0));
-    let input_handle = input_device.create_input_stream(
-        input_config,
-        InputProxy {
-            buffer: producer,
-            output_sample_rate: output_sample_rate.clone(),
-        },
-    ).map_err(DuplexCallbackError::InputError)?;
-    let output_handle = output_device.create_output_stream(
-        output_config,
-        DuplexCallback {
-            input: consumer,
-            callback,
-            storage: AudioBuffer::zeroed(
-                input_config.channels.count(),
-                input_config.samplerate as _,
-            ),
-            output_sample_rate,
-        },
-    ).map_err(DuplexCallbackError::OutputError)?;
+    let (input_proxy, duplex_callback) = duplex_callbacks(&input_config, callback);
+    let input_handle = input_device
+        .create_input_stream(input_config, input_proxy)
+        .map_err(DuplexCallbackError::InputError)?;
+    let output_handle = output_device
+        .create_output_stream(output_config, duplex_callback)
+        .map_err(DuplexCallbackError::OutputError)?;
     Ok(DuplexStreamHandle {
         input_handle,
         output_handle,
     })
 }
+
+/// Create the callbacks of the input and output streams of a duplex stream, for an input stream
+/// opened with the given configuration.
+///
+/// This allocates all the buffers of the duplex data path, so that the callbacks do not allocate
+/// once running. Use [`create_duplex_stream`] to open both streams directly.
+pub fn duplex_callbacks<Callback: AudioDuplexCallback>(
+    input_config: &StreamConfig,
+    callback: Callback,
+) -> (InputProxy, DuplexCallback<Callback>) {
+    let (producer, consumer) = rtrb::RingBuffer::new(input_config.samplerate as _);
+    let output_sample_rate = Arc::new(AtomicU64::new(0));
+    let capture_end = Arc::new(AtomicU64::new(0));
+    let overrun = Arc::new(AtomicBool::new(false));
+    let channels = input_config.input_channels.count();
+    let input_proxy = InputProxy {
+        buffer: producer,
+        // The ratio is set once the output sample rate is known
+        resampler: LinearResampler::new(channels, 1., 1.),
+        resampled: AudioBuffer::zeroed(channels, RESAMPLE_CHUNK_FRAMES),
+        output_sample_rate: output_sample_rate.clone(),
+        capture_end: capture_end.clone(),
+        overrun: overrun.clone(),
+    };
+    let duplex_callback = DuplexCallback {
+        input: consumer,
+        callback,
+        storage: AudioBuffer::zeroed(channels, input_config.samplerate as _),
+        output_sample_rate,
+        capture_end,
+        overrun,
+        input_timestamp: None,
+    };
+    (input_proxy, duplex_callback)
+}
@@ -0,0 +1,128 @@
+//! Notifications of changes in the state of audio streams.
+//!
+//! Stream handles broadcast [`StreamEvent`]s to every receiver created with
+//! [`AudioStreamHandle::subscribe_events`](crate::AudioStreamHandle::subscribe_events). Each
+//! receiver has its own bounded queue, which it polls without locking; events are dropped for
+//! receivers whose queue is full.
+//!
+//! ```rust
+//! use interflow::events::{StreamEvent, StreamEventSender};
+//! // Backends keep the sender, and give out receivers from their stream handles
+//! let sender = StreamEventSender::new();
+//! let mut receiver = sender.subscribe();
+//! sender.send(StreamEvent::Started);
+//! sender.send(StreamEvent::XRun);
+//! let events = receiver.try_iter().collect::<Vec<_>>();
+//! assert_eq!(events, [StreamEvent::Started, StreamEvent::XRun]);
+//! drop(sender);
+//! assert!(receiver.is_disconnected());
+//! ```
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Maximum number of events queued for a receiver.
+pub const EVENT_QUEUE_CAPACITY: usize = 64;
+
+/// Notification of a change in the state of an audio stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StreamEvent {
+    /// The stream has started processing audio, or has resumed after being paused.
+    Started,
+    /// The stream has been paused, for example because the system suspended the device.
+    Paused,
+    /// The device ran out of audio to play, or of room for the audio it captured. The next
+    /// callback is flagged as [discontinuous](crate::AudioCallbackContext::discontinuity).
+    XRun,
+    /// The stream has moved to another device, or reopened its device after it disappeared. The
+    /// callback is prepared again with the new stream configuration.
+    DeviceChanged,
+    /// The stream is about to be opened again after its device failed, once the delay has
+    /// passed. Attempts are counted from 1. See the [`recovery`](crate::recovery) module.
+    Recovering {
+        /// Number of the attempt
+        attempt: usize,
+        /// Delay before the attempt
+        delay: Duration,
+    },
+    /// The stream has stopped because of an error, described by the message. The error itself is
+    /// returned by [`AudioStreamHandle::eject`](crate::AudioStreamHandle::eject).
+    Error(Arc<str>),
+    /// The stream has been ejected. No more events are sent afterwards.
+    Ejected,
+}
+
+/// Sending side of stream events, kept by stream implementations.
+///
+/// Senders can be cloned, for events coming from several threads. Sending never blocks, so that
+/// it can be done from audio threads; events sent while a receiver is being subscribed are
+/// dropped.
+#[derive(Debug, Clone, Default)]
+pub struct StreamEventSender {
+    subscribers: Arc<Mutex<Vec<rtrb::Producer<StreamEvent>>>>,
+}
+
+impl StreamEventSender {
+    /// Create a sender without any receivers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a receiver for the events sent from now on.
+    pub fn subscribe(&self) -> StreamEventReceiver {
+        let (producer, consumer) = rtrb::RingBuffer::new(EVENT_QUEUE_CAPACITY);
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        // Dropped receivers are only pruned here, so that sending never deallocates
+        subscribers.retain(|subscriber| !subscriber.is_abandoned());
+        subscribers.push(producer);
+        StreamEventReceiver {
+            consumer: Some(consumer),
+        }
+    }
+
+    /// Send an event to all receivers.
+    pub fn send(&self, event: StreamEvent) {
+        let Ok(mut subscribers) = self.subscribers.try_lock() else {
+            return;
+        };
+        for subscriber in subscribers.iter_mut() {
+            let _ = subscriber.push(event.clone());
+        }
+    }
+}
+
+/// Receiving side of stream events, created by
+/// [`AudioStreamHandle::subscribe_events`](crate::AudioStreamHandle::subscribe_events).
+#[derive(Debug, Default)]
+pub struct StreamEventReceiver {
+    consumer: Option<rtrb::Consumer<StreamEvent>>,
+}
+
+impl StreamEventReceiver {
+    /// Create a receiver which never receives any event, for streams which do not report them.
+    pub fn disconnected() -> Self {
+        Self::default()
+    }
+
+    /// Take the next event, if any.
+    pub fn try_recv(&mut self) -> Option<StreamEvent> {
+        self.consumer.as_mut()?.pop().ok()
+    }
+
+    /// Iterate over the events received so far, without blocking.
+    pub fn try_iter(&mut self) -> impl '_ + Iterator<Item = StreamEvent> {
+        std::iter::from_fn(|| self.try_recv())
+    }
+
+    /// Whether no more events will be received: the stream is gone, and all of its events have
+    /// been taken.
+    pub fn is_disconnected(&self) -> bool {
+        self.consumer.as_ref().map_or(true, |consumer| {
+            consumer.is_abandoned() && consumer.is_empty()
+        })
+    }
+}
@@ -0,0 +1,296 @@
+//! Speaker layouts and channel positions.
+//!
+//! A [`SpeakerLayout`] tells which speaker each channel of a stream is meant for. Channels of a
+//! layout are always ordered the same way as [`ChannelPosition`] is declared, which follows the
+//! order used by most drivers (front left, front right, front center, LFE, ...).
+//!
+//! Layouts also convert from and to speaker masks, where each bit corresponds to a position in the
+//! same order. These masks are the same as WASAPI channel masks (`dwChannelMask`) and CoreAudio
+//! channel bitmaps.
+
+use core::fmt;
+
+use thiserror::Error;
+
+use crate::channel_map::{Bitset, ChannelMap32, CreateBitset};
+
+/// Position of a channel in a speaker layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ChannelPosition {
+    /// Front left (FL)
+    FrontLeft,
+    /// Front right (FR)
+    FrontRight,
+    /// Front center (FC)
+    FrontCenter,
+    /// Low-frequency effects, or subwoofer (LFE)
+    LowFrequency,
+    /// Back left, also called rear left (BL)
+    BackLeft,
+    /// Back right, also called rear right (BR)
+    BackRight,
+    /// Front left of center (FLC)
+    FrontLeftOfCenter,
+    /// Front right of center (FRC)
+    FrontRightOfCenter,
+    /// Back center (BC)
+    BackCenter,
+    /// Side left (SL)
+    SideLeft,
+    /// Side right (SR)
+    SideRight,
+    /// Top center, above the listener (TC)
+    TopCenter,
+    /// Top front left (TFL)
+    TopFrontLeft,
+    /// Top front center (TFC)
+    TopFrontCenter,
+    /// Top front right (TFR)
+    TopFrontRight,
+    /// Top back left (TBL)
+    TopBackLeft,
+    /// Top back center (TBC)
+    TopBackCenter,
+    /// Top back right (TBR)
+    TopBackRight,
+}
+
+impl ChannelPosition {
+    /// All channel positions, in channel order.
+    pub const ALL: [Self; 18] = [
+        Self::FrontLeft,
+        Self::FrontRight,
+        Self::FrontCenter,
+        Self::LowFrequency,
+        Self::BackLeft,
+        Self::BackRight,
+        Self::FrontLeftOfCenter,
+        Self::FrontRightOfCenter,
+        Self::BackCenter,
+        Self::SideLeft,
+        Self::SideRight,
+        Self::TopCenter,
+        Self::TopFrontLeft,
+        Self::TopFrontCenter,
+        Self::TopFrontRight,
+        Self::TopBackLeft,
+        Self::TopBackCenter,
+        Self::TopBackRight,
+    ];
+
+    /// Short name of the position, for example "FL" or "LFE".
+    pub const fn short_name(&self) -> &'static str {
+        match self {
+            Self::FrontLeft => "FL",
+            Self::FrontRight => "FR",
+            Self::FrontCenter => "FC",
+            Self::LowFrequency => "LFE",
+            Self::BackLeft => "BL",
+            Self::BackRight => "BR",
+            Self::FrontLeftOfCenter => "FLC",
+            Self::FrontRightOfCenter => "FRC",
+            Self::BackCenter => "BC",
+            Self::SideLeft => "SL",
+            Self::SideRight => "SR",
+            Self::TopCenter => "TC",
+            Self::TopFrontLeft => "TFL",
+            Self::TopFrontCenter => "TFC",
+            Self::TopFrontRight => "TFR",
+            Self::TopBackLeft => "TBL",
+            Self::TopBackCenter => "TBC",
+            Self::TopBackRight => "TBR",
+        }
+    }
+}
+
+impl fmt::Display for ChannelPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.short_name())
+    }
+}
+
+/// Set of speaker positions, describing the channels of a stream.
+///
+/// The channels of a layout are ordered by position, so that for example the LFE channel of
+/// [`SpeakerLayout::SURROUND_5_1`] is its fourth channel. Devices which order their channels
+/// differently report the position of each of their channels in
+/// [`Channel::position`](crate::Channel::position).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpeakerLayout {
+    positions: ChannelMap32,
+}
+
+impl SpeakerLayout {
+    /// Single channel layout.
+    pub const MONO: Self = Self::from_slice(&[ChannelPosition::FrontCenter]);
+    /// Front left and right channels.
+    pub const STEREO: Self =
+        Self::from_slice(&[ChannelPosition::FrontLeft, ChannelPosition::FrontRight]);
+    /// Front and back left and right channels.
+    pub const QUAD: Self = Self::from_slice(&[
+        ChannelPosition::FrontLeft,
+        ChannelPosition::FrontRight,
+        ChannelPosition::BackLeft,
+        ChannelPosition::BackRight,
+    ]);
+    /// 5.1 surround: front left, right and center channels, LFE, and back left and right
+    /// channels.
+    pub const SURROUND_5_1: Self = Self::from_slice(&[
+        ChannelPosition::FrontLeft,
+        ChannelPosition::FrontRight,
+        ChannelPosition::FrontCenter,
+        ChannelPosition::LowFrequency,
+        ChannelPosition::BackLeft,
+        ChannelPosition::BackRight,
+    ]);
+    /// 7.1 surround: 5.1 surround with additional side left and right channels.
+    pub const SURROUND_7_1: Self = Self::from_slice(&[
+        ChannelPosition::FrontLeft,
+        ChannelPosition::FrontRight,
+        ChannelPosition::FrontCenter,
+        ChannelPosition::LowFrequency,
+        ChannelPosition::BackLeft,
+        ChannelPosition::BackRight,
+        ChannelPosition::SideLeft,
+        ChannelPosition::SideRight,
+    ]);
+    /// 7.1.4 surround: 7.1 surround with four height channels.
+    pub const SURROUND_7_1_4: Self = Self::from_slice(&[
+        ChannelPosition::FrontLeft,
+        ChannelPosition::FrontRight,
+        ChannelPosition::FrontCenter,
+        ChannelPosition::LowFrequency,
+        ChannelPosition::BackLeft,
+        ChannelPosition::BackRight,
+        ChannelPosition::SideLeft,
+        ChannelPosition::SideRight,
+        ChannelPosition::TopFrontLeft,
+        ChannelPosition::TopFrontRight,
+        ChannelPosition::TopBackLeft,
+        ChannelPosition::TopBackRight,
+    ]);
+
+    /// Presets, by increasing number of channels.
+    pub const PRESETS: [Self; 6] = [
+        Self::MONO,
+        Self::STEREO,
+        Self::QUAD,
+        Self::SURROUND_5_1,
+        Self::SURROUND_7_1,
+        Self::SURROUND_7_1_4,
+    ];
+
+    const fn from_slice(positions: &[ChannelPosition]) -> Self {
+        let mut mask = 0;
+        let mut i = 0;
+        while i < positions.len() {
+            mask |= 1 << positions[i] as u32;
+            i += 1;
+        }
+        Self { positions: mask }
+    }
+
+    /// Layout made of the given positions. Positions given more than once are only used once.
+    pub fn from_positions(positions: impl IntoIterator<Item = ChannelPosition>) -> Self {
+        Self {
+            positions: ChannelMap32::default().with_indices(positions.into_iter().map(|p| p as _)),
+        }
+    }
+
+    /// Layout from a speaker mask, where bit `n` is set when the layout contains the `n`-th
+    /// position of [`ChannelPosition::ALL`]. Bits which do not correspond to a position are
+    /// ignored.
+    pub const fn from_mask(mask: u32) -> Self {
+        Self {
+            positions: mask & ((1 << ChannelPosition::ALL.len()) - 1),
+        }
+    }
+
+    /// Speaker mask of this layout. See [`Self::from_mask`].
+    pub const fn mask(&self) -> u32 {
+        self.positions
+    }
+
+    /// Preset layout for the given number of channels, if there is one.
+    pub fn for_channel_count(count: usize) -> Option<Self> {
+        Self::PRESETS
+            .into_iter()
+            .find(|layout| layout.channel_count() == count)
+    }
+
+    /// Number of channels in this layout.
+    pub fn channel_count(&self) -> usize {
+        self.positions.count()
+    }
+
+    /// Positions of the channels of this layout, in channel order.
+    pub fn positions(&self) -> impl '_ + Iterator<Item = ChannelPosition> {
+        ChannelPosition::ALL
+            .into_iter()
+            .filter(|&position| self.contains(position))
+    }
+
+    /// Whether the layout has a channel for the given position.
+    pub fn contains(&self, position: ChannelPosition) -> bool {
+        self.positions.get_index(position as _)
+    }
+
+    /// Position of the channel at the given index.
+    pub fn position(&self, channel: usize) -> Option<ChannelPosition> {
+        self.positions().nth(channel)
+    }
+
+    /// Index of the channel at the given position, if the layout contains it.
+    pub fn channel_index(&self, position: ChannelPosition) -> Option<usize> {
+        self.positions().position(|p| p == position)
+    }
+
+    /// Map of the channels to request for a stream using this layout.
+    pub fn channel_map(&self) -> ChannelMap32 {
+        ChannelMap32::first_n(self.channel_count())
+    }
+}
+
+impl From<SpeakerLayout> for ChannelMap32 {
+    fn from(layout: SpeakerLayout) -> Self {
+        layout.channel_map()
+    }
+}
+
+/// Error returned when no preset layout has the requested number of channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("No speaker layout for {0} channels")]
+pub struct UnknownLayout(pub usize);
+
+/// Picks the preset layout with as many channels as the channel map requests.
+impl TryFrom<ChannelMap32> for SpeakerLayout {
+    type Error = UnknownLayout;
+
+    fn try_from(channels: ChannelMap32) -> Result<Self, Self::Error> {
+        let count = channels.count();
+        Self::for_channel_count(count).ok_or(UnknownLayout(count))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ChannelPosition, SpeakerLayout};
+
+    #[test]
+    fn test_preset_positions() {
+        let layout = SpeakerLayout::SURROUND_5_1;
+        assert_eq!(6, layout.channel_count());
+        assert_eq!(Some(ChannelPosition::LowFrequency), layout.position(3));
+        assert_eq!(Some(4), layout.channel_index(ChannelPosition::BackLeft));
+        assert_eq!(None, layout.channel_index(ChannelPosition::SideLeft));
+    }
+
+    #[test]
+    fn test_channel_map_roundtrip() {
+        for layout in SpeakerLayout::PRESETS {
+            let channels = u32::from(layout);
+            assert_eq!(Ok(layout), SpeakerLayout::try_from(channels));
+        }
+        assert!(SpeakerLayout::try_from(0b111u32).is_err());
+    }
+}
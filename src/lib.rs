@@ -2,16 +2,48 @@
 #![warn(missing_docs)]
 
 use std::borrow::Cow;
+use std::time::Duration;
 
-use crate::audio_buffer::{AudioMut, AudioRef};
-use crate::channel_map::ChannelMap32;
+use crate::audio_buffer::{AudioMut, AudioRef, SampleFormat};
+use crate::channel_map::{Bitset, ChannelMap32, ChannelMapDyn, ChannelOutOfRange};
+use crate::clock::HostTime;
+use crate::control::StreamControl;
+use crate::duplex::AudioDuplexCallback;
+use crate::events::StreamEventReceiver;
+use crate::layout::ChannelPosition;
+use crate::metering::StreamLevels;
 use crate::timestamp::Timestamp;
 
 pub mod audio_buffer;
 pub mod backends;
+#[cfg(feature = "futures-core")]
+pub mod capture;
 pub mod channel_map;
+pub mod clock;
+pub mod combinators;
+pub mod compat;
+pub mod control;
+#[cfg(feature = "dasp")]
+pub mod dasp;
+pub mod diagnostics;
+pub mod events;
+pub mod layout;
+pub mod metering;
+pub mod mixer;
 pub mod prelude;
+pub mod queue;
+mod realtime;
+pub mod recorder;
+pub mod recovery;
+pub mod resample;
+pub mod scheduler;
+pub mod signals;
+pub mod switcher;
+#[cfg(feature = "symphonia")]
+pub mod symphonia;
+pub mod tee;
 pub mod timestamp;
+pub mod transport;
 pub mod duplex;
 
 /// Audio drivers provide access to the inputs and outputs of physical devices.
@@ -36,6 +68,50 @@ pub trait AudioDriver {
 
     /// List all devices available through this audio driver.
     fn list_devices(&self) -> Result<impl IntoIterator<Item = Self::Device>, Self::Error>;
+
+    /// Find a device from an identifier previously returned by [`AudioDevice::device_id`].
+    /// Returns `None` when no such device is currently available.
+    ///
+    /// The default implementation searches through [`Self::list_devices`].
+    fn device_by_id(&self, id: &str) -> Result<Option<Self::Device>, Self::Error> {
+        Ok(self.list_devices()?.into_iter().find(|device| {
+            device
+                .device_id()
+                .is_ok_and(|device_id| device_id.as_deref() == Some(id))
+        }))
+    }
+}
+
+/// Notification of a change in the devices provided by an audio driver.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum DeviceEvent<Device> {
+    /// A device has been added, for example when plugging in an audio interface.
+    DeviceAdded(Device),
+    /// A device has been removed. Streams can no longer be opened from it.
+    DeviceRemoved(Device),
+    /// The default device of the given type has changed. The new default device is `None` when
+    /// there is no default device of this type anymore.
+    DefaultChanged {
+        /// Type of the default device which has changed.
+        device_type: DeviceType,
+        /// New default device.
+        device: Option<Device>,
+    },
+}
+
+/// Trait for audio drivers which can notify about changes in their devices, so that applications
+/// don't need to poll [`AudioDriver::list_devices`] or [`AudioDriver::default_device`].
+pub trait AudioDriverEvents: AudioDriver {
+    /// Handle to an active subscription. Notifications stop when the subscription is dropped.
+    type Subscription;
+
+    /// Subscribe to device events. The callback is called from a thread managed by the driver,
+    /// and should return quickly.
+    fn subscribe_device_events(
+        &self,
+        callback: impl 'static + Send + FnMut(DeviceEvent<Self::Device>),
+    ) -> Result<Self::Subscription, Self::Error>;
 }
 
 /// Devices are either inputs, outputs, or provide both at the same time.
@@ -50,25 +126,317 @@ pub enum DeviceType {
 }
 
 /// Configuration for an audio stream.
+///
+/// Streams are opened with a [`ChannelMap32`], and can only use the first 32 channels of a
+/// device. A configuration can be described with a [`ChannelMapDyn`], for example when parsed from
+/// user settings, and converted back as long as it only selects channels within the first 32.
 #[derive(Debug, Clone, Copy, PartialEq)]
-pub struct StreamConfig {
+pub struct StreamConfig<Channels = ChannelMap32> {
     /// Configured sample rate of the requested stream. The opened stream can have a different
     /// sample rate, so don't rely on this parameter being correct at runtime.
     pub samplerate: f64,
-    /// Map of channels requested by the stream. Entries correspond in order to
+    /// Map of input channels requested by the stream. Entries correspond in order to
     /// [AudioDevice::channel_map].
     ///
-    /// Some drivers allow specifying which channels are going to be opened and available through
-    /// the audio buffers. For other drivers, only the number of requested channels is used, and
-    /// order does not matter.
-    pub channels: ChannelMap32,
+    /// Only the selected channels are available through the audio buffers, in increasing channel
+    /// order. For example, selecting channels 3 and 4 of an 8-channel interface gives stereo
+    /// buffers containing exactly those two channels.
+    ///
+    /// Output streams ignore this field.
+    pub input_channels: Channels,
+    /// Map of output channels requested by the stream, selected the same way as
+    /// [`Self::input_channels`].
+    ///
+    /// Input streams ignore this field.
+    pub output_channels: Channels,
     /// Range of preferential buffer sizes. The library will make a bast-effort attempt at
     /// honoring this setting, and in future versions may provide additional buffering to ensure
     /// it, but for now you should not make assumptions on buffer sizes based on this setting.
-    pub buffer_size_range: (Option<usize>, Option<usize>),
+    ///
+    /// Sizes can be given as durations, which are converted to frames at the sample rate the
+    /// stream is opened with. Configurations given back by drivers always use frames.
+    pub buffer_size_range: (Option<BufferSize>, Option<BufferSize>),
     /// Whether the device should be exclusively held (meaning no other application can open the
     /// same device).
     pub exclusive: bool,
+    /// Open the stream in shared mode when exclusive access is requested, but the device does
+    /// not support it or is held by another application (see
+    /// [`AudioDevice::supports_exclusive`]). The configuration given to the callback then has
+    /// [`Self::exclusive`] unset.
+    pub fallback_to_shared: bool,
+    /// Sample format exchanged with the driver, or `None` to let the backend pick one. Pinning a
+    /// format (for example 24-bit integers in exclusive mode) fails to open the stream when the
+    /// backend or the device does not support it; callbacks always receive `f32` samples
+    /// regardless.
+    ///
+    /// The configuration given to the callback reports the format the stream was opened with.
+    pub sample_format: Option<SampleFormat>,
+    /// Gain offsets applied by the backend to the input channels, after they are read from the
+    /// device and before they are given to the callback.
+    pub input_trim: ChannelTrim,
+    /// Gain offsets applied by the backend to the output channels, after the callback has
+    /// written them and before they are sent to the device.
+    pub output_trim: ChannelTrim,
+    /// Measure the peak and RMS levels of every channel of the stream, after input trims and
+    /// before output trims are applied, which can then be read with
+    /// [`AudioStreamHandle::levels`].
+    pub metering: bool,
+}
+
+impl<Channels> StreamConfig<Channels> {
+    /// Range of preferential buffer sizes in frames, converting durations at the sample rate of
+    /// this configuration.
+    pub fn buffer_frames_range(&self) -> (Option<usize>, Option<usize>) {
+        let (min, max) = self.buffer_size_range;
+        (
+            min.map(|size| size.frames(self.samplerate)),
+            max.map(|size| size.frames(self.samplerate)),
+        )
+    }
+
+    /// Same configuration, with its channel maps converted by the given function.
+    pub fn map_channels<C>(self, mut f: impl FnMut(Channels) -> C) -> StreamConfig<C> {
+        StreamConfig {
+            samplerate: self.samplerate,
+            input_channels: f(self.input_channels),
+            output_channels: f(self.output_channels),
+            buffer_size_range: self.buffer_size_range,
+            exclusive: self.exclusive,
+            fallback_to_shared: self.fallback_to_shared,
+            sample_format: self.sample_format,
+            input_trim: self.input_trim,
+            output_trim: self.output_trim,
+            metering: self.metering,
+        }
+    }
+}
+
+impl StreamConfig {
+    /// Configuration to open a stream with, where exclusive access is dropped when it is not
+    /// available and [`Self::fallback_to_shared`] is set.
+    pub(crate) fn resolve_exclusive(mut self, supports_exclusive: impl FnOnce() -> bool) -> Self {
+        if self.exclusive && self.fallback_to_shared && !supports_exclusive() {
+            self.exclusive = false;
+        }
+        self
+    }
+
+    /// Checks this configuration against the given device, returning the reasons it cannot be
+    /// used to open a stream on it.
+    ///
+    /// The device has the final say through [`AudioDevice::is_config_supported`]; when it rejects
+    /// the configuration without any more specific reason being found from what the device
+    /// reports, [`ConfigViolation::Unsupported`] is returned.
+    pub fn validate<Device: AudioDevice>(
+        &self,
+        device: &Device,
+    ) -> Result<(), Vec<ConfigViolation>> {
+        if device.is_config_supported(self) {
+            return Ok(());
+        }
+        let mut violations = vec![];
+        if self.exclusive && !self.fallback_to_shared && !device.supports_exclusive() {
+            violations.push(ConfigViolation::ExclusiveNotAvailable);
+        }
+        if let Some(configs) = device.enumerate_configurations() {
+            let mut supported = configs
+                .into_iter()
+                .filter(|config| config.exclusive == self.exclusive)
+                .map(|config| config.samplerate)
+                .collect::<Vec<_>>();
+            supported.sort_by(f64::total_cmp);
+            supported.dedup();
+            if !supported.is_empty() && !supported.contains(&self.samplerate) {
+                violations.push(ConfigViolation::SampleRateNotSupported {
+                    requested: self.samplerate,
+                    supported,
+                });
+            }
+        }
+        let available = device.channel_map().into_iter().count();
+        let requested = (self.input_channels | self.output_channels)
+            .indices()
+            .into_iter()
+            .last()
+            .map_or(0, |index| index + 1);
+        if available > 0 && requested > available {
+            violations.push(ConfigViolation::TooManyChannels {
+                requested,
+                available,
+            });
+        }
+        if let Ok((min, max)) = device.buffer_size_range() {
+            let out_of_range = |size: usize| {
+                min.is_some_and(|min| size < min) || max.is_some_and(|max| size > max)
+            };
+            let (requested_min, requested_max) = self.buffer_frames_range();
+            if let Some(requested) = [requested_min, requested_max]
+                .into_iter()
+                .flatten()
+                .find(|&size| out_of_range(size))
+            {
+                violations.push(ConfigViolation::BufferSizeNotSupported {
+                    requested,
+                    min,
+                    max,
+                });
+            }
+        }
+        if violations.is_empty() {
+            violations.push(ConfigViolation::Unsupported);
+        }
+        Err(violations)
+    }
+}
+
+/// Size of an audio buffer, either in frames or as a duration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferSize {
+    /// Number of frames
+    Frames(usize),
+    /// Duration of the buffer, converted to a number of frames at the stream sample rate
+    Duration(Duration),
+}
+
+impl BufferSize {
+    /// Number of frames of a buffer of this size, at the given sample rate. Durations are
+    /// rounded to the nearest frame.
+    pub fn frames(&self, samplerate: f64) -> usize {
+        match self {
+            Self::Frames(frames) => *frames,
+            Self::Duration(duration) => (duration.as_secs_f64() * samplerate).round() as usize,
+        }
+    }
+}
+
+impl From<usize> for BufferSize {
+    fn from(frames: usize) -> Self {
+        Self::Frames(frames)
+    }
+}
+
+impl From<Duration> for BufferSize {
+    fn from(duration: Duration) -> Self {
+        Self::Duration(duration)
+    }
+}
+
+/// Per-channel gain offsets of a stream, in decibels, for example to calibrate the speakers of a
+/// monitoring setup without applying gains in the callback.
+///
+/// Entries correspond in order to the channels of the stream buffers, that is to the selected
+/// channels of the stream and not to the channels of the device. All channels start at 0 dB.
+///
+/// Trims cover 32 channels, which is the most a stream can open.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ChannelTrim {
+    gains: [f32; 32],
+}
+
+impl ChannelTrim {
+    /// Same trim, with the gain of the given channel set to `gain` dB.
+    ///
+    /// Panics when the channel is 32 or higher.
+    pub fn with_gain(mut self, channel: usize, gain: f32) -> Self {
+        self.set_gain(channel, gain);
+        self
+    }
+
+    /// Set the gain of the given channel, in decibels.
+    ///
+    /// Panics when the channel is 32 or higher.
+    pub fn set_gain(&mut self, channel: usize, gain: f32) {
+        self.gains[channel] = gain;
+    }
+
+    /// Gain of the given channel, in decibels. Channels past the trimmed channels have a gain of
+    /// 0 dB.
+    pub fn gain(&self, channel: usize) -> f32 {
+        self.gains.get(channel).copied().unwrap_or(0.)
+    }
+
+    /// Whether all channels are left at 0 dB.
+    pub fn is_unity(&self) -> bool {
+        self.gains.iter().all(|&gain| gain == 0.)
+    }
+
+    /// Linear amplitudes of the first `channels` channels, or `None` when none of them are
+    /// trimmed and samples can be passed through untouched.
+    pub(crate) fn amplitudes(&self, channels: usize) -> Option<Vec<f32>> {
+        let gains = &self.gains[..channels.min(self.gains.len())];
+        if gains.iter().all(|&gain| gain == 0.) {
+            return None;
+        }
+        let mut amplitudes = vec![1.; channels];
+        for (amplitude, gain) in amplitudes.iter_mut().zip(gains) {
+            *amplitude = 10f32.powf(gain / 20.);
+        }
+        Some(amplitudes)
+    }
+}
+
+/// Reason why a stream configuration cannot be used on a device, as given by
+/// [`StreamConfig::validate`].
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ConfigViolation {
+    /// The sample rate is not supported by the device.
+    #[error("Sample rate of {requested} Hz is not supported (supported rates: {supported:?})")]
+    SampleRateNotSupported {
+        /// Requested sample rate
+        requested: f64,
+        /// Sample rates supported by the device, in increasing order
+        supported: Vec<f64>,
+    },
+    /// Channels past the last channel of the device were requested.
+    #[error("{requested} channels are needed, but the device only has {available}")]
+    TooManyChannels {
+        /// Number of device channels needed to open all requested channels
+        requested: usize,
+        /// Number of channels of the device
+        available: usize,
+    },
+    /// The requested buffer size is outside of the range supported by the device.
+    #[error("Buffer size of {requested} frames is not supported (range: {min:?} to {max:?})")]
+    BufferSizeNotSupported {
+        /// Requested buffer size, in frames
+        requested: usize,
+        /// Minimum buffer size of the device, if known
+        min: Option<usize>,
+        /// Maximum buffer size of the device, if known
+        max: Option<usize>,
+    },
+    /// The device cannot be opened in exclusive mode.
+    #[error("Exclusive mode is not available on this device")]
+    ExclusiveNotAvailable,
+    /// The device does not support the configuration, for a reason it does not report.
+    #[error("Configuration is not supported by the device")]
+    Unsupported,
+}
+
+impl From<StreamConfig> for StreamConfig<ChannelMapDyn> {
+    fn from(config: StreamConfig) -> Self {
+        config.map_channels(ChannelMapDyn::from)
+    }
+}
+
+/// Fails when channels past the first 32 are selected, as streams cannot open them.
+impl TryFrom<StreamConfig<ChannelMapDyn>> for StreamConfig {
+    type Error = ChannelOutOfRange;
+
+    fn try_from(config: StreamConfig<ChannelMapDyn>) -> Result<Self, Self::Error> {
+        Ok(StreamConfig {
+            samplerate: config.samplerate,
+            input_channels: ChannelMap32::try_from(&config.input_channels)?,
+            output_channels: ChannelMap32::try_from(&config.output_channels)?,
+            buffer_size_range: config.buffer_size_range,
+            exclusive: config.exclusive,
+            fallback_to_shared: config.fallback_to_shared,
+            sample_format: config.sample_format,
+            input_trim: config.input_trim,
+            output_trim: config.output_trim,
+            metering: config.metering,
+        })
+    }
 }
 
 /// Audio channel description.
@@ -78,6 +446,8 @@ pub struct Channel<'a> {
     pub index: usize,
     /// Display name for the channel, if available, else a generic name like "Channel 1"
     pub name: Cow<'a, str>,
+    /// Speaker position of the channel, if reported by the driver
+    pub position: Option<ChannelPosition>,
 }
 
 /// Trait for types describing audio devices. Audio devices have zero or more inputs and outputs,
@@ -93,6 +463,14 @@ pub trait AudioDevice {
     /// Device type. Either input, output, or duplex.
     fn device_type(&self) -> DeviceType;
 
+    /// Stable identifier for this device, which can be persisted (for example in the settings
+    /// of an application) and given to [`AudioDriver::device_by_id`] to find the device again.
+    ///
+    /// The default implementation does not provide an identifier.
+    fn device_id(&self) -> Result<Option<Cow<'_, str>>, Self::Error> {
+        Ok(None)
+    }
+
     /// Iterator of the available channels in this device. Channel indices are used when
     /// specifying which channels to open when creating an audio stream.
     fn channel_map(&self) -> impl IntoIterator<Item = Channel>;
@@ -104,6 +482,39 @@ pub trait AudioDevice {
     /// Enumerate all possible configurations this device supports. If that is not provided by
     /// the device, and not easily generated manually, this will return `None`.
     fn enumerate_configurations(&self) -> Option<impl IntoIterator<Item = StreamConfig>>;
+
+    /// Whether streams can currently be opened with exclusive access to this device. This
+    /// requires the driver to support exclusive access, and no other application to hold the
+    /// device.
+    ///
+    /// The default implementation reports that exclusive access is not supported.
+    fn supports_exclusive(&self) -> bool {
+        false
+    }
+
+    /// Range of buffer sizes (in frames) this device supports, given as minimum and maximum
+    /// values. Either bound is `None` when it is not known.
+    ///
+    /// The default implementation does not provide any information.
+    fn buffer_size_range(&self) -> Result<(Option<usize>, Option<usize>), Self::Error> {
+        Ok((None, None))
+    }
+}
+
+/// Trait for devices exposing a hardware volume control, which is the same volume users change
+/// through the system mixer.
+pub trait AudioDeviceVolume: AudioDevice {
+    /// Current volume of the device, as a linear gain between 0 (silence) and 1 (full volume).
+    fn volume(&self) -> Result<f32, Self::Error>;
+
+    /// Change the volume of the device, given as a linear gain between 0 and 1.
+    fn set_volume(&self, volume: f32) -> Result<(), Self::Error>;
+
+    /// Whether the device is muted. Devices without a mute control are never muted.
+    fn is_muted(&self) -> Result<bool, Self::Error>;
+
+    /// Mute or unmute the device. This does nothing on devices without a mute control.
+    fn set_muted(&self, muted: bool) -> Result<(), Self::Error>;
 }
 
 /// Marker trait for values which are [Send] everywhere but on the web (as WASM does not yet have
@@ -190,6 +601,40 @@ pub trait AudioOutputDevice: AudioDevice {
     }
 }
 
+/// Trait for types which can provide duplex streams, where input and output audio are processed
+/// together in the same callback.
+///
+/// Duplex devices require a [`AudioDuplexCallback`] which receives the input audio data and
+/// produces the output audio data at the same time.
+pub trait AudioDuplexDevice: AudioDevice {
+    /// Type of the resulting stream. This stream can be used to control the audio processing
+    /// externally, or stop it completely and give back ownership of the callback with
+    /// [`AudioStreamHandle::eject`].
+    type StreamHandle<Callback: AudioDuplexCallback>: AudioStreamHandle<Callback>;
+
+    /// Default configuration for duplex streams on this device.
+    fn default_duplex_config(&self) -> Result<StreamConfig, Self::Error>;
+
+    /// Creates a duplex stream with the provided stream configuration, opening both its input
+    /// and output channels.
+    ///
+    /// A duplex callback is required to process the audio, whose ownership will be transferred
+    /// to the audio stream.
+    fn create_duplex_stream<Callback: SendEverywhereButOnWeb + AudioDuplexCallback>(
+        &self,
+        stream_config: StreamConfig,
+        callback: Callback,
+    ) -> Result<Self::StreamHandle<Callback>, Self::Error>;
+
+    /// Creates a duplex stream using the default duplex configuration of this device.
+    fn default_duplex_stream<Callback: SendEverywhereButOnWeb + AudioDuplexCallback>(
+        &self,
+        callback: Callback,
+    ) -> Result<Self::StreamHandle<Callback>, Self::Error> {
+        self.create_duplex_stream(self.default_duplex_config()?, callback)
+    }
+}
+
 /// Trait for types which handles an audio stream (input or output).
 pub trait AudioStreamHandle<Callback> {
     /// Type of errors which have caused the stream to fail.
@@ -200,39 +645,148 @@ pub trait AudioStreamHandle<Callback> {
     /// An error can occur when an irrecoverable error has occured and ownership has been lost
     /// already.
     fn eject(self) -> Result<Callback, Self::Error>;
+
+    /// Latency of the stream as reported by the driver, which can be used to compensate for the
+    /// time audio takes to go through the driver and the hardware.
+    ///
+    /// The default implementation does not provide any information.
+    fn latency(&self) -> StreamLatency {
+        StreamLatency::default()
+    }
+
+    /// Subscribe to the [events](crate::events::StreamEvent) of this stream, such as xruns and errors. Events
+    /// are received from the moment of subscription on.
+    ///
+    /// The default implementation returns a receiver which never receives any event.
+    fn subscribe_events(&self) -> StreamEventReceiver {
+        StreamEventReceiver::disconnected()
+    }
+
+    /// Levels of the latest buffers processed by the stream, when it was opened with
+    /// [`StreamConfig::metering`] set.
+    ///
+    /// The default implementation does not provide any information, returning `None`.
+    fn levels(&self) -> Option<StreamLevels> {
+        None
+    }
+
+    /// Control of the stream from other threads, to pause it, change its volume or replace its
+    /// callback. See the [`control`](crate::control) module.
+    ///
+    /// The default implementation returns `None`, for streams which cannot be controlled.
+    fn control(&self) -> Option<&StreamControl<Callback>> {
+        None
+    }
+}
+
+/// Latency of an audio stream, in frames. Each direction is `None` when the stream does not
+/// process audio in that direction, or when the latency is not known.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamLatency {
+    /// Frames between the moment audio is captured by the device and the moment it is given to
+    /// the callback.
+    pub input: Option<usize>,
+    /// Frames between the moment audio is written by the callback and the moment it is played
+    /// back by the device.
+    pub output: Option<usize>,
+}
+
+impl StreamLatency {
+    /// Round-trip latency, which is the sum of the input and output latencies, if both are
+    /// known.
+    pub fn round_trip(&self) -> Option<usize> {
+        Some(self.input? + self.output?)
+    }
+}
+
+/// Plain-old-data object holding references to the input audio buffer and the associated
+/// time-keeping [`Timestamp`]. This timestamp is associated with the stream, and in the cases
+/// where the driver provides timing information, it is used instead of relying on
+/// sample-counting.
+pub struct AudioInput<'a, T> {
+    /// Associated time stamp for this callback. The time represents the duration for which the
+    /// stream has been opened, and is either provided by the driver if available, or is kept up
+    /// manually by the library.
+    pub timestamp: Timestamp,
+    /// Time at which the first frame of the buffer was captured by the device, on the same clock
+    /// as [`AudioCallbackContext::output_time`]. This is `None` when the driver does not report
+    /// timing information.
+    pub capture_time: Option<HostTime>,
+    /// Audio buffer data.
+    pub buffer: AudioRef<'a, T>,
 }
 
-#[duplicate::duplicate_item(
-    name            bufty;
-    [AudioInput]    [AudioRef < 'a, T >];
-    [AudioOutput]   [AudioMut < 'a, T >];
-)]
-/// Plain-old-data object holding references to the audio buffer and the associated time-keeping
-/// [`Timestamp`]. This timestamp is associated with the stream, and in the cases where the
-/// driver provides timing information, it is used instead of relying on sample-counting.
-pub struct name<'a, T> {
+/// Plain-old-data object holding references to the output audio buffer and the associated
+/// time-keeping [`Timestamp`]. This timestamp is associated with the stream, and in the cases
+/// where the driver provides timing information, it is used instead of relying on
+/// sample-counting.
+pub struct AudioOutput<'a, T> {
     /// Associated time stamp for this callback. The time represents the duration for which the
     /// stream has been opened, and is either provided by the driver if available, or is kept up
     /// manually by the library.
     pub timestamp: Timestamp,
     /// Audio buffer data.
-    pub buffer: bufty,
+    pub buffer: AudioMut<'a, T>,
 }
 
 /// Plain-old-data object holding the passed-in stream configuration, as well as a general
 /// callback timestamp, which can be different from the input and output streams in case of
 /// cross-stream latencies; differences in timing can indicate desync.
+#[derive(Debug, Clone, Copy)]
 pub struct AudioCallbackContext {
     /// Passed-in stream configuration. Values have been updated where necessary to correspond to
     /// the actual stream properties.
     pub stream_config: StreamConfig,
     /// Callback-wide timestamp.
     pub timestamp: Timestamp,
+    /// Estimated time at which the first frame of the output buffer given to this callback is
+    /// played by the device, for sample-accurate scheduling against other clocks. Times of all
+    /// streams are given on the same [host clock](crate::clock).
+    ///
+    /// This is `None` for input streams, when preparing the callback, and when the driver does
+    /// not report timing information.
+    pub output_time: Option<HostTime>,
+    /// Time by which the callback has to return for the stream not to glitch. It is computed
+    /// from the time at which the callback was called and the duration of its buffers.
+    ///
+    /// This is `None` when preparing the callback.
+    pub deadline: Option<HostTime>,
+    /// Number of frames queued in the device when the callback was called. For output streams,
+    /// these are frames given to the device but not played yet; for input streams, frames
+    /// captured by the device but not given to the callback yet. Duplex streams report the
+    /// output side.
+    ///
+    /// This is `None` when preparing the callback, and when the driver does not report it.
+    pub queued_frames: Option<usize>,
+    /// Number of frames the stream advanced by since the previous callback. This is `None` when
+    /// preparing the callback, for the first callback, and when the timestamps of both callbacks
+    /// are of different generations.
+    pub frames_since_last_callback: Option<u64>,
+    /// Whether audio was lost or the stream restarted between the previous callback and this one
+    /// (after an xrun, a device stall or a recovery). Processing with internal state, such as
+    /// reverb tails or codecs, should reset it.
+    pub discontinuity: bool,
+}
+
+impl AudioCallbackContext {
+    /// Time remaining before the [deadline](Self::deadline) of the callback, which processing
+    /// can use to reduce its quality instead of glitching when running late. Returns zero once
+    /// the deadline has passed, and `None` when there is no deadline.
+    pub fn budget(&self) -> Option<Duration> {
+        let deadline = self.deadline?;
+        Some(deadline.saturating_duration_since(HostTime::now()))
+    }
 }
 
 /// Trait of types which process input audio data. This is the trait that users will want to
 /// implement when processing an input device.
 pub trait AudioInputCallback {
+    /// Prepare the callback for processing. This is called before any audio is processed, and
+    /// again whenever the stream configuration changes (for example after the stream has
+    /// recovered from its device disappearing).
+    #[allow(unused_variables)]
+    fn prepare(&mut self, context: AudioCallbackContext) {}
+
     /// Callback called when input data is available to be processed.
     fn on_input_data(&mut self, context: AudioCallbackContext, input: AudioInput<f32>);
 }
@@ -240,6 +794,12 @@ pub trait AudioInputCallback {
 /// Trait of types which process output audio data. This is the trait that users will want to
 /// implement when processing an output device.
 pub trait AudioOutputCallback {
+    /// Prepare the callback for processing. This is called before any audio is processed, and
+    /// again whenever the stream configuration changes (for example after the stream has
+    /// recovered from its device disappearing).
+    #[allow(unused_variables)]
+    fn prepare(&mut self, context: AudioCallbackContext) {}
+
     /// Callback called when output data is available to be processed.
     fn on_output_data(&mut self, context: AudioCallbackContext, input: AudioOutput<f32>);
 }
@@ -0,0 +1,221 @@
+//! Level metering built into streams.
+//!
+//! Streams opened with [`StreamConfig::metering`](crate::StreamConfig::metering) set measure the
+//! peak and RMS levels of each of their channels, for every buffer they process. The levels of
+//! the latest buffer are read from the stream handle with
+//! [`AudioStreamHandle::levels`](crate::AudioStreamHandle::levels), for instance to draw meters
+//! in a UI, without changing the callback.
+//!
+//! Levels go from the audio thread to readers through triple buffers, so that neither side ever
+//! waits for the other.
+//!
+//! ```no_run
+//! use interflow::prelude::*;
+//! let device = default_output_device();
+//! let mut config = device.default_output_config().unwrap();
+//! config.metering = true;
+//! let stream = device
+//!     .create_output_stream(config, interflow::signals::Sine::new(440.))
+//!     .unwrap();
+//! std::thread::sleep(std::time::Duration::from_millis(100));
+//! let levels = stream.levels().unwrap();
+//! for (channel, level) in levels.output.iter().enumerate() {
+//!     println!("Channel {channel}: peak {:.3}, RMS {:.3}", level.peak, level.rms);
+//! }
+//! stream.eject().unwrap();
+//! ```
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::audio_buffer::AudioRef;
+
+/// Levels of a channel over a buffer.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChannelLevel {
+    /// Peak linear amplitude.
+    pub peak: f32,
+    /// RMS linear amplitude.
+    pub rms: f32,
+}
+
+/// Levels of the channels of a stream, over the latest buffer it processed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamLevels {
+    /// Levels of the input channels, in the order of the input buffers. Empty for output
+    /// streams.
+    pub input: Vec<ChannelLevel>,
+    /// Levels of the output channels, in the order of the output buffers. Empty for input
+    /// streams.
+    pub output: Vec<ChannelLevel>,
+}
+
+/// Create a level tap for buffers of the given number of channels, along with its reader.
+pub(crate) fn level_tap(channels: usize) -> (LevelTap, LevelReader) {
+    let slot = || UnsafeCell::new(vec![ChannelLevel::default(); channels].into_boxed_slice());
+    let shared = Arc::new(TripleBuffer {
+        slots: [slot(), slot(), slot()],
+        middle: AtomicUsize::new(1),
+    });
+    let tap = LevelTap {
+        shared: shared.clone(),
+        back: 0,
+    };
+    let reader = LevelReader { shared, front: 2 };
+    (tap, reader)
+}
+
+/// Audio thread side of a meter, measuring buffers.
+pub(crate) struct LevelTap {
+    shared: Arc<TripleBuffer>,
+    back: usize,
+}
+
+impl LevelTap {
+    /// Measure the levels of the given buffer, and publish them.
+    pub(crate) fn process(&mut self, buffer: AudioRef<f32>) {
+        // Safety: the back slot is only accessed by the tap until it is published
+        let levels = unsafe { &mut *self.shared.slots[self.back].get() };
+        for (level, samples) in levels.iter_mut().zip(buffer.channels()) {
+            let mut peak = 0f32;
+            let mut sum = 0f32;
+            for sample in samples.iter() {
+                peak = peak.max(sample.abs());
+                sum += sample * sample;
+            }
+            level.peak = peak;
+            level.rms = (sum / samples.len().max(1) as f32).sqrt();
+        }
+        let previous = self.shared.middle.swap(self.back | FRESH, Ordering::AcqRel);
+        self.back = previous & INDEX_MASK;
+    }
+}
+
+/// Reading side of a meter.
+pub(crate) struct LevelReader {
+    shared: Arc<TripleBuffer>,
+    front: usize,
+}
+
+impl LevelReader {
+    /// Latest levels published by the tap.
+    pub(crate) fn read(&mut self) -> &[ChannelLevel] {
+        if self.shared.middle.load(Ordering::Relaxed) & FRESH != 0 {
+            let previous = self.shared.middle.swap(self.front, Ordering::AcqRel);
+            self.front = previous & INDEX_MASK;
+        }
+        // Safety: the front slot is only accessed by the reader until it is swapped back
+        unsafe { &*self.shared.slots[self.front].get() }
+    }
+}
+
+/// Meters of a stream, as kept by its handle.
+#[derive(Default)]
+pub(crate) struct StreamMeter {
+    input: Option<Mutex<LevelReader>>,
+    output: Option<Mutex<LevelReader>>,
+}
+
+impl StreamMeter {
+    /// Create the meter of a stream and its taps, for the given number of input and output
+    /// channels. There are no taps when metering is disabled.
+    pub(crate) fn new(
+        enabled: bool,
+        input_channels: usize,
+        output_channels: usize,
+    ) -> (Self, Option<LevelTap>, Option<LevelTap>) {
+        if !enabled {
+            return (Self::default(), None, None);
+        }
+        let (input_tap, input) = match input_channels {
+            0 => (None, None),
+            channels => {
+                let (tap, reader) = level_tap(channels);
+                (Some(tap), Some(Mutex::new(reader)))
+            }
+        };
+        let (output_tap, output) = match output_channels {
+            0 => (None, None),
+            channels => {
+                let (tap, reader) = level_tap(channels);
+                (Some(tap), Some(Mutex::new(reader)))
+            }
+        };
+        (Self { input, output }, input_tap, output_tap)
+    }
+
+    /// Latest levels of the stream, or `None` when metering is disabled.
+    pub(crate) fn levels(&self) -> Option<StreamLevels> {
+        let read = |reader: &Option<Mutex<LevelReader>>| {
+            reader.as_ref().map_or(Vec::new(), |reader| {
+                let mut reader = reader.lock().unwrap_or_else(|err| err.into_inner());
+                reader.read().to_vec()
+            })
+        };
+        if self.input.is_none() && self.output.is_none() {
+            return None;
+        }
+        Some(StreamLevels {
+            input: read(&self.input),
+            output: read(&self.output),
+        })
+    }
+}
+
+/// Flag set on the middle index when it holds levels the reader has not seen yet.
+const FRESH: usize = 0b100;
+const INDEX_MASK: usize = 0b11;
+
+/// Three slots of levels, owned by the tap (back), the reader (front), or waiting to be read
+/// (middle).
+struct TripleBuffer {
+    slots: [UnsafeCell<Box<[ChannelLevel]>>; 3],
+    /// Index of the middle slot, with the [`FRESH`] flag
+    middle: AtomicUsize,
+}
+
+// Safety: slots are only accessed by the side owning them, which hand them over through `middle`
+unsafe impl Sync for TripleBuffer {}
+
+#[cfg(test)]
+mod test {
+    use super::{level_tap, ChannelLevel};
+    use crate::audio_buffer::AudioRef;
+
+    fn levels(peak: f32, rms: f32) -> ChannelLevel {
+        ChannelLevel { peak, rms }
+    }
+
+    #[test]
+    fn test_read_before_publish() {
+        let (_tap, mut reader) = level_tap(2);
+        assert_eq!([ChannelLevel::default(); 2], reader.read());
+    }
+
+    #[test]
+    fn test_publish_then_read() {
+        let (mut tap, mut reader) = level_tap(2);
+        let samples = [0.5, -1., -0.5, 1.];
+        tap.process(AudioRef::from_interleaved(&samples, 2).unwrap());
+        assert_eq!([levels(0.5, 0.5), levels(1., 1.)], reader.read());
+        // Reading again without a new buffer gives the same levels
+        assert_eq!([levels(0.5, 0.5), levels(1., 1.)], reader.read());
+    }
+
+    #[test]
+    fn test_read_latest() {
+        let (mut tap, mut reader) = level_tap(1);
+        for level in [0.25, 0.5, 0.75] {
+            tap.process(AudioRef::from_interleaved(&[level], 1).unwrap());
+        }
+        assert_eq!([levels(0.75, 0.75)], reader.read());
+        tap.process(AudioRef::from_interleaved(&[0.125], 1).unwrap());
+        assert_eq!([levels(0.125, 0.125)], reader.read());
+        // The slot given back by the reader is reused without publishing stale levels
+        tap.process(AudioRef::from_interleaved(&[1.], 1).unwrap());
+        tap.process(AudioRef::from_interleaved(&[0.5], 1).unwrap());
+        assert_eq!([levels(0.5, 0.5)], reader.read());
+        assert_eq!([levels(0.5, 0.5)], reader.read());
+    }
+}
@@ -0,0 +1,478 @@
+//! Software mixer sharing a single output stream between several parts of an application.
+//!
+//! [`create_mixer`] opens an output stream whose callback sums the audio of any number of
+//! voices. Voices are added from any thread, either as callbacks rendering their own audio
+//! ([`Mixer::add_voice`]) or as queues of samples written from another thread
+//! ([`Mixer::add_buffer_voice`]), and are controlled through [`Voice`] handles. The mixer
+//! callback neither allocates nor locks.
+//!
+//! ```no_run
+//! use interflow::prelude::*;
+//! use interflow::mixer::create_mixer;
+//! let device = default_output_device();
+//! let config = device.default_output_config().unwrap();
+//! let mixer = create_mixer(&device, config).unwrap();
+//!
+//! // Samples written by a decoder thread, for instance
+//! let mut music = mixer.add_buffer_voice(48000).unwrap();
+//! music.voice().set_gain(0.5);
+//! let silence = [0f32; 2 * 480];
+//! music.write(&silence);
+//!
+//! // Dropping a voice removes it from the mix
+//! drop(music);
+//! mixer.stop().unwrap();
+//! ```
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::audio_buffer::AudioBuffer;
+use crate::channel_map::Bitset;
+use crate::timestamp::Timestamp;
+use crate::{
+    AudioCallbackContext, AudioOutput, AudioOutputCallback, AudioOutputDevice, AudioStreamHandle,
+    StreamConfig,
+};
+
+/// Maximum number of voices a mixer plays at the same time. Voices added past this number wait
+/// for others to be removed.
+pub const MAX_VOICES: usize = 64;
+
+/// Number of frames voices render at a time; larger buffers are rendered in several chunks.
+const CHUNK_FRAMES: usize = 512;
+
+/// Create a mixer playing on the given output device with the given stream configuration.
+pub fn create_mixer<Device: AudioOutputDevice>(
+    device: &Device,
+    stream_config: StreamConfig,
+) -> Result<Mixer<Device::StreamHandle<MixerCallback>>, Device::Error> {
+    new_mixer(stream_config, |callback| {
+        device.create_output_stream(stream_config, callback)
+    })
+}
+
+/// Create a mixer whose callback is run by the stream created with `create_stream`.
+fn new_mixer<Handle, Error>(
+    stream_config: StreamConfig,
+    create_stream: impl FnOnce(MixerCallback) -> Result<Handle, Error>,
+) -> Result<Mixer<Handle>, Error> {
+    let (commands, incoming) = rtrb::RingBuffer::new(MAX_VOICES);
+    let (outgoing, garbage) = rtrb::RingBuffer::new(MAX_VOICES);
+    let channels = Arc::new(AtomicUsize::new(stream_config.output_channels.count()));
+    let callback = MixerCallback {
+        stream_config,
+        channels: channels.clone(),
+        incoming,
+        outgoing,
+        voices: Vec::with_capacity(MAX_VOICES),
+    };
+    let handle = create_stream(callback)?;
+    Ok(Mixer {
+        handle,
+        channels,
+        commands: Mutex::new(commands),
+        garbage: Arc::new(Mutex::new(garbage)),
+    })
+}
+
+/// Mixer playing voices on an output stream, created by [`create_mixer`].
+///
+/// Voices can be added through a shared reference, so that the mixer can be shared between
+/// threads.
+pub struct Mixer<Handle> {
+    handle: Handle,
+    /// Number of output channels of the stream, as resolved by the backend.
+    channels: Arc<AtomicUsize>,
+    commands: Mutex<rtrb::Producer<VoiceSlot>>,
+    garbage: Arc<Mutex<rtrb::Consumer<VoiceSlot>>>,
+}
+
+impl<Handle> Mixer<Handle> {
+    /// Number of output channels voices render. This is the number of channels the stream was
+    /// opened with, which may differ from the requested configuration once the stream started.
+    pub fn channels(&self) -> usize {
+        self.channels.load(Ordering::Relaxed)
+    }
+
+    /// Add a voice rendering its audio with the given callback, which is prepared and called
+    /// from the audio thread. Returns `None` when too many voices are waiting to be added.
+    pub fn add_voice(&self, callback: impl 'static + Send + AudioOutputCallback) -> Option<Voice> {
+        self.collect_garbage();
+        let shared = Arc::new(VoiceShared {
+            gain: AtomicU32::new(1f32.to_bits()),
+            removed: AtomicBool::new(false),
+        });
+        let slot = VoiceSlot {
+            shared: shared.clone(),
+            callback: Box::new(callback),
+            scratch: AudioBuffer::zeroed(self.channels(), CHUNK_FRAMES),
+            prepared: false,
+        };
+        let mut commands = self.commands.lock().unwrap_or_else(|err| err.into_inner());
+        commands.push(slot).ok()?;
+        Some(Voice {
+            shared,
+            garbage: self.garbage.clone(),
+        })
+    }
+
+    /// Add a voice playing interleaved samples written to the returned handle, which can queue
+    /// up to `capacity` frames. Frames are played as soon as they are written; silence is played
+    /// when the queue runs empty.
+    pub fn add_buffer_voice(&self, capacity: usize) -> Option<BufferVoice> {
+        let channels = self.channels().max(1);
+        let (producer, consumer) = rtrb::RingBuffer::new(capacity * channels);
+        let voice = self.add_voice(BufferSource { consumer })?;
+        Some(BufferVoice {
+            voice,
+            producer,
+            channels,
+        })
+    }
+
+    /// Drop the voices removed by the audio thread, which hands them back instead of
+    /// deallocating them itself.
+    ///
+    /// Removed voices are collected when voices are added or dropped. The audio thread keeps
+    /// removed voices, without playing them, while too many of them wait to be collected, so
+    /// applications which remove voices without adding or dropping others should call this
+    /// regularly.
+    pub fn collect_garbage(&self) {
+        let mut garbage = self.garbage.lock().unwrap_or_else(|err| err.into_inner());
+        while garbage.pop().is_ok() {}
+    }
+}
+
+impl<Handle: AudioStreamHandle<MixerCallback>> Mixer<Handle> {
+    /// Stop the output stream, dropping all voices.
+    pub fn stop(self) -> Result<(), Handle::Error> {
+        self.handle.eject()?;
+        Ok(())
+    }
+}
+
+/// Handle to a voice of a [`Mixer`]. Dropping it removes the voice from the mix.
+pub struct Voice {
+    shared: Arc<VoiceShared>,
+    garbage: Arc<Mutex<rtrb::Consumer<VoiceSlot>>>,
+}
+
+impl fmt::Debug for Voice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Voice")
+            .field("shared", &self.shared)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Voice {
+    /// Linear gain applied to the voice.
+    pub fn gain(&self) -> f32 {
+        f32::from_bits(self.shared.gain.load(Ordering::Relaxed))
+    }
+
+    /// Set the linear gain applied to the voice.
+    pub fn set_gain(&self, gain: f32) {
+        self.shared.gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+}
+
+impl Drop for Voice {
+    fn drop(&mut self) {
+        self.shared.removed.store(true, Ordering::Relaxed);
+        // This voice is handed back by the audio thread later on, collect the voices removed
+        // before it. Voices may own other voices, whose handles are then dropped while the
+        // garbage is already being collected.
+        if let Ok(mut garbage) = self.garbage.try_lock() {
+            while garbage.pop().is_ok() {}
+        }
+    }
+}
+
+/// Voice of a [`Mixer`] playing samples written to it, created by [`Mixer::add_buffer_voice`].
+/// Dropping it removes the voice from the mix, including samples not played yet.
+pub struct BufferVoice {
+    voice: Voice,
+    producer: rtrb::Producer<f32>,
+    channels: usize,
+}
+
+impl BufferVoice {
+    /// Handle controlling this voice.
+    pub fn voice(&self) -> &Voice {
+        &self.voice
+    }
+
+    /// Number of frames that can be written without blocking.
+    pub fn available(&self) -> usize {
+        self.producer.slots() / self.channels
+    }
+
+    /// Queue interleaved samples to be played, returning the number of frames written. Only
+    /// whole frames are written, and only as many as there is room for.
+    pub fn write(&mut self, samples: &[f32]) -> usize {
+        let frames = self.available().min(samples.len() / self.channels);
+        let len = frames * self.channels;
+        if let Ok(chunk) = self.producer.write_chunk_uninit(len) {
+            chunk.fill_from_iter(samples[..len].iter().copied());
+        }
+        frames
+    }
+}
+
+/// Output callback of a [`Mixer`], which renders and sums its voices.
+pub struct MixerCallback {
+    stream_config: StreamConfig,
+    channels: Arc<AtomicUsize>,
+    incoming: rtrb::Consumer<VoiceSlot>,
+    outgoing: rtrb::Producer<VoiceSlot>,
+    voices: Vec<VoiceSlot>,
+}
+
+impl AudioOutputCallback for MixerCallback {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.stream_config = context.stream_config;
+        let channels = context.stream_config.output_channels.count();
+        self.channels.store(channels, Ordering::Relaxed);
+        // Voices added before the stream resolved its configuration render the requested number
+        // of channels, take them in to resize their scratch buffer while allocating is allowed
+        while self.voices.len() < MAX_VOICES {
+            let Ok(voice) = self.incoming.pop() else {
+                break;
+            };
+            self.voices.push(voice);
+        }
+        for voice in &mut self.voices {
+            if voice.scratch.num_channels() != channels {
+                voice.scratch = AudioBuffer::zeroed(channels, CHUNK_FRAMES);
+            }
+            // Voices are prepared again before they are next rendered
+            voice.prepared = false;
+        }
+    }
+
+    fn on_output_data(&mut self, context: AudioCallbackContext, mut output: AudioOutput<f32>) {
+        while self.voices.len() < MAX_VOICES {
+            let Ok(voice) = self.incoming.pop() else {
+                break;
+            };
+            self.voices.push(voice);
+        }
+        output.buffer.as_interleaved_mut().fill(0.);
+
+        let mut i = 0;
+        while i < self.voices.len() {
+            if self.voices[i].shared.removed.load(Ordering::Relaxed) {
+                // Keep the voice until it can be handed back, so that it is never deallocated
+                // here
+                if self.outgoing.is_full() {
+                    i += 1;
+                    continue;
+                }
+                let voice = self.voices.swap_remove(i);
+                let _ = self.outgoing.push(voice);
+                continue;
+            }
+            let voice = &mut self.voices[i];
+            if !voice.prepared {
+                voice.callback.prepare(AudioCallbackContext {
+                    stream_config: self.stream_config,
+                    timestamp: Timestamp::new(self.stream_config.samplerate),
+                    output_time: None,
+                    deadline: None,
+                    queued_frames: None,
+                    frames_since_last_callback: None,
+                    discontinuity: false,
+                });
+                voice.prepared = true;
+            }
+            voice.render(&context, &mut output);
+            i += 1;
+        }
+    }
+}
+
+/// Control state of a voice, shared between its handle and the audio thread.
+#[derive(Debug)]
+struct VoiceShared {
+    gain: AtomicU32,
+    removed: AtomicBool,
+}
+
+/// Voice as owned by the audio thread.
+struct VoiceSlot {
+    shared: Arc<VoiceShared>,
+    callback: Box<dyn Send + AudioOutputCallback>,
+    scratch: AudioBuffer<f32>,
+    prepared: bool,
+}
+
+impl VoiceSlot {
+    fn render(&mut self, context: &AudioCallbackContext, output: &mut AudioOutput<f32>) {
+        let gain = f32::from_bits(self.shared.gain.load(Ordering::Relaxed));
+        let samplerate = context.stream_config.samplerate;
+        let num_samples = output.buffer.num_samples();
+        let mut offset = 0;
+        while offset < num_samples {
+            let frames = CHUNK_FRAMES.min(num_samples - offset);
+            let offset_duration = Duration::from_secs_f64(offset as f64 / samplerate);
+            let context = AudioCallbackContext {
+                stream_config: context.stream_config,
+                timestamp: context.timestamp + offset as u64,
+                output_time: context.output_time.map(|time| time + offset_duration),
+                deadline: context.deadline,
+                queued_frames: context.queued_frames,
+                frames_since_last_callback: context.frames_since_last_callback,
+                discontinuity: context.discontinuity,
+            };
+            let mut scratch = self.scratch.slice_mut(..frames);
+            scratch.as_interleaved_mut().fill(0.);
+            self.callback.on_output_data(
+                context,
+                AudioOutput {
+                    timestamp: output.timestamp + offset as u64,
+                    buffer: scratch,
+                },
+            );
+            output
+                .buffer
+                .slice_mut(offset..offset + frames)
+                .mix(self.scratch.slice(..frames), gain);
+            offset += frames;
+        }
+    }
+}
+
+/// Source of a [`BufferVoice`], playing the samples written to it.
+struct BufferSource {
+    consumer: rtrb::Consumer<f32>,
+}
+
+impl AudioOutputCallback for BufferSource {
+    fn on_output_data(&mut self, _context: AudioCallbackContext, mut output: AudioOutput<f32>) {
+        let channels = output.buffer.num_channels();
+        let frames = output
+            .buffer
+            .num_samples()
+            .min(self.consumer.slots() / channels.max(1));
+        for i in 0..frames {
+            for sample in output.buffer.get_frame_mut(i) {
+                *sample = self.consumer.pop().unwrap_or(0.);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{new_mixer, Mixer, MixerCallback};
+    use crate::audio_buffer::AudioBuffer;
+    use crate::timestamp::Timestamp;
+    use crate::{
+        AudioCallbackContext, AudioOutput, AudioOutputCallback, ChannelTrim, StreamConfig,
+    };
+
+    const FRAMES: usize = 64;
+
+    /// Voice playing a constant signal.
+    struct Constant(f32);
+
+    impl AudioOutputCallback for Constant {
+        fn on_output_data(&mut self, _context: AudioCallbackContext, mut output: AudioOutput<f32>) {
+            output.buffer.as_interleaved_mut().fill(self.0);
+        }
+    }
+
+    fn context(output_channels: u32) -> AudioCallbackContext {
+        AudioCallbackContext {
+            stream_config: StreamConfig {
+                samplerate: 48000.,
+                input_channels: 0,
+                output_channels,
+                buffer_size_range: (None, None),
+                exclusive: false,
+                fallback_to_shared: false,
+                sample_format: None,
+                input_trim: ChannelTrim::default(),
+                output_trim: ChannelTrim::default(),
+                metering: false,
+            },
+            timestamp: Timestamp::new(48000.),
+            output_time: None,
+            deadline: None,
+            queued_frames: None,
+            frames_since_last_callback: None,
+            discontinuity: false,
+        }
+    }
+
+    fn mixer(output_channels: u32) -> (Mixer<()>, MixerCallback) {
+        let mut callback = None;
+        let mixer = new_mixer(context(output_channels).stream_config, |mixer_callback| {
+            callback = Some(mixer_callback);
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+        (mixer, callback.unwrap())
+    }
+
+    fn render(callback: &mut MixerCallback, channels: usize) -> AudioBuffer<f32> {
+        let mut buffer = AudioBuffer::zeroed(channels, FRAMES);
+        let output = AudioOutput {
+            buffer: buffer.as_mut(),
+            timestamp: Timestamp::new(48000.),
+        };
+        callback.on_output_data(context(0), output);
+        buffer
+    }
+
+    #[test]
+    fn test_mixer_uses_resolved_channels() {
+        // Stereo is requested, but the stream opens a single channel
+        let (mixer, mut callback) = mixer(0b11);
+        let early = mixer.add_voice(Constant(1.)).unwrap();
+        callback.prepare(context(0b1));
+        assert_eq!(1, mixer.channels());
+        assert_eq!(1, callback.voices[0].scratch.num_channels());
+        let mut late = mixer.add_buffer_voice(FRAMES).unwrap();
+        assert_eq!(FRAMES, late.write(&[1.; FRAMES]));
+        assert_eq!(1, callback.incoming.slots());
+        let output = render(&mut callback, 1);
+        assert_eq!(1, callback.voices[1].scratch.num_channels());
+        assert!(output.as_interleaved().iter().all(|&x| x == 2.));
+        drop((early, late));
+    }
+
+    #[test]
+    fn test_mixer_voices() {
+        let (mixer, mut callback) = mixer(0b11);
+        callback.prepare(context(0b11));
+        let mut first = mixer.add_buffer_voice(FRAMES).unwrap();
+        let mut second = mixer.add_buffer_voice(FRAMES).unwrap();
+        first.voice().set_gain(0.5);
+        second.voice().set_gain(0.25);
+        first.write(&[1.; 2 * FRAMES]);
+        second.write(&[2.; 2 * FRAMES]);
+        let output = render(&mut callback, 2);
+        assert_eq!(2, callback.voices.len());
+        assert!(output.as_interleaved().iter().all(|&x| x == 1.));
+
+        // Removed voices are handed back to be dropped outside the audio thread
+        drop(first);
+        second.write(&[2.; 2 * FRAMES]);
+        let output = render(&mut callback, 2);
+        assert_eq!(1, callback.voices.len());
+        assert!(output.as_interleaved().iter().all(|&x| x == 0.5));
+        assert_eq!(1, mixer.garbage.lock().unwrap().slots());
+        mixer.collect_garbage();
+        assert_eq!(0, mixer.garbage.lock().unwrap().slots());
+
+        drop(second);
+        let output = render(&mut callback, 2);
+        assert!(callback.voices.is_empty());
+        assert!(output.as_interleaved().iter().all(|&x| x == 0.));
+    }
+}
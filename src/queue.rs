@@ -0,0 +1,368 @@
+//! Blocking read/write access to streams, for applications preferring to push and pull audio
+//! rather than providing callbacks.
+//!
+//! [`create_output_queue`] and [`create_input_queue`] open streams whose callbacks exchange audio
+//! with ring buffers, which the application writes to with [`OutputQueue::write`] or reads from
+//! with [`InputQueue::read`]. Both block until all the requested audio has been transferred,
+//! in the manner of PortAudio's blocking API. How much audio is buffered, and when blocked
+//! threads wake up, is configured with [`QueueConfig`].
+//!
+//! ```no_run
+//! use interflow::prelude::*;
+//! use interflow::queue::{create_output_queue, QueueConfig};
+//! let device = default_output_device();
+//! let config = device.default_output_config().unwrap();
+//! let mut queue = create_output_queue(&device, config, QueueConfig::new(4096)).unwrap();
+//! let mut sine = interflow::signals::Sine::new(440.);
+//! let channels = queue.channels();
+//! let mut samples = vec![0.; 512 * channels];
+//! for _ in 0..100 {
+//!     for frame in samples.chunks_mut(channels) {
+//!         frame.fill(sine.next_sample(config.samplerate as f32));
+//!     }
+//!     queue.write(&samples);
+//! }
+//! queue.stop().unwrap();
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::channel_map::Bitset;
+use crate::{
+    AudioCallbackContext, AudioInput, AudioInputCallback, AudioInputDevice, AudioOutput,
+    AudioOutputCallback, AudioOutputDevice, AudioStreamHandle, StreamConfig,
+};
+
+/// Shortest time blocked readers and writers sleep for between checks of the queue.
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Buffering configuration of a queue. All quantities are in frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// Maximum number of frames held in the queue.
+    pub capacity: usize,
+    /// Output queues: writers blocked on a full queue resume once it has drained down to this
+    /// many frames. Unused by input queues.
+    pub low_watermark: usize,
+    /// Output queues: playback starts, or resumes after the queue has run dry, once this many
+    /// frames have been written. Input queues: readers blocked on an empty queue resume once this
+    /// many frames, or enough to complete their read, are available.
+    pub high_watermark: usize,
+}
+
+impl QueueConfig {
+    /// Configuration for a queue of the given capacity, with watermarks at a quarter and half of
+    /// it.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            low_watermark: capacity / 4,
+            high_watermark: capacity / 2,
+        }
+    }
+}
+
+/// Create an output stream on the given device, playing the audio written to the returned queue.
+pub fn create_output_queue<Device: AudioOutputDevice>(
+    device: &Device,
+    stream_config: StreamConfig,
+    queue_config: QueueConfig,
+) -> Result<OutputQueue<Device::StreamHandle<OutputQueueCallback>>, Device::Error> {
+    let channels = stream_config.output_channels.count().max(1);
+    let capacity = queue_config.capacity.max(1);
+    let (producer, consumer) = rtrb::RingBuffer::new(capacity * channels);
+    let shared = Arc::new(Shared::new(stream_config.samplerate));
+    let callback = OutputQueueCallback {
+        consumer,
+        channels,
+        high_watermark: queue_config.high_watermark.min(capacity),
+        playing: false,
+        shared: shared.clone(),
+    };
+    let handle = device.create_output_stream(stream_config, callback)?;
+    Ok(OutputQueue {
+        handle,
+        producer,
+        channels,
+        capacity,
+        low_watermark: queue_config.low_watermark.min(capacity),
+        shared,
+    })
+}
+
+/// Create an input stream on the given device, whose audio is read from the returned queue.
+pub fn create_input_queue<Device: AudioInputDevice>(
+    device: &Device,
+    stream_config: StreamConfig,
+    queue_config: QueueConfig,
+) -> Result<InputQueue<Device::StreamHandle<InputQueueCallback>>, Device::Error> {
+    let channels = stream_config.input_channels.count().max(1);
+    let capacity = queue_config.capacity.max(1);
+    let (producer, consumer) = rtrb::RingBuffer::new(capacity * channels);
+    let shared = Arc::new(Shared::new(stream_config.samplerate));
+    let callback = InputQueueCallback {
+        producer,
+        shared: shared.clone(),
+    };
+    let handle = device.create_input_stream(stream_config, callback)?;
+    Ok(InputQueue {
+        handle,
+        consumer,
+        channels,
+        high_watermark: queue_config.high_watermark.clamp(1, capacity),
+        shared,
+    })
+}
+
+/// Queue of audio played by an output stream, created by [`create_output_queue`].
+pub struct OutputQueue<Handle> {
+    handle: Handle,
+    producer: rtrb::Producer<f32>,
+    channels: usize,
+    capacity: usize,
+    low_watermark: usize,
+    shared: Arc<Shared>,
+}
+
+impl<Handle> OutputQueue<Handle> {
+    /// Number of channels of the interleaved samples written to the queue.
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Number of frames that can be written without blocking.
+    pub fn write_available(&self) -> usize {
+        self.producer.slots() / self.channels
+    }
+
+    /// Number of frames which were not written in time to be played, and were replaced with
+    /// silence.
+    pub fn underruns(&self) -> u64 {
+        self.shared.xruns.load(Ordering::Relaxed)
+    }
+
+    /// Write interleaved samples to the queue, blocking until they have all been queued. Only
+    /// whole frames are written. Returns the number of frames written, which is less than
+    /// requested only when the stream has stopped.
+    pub fn write(&mut self, samples: &[f32]) -> usize {
+        let frames = samples.len() / self.channels;
+        let mut written = 0;
+        while written < frames {
+            if self.producer.is_abandoned() {
+                break;
+            }
+            let available = self.write_available();
+            if available == 0 {
+                self.wait_for_low_watermark();
+                continue;
+            }
+            let count = available.min(frames - written);
+            let start = written * self.channels;
+            let len = count * self.channels;
+            if let Ok(chunk) = self.producer.write_chunk_uninit(len) {
+                chunk.fill_from_iter(samples[start..start + len].iter().copied());
+            }
+            written += count;
+        }
+        written
+    }
+
+    /// Sleep until the queue has drained down to the low watermark.
+    fn wait_for_low_watermark(&self) {
+        loop {
+            let queued = self.capacity - self.write_available();
+            if queued <= self.low_watermark || self.producer.is_abandoned() {
+                return;
+            }
+            std::thread::sleep(self.shared.duration_of(queued - self.low_watermark));
+        }
+    }
+}
+
+impl<Handle: AudioStreamHandle<OutputQueueCallback>> OutputQueue<Handle> {
+    /// Stop the output stream, dropping any audio not played yet.
+    pub fn stop(self) -> Result<(), Handle::Error> {
+        self.handle.eject()?;
+        Ok(())
+    }
+}
+
+/// Queue of audio captured by an input stream, created by [`create_input_queue`].
+pub struct InputQueue<Handle> {
+    handle: Handle,
+    consumer: rtrb::Consumer<f32>,
+    channels: usize,
+    high_watermark: usize,
+    shared: Arc<Shared>,
+}
+
+impl<Handle> InputQueue<Handle> {
+    /// Number of channels of the interleaved samples read from the queue.
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Number of frames that can be read without blocking.
+    pub fn read_available(&self) -> usize {
+        self.consumer.slots() / self.channels
+    }
+
+    /// Number of captured frames dropped because the queue was full.
+    pub fn overruns(&self) -> u64 {
+        self.shared.xruns.load(Ordering::Relaxed)
+    }
+
+    /// Read interleaved samples from the queue, blocking until the buffer has been filled. Only
+    /// whole frames are read. Returns the number of frames read, which is less than requested
+    /// only when the stream has stopped.
+    pub fn read(&mut self, samples: &mut [f32]) -> usize {
+        let frames = samples.len() / self.channels;
+        let mut read = 0;
+        while read < frames {
+            let available = self.read_available();
+            if available == 0 {
+                if self.consumer.is_abandoned() {
+                    break;
+                }
+                self.wait_for_frames(self.high_watermark.min(frames - read));
+                continue;
+            }
+            let count = available.min(frames - read);
+            let start = read * self.channels;
+            let len = count * self.channels;
+            if let Ok(chunk) = self.consumer.read_chunk(len) {
+                let (first, second) = chunk.as_slices();
+                samples[start..start + first.len()].copy_from_slice(first);
+                samples[start + first.len()..start + len].copy_from_slice(second);
+                chunk.commit_all();
+            }
+            read += count;
+        }
+        read
+    }
+
+    /// Sleep until the given number of frames are available.
+    fn wait_for_frames(&self, frames: usize) {
+        loop {
+            let available = self.read_available();
+            if available >= frames || self.consumer.is_abandoned() {
+                return;
+            }
+            std::thread::sleep(self.shared.duration_of(frames - available));
+        }
+    }
+}
+
+impl<Handle: AudioStreamHandle<InputQueueCallback>> InputQueue<Handle> {
+    /// Stop the input stream, dropping any audio not read yet.
+    pub fn stop(self) -> Result<(), Handle::Error> {
+        self.handle.eject()?;
+        Ok(())
+    }
+}
+
+/// Output callback of an [`OutputQueue`], playing the audio written to it.
+pub struct OutputQueueCallback {
+    consumer: rtrb::Consumer<f32>,
+    channels: usize,
+    high_watermark: usize,
+    /// Whether enough audio was queued to start playback
+    playing: bool,
+    shared: Arc<Shared>,
+}
+
+impl AudioOutputCallback for OutputQueueCallback {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.shared.set_samplerate(context.stream_config.samplerate);
+    }
+
+    fn on_output_data(&mut self, _context: AudioCallbackContext, mut output: AudioOutput<f32>) {
+        let num_samples = output.buffer.num_samples();
+        let available = self.consumer.slots() / self.channels;
+        if !self.playing && available >= self.high_watermark {
+            self.playing = true;
+        }
+        let frames = if self.playing {
+            num_samples.min(available)
+        } else {
+            0
+        };
+        for i in 0..frames {
+            let mut frame = output.buffer.get_frame_mut(i);
+            for ch in 0..self.channels {
+                let sample = self.consumer.pop().unwrap_or(0.);
+                if let Some(out) = frame.get_mut(ch) {
+                    *out = sample;
+                }
+            }
+        }
+        for i in frames..num_samples {
+            output.buffer.get_frame_mut(i).fill(0.);
+        }
+        if self.playing && frames < num_samples {
+            // Wait for the queue to fill up to the high watermark again before resuming
+            self.playing = false;
+            self.shared
+                .xruns
+                .fetch_add((num_samples - frames) as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Input callback of an [`InputQueue`], queueing the audio it captures.
+pub struct InputQueueCallback {
+    producer: rtrb::Producer<f32>,
+    shared: Arc<Shared>,
+}
+
+impl AudioInputCallback for InputQueueCallback {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.shared.set_samplerate(context.stream_config.samplerate);
+    }
+
+    fn on_input_data(&mut self, _context: AudioCallbackContext, input: AudioInput<f32>) {
+        let frames = input.buffer.num_samples();
+        let len = frames * input.buffer.num_channels();
+        match self.producer.write_chunk_uninit(len) {
+            Ok(chunk) => {
+                chunk.fill_from_iter(input.buffer.as_interleaved().iter().copied());
+            }
+            Err(_) => {
+                self.shared
+                    .xruns
+                    .fetch_add(frames as u64, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// State shared between a queue and its callback.
+#[derive(Debug)]
+struct Shared {
+    samplerate: AtomicU64,
+    /// Frames lost to underruns for output queues, or overruns for input queues
+    xruns: AtomicU64,
+}
+
+impl Shared {
+    fn new(samplerate: f64) -> Self {
+        Self {
+            samplerate: AtomicU64::new(samplerate.to_bits()),
+            xruns: AtomicU64::new(0),
+        }
+    }
+
+    fn set_samplerate(&self, samplerate: f64) {
+        self.samplerate
+            .store(samplerate.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Time the stream takes to process the given number of frames, used to estimate how long
+    /// blocked threads should sleep for.
+    fn duration_of(&self, frames: usize) -> Duration {
+        let samplerate = f64::from_bits(self.samplerate.load(Ordering::Relaxed));
+        Duration::from_secs_f64(frames as f64 / samplerate).max(MIN_POLL_INTERVAL)
+    }
+}
@@ -0,0 +1,21 @@
+//! Checks of the realtime safety of audio threads.
+//!
+//! With the `assert_no_alloc` feature, backends run the processing of each cycle of their audio
+//! threads, callbacks included, within [`assert_no_alloc::assert_no_alloc`]. Allocations made
+//! there are then reported, provided that the application uses `assert_no_alloc::AllocDisabler`
+//! as its global allocator. Error handling and stream management run outside of the checks.
+
+/// Run the processing of a cycle of an audio thread, checking that it does not allocate.
+#[cfg(feature = "assert_no_alloc")]
+#[inline(always)]
+pub(crate) fn process_cycle<T>(process: impl FnOnce() -> T) -> T {
+    assert_no_alloc::assert_no_alloc(process)
+}
+
+/// Run the processing of a cycle of an audio thread. Allocations are only checked with the
+/// `assert_no_alloc` feature.
+#[cfg(not(feature = "assert_no_alloc"))]
+#[inline(always)]
+pub(crate) fn process_cycle<T>(process: impl FnOnce() -> T) -> T {
+    process()
+}
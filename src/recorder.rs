@@ -0,0 +1,356 @@
+//! Recording of input devices to memory or to WAV files.
+//!
+//! [`start_recording`] opens an input stream on a device, and hands the captured audio over to a
+//! writer thread, which stores it into a growable buffer or streams it into a WAV file. The
+//! audio callback itself only copies samples into a ring buffer, and never blocks on I/O.
+//!
+//! Any input device can be recorded, including loopback or monitor devices which capture the
+//! output of the system.
+//!
+//! ```no_run
+//! use interflow::prelude::*;
+//! use interflow::recorder::{start_recording, RecordingSink};
+//! let device = default_input_device();
+//! let config = device.default_input_config().unwrap();
+//! let recorder = start_recording(&device, config, RecordingSink::Wav("take.wav".into())).unwrap();
+//! std::thread::sleep(std::time::Duration::from_secs(5));
+//! println!("Peak levels: {:?}", recorder.levels());
+//! let recording = recorder.stop().unwrap();
+//! println!("Recorded {} frames", recording.frames);
+//! ```
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::audio_buffer::{AudioBuffer, AudioRef};
+use crate::channel_map::Bitset;
+use crate::{
+    AudioCallbackContext, AudioInput, AudioInputCallback, AudioInputDevice, AudioStreamHandle,
+    StreamConfig,
+};
+
+/// Duration of audio the ring buffer between the audio callback and the writer thread can hold.
+const RING_BUFFER_DURATION: f64 = 2.0;
+
+/// Interval at which the writer thread moves captured audio to its destination.
+const WRITER_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Maximum number of channels whose level is metered.
+const MAX_METERED_CHANNELS: usize = 32;
+
+/// Destination of a recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordingSink {
+    /// Keep the recording in memory, returned as a buffer when it stops.
+    Memory,
+    /// Stream the recording into a 32-bit float WAV file at the given path, which is created or
+    /// truncated when the recording starts.
+    Wav(PathBuf),
+}
+
+/// Errors which can happen while recording.
+#[derive(Debug, Error)]
+pub enum RecorderError<StreamError> {
+    /// The input stream could not be created, or failed.
+    #[error(transparent)]
+    Stream(StreamError),
+    /// The recording could not be written.
+    #[error("Cannot write recording: {0}")]
+    Io(#[from] io::Error),
+    /// The writer thread panicked, and the recording is lost.
+    #[error("Recording thread panicked")]
+    WriterPanicked,
+    /// The input stream failed while recording. The audio captured until then is still written
+    /// and finalized.
+    #[error("Recording stream failed: {error}")]
+    StreamFailed {
+        /// Error the input stream failed with.
+        #[source]
+        error: StreamError,
+        /// Audio recorded before the stream failed.
+        recording: Recording,
+    },
+}
+
+/// Finished recording, returned by [`Recorder::stop`].
+#[derive(Debug)]
+pub struct Recording {
+    /// Sample rate of the recording.
+    pub samplerate: f64,
+    /// Number of channels of the recording.
+    pub channels: usize,
+    /// Number of frames recorded.
+    pub frames: u64,
+    /// Frames dropped because the writer thread could not keep up with the audio callback.
+    pub dropped_frames: u64,
+    /// Recorded audio, when recording to [`RecordingSink::Memory`].
+    pub buffer: Option<AudioBuffer<f32>>,
+}
+
+/// Recording in progress, created by [`start_recording`].
+pub struct Recorder<Handle> {
+    handle: Handle,
+    writer: JoinHandle<io::Result<Sink>>,
+    shared: Arc<Shared>,
+}
+
+/// Input callback used by recordings, which passes the captured audio to the writer thread.
+pub struct RecorderCallback {
+    producer: rtrb::Producer<f32>,
+    shared: Arc<Shared>,
+}
+
+/// Start recording the given input device with the given stream configuration.
+pub fn start_recording<Device: AudioInputDevice>(
+    device: &Device,
+    stream_config: StreamConfig,
+    sink: RecordingSink,
+) -> Result<Recorder<Device::StreamHandle<RecorderCallback>>, RecorderError<Device::Error>> {
+    let mut sink = match sink {
+        RecordingSink::Memory => Sink::Memory(Vec::new()),
+        RecordingSink::Wav(path) => Sink::Wav(WavWriter::create(path)?),
+    };
+    let channels = stream_config.input_channels.count().max(1);
+    let capacity = (stream_config.samplerate * RING_BUFFER_DURATION) as usize * channels;
+    let (producer, mut consumer) = rtrb::RingBuffer::new(capacity);
+    let shared = Arc::new(Shared::default());
+    shared.set_format(stream_config.samplerate, channels);
+    let writer = std::thread::spawn({
+        let shared = shared.clone();
+        move || {
+            loop {
+                let abandoned = consumer.is_abandoned() || shared.stopped.load(Ordering::Acquire);
+                let available = consumer.slots();
+                if available > 0 {
+                    let chunk = consumer.read_chunk(available).unwrap();
+                    let (first, second) = chunk.as_slices();
+                    sink.write(first)?;
+                    sink.write(second)?;
+                    chunk.commit_all();
+                } else if abandoned {
+                    break;
+                } else {
+                    std::thread::sleep(WRITER_POLL_INTERVAL);
+                }
+            }
+            Ok(sink)
+        }
+    });
+    let callback = RecorderCallback {
+        producer,
+        shared: shared.clone(),
+    };
+    let handle = device
+        .create_input_stream(stream_config, callback)
+        .map_err(RecorderError::Stream)?;
+    Ok(Recorder {
+        handle,
+        writer,
+        shared,
+    })
+}
+
+impl<Handle> Recorder<Handle> {
+    /// Peak level of each channel since the last call, as linear amplitudes.
+    pub fn levels(&self) -> Vec<f32> {
+        let channels = self.channels().min(MAX_METERED_CHANNELS);
+        self.shared.peaks[..channels]
+            .iter()
+            .map(|peak| f32::from_bits(peak.swap(0, Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Sample rate of the recording.
+    pub fn samplerate(&self) -> f64 {
+        f64::from_bits(self.shared.samplerate.load(Ordering::Relaxed))
+    }
+
+    /// Number of channels of the recording.
+    pub fn channels(&self) -> usize {
+        self.shared.channels.load(Ordering::Relaxed)
+    }
+
+    /// Number of frames recorded so far.
+    pub fn frames(&self) -> u64 {
+        self.shared.frames.load(Ordering::Relaxed)
+    }
+
+    /// Number of frames dropped so far, because the writer thread could not keep up.
+    pub fn dropped_frames(&self) -> u64 {
+        self.shared.dropped_frames.load(Ordering::Relaxed)
+    }
+}
+
+impl<Handle: AudioStreamHandle<RecorderCallback>> Recorder<Handle> {
+    /// Stop the recording, waiting for all captured audio to be written, and finalize it.
+    ///
+    /// When the input stream failed, the recording is still finalized, and returned along with
+    /// the stream error in [`RecorderError::StreamFailed`].
+    pub fn stop(self) -> Result<Recording, RecorderError<Handle::Error>> {
+        // Dropping the callback lets the writer thread know that no more audio is coming
+        let stream_error = match self.handle.eject() {
+            Ok(callback) => {
+                drop(callback);
+                None
+            }
+            Err(error) => {
+                self.shared.stopped.store(true, Ordering::Release);
+                Some(error)
+            }
+        };
+        let sink = self
+            .writer
+            .join()
+            .map_err(|_| RecorderError::WriterPanicked)??;
+        let samplerate = f64::from_bits(self.shared.samplerate.load(Ordering::Relaxed));
+        let channels = self.shared.channels.load(Ordering::Relaxed);
+        let buffer = sink.finalize(samplerate, channels)?;
+        let recording = Recording {
+            samplerate,
+            channels,
+            frames: self.shared.frames.load(Ordering::Relaxed),
+            dropped_frames: self.shared.dropped_frames.load(Ordering::Relaxed),
+            buffer,
+        };
+        match stream_error {
+            Some(error) => Err(RecorderError::StreamFailed { error, recording }),
+            None => Ok(recording),
+        }
+    }
+}
+
+impl AudioInputCallback for RecorderCallback {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        let channels = context.stream_config.input_channels.count();
+        self.shared
+            .set_format(context.stream_config.samplerate, channels);
+    }
+
+    fn on_input_data(&mut self, _context: AudioCallbackContext, input: AudioInput<f32>) {
+        let frames = input.buffer.num_samples();
+        let channels = input.buffer.num_channels();
+        for (peak, channel) in self.shared.peaks.iter().zip(input.buffer.channels()) {
+            let level = channel.iter().fold(0f32, |max, x| max.max(x.abs()));
+            let _ = peak.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                (level > f32::from_bits(bits)).then_some(level.to_bits())
+            });
+        }
+        match self.producer.write_chunk_uninit(frames * channels) {
+            Ok(chunk) => {
+                chunk.fill_from_iter(input.buffer.as_interleaved().iter().copied());
+                self.shared.frames.fetch_add(frames as _, Ordering::Relaxed);
+            }
+            Err(_) => {
+                self.shared
+                    .dropped_frames
+                    .fetch_add(frames as _, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// State shared between the recorder, its callback, and its writer thread.
+#[derive(Debug, Default)]
+struct Shared {
+    samplerate: AtomicU64,
+    channels: AtomicUsize,
+    frames: AtomicU64,
+    dropped_frames: AtomicU64,
+    peaks: [AtomicU32; MAX_METERED_CHANNELS],
+    /// Set when the recording stops without the callback being handed back, as the writer
+    /// thread cannot rely on the ring buffer being abandoned then.
+    stopped: AtomicBool,
+}
+
+impl Shared {
+    fn set_format(&self, samplerate: f64, channels: usize) {
+        self.samplerate
+            .store(samplerate.to_bits(), Ordering::Relaxed);
+        self.channels.store(channels, Ordering::Relaxed);
+    }
+}
+
+/// Destination the writer thread writes interleaved samples to.
+enum Sink {
+    Memory(Vec<f32>),
+    Wav(WavWriter),
+}
+
+impl Sink {
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        match self {
+            Self::Memory(buffer) => {
+                buffer.extend_from_slice(samples);
+                Ok(())
+            }
+            Self::Wav(writer) => writer.write(samples),
+        }
+    }
+
+    fn finalize(self, samplerate: f64, channels: usize) -> io::Result<Option<AudioBuffer<f32>>> {
+        match self {
+            Self::Memory(samples) => Ok(AudioRef::from_interleaved(&samples, channels.max(1))
+                .map(|buffer| buffer.to_owned())),
+            Self::Wav(writer) => {
+                writer.finalize(samplerate, channels)?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Streaming writer of 32-bit float WAV files, whose header is completed when finalized.
+struct WavWriter {
+    file: BufWriter<File>,
+    data_size: u64,
+}
+
+impl WavWriter {
+    const HEADER_SIZE: u64 = 44;
+
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        // Placeholder header, rewritten with the actual format and sizes when finalizing
+        file.write_all(&[0; Self::HEADER_SIZE as usize])?;
+        Ok(Self { file, data_size: 0 })
+    }
+
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_size += samples.len() as u64 * 4;
+        Ok(())
+    }
+
+    fn finalize(mut self, samplerate: f64, channels: usize) -> io::Result<()> {
+        // WAV files cannot describe data chunks larger than 4 GiB
+        let data_size = self.data_size.min(u32::MAX as u64 - Self::HEADER_SIZE) as u32;
+        let channels = channels as u16;
+        let samplerate = samplerate as u32;
+        let block_align = channels * 4;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(b"RIFF")?;
+        self.file.write_all(&(data_size + 36).to_le_bytes())?;
+        self.file.write_all(b"WAVEfmt ")?;
+        self.file.write_all(&16u32.to_le_bytes())?;
+        // WAVE_FORMAT_IEEE_FLOAT
+        self.file.write_all(&3u16.to_le_bytes())?;
+        self.file.write_all(&channels.to_le_bytes())?;
+        self.file.write_all(&samplerate.to_le_bytes())?;
+        self.file
+            .write_all(&(samplerate * block_align as u32).to_le_bytes())?;
+        self.file.write_all(&block_align.to_le_bytes())?;
+        self.file.write_all(&32u16.to_le_bytes())?;
+        self.file.write_all(b"data")?;
+        self.file.write_all(&data_size.to_le_bytes())?;
+        self.file.flush()
+    }
+}
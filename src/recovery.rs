@@ -0,0 +1,514 @@
+//! Automatic recovery of streams whose device fails.
+//!
+//! Streams stop when their device is lost, invalidated or suspended, for example when a USB
+//! audio interface is unplugged. A [`RecoveringStream`] watches the stream it wraps, and when it
+//! stops with an error, opens it again on the same device, or on the default device when the
+//! same device is not available anymore and [`RecoveryOptions::fallback_to_default`] is set.
+//! Devices are found again from their [identifier](crate::AudioDevice::device_id), or from their
+//! name for drivers which do not provide identifiers.
+//!
+//! Attempts are spaced with an exponential backoff, and reported as
+//! [`StreamEvent::Recovering`] events; [`StreamEvent::DeviceChanged`] is sent once the stream
+//! runs again.
+//!
+//! The callback is shared with each stream opened in turn, and prepared again when it starts
+//! processing the audio of a new stream. Nothing is played nor recorded while the device is
+//! missing; buffers given while the callback is being handed over are filled with silence.
+//!
+//! ```no_run
+//! # #[cfg(target_os = "linux")] {
+//! use interflow::backends::alsa::AlsaDriver;
+//! use interflow::prelude::*;
+//! use interflow::recovery::{recovering_output_stream, RecoveryOptions};
+//! use interflow::signals::Sine;
+//! let driver = AlsaDriver;
+//! let device = default_output_device_from(&driver);
+//! let config = device.default_output_config().unwrap();
+//! let options = RecoveryOptions {
+//!     fallback_to_default: true,
+//!     ..RecoveryOptions::default()
+//! };
+//! let stream =
+//!     recovering_output_stream(driver, &device, config, Sine::new(440.), options).unwrap();
+//! std::thread::sleep(std::time::Duration::from_secs(60));
+//! stream.eject().unwrap();
+//! # }
+//! ```
+
+use std::marker::PhantomData;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, TryLockError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::diagnostics;
+use crate::duplex::AudioDuplexCallback;
+use crate::events::{StreamEvent, StreamEventReceiver, StreamEventSender};
+use crate::{
+    AudioCallbackContext, AudioDevice, AudioDriver, AudioDuplexDevice, AudioInput,
+    AudioInputCallback, AudioInputDevice, AudioOutput, AudioOutputCallback, AudioOutputDevice,
+    AudioStreamHandle, DeviceType, SendEverywhereButOnWeb, StreamConfig,
+};
+
+/// Interval at which the supervisor of a recovering stream checks on the stream it wraps.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Options of the recovery of a stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecoveryOptions {
+    /// Open the stream on the default device when its device is not available anymore.
+    pub fallback_to_default: bool,
+    /// Delay before the first attempt to open the stream again.
+    pub initial_delay: Duration,
+    /// Maximum delay between two attempts. Delays double after each failed attempt, up to
+    /// this one.
+    pub max_delay: Duration,
+    /// Number of attempts after which the stream is given up on, or `None` to retry forever.
+    /// The callback can still be taken back with [`RecoveringStream::eject`] afterwards.
+    pub max_attempts: Option<usize>,
+}
+
+impl Default for RecoveryOptions {
+    fn default() -> Self {
+        Self {
+            fallback_to_default: false,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+            max_attempts: None,
+        }
+    }
+}
+
+impl RecoveryOptions {
+    /// Delay before the given attempt, counting from 1.
+    fn delay(&self, attempt: usize) -> Duration {
+        let factor = 1u32 << attempt.saturating_sub(1).min(16);
+        self.initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay)
+    }
+}
+
+/// Errors of recovering streams.
+#[derive(Debug, Error)]
+pub enum RecoveryError {
+    /// The callback could not be taken back from the streams it was given to.
+    #[error("The callback has been lost by the stream")]
+    CallbackLost,
+}
+
+/// Callback given to each stream opened by a [`RecoveringStream`], sharing the callback of the
+/// application.
+pub struct SharedCallback<Callback> {
+    callback: Arc<Mutex<Callback>>,
+    /// Whether the next buffer is the first one of a stream opened after a failure
+    reopened: bool,
+}
+
+impl<Callback> SharedCallback<Callback> {
+    /// Lock the callback from the audio thread, without waiting for it.
+    fn try_lock(&self) -> Option<std::sync::MutexGuard<'_, Callback>> {
+        match self.callback.try_lock() {
+            Ok(callback) => Some(callback),
+            Err(TryLockError::Poisoned(err)) => Some(err.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+
+    fn context(&mut self, context: AudioCallbackContext) -> AudioCallbackContext {
+        let discontinuity = std::mem::take(&mut self.reopened) || context.discontinuity;
+        AudioCallbackContext {
+            discontinuity,
+            ..context
+        }
+    }
+}
+
+impl<Callback: AudioInputCallback> AudioInputCallback for SharedCallback<Callback> {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        let mut callback = self.callback.lock().unwrap_or_else(|err| err.into_inner());
+        callback.prepare(context);
+    }
+
+    fn on_input_data(&mut self, context: AudioCallbackContext, input: AudioInput<f32>) {
+        let context = self.context(context);
+        if let Some(mut callback) = self.try_lock() {
+            callback.on_input_data(context, input);
+        }
+    }
+}
+
+impl<Callback: AudioOutputCallback> AudioOutputCallback for SharedCallback<Callback> {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        let mut callback = self.callback.lock().unwrap_or_else(|err| err.into_inner());
+        callback.prepare(context);
+    }
+
+    fn on_output_data(&mut self, context: AudioCallbackContext, mut output: AudioOutput<f32>) {
+        let context = self.context(context);
+        match self.try_lock() {
+            Some(mut callback) => callback.on_output_data(context, output),
+            None => output.buffer.as_interleaved_mut().fill(0.),
+        }
+    }
+}
+
+impl<Callback: AudioDuplexCallback> AudioDuplexCallback for SharedCallback<Callback> {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        let mut callback = self.callback.lock().unwrap_or_else(|err| err.into_inner());
+        callback.prepare(context);
+    }
+
+    fn on_audio_data(
+        &mut self,
+        context: AudioCallbackContext,
+        input: AudioInput<f32>,
+        mut output: AudioOutput<f32>,
+    ) {
+        let context = self.context(context);
+        match self.try_lock() {
+            Some(mut callback) => callback.on_audio_data(context, input, output),
+            None => output.buffer.as_interleaved_mut().fill(0.),
+        }
+    }
+}
+
+/// Stream which is opened again when its device fails. See the [module documentation](self).
+pub struct RecoveringStream<Callback> {
+    stop: mpsc::Sender<()>,
+    supervisor: JoinHandle<Arc<Mutex<Callback>>>,
+    events: StreamEventSender,
+}
+
+impl<Callback> AudioStreamHandle<Callback> for RecoveringStream<Callback> {
+    type Error = RecoveryError;
+
+    fn eject(self) -> Result<Callback, Self::Error> {
+        let _ = self.stop.send(());
+        let callback = self
+            .supervisor
+            .join()
+            .map_err(|_| RecoveryError::CallbackLost)?;
+        let callback = Arc::try_unwrap(callback).map_err(|_| RecoveryError::CallbackLost)?;
+        self.events.send(StreamEvent::Ejected);
+        Ok(callback.into_inner().unwrap_or_else(|err| err.into_inner()))
+    }
+
+    /// Events of the streams opened in turn are forwarded, along with the events of the
+    /// recovery itself.
+    fn subscribe_events(&self) -> StreamEventReceiver {
+        self.events.subscribe()
+    }
+}
+
+/// Open an output stream which is opened again when its device fails.
+///
+/// The driver is used to find the device again, through [`AudioDriver::device_by_id`], or the
+/// default output device when falling back to it.
+pub fn recovering_output_stream<Driver, Callback>(
+    driver: Driver,
+    device: &Driver::Device,
+    config: StreamConfig,
+    callback: Callback,
+    options: RecoveryOptions,
+) -> Result<RecoveringStream<Callback>, <Driver::Device as AudioDevice>::Error>
+where
+    Driver: 'static + Send + AudioDriver,
+    Driver::Device: AudioOutputDevice,
+    <Driver::Device as AudioOutputDevice>::StreamHandle<SharedCallback<Callback>>: 'static + Send,
+    Callback: SendEverywhereButOnWeb + AudioOutputCallback,
+{
+    let open = move |device: &Driver::Device, callback| {
+        device
+            .create_output_stream(config, callback)
+            .map(Opened::boxed)
+    };
+    start(driver, device, DeviceType::Output, callback, options, open)
+}
+
+/// Open an input stream which is opened again when its device fails.
+///
+/// The driver is used to find the device again, through [`AudioDriver::device_by_id`], or the
+/// default input device when falling back to it.
+pub fn recovering_input_stream<Driver, Callback>(
+    driver: Driver,
+    device: &Driver::Device,
+    config: StreamConfig,
+    callback: Callback,
+    options: RecoveryOptions,
+) -> Result<RecoveringStream<Callback>, <Driver::Device as AudioDevice>::Error>
+where
+    Driver: 'static + Send + AudioDriver,
+    Driver::Device: AudioInputDevice,
+    <Driver::Device as AudioInputDevice>::StreamHandle<SharedCallback<Callback>>: 'static + Send,
+    Callback: SendEverywhereButOnWeb + AudioInputCallback,
+{
+    let open = move |device: &Driver::Device, callback| {
+        device
+            .create_input_stream(config, callback)
+            .map(Opened::boxed)
+    };
+    start(driver, device, DeviceType::Input, callback, options, open)
+}
+
+/// Open a duplex stream which is opened again when its device fails.
+///
+/// The driver is used to find the device again, through [`AudioDriver::device_by_id`], or the
+/// default duplex device when falling back to it.
+pub fn recovering_duplex_stream<Driver, Callback>(
+    driver: Driver,
+    device: &Driver::Device,
+    config: StreamConfig,
+    callback: Callback,
+    options: RecoveryOptions,
+) -> Result<RecoveringStream<Callback>, <Driver::Device as AudioDevice>::Error>
+where
+    Driver: 'static + Send + AudioDriver,
+    Driver::Device: AudioDuplexDevice,
+    <Driver::Device as AudioDuplexDevice>::StreamHandle<SharedCallback<Callback>>: 'static + Send,
+    Callback: SendEverywhereButOnWeb + AudioDuplexCallback,
+{
+    let open = move |device: &Driver::Device, callback| {
+        device
+            .create_duplex_stream(config, callback)
+            .map(Opened::boxed)
+    };
+    start(driver, device, DeviceType::Duplex, callback, options, open)
+}
+
+/// Stream opened by a recovering stream, with its callback type erased.
+trait Stream: Send {
+    fn subscribe_events(&self) -> StreamEventReceiver;
+
+    /// Stop the stream, whose callback is not needed since it is shared.
+    fn close(self: Box<Self>);
+}
+
+struct Opened<Handle, Callback> {
+    handle: Handle,
+    _callback: PhantomData<fn() -> Callback>,
+}
+
+impl<Handle, Callback> Opened<Handle, Callback>
+where
+    Handle: 'static + Send + AudioStreamHandle<Callback>,
+    Callback: 'static,
+{
+    fn boxed(handle: Handle) -> Box<dyn Stream> {
+        Box::new(Self {
+            handle,
+            _callback: PhantomData,
+        })
+    }
+}
+
+impl<Handle: Send + AudioStreamHandle<Callback>, Callback> Stream for Opened<Handle, Callback> {
+    fn subscribe_events(&self) -> StreamEventReceiver {
+        self.handle.subscribe_events()
+    }
+
+    fn close(self: Box<Self>) {
+        if let Err(err) = self.handle.eject() {
+            log::debug!("Stream stopped with error: {err}");
+        }
+    }
+}
+
+/// Open the first stream on the calling thread, and spawn the supervisor recovering it.
+fn start<Driver, Callback, Error>(
+    driver: Driver,
+    device: &Driver::Device,
+    device_type: DeviceType,
+    callback: Callback,
+    options: RecoveryOptions,
+    mut open: impl 'static
+        + Send
+        + FnMut(&Driver::Device, SharedCallback<Callback>) -> Result<Box<dyn Stream>, Error>,
+) -> Result<RecoveringStream<Callback>, Error>
+where
+    Driver: 'static + Send + AudioDriver,
+    Callback: 'static + Send,
+    Error: std::fmt::Display,
+{
+    let same_device = SameDevice {
+        id: device.device_id().ok().flatten().map(|id| id.into_owned()),
+        name: device.name().into_owned(),
+        device_type: device.device_type(),
+    };
+    let callback = Arc::new(Mutex::new(callback));
+    let stream = open(
+        device,
+        SharedCallback {
+            callback: callback.clone(),
+            reopened: false,
+        },
+    )?;
+    let events = StreamEventSender::new();
+    let (stop, stop_rx) = mpsc::channel();
+    let supervisor = std::thread::spawn({
+        let events = events.clone();
+        move || {
+            let supervisor = Supervisor {
+                driver,
+                same_device,
+                device_type,
+                options,
+                events,
+                stop: stop_rx,
+            };
+            supervisor.run(stream, |device: &Driver::Device| {
+                let callback = SharedCallback {
+                    callback: callback.clone(),
+                    reopened: true,
+                };
+                open(device, callback).map_err(|err| err.to_string())
+            });
+            callback
+        }
+    });
+    Ok(RecoveringStream {
+        stop,
+        supervisor,
+        events,
+    })
+}
+
+/// Description of the device a stream was first opened on, to find it again.
+struct SameDevice {
+    id: Option<String>,
+    name: String,
+    device_type: DeviceType,
+}
+
+/// Thread watching a stream, and opening it again when it fails.
+struct Supervisor<Driver> {
+    driver: Driver,
+    same_device: SameDevice,
+    device_type: DeviceType,
+    options: RecoveryOptions,
+    events: StreamEventSender,
+    stop: mpsc::Receiver<()>,
+}
+
+impl<Driver: AudioDriver> Supervisor<Driver> {
+    fn run(
+        &self,
+        mut stream: Box<dyn Stream>,
+        mut open: impl FnMut(&Driver::Device) -> Result<Box<dyn Stream>, String>,
+    ) {
+        loop {
+            if !self.watch(&*stream) {
+                stream.close();
+                return;
+            }
+            stream.close();
+            match self.recover(&mut open) {
+                Some(recovered) => {
+                    self.events.send(StreamEvent::DeviceChanged);
+                    stream = recovered;
+                }
+                None => {
+                    // Keep the callback until the stream is ejected
+                    let _ = self.stop.recv();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Forward the events of the stream until it fails, returning `true`, or until the stream
+    /// is ejected, returning `false`.
+    fn watch(&self, stream: &dyn Stream) -> bool {
+        let mut receiver = stream.subscribe_events();
+        loop {
+            for event in receiver.try_iter() {
+                match event {
+                    StreamEvent::Ejected => {}
+                    StreamEvent::Error(message) => {
+                        diagnostics::warn!("Stream failed, recovering: {message}");
+                        self.events.send(StreamEvent::Error(message));
+                        return true;
+                    }
+                    event => self.events.send(event),
+                }
+            }
+            if receiver.is_disconnected() {
+                return true;
+            }
+            if self.wait(POLL_INTERVAL) {
+                return false;
+            }
+        }
+    }
+
+    /// Open the stream again, with backoff between attempts. Returns `None` when giving up, or
+    /// when the stream is ejected in the meantime.
+    fn recover(
+        &self,
+        open: &mut impl FnMut(&Driver::Device) -> Result<Box<dyn Stream>, String>,
+    ) -> Option<Box<dyn Stream>> {
+        for attempt in 1.. {
+            if self
+                .options
+                .max_attempts
+                .is_some_and(|max_attempts| attempt > max_attempts)
+            {
+                let message = format!(
+                    "Gave up recovering the stream after {} attempts",
+                    attempt - 1
+                );
+                diagnostics::error!("{message}");
+                self.events.send(StreamEvent::Error(message.into()));
+                return None;
+            }
+            let delay = self.options.delay(attempt);
+            self.events.send(StreamEvent::Recovering { attempt, delay });
+            if self.wait(delay) {
+                return None;
+            }
+            let Some(device) = self.find_device() else {
+                log::debug!("Recovery attempt {attempt}: device not available");
+                continue;
+            };
+            match open(&device) {
+                Ok(stream) => {
+                    log::info!("Stream recovered after {attempt} attempts");
+                    return Some(stream);
+                }
+                Err(err) => log::debug!("Recovery attempt {attempt} failed: {err}"),
+            }
+        }
+        None
+    }
+
+    /// Device to open the stream on, if any is available.
+    fn find_device(&self) -> Option<Driver::Device> {
+        let device = match &self.same_device.id {
+            Some(id) => self.driver.device_by_id(id).ok().flatten(),
+            None => self.driver.list_devices().ok().and_then(|devices| {
+                devices.into_iter().find(|device| {
+                    device.device_type() == self.same_device.device_type
+                        && device.name() == self.same_device.name
+                })
+            }),
+        };
+        match device {
+            Some(device) => Some(device),
+            None if self.options.fallback_to_default => {
+                self.driver.default_device(self.device_type).ok().flatten()
+            }
+            None => None,
+        }
+    }
+
+    /// Wait for the given duration, returning `true` when the stream is ejected in the
+    /// meantime.
+    fn wait(&self, duration: Duration) -> bool {
+        !matches!(
+            self.stop.recv_timeout(duration),
+            Err(RecvTimeoutError::Timeout)
+        )
+    }
+}
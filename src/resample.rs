@@ -0,0 +1,394 @@
+//! Sample rate conversion of audio buffers.
+//!
+//! [`Resampler`]s convert audio streamed through them in buffers of any size, keeping their
+//! state between calls, and can also convert whole buffers at once with [`Resampler::resample`].
+//! Two implementations are provided:
+//!
+//! - [`LinearResampler`], which interpolates linearly between input frames. It is cheap, but lets
+//!   aliasing through, and is best suited to small ratio changes (such as drift compensation).
+//! - [`SincResampler`], which filters with a windowed sinc kernel. It is more expensive, but
+//!   suited to converting file sample rates to stream rates.
+//!
+//! ```rust
+//! use interflow::audio_buffer::AudioBuffer;
+//! use interflow::resample::{LinearResampler, Resampler, SincResampler};
+//! let input = AudioBuffer::<f32>::fill(2, 480, 0.5);
+//! let output = SincResampler::new(2, 48000., 44100.).resample(input.as_ref());
+//! assert_eq!(output.num_samples(), 441);
+//!
+//! let ramp = AudioBuffer::<f32>::fill_with(1, 4, |_, i| i as f32);
+//! let output = LinearResampler::new(1, 1., 2.).resample(ramp.as_ref());
+//! assert_eq!(output.get_channel(0).to_vec(), [0., 0.5, 1., 1.5, 2., 2.5, 3., 1.5]);
+//! ```
+
+use std::f64::consts::PI;
+
+use crate::audio_buffer::{AudioBuffer, AudioMut, AudioRef};
+
+/// Trait of sample rate converters, which stream audio from one sample rate to another.
+pub trait Resampler {
+    /// Number of channels processed.
+    fn channels(&self) -> usize;
+
+    /// Conversion ratio, as the output sample rate divided by the input sample rate.
+    fn ratio(&self) -> f64;
+
+    /// Change the conversion ratio. The change applies from the next output frame, without
+    /// resetting the state of the resampler.
+    fn set_ratio(&mut self, ratio: f64);
+
+    /// Number of input frames the resampler needs past an output frame to compute it. Output
+    /// lags this many frames behind the input when streaming.
+    fn latency(&self) -> usize;
+
+    /// Clear the state of the resampler, as if no audio had been processed.
+    fn reset(&mut self);
+
+    /// Convert the input into the output, returning the number of input frames consumed and of
+    /// output frames written. Processing stops when either all the input is consumed or the
+    /// output is full; input not consumed should be given again to the next call.
+    ///
+    /// Both buffers must have [`Self::channels`] channels. Realtime-safe.
+    fn process(&mut self, input: AudioRef<f32>, output: AudioMut<f32>) -> (usize, usize);
+
+    /// Convert a whole buffer, resetting the resampler first. The output has the duration of the
+    /// input, rounded to the nearest frame.
+    ///
+    /// Not realtime-safe.
+    fn resample(&mut self, input: AudioRef<f32>) -> AudioBuffer<f32> {
+        self.reset();
+        let frames = (input.num_samples() as f64 * self.ratio()).round() as usize;
+        let mut output = AudioBuffer::zeroed(self.channels(), frames);
+        let (_, mut written) = self.process(input, output.as_mut());
+        // Feed silence past the end of the input to flush the frames held back by the latency
+        let padding = AudioBuffer::zeroed(self.channels(), self.latency() + 1);
+        while written < frames {
+            let (_, count) = self.process(padding.as_ref(), output.slice_mut(written..));
+            written += count;
+        }
+        output
+    }
+}
+
+/// Resampler interpolating linearly between input frames.
+#[derive(Debug, Clone)]
+pub struct LinearResampler {
+    core: Core,
+}
+
+impl LinearResampler {
+    /// Create a resampler converting the given number of channels from one sample rate to
+    /// another.
+    pub fn new(channels: usize, from_samplerate: f64, to_samplerate: f64) -> Self {
+        Self {
+            core: Core::new(channels, 2, to_samplerate / from_samplerate),
+        }
+    }
+}
+
+impl Resampler for LinearResampler {
+    fn channels(&self) -> usize {
+        self.core.channels
+    }
+
+    fn ratio(&self) -> f64 {
+        self.core.ratio
+    }
+
+    fn set_ratio(&mut self, ratio: f64) {
+        self.core.ratio = ratio;
+    }
+
+    fn latency(&self) -> usize {
+        1
+    }
+
+    fn reset(&mut self) {
+        self.core.reset();
+    }
+
+    fn process(&mut self, input: AudioRef<f32>, output: AudioMut<f32>) -> (usize, usize) {
+        self.core.process(input, output, |phase, weights| {
+            weights[0] = (1. - phase) as f32;
+            weights[1] = phase as f32;
+        })
+    }
+}
+
+/// Number of phases between two input frames the sinc kernel is tabulated at. Kernels at other
+/// phases are interpolated linearly between the two nearest ones.
+const SINC_PHASES: usize = 256;
+
+/// Resampler filtering with a sinc kernel under a Blackman window, which also low-passes the
+/// input when downsampling to prevent aliasing.
+///
+/// The kernel is tabulated when the resampler is created, and again when changing the ratio
+/// moves the cutoff frequency, which happens when downsampling.
+#[derive(Debug, Clone)]
+pub struct SincResampler {
+    core: Core,
+    half_width: usize,
+    cutoff: f64,
+    /// Kernel weights for each of the `SINC_PHASES + 1` phases, one row of taps per phase
+    table: Vec<f32>,
+}
+
+impl SincResampler {
+    /// Default number of input frames on each side of an output frame the kernel spans.
+    pub const DEFAULT_HALF_WIDTH: usize = 16;
+
+    /// Create a resampler converting the given number of channels from one sample rate to
+    /// another, with the default kernel width.
+    pub fn new(channels: usize, from_samplerate: f64, to_samplerate: f64) -> Self {
+        Self::with_half_width(
+            channels,
+            from_samplerate,
+            to_samplerate,
+            Self::DEFAULT_HALF_WIDTH,
+        )
+    }
+
+    /// Create a resampler whose kernel spans `half_width` input frames on each side of an output
+    /// frame. Wider kernels filter more sharply, at a higher cost and latency.
+    pub fn with_half_width(
+        channels: usize,
+        from_samplerate: f64,
+        to_samplerate: f64,
+        half_width: usize,
+    ) -> Self {
+        let half_width = half_width.max(1);
+        let ratio = to_samplerate / from_samplerate;
+        let mut resampler = Self {
+            core: Core::new(channels, 2 * half_width, ratio),
+            half_width,
+            cutoff: ratio.min(1.),
+            table: vec![0.; (SINC_PHASES + 1) * 2 * half_width],
+        };
+        resampler.fill_table();
+        resampler
+    }
+
+    fn fill_table(&mut self) {
+        let half_width = self.half_width as f64;
+        // Lower the cutoff below the output Nyquist frequency when downsampling
+        let cutoff = self.cutoff;
+        for (i, weights) in self.table.chunks_exact_mut(self.core.taps).enumerate() {
+            let position = half_width - 1. + i as f64 / SINC_PHASES as f64;
+            let mut sum = 0.;
+            for (j, weight) in weights.iter_mut().enumerate() {
+                let x = position - j as f64;
+                let window = 0.42
+                    + 0.5 * (PI * x / half_width).cos()
+                    + 0.08 * (2. * PI * x / half_width).cos();
+                let value = cutoff * sinc(cutoff * x) * window.max(0.);
+                *weight = value as f32;
+                sum += value;
+            }
+            // Normalizing the kernel keeps the gain at DC to exactly 1
+            if sum != 0. {
+                for weight in weights.iter_mut() {
+                    *weight /= sum as f32;
+                }
+            }
+        }
+    }
+}
+
+impl Resampler for SincResampler {
+    fn channels(&self) -> usize {
+        self.core.channels
+    }
+
+    fn ratio(&self) -> f64 {
+        self.core.ratio
+    }
+
+    fn set_ratio(&mut self, ratio: f64) {
+        self.core.ratio = ratio;
+        if ratio.min(1.) != self.cutoff {
+            self.cutoff = ratio.min(1.);
+            self.fill_table();
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.half_width
+    }
+
+    fn reset(&mut self) {
+        self.core.reset();
+    }
+
+    fn process(&mut self, input: AudioRef<f32>, output: AudioMut<f32>) -> (usize, usize) {
+        let taps = self.core.taps;
+        let table = &self.table;
+        self.core.process(input, output, |phase, weights| {
+            let position = phase.clamp(0., 1.) * SINC_PHASES as f64;
+            let index = (position as usize).min(SINC_PHASES - 1);
+            let fraction = (position - index as f64) as f32;
+            let below = &table[index * taps..(index + 1) * taps];
+            let above = &table[(index + 1) * taps..(index + 2) * taps];
+            for ((weight, a), b) in weights.iter_mut().zip(below).zip(above) {
+                *weight = a + (b - a) * fraction;
+            }
+        })
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0. {
+        1.
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// State shared by resamplers computing output frames as weighted sums of a fixed number of
+/// input frames.
+#[derive(Debug, Clone)]
+struct Core {
+    channels: usize,
+    taps: usize,
+    ratio: f64,
+    /// Last `taps` input frames of each channel, oldest first
+    history: Vec<f32>,
+    weights: Vec<f32>,
+    /// Position of the next output frame, in input frames past the middle of the history
+    phase: f64,
+}
+
+impl Core {
+    fn new(channels: usize, taps: usize, ratio: f64) -> Self {
+        let mut core = Self {
+            channels,
+            taps,
+            ratio,
+            history: vec![0.; channels * taps],
+            weights: vec![0.; taps],
+            phase: 0.,
+        };
+        core.reset();
+        core
+    }
+
+    fn reset(&mut self) {
+        self.history.fill(0.);
+        // The first output frame is aligned with the first input frame, which takes filling the
+        // history up to its middle
+        self.phase = (self.taps - self.taps / 2 + 1) as f64;
+    }
+
+    fn process(
+        &mut self,
+        input: AudioRef<f32>,
+        mut output: AudioMut<f32>,
+        mut kernel: impl FnMut(f64, &mut [f32]),
+    ) -> (usize, usize) {
+        let step = self.ratio.recip();
+        let mut consumed = 0;
+        let mut written = 0;
+        loop {
+            while self.phase < 1. {
+                if written == output.num_samples() {
+                    return (consumed, written);
+                }
+                kernel(self.phase, &mut self.weights);
+                let mut frame = output.get_frame_mut(written);
+                for (ch, history) in self.history.chunks_exact(self.taps).enumerate() {
+                    frame[ch] = history.iter().zip(&self.weights).map(|(x, w)| x * w).sum();
+                }
+                written += 1;
+                self.phase += step;
+            }
+            if consumed == input.num_samples() {
+                return (consumed, written);
+            }
+            let frame = input.get_frame(consumed);
+            for (history, sample) in self.history.chunks_exact_mut(self.taps).zip(frame) {
+                history.copy_within(1.., 0);
+                history[self.taps - 1] = *sample;
+            }
+            consumed += 1;
+            self.phase -= 1.;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::TAU;
+
+    use super::{LinearResampler, Resampler, SincResampler};
+    use crate::audio_buffer::AudioBuffer;
+
+    fn resamplers(from: f64, to: f64) -> [(Box<dyn Resampler>, f32); 2] {
+        [
+            (Box::new(LinearResampler::new(2, from, to)), 5e-3),
+            (Box::new(SincResampler::new(2, from, to)), 1e-3),
+        ]
+    }
+
+    /// Maximum difference between the output and the expected signal, away from the edges.
+    fn max_error(output: &AudioBuffer<f32>, expected: impl Fn(usize) -> f32) -> f32 {
+        let frames = output.num_samples();
+        let mut error = 0f32;
+        for channel in output.channels() {
+            for i in 32..frames - 32 {
+                error = error.max((channel[i] - expected(i)).abs());
+            }
+        }
+        error
+    }
+
+    #[test]
+    fn test_dc() {
+        for (from, to) in [(48000., 44100.), (44100., 48000.), (48000., 48000.)] {
+            for (mut resampler, tolerance) in resamplers(from, to) {
+                let input = AudioBuffer::<f32>::fill(2, 4800, 0.5);
+                let output = resampler.resample(input.as_ref());
+                let error = max_error(&output, |_| 0.5);
+                assert!(error < tolerance, "{from} -> {to}: {error}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_sine() {
+        let sine = |samplerate: f64| move |i: usize| (TAU * 1000. * i as f64 / samplerate).sin();
+        for (from, to) in [(48000., 44100.), (44100., 48000.)] {
+            for (mut resampler, tolerance) in resamplers(from, to) {
+                let input = AudioBuffer::<f32>::fill_with(2, 4800, |_, i| sine(from)(i) as f32);
+                let output = resampler.resample(input.as_ref());
+                let error = max_error(&output, |i| sine(to)(i) as f32);
+                assert!(error < tolerance, "{from} -> {to}: {error}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunked() {
+        let input = AudioBuffer::<f32>::fill_with(2, 1000, |ch, i| ((i * 7 + ch) % 13) as f32);
+        for (mut resampler, _) in resamplers(48000., 44100.) {
+            let mut expected = AudioBuffer::zeroed(2, 1000);
+            resampler.reset();
+            let (_, frames) = resampler.process(input.as_ref(), expected.as_mut());
+
+            let mut output = AudioBuffer::zeroed(2, 1000);
+            resampler.reset();
+            let (mut consumed, mut written) = (0, 0);
+            while consumed < input.num_samples() {
+                let input_end = (consumed + 7).min(input.num_samples());
+                let output_end = (written + 5).min(output.num_samples());
+                let (read, count) = resampler.process(
+                    input.slice(consumed..input_end),
+                    output.slice_mut(written..output_end),
+                );
+                assert!(read + count > 0, "The output is too short");
+                consumed += read;
+                written += count;
+            }
+            assert_eq!(frames, written);
+            assert_eq!(expected.as_interleaved(), output.as_interleaved());
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Sample-accurate scheduling of events from a control thread into an audio callback.
+//!
+//! [`event_scheduler`] creates a pair of an [`EventSender`], which a control thread (UI, MIDI
+//! input, network, ...) uses to enqueue events at a given [`Timestamp`], and an
+//! [`EventScheduler`], which the audio callback uses to drain the events that fall within the
+//! buffer it is processing, along with their offset in frames into that buffer.
+//!
+//! ```rust
+//! use interflow::scheduler::event_scheduler;
+//! use interflow::timestamp::Timestamp;
+//! let (mut sender, mut scheduler) = event_scheduler(16);
+//! sender.schedule(Timestamp::from_count(48000., 300), "note off").unwrap();
+//! sender.schedule(Timestamp::from_count(48000., 100), "note on").unwrap();
+//!
+//! // In the audio callback, processing 256 frames
+//! let events = scheduler
+//!     .drain(Timestamp::from_count(48000., 0), 256)
+//!     .collect::<Vec<_>>();
+//! assert_eq!(events, [(100, "note on")]);
+//!
+//! // Next callback
+//! let events = scheduler
+//!     .drain(Timestamp::from_count(48000., 256), 256)
+//!     .collect::<Vec<_>>();
+//! assert_eq!(events, [(44, "note off")]);
+//! ```
+
+use crate::timestamp::Timestamp;
+
+/// Create a connected pair of event sender and scheduler, which can hold up to `capacity`
+/// pending events.
+pub fn event_scheduler<T>(capacity: usize) -> (EventSender<T>, EventScheduler<T>) {
+    let (producer, consumer) = rtrb::RingBuffer::new(capacity);
+    let sender = EventSender { producer };
+    let scheduler = EventScheduler {
+        consumer,
+        pending: Vec::with_capacity(capacity),
+        capacity,
+    };
+    (sender, scheduler)
+}
+
+/// Sending half of an event scheduler, to be used from a control thread.
+pub struct EventSender<T> {
+    producer: rtrb::Producer<(Timestamp, T)>,
+}
+
+impl<T> EventSender<T> {
+    /// Schedule an event at the given time. The event is given back if the queue is full.
+    ///
+    /// Events can be scheduled in any order. Events scheduled at the same time are delivered in
+    /// the order they were scheduled in.
+    pub fn schedule(&mut self, time: Timestamp, event: T) -> Result<(), T> {
+        self.producer
+            .push((time, event))
+            .map_err(|rtrb::PushError::Full((_, event))| event)
+    }
+
+    /// Number of events that can be scheduled before the queue is full.
+    pub fn available(&self) -> usize {
+        self.producer.slots()
+    }
+}
+
+/// Receiving half of an event scheduler, to be used from the audio callback.
+///
+/// Events are kept sorted in preallocated storage, and the scheduler neither allocates nor locks,
+/// which makes it safe to use in realtime contexts.
+pub struct EventScheduler<T> {
+    consumer: rtrb::Consumer<(Timestamp, T)>,
+    /// Pending events, sorted by descending time so that the next event is popped from the end
+    pending: Vec<(u64, T)>,
+    capacity: usize,
+}
+
+impl<T> EventScheduler<T> {
+    /// Drain the events falling within the buffer of `frames` frames starting at `timestamp`,
+    /// along with their offset into the buffer. Events which were scheduled before the start of
+    /// the buffer (because they arrived late) are returned with an offset of 0.
+    ///
+    /// Event times are compared with the timestamp at its sample rate; the generation of the
+    /// timestamps is not taken into account.
+    pub fn drain(&mut self, timestamp: Timestamp, frames: usize) -> DrainEvents<'_, T> {
+        self.receive(timestamp.samplerate);
+        DrainEvents {
+            pending: &mut self.pending,
+            start: timestamp.counter,
+            end: timestamp.counter.saturating_add(frames as u64),
+        }
+    }
+
+    /// Number of events received from the sender and not drained yet.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Drop all pending events, including those not received yet.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        while self.consumer.pop().is_ok() {}
+    }
+
+    fn receive(&mut self, samplerate: f64) {
+        while self.pending.len() < self.capacity {
+            let Ok((time, event)) = self.consumer.pop() else {
+                break;
+            };
+            let counter = if time.samplerate == samplerate {
+                time.counter
+            } else {
+                Timestamp::from_seconds(samplerate, time.as_seconds()).counter
+            };
+            // Inserting before events of the same time keeps them in scheduling order
+            let index = self.pending.partition_point(|(other, _)| *other > counter);
+            self.pending.insert(index, (counter, event));
+        }
+    }
+}
+
+/// Iterator over the events of a buffer, returned by [`EventScheduler::drain`]. Events not
+/// consumed from the iterator are kept for later buffers.
+pub struct DrainEvents<'a, T> {
+    pending: &'a mut Vec<(u64, T)>,
+    start: u64,
+    end: u64,
+}
+
+impl<T> Iterator for DrainEvents<'_, T> {
+    type Item = (usize, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (counter, _) = self.pending.last()?;
+        if *counter >= self.end {
+            return None;
+        }
+        let (counter, event) = self.pending.pop()?;
+        Some((counter.saturating_sub(self.start) as usize, event))
+    }
+}
@@ -0,0 +1,348 @@
+//! Test signal generators, for checking devices and measuring latencies.
+//!
+//! All generators implement [`AudioOutputCallback`], writing the same signal to every output
+//! channel, and can also be sampled one sample at a time with their `next_sample` method. They
+//! neither allocate nor lock, and are safe to run in realtime contexts.
+//!
+//! Generators start at [`DEFAULT_AMPLITUDE`], which can be changed through their `amplitude`
+//! field.
+//!
+//! ```rust
+//! use interflow::signals::Sine;
+//! let mut sine = Sine::new(1000.);
+//! // A quarter of a period of a 1 kHz sine at 48 kHz
+//! let samples = (0..13).map(|_| sine.next_sample(48000.)).collect::<Vec<_>>();
+//! assert_eq!(samples[0], 0.);
+//! assert!((samples[12] - sine.amplitude).abs() < 1e-6);
+//! ```
+
+use std::f32::consts::TAU;
+use std::time::Duration;
+
+use crate::{AudioCallbackContext, AudioOutput, AudioOutputCallback};
+
+/// Amplitude generators start with, at -18 dBFS so as not to blow up speakers and ears.
+pub const DEFAULT_AMPLITUDE: f32 = 0.125;
+
+/// Sine wave at a fixed frequency.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sine {
+    /// Frequency of the sine, in Hz.
+    pub frequency: f32,
+    /// Linear amplitude of the sine.
+    pub amplitude: f32,
+    /// Current phase, as a fraction of a period.
+    pub phase: f32,
+}
+
+impl Sine {
+    /// Create a sine wave at the given frequency.
+    pub fn new(frequency: f32) -> Self {
+        Self {
+            frequency,
+            amplitude: DEFAULT_AMPLITUDE,
+            phase: 0.,
+        }
+    }
+
+    /// Generate the next sample at the given sample rate.
+    pub fn next_sample(&mut self, samplerate: f32) -> f32 {
+        let y = self.amplitude * (TAU * self.phase).sin();
+        self.phase = (self.phase + self.frequency / samplerate).fract();
+        y
+    }
+}
+
+impl AudioOutputCallback for Sine {
+    fn on_output_data(&mut self, context: AudioCallbackContext, output: AudioOutput<f32>) {
+        let samplerate = context.stream_config.samplerate as f32;
+        render(output, || self.next_sample(samplerate));
+    }
+}
+
+/// Progression of the frequency of a [`Sweep`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SweepKind {
+    /// The frequency changes by the same number of Hz every second.
+    Linear,
+    /// The frequency changes by the same number of octaves every second (exponential sweep),
+    /// which is the usual choice for impulse response measurements.
+    Logarithmic,
+}
+
+/// Sine wave sweeping from one frequency to another over a given duration.
+///
+/// ```rust
+/// use std::time::Duration;
+/// use interflow::signals::Sweep;
+/// let mut sweep = Sweep::logarithmic(20., 20000., Duration::from_millis(10));
+/// for _ in 0..480 {
+///     sweep.next_sample(48000.);
+/// }
+/// assert!(sweep.is_finished());
+/// assert_eq!(sweep.next_sample(48000.), 0.);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sweep {
+    /// Frequency at the start of the sweep, in Hz.
+    pub start: f32,
+    /// Frequency at the end of the sweep, in Hz.
+    pub end: f32,
+    /// Duration of the sweep.
+    pub duration: Duration,
+    /// Progression of the frequency.
+    pub kind: SweepKind,
+    /// Linear amplitude of the sweep.
+    pub amplitude: f32,
+    /// Whether the sweep starts over once finished, instead of playing silence.
+    pub looping: bool,
+    phase: f32,
+    elapsed: u64,
+}
+
+impl Sweep {
+    /// Create a sweep whose frequency changes linearly.
+    pub fn linear(start: f32, end: f32, duration: Duration) -> Self {
+        Self::new(start, end, duration, SweepKind::Linear)
+    }
+
+    /// Create a sweep whose frequency changes exponentially.
+    pub fn logarithmic(start: f32, end: f32, duration: Duration) -> Self {
+        Self::new(start, end, duration, SweepKind::Logarithmic)
+    }
+
+    fn new(start: f32, end: f32, duration: Duration, kind: SweepKind) -> Self {
+        Self {
+            start,
+            end,
+            duration,
+            kind,
+            amplitude: DEFAULT_AMPLITUDE,
+            looping: false,
+            phase: 0.,
+            elapsed: 0,
+        }
+    }
+
+    /// Restart the sweep from its start frequency.
+    pub fn restart(&mut self) {
+        self.phase = 0.;
+        self.elapsed = 0;
+    }
+
+    /// Whether the sweep has reached its end. Looping sweeps are never finished.
+    pub fn is_finished(&self) -> bool {
+        self.elapsed == u64::MAX
+    }
+
+    /// Generate the next sample at the given sample rate.
+    pub fn next_sample(&mut self, samplerate: f32) -> f32 {
+        if self.elapsed == u64::MAX {
+            return 0.;
+        }
+        let length = ((self.duration.as_secs_f64() * samplerate as f64) as u64).max(1);
+        if self.elapsed >= length {
+            self.restart();
+        }
+        let t = self.elapsed as f32 / length as f32;
+        let frequency = match self.kind {
+            SweepKind::Linear => self.start + (self.end - self.start) * t,
+            SweepKind::Logarithmic => self.start * (self.end / self.start).powf(t),
+        };
+        let y = self.amplitude * (TAU * self.phase).sin();
+        self.phase = (self.phase + frequency / samplerate).fract();
+        self.elapsed += 1;
+        if !self.looping && self.elapsed >= length {
+            self.elapsed = u64::MAX;
+        }
+        y
+    }
+}
+
+impl AudioOutputCallback for Sweep {
+    fn on_output_data(&mut self, context: AudioCallbackContext, output: AudioOutput<f32>) {
+        let samplerate = context.stream_config.samplerate as f32;
+        render(output, || self.next_sample(samplerate));
+    }
+}
+
+/// White noise, with equal energy at all frequencies.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WhiteNoise {
+    /// Peak linear amplitude of the noise.
+    pub amplitude: f32,
+    state: u64,
+}
+
+impl Default for WhiteNoise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WhiteNoise {
+    /// Create a white noise generator with a fixed seed.
+    pub fn new() -> Self {
+        Self::with_seed(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Create a white noise generator with the given seed. Generators with the same seed produce
+    /// the same noise.
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            amplitude: DEFAULT_AMPLITUDE,
+            // The xorshift state must not be zero
+            state: seed.max(1),
+        }
+    }
+
+    /// Generate the next sample.
+    pub fn next_sample(&mut self) -> f32 {
+        self.amplitude * self.next_unit()
+    }
+
+    /// Next value of the xorshift64* generator, uniformly distributed in [-1, 1).
+    fn next_unit(&mut self) -> f32 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let bits = self.state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 40;
+        bits as f32 / (1u32 << 23) as f32 - 1.
+    }
+}
+
+impl AudioOutputCallback for WhiteNoise {
+    fn on_output_data(&mut self, _context: AudioCallbackContext, output: AudioOutput<f32>) {
+        render(output, || self.next_sample());
+    }
+}
+
+/// Pink noise, with equal energy in every octave, filtered from white noise with Paul Kellet's
+/// method.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PinkNoise {
+    /// Approximate peak linear amplitude of the noise.
+    pub amplitude: f32,
+    white: WhiteNoise,
+    filter: [f32; 7],
+}
+
+impl Default for PinkNoise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PinkNoise {
+    /// Create a pink noise generator with a fixed seed.
+    pub fn new() -> Self {
+        Self::with_seed(WhiteNoise::new().state)
+    }
+
+    /// Create a pink noise generator with the given seed.
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            amplitude: DEFAULT_AMPLITUDE,
+            white: WhiteNoise::with_seed(seed),
+            filter: [0.; 7],
+        }
+    }
+
+    /// Generate the next sample.
+    pub fn next_sample(&mut self) -> f32 {
+        let white = self.white.next_unit();
+        let b = &mut self.filter;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.153852;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b[..6].iter().sum::<f32>() + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+        // The filter has a gain of about 5 at its peak
+        self.amplitude * pink * 0.2
+    }
+}
+
+impl AudioOutputCallback for PinkNoise {
+    fn on_output_data(&mut self, _context: AudioCallbackContext, output: AudioOutput<f32>) {
+        render(output, || self.next_sample());
+    }
+}
+
+/// Single-sample impulses, played once or periodically, for instance to measure the round-trip
+/// latency of a device by looking for them in its input.
+///
+/// ```rust
+/// use std::time::Duration;
+/// use interflow::signals::Impulse;
+/// let mut impulse = Impulse::periodic(Duration::from_millis(1));
+/// let samples = (0..96).map(|_| impulse.next_sample(48000.)).collect::<Vec<_>>();
+/// assert_eq!(samples[0], impulse.amplitude);
+/// assert_eq!(samples[48], impulse.amplitude);
+/// assert_eq!(samples.iter().filter(|x| **x != 0.).count(), 2);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Impulse {
+    /// Time between impulses, or `None` to play a single impulse.
+    pub period: Option<Duration>,
+    /// Linear amplitude of the impulses.
+    pub amplitude: f32,
+    elapsed: u64,
+}
+
+impl Impulse {
+    /// Create a generator playing a single impulse, on its first sample.
+    pub fn once() -> Self {
+        Self {
+            period: None,
+            amplitude: DEFAULT_AMPLITUDE,
+            elapsed: 0,
+        }
+    }
+
+    /// Create a generator playing an impulse every `period`, starting on its first sample.
+    pub fn periodic(period: Duration) -> Self {
+        Self {
+            period: Some(period),
+            ..Self::once()
+        }
+    }
+
+    /// Start over, playing an impulse on the next sample.
+    pub fn restart(&mut self) {
+        self.elapsed = 0;
+    }
+
+    /// Generate the next sample at the given sample rate.
+    pub fn next_sample(&mut self, samplerate: f32) -> f32 {
+        let y = if self.elapsed == 0 {
+            self.amplitude
+        } else {
+            0.
+        };
+        self.elapsed = self.elapsed.saturating_add(1);
+        if let Some(period) = self.period {
+            let length = (period.as_secs_f64() * samplerate as f64).round() as u64;
+            if self.elapsed >= length.max(1) {
+                self.elapsed = 0;
+            }
+        }
+        y
+    }
+}
+
+impl AudioOutputCallback for Impulse {
+    fn on_output_data(&mut self, context: AudioCallbackContext, output: AudioOutput<f32>) {
+        let samplerate = context.stream_config.samplerate as f32;
+        render(output, || self.next_sample(samplerate));
+    }
+}
+
+/// Write a mono signal to all channels of an output buffer.
+fn render(mut output: AudioOutput<f32>, mut next_sample: impl FnMut() -> f32) {
+    for i in 0..output.buffer.num_samples() {
+        output.buffer.set_mono(i, next_sample());
+    }
+}
@@ -0,0 +1,225 @@
+//! Glitch-free switching between output callbacks.
+//!
+//! A [`CallbackSwitcher`] plays an active callback, and replaces it with callbacks sent from a
+//! control thread through its [`SwitcherHandle`], crossfading between the old and new callbacks
+//! on the audio thread. This is useful to switch between scenes or presets while the stream is
+//! running. The switcher neither allocates nor locks on the audio thread; replaced callbacks are
+//! handed back to the control thread to be dropped there.
+//!
+//! ```no_run
+//! use interflow::prelude::*;
+//! use interflow::signals::{PinkNoise, Sine};
+//! use interflow::switcher::CallbackSwitcher;
+//! let device = default_output_device();
+//! let config = device.default_output_config().unwrap();
+//! // Crossfade over 100 ms
+//! let frames = (config.samplerate / 10.) as usize;
+//! let (switcher, mut handle) = CallbackSwitcher::new(Sine::new(440.), frames);
+//! let stream = device.create_output_stream(config, switcher).unwrap();
+//! std::thread::sleep(std::time::Duration::from_secs(1));
+//! assert!(handle.switch(PinkNoise::new()));
+//! std::thread::sleep(std::time::Duration::from_secs(1));
+//! stream.eject().unwrap();
+//! ```
+
+use std::time::Duration;
+
+use crate::audio_buffer::AudioBuffer;
+use crate::channel_map::Bitset;
+use crate::timestamp::Timestamp;
+use crate::{AudioCallbackContext, AudioOutput, AudioOutputCallback};
+
+/// Maximum number of replacement callbacks waiting to be switched to.
+pub const MAX_PENDING_SWITCHES: usize = 16;
+
+/// Number of frames rendered at a time during crossfades; larger buffers are rendered in several
+/// chunks.
+const CHUNK_FRAMES: usize = 512;
+
+type BoxedCallback = Box<dyn Send + AudioOutputCallback>;
+
+/// Output callback playing an active callback, which can be replaced from another thread.
+///
+/// Replacements are faded in linearly while the previous callback is faded out. Replacements sent
+/// during a crossfade wait for it to finish, and are then switched to in order.
+pub struct CallbackSwitcher {
+    active: BoxedCallback,
+    fade: Option<Fade>,
+    crossfade_frames: usize,
+    incoming: rtrb::Consumer<BoxedCallback>,
+    outgoing: rtrb::Producer<BoxedCallback>,
+    scratch: AudioBuffer<f32>,
+}
+
+/// Control handle of a [`CallbackSwitcher`], sending it replacement callbacks.
+pub struct SwitcherHandle {
+    commands: rtrb::Producer<BoxedCallback>,
+    garbage: rtrb::Consumer<BoxedCallback>,
+}
+
+impl CallbackSwitcher {
+    /// Create a switcher playing the given callback, crossfading over `crossfade_frames` frames
+    /// when switching callbacks. Switches are immediate with no crossfade frames.
+    pub fn new(
+        callback: impl 'static + Send + AudioOutputCallback,
+        crossfade_frames: usize,
+    ) -> (Self, SwitcherHandle) {
+        let (commands, incoming) = rtrb::RingBuffer::new(MAX_PENDING_SWITCHES);
+        // Room for every pending callback, along with the one being faded out
+        let (outgoing, garbage) = rtrb::RingBuffer::new(MAX_PENDING_SWITCHES + 1);
+        let switcher = Self {
+            active: Box::new(callback),
+            fade: None,
+            crossfade_frames,
+            incoming,
+            outgoing,
+            scratch: AudioBuffer::default(),
+        };
+        (switcher, SwitcherHandle { commands, garbage })
+    }
+
+    fn render_crossfade(&mut self, context: &AudioCallbackContext, output: &mut AudioOutput<f32>) {
+        let samplerate = context.stream_config.samplerate;
+        let num_samples = output.buffer.num_samples();
+        let mut offset = 0;
+        while offset < num_samples {
+            let Some(fade) = &mut self.fade else {
+                // The crossfade ended within this buffer
+                let context = offset_context(context, offset, samplerate);
+                self.active.on_output_data(
+                    context,
+                    AudioOutput {
+                        timestamp: output.timestamp + offset as u64,
+                        buffer: output.buffer.slice_mut(offset..),
+                    },
+                );
+                return;
+            };
+            let frames = CHUNK_FRAMES
+                .min(num_samples - offset)
+                .min(self.crossfade_frames - fade.position);
+            let chunk_context = offset_context(context, offset, samplerate);
+            let timestamp = output.timestamp + offset as u64;
+
+            let mut chunk = output.buffer.slice_mut(offset..offset + frames);
+            chunk.as_interleaved_mut().fill(0.);
+            self.active.on_output_data(
+                chunk_context,
+                AudioOutput {
+                    timestamp,
+                    buffer: chunk,
+                },
+            );
+            let mut scratch = self.scratch.slice_mut(..frames);
+            scratch.as_interleaved_mut().fill(0.);
+            fade.callback.on_output_data(
+                chunk_context,
+                AudioOutput {
+                    timestamp,
+                    buffer: scratch,
+                },
+            );
+
+            let mut chunk = output.buffer.slice_mut(offset..offset + frames);
+            let scratch = self.scratch.slice(..frames);
+            for i in 0..frames {
+                let gain = (fade.position + i) as f32 / self.crossfade_frames as f32;
+                let old = scratch.get_frame(i);
+                for (sample, old) in chunk.get_frame_mut(i).iter_mut().zip(old.iter()) {
+                    *sample = gain * *sample + (1. - gain) * old;
+                }
+            }
+            fade.position += frames;
+            if fade.position >= self.crossfade_frames {
+                let fade = self.fade.take().unwrap();
+                // Only deallocate here when the handle is not collecting replaced callbacks
+                let _ = self.outgoing.push(fade.callback);
+            }
+            offset += frames;
+        }
+    }
+}
+
+impl SwitcherHandle {
+    /// Switch to the given callback. Returns `false` when too many switches are already pending,
+    /// in which case the callback is dropped.
+    pub fn switch(&mut self, callback: impl 'static + Send + AudioOutputCallback) -> bool {
+        self.collect_garbage();
+        self.commands.push(Box::new(callback)).is_ok()
+    }
+
+    /// Number of switches sent but not yet started by the audio thread.
+    pub fn pending(&self) -> usize {
+        MAX_PENDING_SWITCHES - self.commands.slots()
+    }
+
+    /// Drop the callbacks replaced since the last call.
+    fn collect_garbage(&mut self) {
+        while self.garbage.pop().is_ok() {}
+    }
+}
+
+impl AudioOutputCallback for CallbackSwitcher {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        self.active.prepare(context);
+        if let Some(fade) = &mut self.fade {
+            fade.callback.prepare(context);
+        }
+        let channels = context.stream_config.output_channels.count();
+        if self.scratch.num_channels() != channels {
+            self.scratch = AudioBuffer::zeroed(channels, CHUNK_FRAMES);
+        }
+    }
+
+    fn on_output_data(&mut self, context: AudioCallbackContext, mut output: AudioOutput<f32>) {
+        if self.fade.is_none() {
+            if let Ok(mut callback) = self.incoming.pop() {
+                let stream_config = context.stream_config;
+                callback.prepare(AudioCallbackContext {
+                    stream_config,
+                    timestamp: Timestamp::new(stream_config.samplerate),
+                    output_time: None,
+                    deadline: None,
+                    queued_frames: None,
+                    frames_since_last_callback: None,
+                    discontinuity: false,
+                });
+                let previous = std::mem::replace(&mut self.active, callback);
+                if self.crossfade_frames == 0 || self.scratch.num_channels() == 0 {
+                    let _ = self.outgoing.push(previous);
+                } else {
+                    self.fade = Some(Fade {
+                        callback: previous,
+                        position: 0,
+                    });
+                }
+            }
+        }
+        if self.fade.is_some() {
+            self.render_crossfade(&context, &mut output);
+        } else {
+            self.active.on_output_data(context, output);
+        }
+    }
+}
+
+/// Callback being faded out.
+struct Fade {
+    callback: BoxedCallback,
+    /// Number of frames of the crossfade rendered so far
+    position: usize,
+}
+
+/// Context of a chunk of the buffer, starting `offset` frames into it.
+fn offset_context(
+    context: &AudioCallbackContext,
+    offset: usize,
+    samplerate: f64,
+) -> AudioCallbackContext {
+    let offset_duration = Duration::from_secs_f64(offset as f64 / samplerate);
+    AudioCallbackContext {
+        timestamp: context.timestamp + offset as u64,
+        output_time: context.output_time.map(|time| time + offset_duration),
+        ..*context
+    }
+}
@@ -0,0 +1,326 @@
+//! Playback of audio files decoded with the [`symphonia`](::symphonia) crate.
+//!
+//! [`DecoderCallback`] decodes a file on a background thread, resamples it to the sample rate of
+//! the stream with a [`SincResampler`], and hands it over to the output callback through a ring
+//! buffer, so that decoding never blocks the audio thread.
+//!
+//! ```no_run
+//! use interflow::prelude::*;
+//! use interflow::symphonia::DecoderCallback;
+//! let device = default_output_device();
+//! let config = device.default_output_config().unwrap();
+//! let callback = DecoderCallback::open("song.mp3", &config).unwrap();
+//! let status = callback.status();
+//! let stream = device.create_output_stream(config, callback).unwrap();
+//! while !status.is_finished() {
+//!     std::thread::sleep(std::time::Duration::from_millis(100));
+//! }
+//! stream.eject().unwrap();
+//! ```
+
+use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use ::symphonia::core::audio::SampleBuffer;
+use ::symphonia::core::codecs::{Decoder, DecoderOptions};
+use ::symphonia::core::errors::Error as SymphoniaError;
+use ::symphonia::core::formats::{FormatOptions, FormatReader};
+use ::symphonia::core::io::MediaSourceStream;
+use ::symphonia::core::meta::MetadataOptions;
+use ::symphonia::core::probe::Hint;
+use thiserror::Error;
+
+use crate::audio_buffer::{AudioBuffer, AudioRef};
+use crate::channel_map::Bitset;
+use crate::diagnostics;
+use crate::resample::{Resampler, SincResampler};
+use crate::{AudioCallbackContext, AudioOutput, AudioOutputCallback, StreamConfig};
+
+/// Duration of decoded audio buffered ahead of the output callback.
+const BUFFER_DURATION: f64 = 0.5;
+
+/// Number of frames resampled at a time by the decoding thread.
+const CHUNK_FRAMES: usize = 1024;
+
+/// Interval at which the decoding thread checks for room in the ring buffer when it is full.
+const DECODER_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Errors which can happen when opening a file for playback.
+#[derive(Debug, Error)]
+pub enum PlaybackError {
+    /// The file could not be opened.
+    #[error("Cannot open file: {0}")]
+    Io(#[from] std::io::Error),
+    /// The file could not be probed, or its codec is not supported.
+    #[error(transparent)]
+    Symphonia(#[from] SymphoniaError),
+    /// The file does not contain any audio track.
+    #[error("No audio track found")]
+    NoTrack,
+}
+
+/// Output callback playing a file decoded by symphonia.
+///
+/// Audio is played on the output channels of the stream configuration given at creation. Mono
+/// files are played on all channels; other files have their channels played in order, with
+/// missing channels left silent. Silence is played once the file has finished playing, or when
+/// decoding cannot keep up.
+pub struct DecoderCallback {
+    consumer: rtrb::Consumer<f32>,
+    channels: usize,
+    status: PlaybackStatus,
+}
+
+/// Status of the playback of a [`DecoderCallback`], which can be queried from any thread.
+#[derive(Debug, Clone, Default)]
+pub struct PlaybackStatus {
+    shared: Arc<Shared>,
+}
+
+impl DecoderCallback {
+    /// Open the file at the given path for playback with the given stream configuration. The
+    /// container format is guessed from the contents of the file and its extension.
+    pub fn open(
+        path: impl AsRef<Path>,
+        stream_config: &StreamConfig,
+    ) -> Result<Self, PlaybackError> {
+        let path = path.as_ref();
+        let mut hint = Hint::new();
+        if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+            hint.with_extension(extension);
+        }
+        let source = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+        let probed = ::symphonia::default::get_probe().format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?;
+        Self::new(probed.format, stream_config)
+    }
+
+    /// Play the default track of the given format reader with the given stream configuration.
+    pub fn new(
+        format: Box<dyn FormatReader>,
+        stream_config: &StreamConfig,
+    ) -> Result<Self, PlaybackError> {
+        let track = format.default_track().ok_or(PlaybackError::NoTrack)?;
+        let decoder = ::symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())?;
+        let samplerate = track
+            .codec_params
+            .sample_rate
+            .map_or(stream_config.samplerate, |rate| rate as f64);
+        let file_channels = track
+            .codec_params
+            .channels
+            .map_or(1, |ch| ch.count())
+            .max(1);
+        let channels = stream_config.output_channels.count().max(1);
+        let capacity = ((stream_config.samplerate * BUFFER_DURATION) as usize)
+            .max(2 * CHUNK_FRAMES)
+            * channels;
+        let (producer, consumer) = rtrb::RingBuffer::new(capacity);
+        let status = PlaybackStatus::default();
+        status.shared.set_samplerate(stream_config.samplerate);
+        let thread = DecoderThread {
+            track_id: track.id,
+            format,
+            decoder,
+            producer,
+            resampler: SincResampler::new(file_channels, samplerate, stream_config.samplerate),
+            resampled: AudioBuffer::zeroed(file_channels, CHUNK_FRAMES),
+            file_samplerate: samplerate,
+            channels,
+            shared: status.shared.clone(),
+        };
+        std::thread::spawn(move || thread.run());
+        Ok(Self {
+            consumer,
+            channels,
+            status,
+        })
+    }
+
+    /// Handle to the status of the playback, to keep once the callback is given to a stream.
+    pub fn status(&self) -> PlaybackStatus {
+        self.status.clone()
+    }
+}
+
+impl PlaybackStatus {
+    /// Whether the whole file has been played, or decoding has stopped because of an error.
+    pub fn is_finished(&self) -> bool {
+        self.shared.finished.load(Ordering::Relaxed)
+    }
+
+    /// Duration of audio played so far.
+    pub fn position(&self) -> Duration {
+        let frames = self.shared.frames_played.load(Ordering::Relaxed);
+        Duration::from_secs_f64(frames as f64 / self.shared.samplerate())
+    }
+}
+
+impl AudioOutputCallback for DecoderCallback {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        // The decoding thread picks up the new rate for the audio it resamples next
+        self.status
+            .shared
+            .set_samplerate(context.stream_config.samplerate);
+    }
+
+    fn on_output_data(&mut self, _context: AudioCallbackContext, mut output: AudioOutput<f32>) {
+        let available = self.consumer.slots() / self.channels;
+        let frames = output.buffer.num_samples().min(available);
+        for i in 0..frames {
+            let mut frame = output.buffer.get_frame_mut(i);
+            for ch in 0..self.channels {
+                let sample = self.consumer.pop().unwrap_or(0.);
+                if let Some(out) = frame.get_mut(ch) {
+                    *out = sample;
+                }
+            }
+        }
+        for i in frames..output.buffer.num_samples() {
+            output.buffer.get_frame_mut(i).fill(0.);
+        }
+        let shared = &self.status.shared;
+        shared
+            .frames_played
+            .fetch_add(frames as u64, Ordering::Relaxed);
+        if self.consumer.is_empty() && shared.decoded.load(Ordering::Acquire) {
+            shared.finished.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// State shared between the callback, its decoding thread, and status handles.
+#[derive(Debug, Default)]
+struct Shared {
+    samplerate: AtomicU64,
+    frames_played: AtomicU64,
+    decoded: AtomicBool,
+    finished: AtomicBool,
+}
+
+impl Shared {
+    fn samplerate(&self) -> f64 {
+        f64::from_bits(self.samplerate.load(Ordering::Relaxed))
+    }
+
+    fn set_samplerate(&self, samplerate: f64) {
+        self.samplerate
+            .store(samplerate.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Decoding thread of a [`DecoderCallback`].
+struct DecoderThread {
+    track_id: u32,
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    producer: rtrb::Producer<f32>,
+    resampler: SincResampler,
+    resampled: AudioBuffer<f32>,
+    file_samplerate: f64,
+    channels: usize,
+    shared: Arc<Shared>,
+}
+
+impl DecoderThread {
+    fn run(mut self) {
+        let mut samples = None::<SampleBuffer<f32>>;
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(err))
+                    if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    break;
+                }
+                Err(err) => {
+                    diagnostics::error!("Cannot read packet: {err}");
+                    break;
+                }
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // Corrupted packets are skipped, as recommended by symphonia
+                Err(SymphoniaError::DecodeError(err)) => {
+                    diagnostics::warn!("Skipping undecodable packet: {err}");
+                    continue;
+                }
+                Err(err) => {
+                    diagnostics::error!("Cannot decode packet: {err}");
+                    break;
+                }
+            };
+            let spec = *decoded.spec();
+            let needed = decoded.capacity() * spec.channels.count();
+            if samples
+                .as_ref()
+                .is_some_and(|buffer| buffer.capacity() < needed)
+            {
+                samples = None;
+            }
+            let buffer =
+                samples.get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, spec));
+            buffer.copy_interleaved_ref(decoded);
+            let Some(input) =
+                AudioRef::from_interleaved(buffer.samples(), self.resampler.channels())
+            else {
+                continue;
+            };
+            if !self.push(input) {
+                // The callback was dropped
+                return;
+            }
+        }
+        // Flush the frames held back by the resampler
+        let padding = AudioBuffer::zeroed(self.resampler.channels(), self.resampler.latency());
+        self.push(padding.as_ref());
+        self.shared.decoded.store(true, Ordering::Release);
+    }
+
+    /// Resample the given frames and send them to the callback, waiting for room in the ring
+    /// buffer. Returns `false` when the callback has been dropped.
+    fn push(&mut self, input: AudioRef<f32>) -> bool {
+        let mut offset = 0;
+        while offset < input.num_samples() {
+            self.resampler
+                .set_ratio(self.shared.samplerate() / self.file_samplerate);
+            let (consumed, written) = self
+                .resampler
+                .process(input.slice(offset..), self.resampled.as_mut());
+            offset += consumed;
+            let len = written * self.channels;
+            while self.producer.slots() < len {
+                if self.producer.is_abandoned() {
+                    return false;
+                }
+                std::thread::sleep(DECODER_POLL_INTERVAL);
+            }
+            let Ok(chunk) = self.producer.write_chunk_uninit(len) else {
+                return false;
+            };
+            let resampled = self.resampled.slice(..written);
+            let file_channels = resampled.num_channels();
+            let channels = self.channels;
+            chunk.fill_from_iter((0..written).flat_map(|i| {
+                let frame = resampled.get_frame(i);
+                (0..channels).map(move |ch| match file_channels {
+                    // Mono files are played on all channels
+                    1 => frame[0],
+                    _ => frame.get(ch).copied().unwrap_or(0.),
+                })
+            }));
+        }
+        true
+    }
+}
@@ -0,0 +1,226 @@
+//! Fan-out of one input stream to several callbacks.
+//!
+//! A [`Tee`] is an input callback which copies the audio it receives to any number of branch
+//! callbacks, each running on its own thread and fed through lock-free queues. This lets, for
+//! instance, a recorder and a level meter observe the same microphone without opening the device
+//! twice, and without a slow branch holding up the audio thread or the other branches.
+//!
+//! ```no_run
+//! use interflow::channel_map::Bitset;
+//! use interflow::prelude::*;
+//! use interflow::tee::Tee;
+//! # struct Meter;
+//! # impl AudioInputCallback for Meter {
+//! #     fn on_input_data(&mut self, _: AudioCallbackContext, _: AudioInput<f32>) {}
+//! # }
+//! let device = default_input_device();
+//! let config = device.default_input_config().unwrap();
+//! let mut tee = Tee::new(config.input_channels.count(), 48000);
+//! let meter = tee.add(Meter);
+//! let stream = device.create_input_stream(config, tee).unwrap();
+//! // ...
+//! drop(stream.eject().unwrap());
+//! let meter = meter.join().unwrap();
+//! ```
+
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::audio_buffer::AudioRef;
+use crate::clock::HostTime;
+use crate::timestamp::Timestamp;
+use crate::{AudioCallbackContext, AudioInput, AudioInputCallback, StreamConfig};
+
+/// Maximum number of buffers queued for a branch, regardless of their size.
+const MAX_QUEUED_BUFFERS: usize = 64;
+
+/// Interval at which branch threads check for new audio when their queue is empty.
+const BRANCH_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Input callback copying its input to several branch callbacks, running on their own threads.
+///
+/// Branches whose queue is full when new audio comes in miss that audio, and are told about it
+/// through [`AudioCallbackContext::discontinuity`] on the next buffer they receive. Branch threads
+/// stop once the tee is dropped and they have processed all queued audio.
+pub struct Tee {
+    branches: Vec<Branch>,
+    channels: usize,
+    capacity: usize,
+}
+
+/// Handle to a branch of a [`Tee`], giving back its callback once the tee is dropped.
+#[derive(Debug)]
+pub struct TeeBranch<Callback> {
+    thread: JoinHandle<Callback>,
+}
+
+impl Tee {
+    /// Create a tee for streams of the given number of channels, whose branches can each queue up
+    /// to `capacity` frames.
+    pub fn new(channels: usize, capacity: usize) -> Self {
+        Self {
+            branches: Vec::new(),
+            channels: channels.max(1),
+            capacity,
+        }
+    }
+
+    /// Add a branch processing the input with the given callback, on a new thread.
+    pub fn add<Callback: 'static + Send + AudioInputCallback>(
+        &mut self,
+        mut callback: Callback,
+    ) -> TeeBranch<Callback> {
+        let (messages, mut message_rx) = rtrb::RingBuffer::new(MAX_QUEUED_BUFFERS);
+        let (samples, mut sample_rx) = rtrb::RingBuffer::new(self.capacity * self.channels);
+        let mut scratch = Vec::with_capacity(self.capacity * self.channels);
+        let thread = std::thread::spawn(move || {
+            loop {
+                // Checked before popping, so that messages sent right before the tee was dropped
+                // are still processed
+                let abandoned = message_rx.is_abandoned();
+                let Ok(message) = message_rx.pop() else {
+                    if abandoned {
+                        break;
+                    }
+                    std::thread::sleep(BRANCH_POLL_INTERVAL);
+                    continue;
+                };
+                match message {
+                    Message::Prepare(stream_config) => callback.prepare(AudioCallbackContext {
+                        stream_config,
+                        timestamp: Timestamp::new(stream_config.samplerate),
+                        output_time: None,
+                        deadline: None,
+                        queued_frames: None,
+                        frames_since_last_callback: None,
+                        discontinuity: false,
+                    }),
+                    Message::Input(block) => {
+                        let len = block.frames * block.channels;
+                        scratch.clear();
+                        // Samples are queued before the message announcing them
+                        if let Ok(chunk) = sample_rx.read_chunk(len) {
+                            let (first, second) = chunk.as_slices();
+                            scratch.extend_from_slice(first);
+                            scratch.extend_from_slice(second);
+                            chunk.commit_all();
+                        }
+                        let Some(buffer) = AudioRef::from_interleaved(&scratch, block.channels)
+                        else {
+                            continue;
+                        };
+                        callback.on_input_data(
+                            block.context(),
+                            AudioInput {
+                                timestamp: block.timestamp,
+                                capture_time: block.capture_time,
+                                buffer,
+                            },
+                        );
+                    }
+                }
+            }
+            callback
+        });
+        self.branches.push(Branch {
+            messages,
+            samples,
+            missed: false,
+        });
+        TeeBranch { thread }
+    }
+}
+
+impl<Callback> TeeBranch<Callback> {
+    /// Wait for the branch thread to stop, which happens once the tee has been dropped, and
+    /// return its callback. Returns an error if the callback panicked.
+    pub fn join(self) -> std::thread::Result<Callback> {
+        self.thread.join()
+    }
+}
+
+impl AudioInputCallback for Tee {
+    fn prepare(&mut self, context: AudioCallbackContext) {
+        for branch in &mut self.branches {
+            // Branches missing the new configuration see the next buffers as discontinuous
+            if branch
+                .messages
+                .push(Message::Prepare(context.stream_config))
+                .is_err()
+            {
+                branch.missed = true;
+            }
+        }
+    }
+
+    fn on_input_data(&mut self, context: AudioCallbackContext, input: AudioInput<f32>) {
+        let frames = input.buffer.num_samples();
+        let channels = input.buffer.num_channels();
+        let len = frames * channels;
+        for branch in &mut self.branches {
+            if branch.messages.is_full() {
+                branch.missed = true;
+                continue;
+            }
+            let Ok(chunk) = branch.samples.write_chunk_uninit(len) else {
+                branch.missed = true;
+                continue;
+            };
+            chunk.fill_from_iter(input.buffer.as_interleaved().iter().copied());
+            let block = Block {
+                stream_config: context.stream_config,
+                timestamp: input.timestamp,
+                capture_time: input.capture_time,
+                queued_frames: context.queued_frames,
+                frames_since_last_callback: context.frames_since_last_callback,
+                discontinuity: context.discontinuity || branch.missed,
+                frames,
+                channels,
+            };
+            // Cannot fail, the queue was checked not to be full
+            let _ = branch.messages.push(Message::Input(block));
+            branch.missed = false;
+        }
+    }
+}
+
+/// Queues of a branch, on the side of the audio thread.
+struct Branch {
+    messages: rtrb::Producer<Message>,
+    samples: rtrb::Producer<f32>,
+    /// Whether the branch missed audio since the last buffer it was sent
+    missed: bool,
+}
+
+enum Message {
+    Prepare(StreamConfig),
+    Input(Block),
+}
+
+/// Description of a buffer of input sent to a branch, whose samples are in the sample queue.
+struct Block {
+    stream_config: StreamConfig,
+    timestamp: Timestamp,
+    capture_time: Option<HostTime>,
+    queued_frames: Option<usize>,
+    frames_since_last_callback: Option<u64>,
+    discontinuity: bool,
+    frames: usize,
+    channels: usize,
+}
+
+impl Block {
+    /// Context given to the branch callback. Branches have no deadline, as they run on their own
+    /// threads.
+    fn context(&self) -> AudioCallbackContext {
+        AudioCallbackContext {
+            stream_config: self.stream_config,
+            timestamp: self.timestamp,
+            output_time: None,
+            deadline: None,
+            queued_frames: self.queued_frames,
+            frames_since_last_callback: self.frames_since_last_callback,
+            discontinuity: self.discontinuity,
+        }
+    }
+}
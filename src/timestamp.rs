@@ -1,7 +1,10 @@
+use std::fmt;
 use std::ops;
 use std::ops::AddAssign;
 use std::time::Duration;
 
+use crate::clock::HostTime;
+
 /// Timestamp value, which computes duration information from a provided samplerate and a running
 /// sample counter.
 ///
@@ -48,24 +51,133 @@ use std::time::Duration;
 /// assert_eq!(ts.counter, 44); // Note that the conversion is lossy, as only whole samples are
 ///                             // stored in the timestamp.
 /// ```
-#[derive(Debug, Copy, Clone, PartialEq)]
+///
+/// Counters of a long-running stream are not guaranteed to keep increasing: the counter wraps
+/// around on overflow, and backends restart it when the device is reconfigured. Each of these
+/// discontinuities increments the [`generation`](Self::generation) of the timestamp, so that
+/// code comparing timestamps can detect them instead of seeing time jump backwards:
+///
+/// ```rust
+/// use interflow::timestamp::Timestamp;
+/// let mut ts = Timestamp::from_count(48000., u64::MAX - 10);
+/// let before = ts;
+/// ts += 20;
+/// assert_eq!(ts.counter, 9);
+/// assert_eq!(ts.generation, 1);
+/// assert_eq!(ts.frames_since(&before), None);
+///
+/// let mut ts = Timestamp::new(48000.);
+/// ts.advance_to(4800);
+/// let before = ts;
+/// ts.advance_to(9600);
+/// assert_eq!(ts.frames_since(&before), Some(4800));
+/// ts.advance_to(0); // The device clock was reset
+/// assert_eq!(ts.generation, 1);
+/// assert_eq!(ts.frames_since(&before), None);
+/// ```
+///
+/// Timestamps display as the time they represent along with their sample rate:
+///
+/// ```rust
+/// use interflow::timestamp::Timestamp;
+/// let ts = Timestamp::from_count(44100., 44100 * 75 + 441);
+/// assert_eq!(ts.to_string(), "01:15.010 @ 44.1kHz");
+/// ```
+#[derive(Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Timestamp {
     /// Number of samples counted in this timestamp.
     pub counter: u64,
     /// Samplerate of the audio stream associated with the counter.
     pub samplerate: f64,
+    /// Generation of the timeline the counter belongs to. It is incremented every time the counter
+    /// is discontinuous, and counters are only comparable between timestamps of the same
+    /// generation.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub generation: u32,
+}
+
+impl fmt::Debug for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Timestamp")
+            .field("counter", &self.counter)
+            .field("samplerate", &self.samplerate)
+            .field("generation", &self.generation)
+            .field(
+                "time",
+                &format_args!("{}", display_duration(self.as_duration())),
+            )
+            .finish()
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} @ {}",
+            display_duration(self.as_duration()),
+            display_samplerate(self.samplerate)
+        )
+    }
+}
+
+/// Format a duration as minutes, seconds and milliseconds (`mm:ss.mmm`). Minutes are not wrapped
+/// into hours.
+///
+/// ```rust
+/// use std::time::Duration;
+/// use interflow::timestamp::display_duration;
+/// assert_eq!(display_duration(Duration::from_millis(61_250)).to_string(), "01:01.250");
+/// ```
+pub fn display_duration(duration: Duration) -> impl fmt::Display {
+    struct DisplayDuration(Duration);
+
+    impl fmt::Display for DisplayDuration {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let millis = self.0.as_millis();
+            let minutes = millis / 60_000;
+            let seconds = millis / 1000 % 60;
+            write!(f, "{minutes:02}:{seconds:02}.{:03}", millis % 1000)
+        }
+    }
+
+    DisplayDuration(duration)
+}
+
+/// Format a sample rate in kilohertz, such as `48kHz` or `44.1kHz`.
+///
+/// ```rust
+/// use interflow::timestamp::display_samplerate;
+/// assert_eq!(display_samplerate(48000.).to_string(), "48kHz");
+/// assert_eq!(display_samplerate(22050.).to_string(), "22.05kHz");
+/// ```
+pub fn display_samplerate(samplerate: f64) -> impl fmt::Display {
+    struct DisplaySamplerate(f64);
+
+    impl fmt::Display for DisplaySamplerate {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}kHz", self.0 / 1000.)
+        }
+    }
+
+    DisplaySamplerate(samplerate)
 }
 
 impl AddAssign<Duration> for Timestamp {
     fn add_assign(&mut self, rhs: Duration) {
         let samples = rhs.as_secs_f64() * self.samplerate;
-        self.counter += samples as u64;
+        *self += samples as u64;
     }
 }
 
 impl AddAssign<u64> for Timestamp {
     fn add_assign(&mut self, rhs: u64) {
-        self.counter += rhs;
+        let (counter, overflow) = self.counter.overflowing_add(rhs);
+        self.counter = counter;
+        if overflow {
+            self.generation = self.generation.wrapping_add(1);
+        }
     }
 }
 
@@ -87,6 +199,7 @@ impl Timestamp {
         Self {
             counter: 0,
             samplerate,
+            generation: 0,
         }
     }
 
@@ -95,6 +208,7 @@ impl Timestamp {
         Self {
             samplerate,
             counter,
+            generation: 0,
         }
     }
 
@@ -111,9 +225,38 @@ impl Timestamp {
         Self {
             samplerate,
             counter: samples as _,
+            generation: 0,
         }
     }
 
+    /// Move the counter to the absolute position reported by a device clock. If the position is
+    /// before the current counter, the device timeline has been reset, and a new generation
+    /// starts.
+    pub fn advance_to(&mut self, counter: u64) {
+        if counter < self.counter {
+            self.generation = self.generation.wrapping_add(1);
+        }
+        self.counter = counter;
+    }
+
+    /// Start a new generation, with the counter reset to zero.
+    pub fn next_generation(&self) -> Self {
+        Self {
+            counter: 0,
+            samplerate: self.samplerate,
+            generation: self.generation.wrapping_add(1),
+        }
+    }
+
+    /// Number of samples elapsed since an earlier timestamp, or `None` if the timestamps are of
+    /// different generations, or if `earlier` is in fact later than this timestamp.
+    pub fn frames_since(&self, earlier: &Self) -> Option<u64> {
+        if self.generation != earlier.generation {
+            return None;
+        }
+        self.counter.checked_sub(earlier.counter)
+    }
+
     /// Compute the duration represented by this [`Timestamp`].
     pub fn as_duration(&self) -> Duration {
         Duration::from_secs_f64(self.as_seconds())
@@ -124,3 +267,243 @@ impl Timestamp {
         self.counter as f64 / self.samplerate
     }
 }
+
+/// Follows the timestamps of successive callbacks of a stream, to tell how many frames elapsed
+/// between them and whether the stream went through a discontinuity.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CallbackTracker {
+    last: Option<(Timestamp, usize)>,
+    discontinuity: bool,
+}
+
+impl CallbackTracker {
+    /// Flag the next callback as discontinuous, for example after an xrun or a recovery.
+    pub(crate) fn mark_discontinuity(&mut self) {
+        self.discontinuity = true;
+    }
+
+    /// Register a callback processing `frames` frames at `timestamp`, returning the number of
+    /// frames elapsed since the previous callback, and whether there is a discontinuity between
+    /// them.
+    ///
+    /// The previous callback is expected to be followed right after its last frame. Callbacks
+    /// skipping more than a buffer past that frame, or going back more than a buffer before the
+    /// previous callback, are discontinuities as well, while smaller differences are jitter of the
+    /// device clock. Callbacks without any frames are not registered, and a pending discontinuity
+    /// is reported to the next callback with frames.
+    pub(crate) fn next(&mut self, timestamp: Timestamp, frames: usize) -> (Option<u64>, bool) {
+        let elapsed = self
+            .last
+            .and_then(|(last, _)| timestamp.frames_since(&last));
+        if frames == 0 {
+            return (elapsed, false);
+        }
+        let gap = self.last.is_some_and(|(last, last_frames)| {
+            let last_frames = last_frames as u64;
+            timestamp.generation != last.generation
+                || timestamp.counter > last.counter + 2 * last_frames
+                || timestamp.counter + last_frames < last.counter
+        });
+        let discontinuity = std::mem::take(&mut self.discontinuity) || gap;
+        self.last = Some((timestamp, frames));
+        (elapsed, discontinuity)
+    }
+}
+
+/// Continuously estimates the ratio between the rate of an audio device clock and the
+/// [host clock](crate::clock).
+///
+/// Feed it, once per callback, the number of frames the stream has processed so far along with
+/// the host time at which that frame position was reached (for instance
+/// [`AudioInput::capture_time`](crate::AudioInput::capture_time) or
+/// [`AudioCallbackContext::output_time`](crate::AudioCallbackContext::output_time)). The
+/// estimator fits a line through the recent observations, weighting them down exponentially as
+/// they get older than the configured window, so that slow changes in drift are tracked over long
+/// recordings.
+///
+/// ```rust
+/// use std::time::Duration;
+/// use interflow::clock::HostTime;
+/// use interflow::timestamp::DriftEstimator;
+/// // Simulate a device running 100 ppm fast, delivering 480 frames per callback
+/// let mut estimator = DriftEstimator::new(48000.);
+/// let device_rate = 48000. * (1. + 100e-6);
+/// for i in 0..10_000u64 {
+///     let frames = i * 480;
+///     let time = Duration::from_secs_f64(frames as f64 / device_rate);
+///     estimator.update(frames, HostTime::from_duration(time));
+/// }
+/// let estimate = estimator.estimate().unwrap();
+/// assert!((estimate.ppm() - 100.).abs() < 1.);
+/// ```
+#[derive(Debug, Clone)]
+pub struct DriftEstimator {
+    samplerate: f64,
+    window: f64,
+    origin: Option<(u64, HostTime)>,
+    last_time: f64,
+    sum_w: f64,
+    sum_w2: f64,
+    sum_x: f64,
+    sum_y: f64,
+    sum_xx: f64,
+    sum_xy: f64,
+    sum_yy: f64,
+}
+
+/// Estimate of the drift between an audio device clock and the host clock, as returned by
+/// [`DriftEstimator::estimate`].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DriftEstimate {
+    /// Ratio of the device clock rate over the host clock rate. A value above 1 means that the
+    /// device processes more frames per host second than its nominal sample rate.
+    pub ratio: f64,
+    /// Standard error of [`Self::ratio`]. The true ratio lies within `ratio ± 2 * std_error` with
+    /// roughly 95% confidence.
+    pub std_error: f64,
+}
+
+impl DriftEstimate {
+    /// Drift of the device clock relative to the host clock, in parts per million.
+    pub fn ppm(&self) -> f64 {
+        (self.ratio - 1.) * 1e6
+    }
+
+    /// Effective sample rate of the device as measured by the host clock.
+    pub fn effective_samplerate(&self, samplerate: f64) -> f64 {
+        samplerate * self.ratio
+    }
+}
+
+impl DriftEstimator {
+    /// Default window over which observations are weighted, in seconds.
+    pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+
+    /// Create a new estimator for a stream running at the given nominal sample rate.
+    pub fn new(samplerate: f64) -> Self {
+        Self::with_window(samplerate, Self::DEFAULT_WINDOW)
+    }
+
+    /// Create a new estimator whose observations lose weight with the given time constant.
+    /// Longer windows give more precise estimates, shorter windows react faster to changes.
+    pub fn with_window(samplerate: f64, window: Duration) -> Self {
+        Self {
+            samplerate,
+            window: window.as_secs_f64(),
+            origin: None,
+            last_time: 0.,
+            sum_w: 0.,
+            sum_w2: 0.,
+            sum_x: 0.,
+            sum_y: 0.,
+            sum_xx: 0.,
+            sum_xy: 0.,
+            sum_yy: 0.,
+        }
+    }
+
+    /// Nominal sample rate of the stream.
+    pub fn samplerate(&self) -> f64 {
+        self.samplerate
+    }
+
+    /// Forget all observations, for instance after the stream has been restarted.
+    pub fn reset(&mut self) {
+        *self = Self::with_window(self.samplerate, Duration::from_secs_f64(self.window));
+    }
+
+    /// Add an observation: `frames` is the number of frames processed by the stream, and `time`
+    /// the host time at which that position was reached.
+    ///
+    /// This method does not allocate, and can be called from the audio callback.
+    pub fn update(&mut self, frames: u64, time: HostTime) {
+        let (frames0, time0) = *self.origin.get_or_insert((frames, time));
+        let x = (frames as f64 - frames0 as f64) / self.samplerate;
+        let y = time.as_duration().as_secs_f64() - time0.as_duration().as_secs_f64();
+        let decay = if self.window > 0. {
+            (-(y - self.last_time).max(0.) / self.window).exp()
+        } else {
+            1.
+        };
+        self.last_time = y;
+
+        self.sum_w = self.sum_w * decay + 1.;
+        self.sum_w2 = self.sum_w2 * decay * decay + 1.;
+        self.sum_x = self.sum_x * decay + x;
+        self.sum_y = self.sum_y * decay + y;
+        self.sum_xx = self.sum_xx * decay + x * x;
+        self.sum_xy = self.sum_xy * decay + x * y;
+        self.sum_yy = self.sum_yy * decay + y * y;
+    }
+
+    /// Current drift estimate, or `None` if not enough observations have been made yet.
+    pub fn estimate(&self) -> Option<DriftEstimate> {
+        let n = self.sum_w * self.sum_w / self.sum_w2;
+        if n <= 2. {
+            return None;
+        }
+        let spread = self.sum_xx - self.sum_x * self.sum_x / self.sum_w;
+        if spread <= 0. {
+            return None;
+        }
+        // Slope in host seconds per device second, and intercept of the fitted line
+        let slope = (self.sum_xy - self.sum_x * self.sum_y / self.sum_w) / spread;
+        if slope <= 0. {
+            return None;
+        }
+        let intercept = (self.sum_y - slope * self.sum_x) / self.sum_w;
+        let sse = (self.sum_yy - intercept * self.sum_y - slope * self.sum_xy).max(0.);
+        let variance = sse / self.sum_w * n / (n - 2.);
+        let slope_error = (variance / spread).sqrt();
+        Some(DriftEstimate {
+            ratio: slope.recip(),
+            std_error: slope_error / (slope * slope),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CallbackTracker, Timestamp};
+
+    fn at(counter: u64) -> Timestamp {
+        Timestamp::from_count(48000., counter)
+    }
+
+    #[test]
+    fn test_tracker_contiguous() {
+        let mut tracker = CallbackTracker::default();
+        assert_eq!((None, false), tracker.next(at(0), 256));
+        assert_eq!((Some(256), false), tracker.next(at(256), 256));
+        // Buffers may change size between callbacks
+        assert_eq!((Some(256), false), tracker.next(at(512), 128));
+        assert_eq!((Some(128), false), tracker.next(at(640), 512));
+    }
+
+    #[test]
+    fn test_tracker_gaps() {
+        let mut tracker = CallbackTracker::default();
+        tracker.next(at(0), 256);
+        assert_eq!((Some(1024), true), tracker.next(at(1024), 256));
+        // Small differences from the expected position are jitter
+        assert_eq!((Some(300), false), tracker.next(at(1324), 256));
+        assert_eq!((Some(200), false), tracker.next(at(1524), 256));
+        assert_eq!((None, false), tracker.next(at(1300), 256));
+        // Going back more than a buffer is a discontinuity
+        assert_eq!((None, true), tracker.next(at(0), 256));
+        assert_eq!((None, true), tracker.next(at(256).next_generation(), 256));
+    }
+
+    #[test]
+    fn test_tracker_skips_empty_callbacks() {
+        let mut tracker = CallbackTracker::default();
+        tracker.next(at(0), 256);
+        assert_eq!((Some(256), false), tracker.next(at(256), 0));
+        assert_eq!((Some(256), false), tracker.next(at(256), 256));
+        tracker.mark_discontinuity();
+        // A pending discontinuity is kept for the next callback with frames
+        assert_eq!((Some(256), false), tracker.next(at(512), 0));
+        assert_eq!((Some(256), true), tracker.next(at(512), 256));
+        assert_eq!((Some(256), false), tracker.next(at(768), 256));
+    }
+}
@@ -0,0 +1,219 @@
+//! Timeline shared between the callbacks of several streams.
+//!
+//! A [`Transport`] holds a play/stop state, a position and an optional tempo, which control
+//! threads update and which stream callbacks read through [`TransportSnapshot`]s. The position
+//! advances with the [host clock](crate::clock), so that callbacks of streams running on
+//! different devices agree on it, and can convert it to and from their own [`Timestamp`]s.
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use interflow::clock::HostTime;
+//! use interflow::timestamp::Timestamp;
+//! use interflow::transport::Transport;
+//! let transport = Transport::new();
+//! transport.seek(Duration::from_secs(10));
+//! transport.play();
+//!
+//! // In a callback, knowing that the buffer starting at `timestamp` is played at `time`
+//! let snapshot = transport.snapshot();
+//! let timestamp = Timestamp::from_count(48000., 4800);
+//! let time = HostTime::now() + Duration::from_millis(5);
+//! let position = snapshot.position_at_timestamp(timestamp, timestamp, time);
+//! assert!(position >= Duration::from_secs(10));
+//! // Stream timestamp at which the transport reaches 11 seconds
+//! let at = snapshot
+//!     .timestamp_at_position(Duration::from_secs(11), timestamp, time)
+//!     .unwrap();
+//! assert!(at.counter > timestamp.counter);
+//! ```
+
+use std::sync::atomic::{fence, AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::clock::HostTime;
+use crate::timestamp::Timestamp;
+
+/// Shared transport, cheaply cloned to be given to several streams and control threads.
+///
+/// Reading the transport from callbacks with [`Self::snapshot`] neither allocates nor locks.
+/// Updates are serialized between control threads.
+#[derive(Debug, Clone, Default)]
+pub struct Transport {
+    shared: Arc<Shared>,
+}
+
+/// State of a [`Transport`] at a given time.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TransportSnapshot {
+    /// Whether the transport is playing.
+    pub playing: bool,
+    /// Position of the transport at [`Self::anchor`].
+    pub position: Duration,
+    /// Host time at which the transport was at [`Self::position`].
+    pub anchor: HostTime,
+    /// Tempo in beats per minute, if the transport has one.
+    pub tempo: Option<f64>,
+}
+
+impl Transport {
+    /// Create a new, stopped transport at position zero, without tempo.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the current state of the transport.
+    pub fn snapshot(&self) -> TransportSnapshot {
+        self.shared.read()
+    }
+
+    /// Start playing from the current position.
+    pub fn play(&self) {
+        self.shared.update(|snapshot, now| {
+            if !snapshot.playing {
+                snapshot.anchor = now;
+                snapshot.playing = true;
+            }
+        });
+    }
+
+    /// Stop playing, keeping the current position.
+    pub fn stop(&self) {
+        self.shared.update(|snapshot, now| {
+            snapshot.position = snapshot.position_at(now);
+            snapshot.anchor = now;
+            snapshot.playing = false;
+        });
+    }
+
+    /// Move the transport to the given position, without changing its play state.
+    pub fn seek(&self, position: Duration) {
+        self.shared.update(|snapshot, now| {
+            snapshot.position = position;
+            snapshot.anchor = now;
+        });
+    }
+
+    /// Set the tempo of the transport, in beats per minute.
+    pub fn set_tempo(&self, tempo: Option<f64>) {
+        self.shared.update(|snapshot, _| snapshot.tempo = tempo);
+    }
+}
+
+impl TransportSnapshot {
+    /// Position of the transport at the given host time.
+    pub fn position_at(&self, time: HostTime) -> Duration {
+        if self.playing {
+            self.position + time.saturating_duration_since(self.anchor)
+        } else {
+            self.position
+        }
+    }
+
+    /// Position of the transport at the given host time, in beats, if it has a tempo.
+    pub fn beats_at(&self, time: HostTime) -> Option<f64> {
+        let tempo = self.tempo?;
+        Some(self.position_at(time).as_secs_f64() * tempo / 60.)
+    }
+
+    /// Position of the transport when a stream reaches `timestamp`, given that the stream was at
+    /// `reference` at host time `reference_time` (for instance, the timestamp and output time of
+    /// the current callback).
+    pub fn position_at_timestamp(
+        &self,
+        timestamp: Timestamp,
+        reference: Timestamp,
+        reference_time: HostTime,
+    ) -> Duration {
+        let offset = timestamp.counter as f64 - reference.counter as f64;
+        let offset = Duration::from_secs_f64(offset.abs() / timestamp.samplerate);
+        let time = if timestamp.counter >= reference.counter {
+            reference_time + offset
+        } else {
+            reference_time.saturating_sub(offset)
+        };
+        self.position_at(time)
+    }
+
+    /// Stream timestamp at which the transport reaches `position`, given that the stream was at
+    /// `reference` at host time `reference_time`. Returns `None` if the transport is stopped, or
+    /// if it reached that position before the start of the stream.
+    pub fn timestamp_at_position(
+        &self,
+        position: Duration,
+        reference: Timestamp,
+        reference_time: HostTime,
+    ) -> Option<Timestamp> {
+        if !self.playing {
+            return None;
+        }
+        let time = self.anchor.as_duration().as_secs_f64() + position.as_secs_f64()
+            - self.position.as_secs_f64();
+        let offset = time - reference_time.as_duration().as_secs_f64();
+        let counter = reference.counter as f64 + offset * reference.samplerate;
+        if counter < 0. {
+            return None;
+        }
+        Some(Timestamp {
+            counter: counter.round() as u64,
+            ..reference
+        })
+    }
+}
+
+/// Transport state, behind a sequence lock so that callbacks can read it without locking.
+#[derive(Debug, Default)]
+struct Shared {
+    writer: Mutex<()>,
+    sequence: AtomicU64,
+    playing: AtomicBool,
+    position: AtomicU64,
+    anchor: AtomicU64,
+    tempo: AtomicU64,
+}
+
+impl Shared {
+    fn read(&self) -> TransportSnapshot {
+        loop {
+            let sequence = self.sequence.load(Ordering::Acquire);
+            if sequence % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let playing = self.playing.load(Ordering::Relaxed);
+            let position = self.position.load(Ordering::Relaxed);
+            let anchor = self.anchor.load(Ordering::Relaxed);
+            let tempo = self.tempo.load(Ordering::Relaxed);
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) != sequence {
+                continue;
+            }
+            return TransportSnapshot {
+                playing,
+                position: Duration::from_nanos(position),
+                anchor: HostTime::from_duration(Duration::from_nanos(anchor)),
+                // A tempo of zero is stored for no tempo
+                tempo: Some(f64::from_bits(tempo)).filter(|tempo| *tempo > 0.),
+            };
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut TransportSnapshot, HostTime)) {
+        let _guard = self.writer.lock().unwrap_or_else(|err| err.into_inner());
+        let mut snapshot = self.read();
+        f(&mut snapshot, HostTime::now());
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(sequence + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.playing.store(snapshot.playing, Ordering::Relaxed);
+        self.position
+            .store(snapshot.position.as_nanos() as u64, Ordering::Relaxed);
+        self.anchor.store(
+            snapshot.anchor.as_duration().as_nanos() as u64,
+            Ordering::Relaxed,
+        );
+        self.tempo
+            .store(snapshot.tempo.unwrap_or(0.).to_bits(), Ordering::Relaxed);
+        self.sequence.store(sequence + 2, Ordering::Release);
+    }
+}
@@ -0,0 +1,192 @@
+//! Checks that the audio processing of the callbacks provided by the library does not allocate,
+//! once they have been prepared.
+
+use assert_no_alloc::{assert_no_alloc, violation_count, AllocDisabler};
+use interflow::audio_buffer::AudioBuffer;
+use interflow::combinators::CallbackExt;
+use interflow::duplex::{duplex_callbacks, AudioDuplexCallback};
+use interflow::signals::{PinkNoise, Sine, WhiteNoise};
+use interflow::switcher::CallbackSwitcher;
+use interflow::timestamp::Timestamp;
+use interflow::{
+    AudioCallbackContext, AudioInput, AudioInputCallback, AudioOutput, AudioOutputCallback,
+    ChannelTrim, StreamConfig,
+};
+
+#[global_allocator]
+static ALLOCATOR: AllocDisabler = AllocDisabler;
+
+const SAMPLERATE: f64 = 48000.;
+const FRAMES: usize = 512;
+
+fn context(timestamp: Timestamp) -> AudioCallbackContext {
+    AudioCallbackContext {
+        stream_config: StreamConfig {
+            samplerate: SAMPLERATE,
+            input_channels: 0,
+            output_channels: 0b11,
+            buffer_size_range: (Some(FRAMES.into()), Some(FRAMES.into())),
+            exclusive: false,
+            fallback_to_shared: false,
+            sample_format: None,
+            input_trim: ChannelTrim::default(),
+            output_trim: ChannelTrim::default(),
+            metering: false,
+        },
+        timestamp,
+        output_time: None,
+        deadline: None,
+        queued_frames: None,
+        frames_since_last_callback: None,
+        discontinuity: false,
+    }
+}
+
+/// Prepare the callback, then process a few buffers checking that it does not allocate.
+fn assert_realtime_safe(mut callback: impl AudioOutputCallback) {
+    let mut buffer = AudioBuffer::<f32>::zeroed(2, FRAMES);
+    let mut timestamp = Timestamp::new(SAMPLERATE);
+    callback.prepare(context(timestamp));
+    for _ in 0..8 {
+        assert_no_alloc(|| {
+            let output = AudioOutput {
+                buffer: buffer.as_mut(),
+                timestamp,
+            };
+            callback.on_output_data(context(timestamp), output);
+        });
+        timestamp += FRAMES as u64;
+    }
+    assert_eq!(violation_count(), 0);
+}
+
+fn input_context(timestamp: Timestamp) -> AudioCallbackContext {
+    let mut context = context(timestamp);
+    context.stream_config.input_channels = 0b11;
+    context.stream_config.output_channels = 0;
+    context
+}
+
+/// Prepare the input callback, then process a few buffers checking that it does not allocate.
+fn assert_input_realtime_safe(mut callback: impl AudioInputCallback) {
+    let buffer = AudioBuffer::<f32>::zeroed(2, FRAMES);
+    let mut timestamp = Timestamp::new(SAMPLERATE);
+    callback.prepare(input_context(timestamp));
+    for _ in 0..8 {
+        assert_no_alloc(|| {
+            let input = AudioInput {
+                buffer: buffer.as_ref(),
+                timestamp,
+                capture_time: None,
+            };
+            callback.on_input_data(input_context(timestamp), input);
+        });
+        timestamp += FRAMES as u64;
+    }
+    assert_eq!(violation_count(), 0);
+}
+
+/// Input callback measuring the level of its input.
+struct Level(f32);
+
+impl AudioInputCallback for Level {
+    fn on_input_data(&mut self, _context: AudioCallbackContext, input: AudioInput<f32>) {
+        self.0 = input.buffer.rms();
+    }
+}
+
+#[test]
+fn signals() {
+    assert_realtime_safe(Sine::new(440.));
+    assert_realtime_safe(WhiteNoise::new());
+    assert_realtime_safe(PinkNoise::new());
+}
+
+#[test]
+fn combinators() {
+    let (tx, _rx) = rtrb::RingBuffer::new(64);
+    let callback = Sine::new(440.)
+        .chain(WhiteNoise::new().with_gain(-24.))
+        .map_buffer(|mut buffer| buffer.change_amplitude(0.5))
+        .with_gain(-6.)
+        .with_meter(tx)
+        .with_max_buffer_size(100);
+    assert_realtime_safe(callback);
+}
+
+#[test]
+fn input_combinators() {
+    let (tx, _rx) = rtrb::RingBuffer::new(64);
+    let callback = Level(0.)
+        .map_buffer(|mut buffer| buffer.change_amplitude(0.5))
+        .with_gain(-6.)
+        .with_meter(tx)
+        .with_max_buffer_size(100);
+    assert_input_realtime_safe(callback);
+}
+
+#[test]
+fn duplex() {
+    struct Passthrough;
+
+    impl AudioDuplexCallback for Passthrough {
+        fn on_audio_data(
+            &mut self,
+            _context: AudioCallbackContext,
+            input: AudioInput<f32>,
+            mut output: AudioOutput<f32>,
+        ) {
+            output.buffer.mix(input.buffer, 1.);
+        }
+    }
+
+    let mut input_context = input_context(Timestamp::new(SAMPLERATE));
+    // Resample from 44.1 kHz to the output sample rate
+    input_context.stream_config.samplerate = 44100.;
+    let (mut input_proxy, mut duplex_callback) =
+        duplex_callbacks(&input_context.stream_config, Passthrough);
+    // The output side shares its sample rate with the input side when it first runs
+    let mut output = AudioBuffer::<f32>::zeroed(2, FRAMES);
+    let output_context = context(Timestamp::new(SAMPLERATE));
+    duplex_callback.prepare(output_context);
+    duplex_callback.on_output_data(
+        output_context,
+        AudioOutput {
+            buffer: output.as_mut(),
+            timestamp: output_context.timestamp,
+        },
+    );
+    // Channels hold distinct values, which must not swap when the queue overflows
+    let input = AudioBuffer::<f32>::fill_with(2, FRAMES, |channel, _| [0.5, -0.5][channel]);
+    for i in 0..100 {
+        let mut input_context = input_context;
+        input_context.timestamp += (i * FRAMES) as u64;
+        assert_no_alloc(|| {
+            let input = AudioInput {
+                buffer: input.as_ref(),
+                timestamp: input_context.timestamp,
+                capture_time: None,
+            };
+            input_proxy.on_input_data(input_context, input);
+        });
+    }
+    output.as_interleaved_mut().fill(0.);
+    duplex_callback.on_output_data(
+        output_context,
+        AudioOutput {
+            buffer: output.as_mut(),
+            timestamp: output_context.timestamp,
+        },
+    );
+    assert!(output.get_channel(0).iter().all(|&sample| sample == 0.5));
+    assert!(output.get_channel(1).iter().all(|&sample| sample == -0.5));
+    assert_realtime_safe(duplex_callback);
+}
+
+#[test]
+fn switcher() {
+    let (switcher, mut handle) = CallbackSwitcher::new(Sine::new(440.), FRAMES * 3);
+    assert!(handle.switch(Sine::new(880.)));
+    assert!(handle.switch(WhiteNoise::new()));
+    assert_realtime_safe(switcher);
+}